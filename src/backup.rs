@@ -0,0 +1,78 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::filename::split_extension;
+
+/// The kind of data held by a file in the `Backups` folder, determined by the
+/// prefix of its filename, e.g. `chatsettingsbackup` for
+/// `chatsettingsbackup.db.crypt1`
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum BackupKind {
+    /// The key used to encrypt backups, e.g. `encrypted_backup.key`
+    Key,
+
+    /// Chat and business settings
+    Settings,
+
+    /// Chat wallpapers
+    Wallpapers,
+
+    /// Any backup not listed above, e.g. stickers or contacts
+    Other,
+}
+
+impl BackupKind {
+    /// All kinds, in the order they are displayed
+    pub const ALL: [BackupKind; 4] = [BackupKind::Key, BackupKind::Settings, BackupKind::Wallpapers, BackupKind::Other];
+
+    /// Classifies a backup file from its path
+    pub fn from_path(path: &Path) -> BackupKind {
+        let filename = path.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+        match split_extension(&filename).0 {
+            "encrypted_backup" | "key" => BackupKind::Key,
+            "chatsettingsbackup" | "commerce_backup" => BackupKind::Settings,
+            "wallpapers" | "wallpaper" => BackupKind::Wallpapers,
+            _ => BackupKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for BackupKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BackupKind::Key => "Key",
+            BackupKind::Settings => "Settings",
+            BackupKind::Wallpapers => "Wallpapers",
+            BackupKind::Other => "Other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How many versions of each kind of backup are kept in the archive. Versions
+/// accumulate when WhatsApp changes the extension of a backup, e.g. from
+/// `.crypt1` to `.crypt14`, since the new file does not replace the old one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BackupRetention {
+    pub key: usize,
+    pub settings: usize,
+    pub wallpapers: usize,
+    pub other: usize,
+}
+
+impl BackupRetention {
+    /// The number of versions of a backup of the given kind which are kept
+    pub fn keep(&self, kind: BackupKind) -> usize {
+        match kind {
+            BackupKind::Key => self.key,
+            BackupKind::Settings => self.settings,
+            BackupKind::Wallpapers => self.wallpapers,
+            BackupKind::Other => self.other,
+        }
+    }
+}
+
+impl Default for BackupRetention {
+    /// Only the newest version of each backup is kept
+    fn default() -> BackupRetention { BackupRetention { key: 1, settings: 1, wallpapers: 1, other: 1 } }
+}
@@ -1,20 +1,51 @@
 #![warn(clippy::pedantic)]
-#![allow(clippy::uninlined_format_args, clippy::doc_markdown)]
+#![allow(clippy::uninlined_format_args, clippy::doc_markdown, clippy::struct_excessive_bools)]
 
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
 
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Offset, Utc};
+use clap::builder::RangedU64ValueParser;
 use clap::{Parser, ValueEnum};
+#[cfg(feature = "progress")]
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
 use thiserror::Error;
-use waa::{ActionType, DataLimit, Error, FileIndex, FilePredicate, FileQuery, FileScore, IndexType};
+use waa::{
+    run_backup, ActionType, BackupError, BackupMode, BackupOptions, BackupReport, BackupRetention, CancelToken,
+    DataLimit, DbPattern, EncryptionKey, Error, ExportLayout, FileIndex, FilePredicate, FileQuery, FileScore,
+    HistoryRecord, IndexOptions, IndexType, MediaCategory, Progress, Timezone, DEFAULT_DB_DATE_FORMAT,
+    DEFAULT_DB_QUIET_PERIOD,
+};
+
+/// Environment variable from which the encryption key is read
+const KEY_VARIABLE: &str = "WAA_KEY";
+
+/// Largest buffer accepted by --copy-chunk-size
+const MAX_COPY_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Exit code of a run interrupted by Ctrl-C
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 fn main() {
-    if let Err(e) = main_internal() {
+    let cli = Cli::parse();
+    let mut report = RunReport::new(&cli);
+    let result = main_internal(&cli, &mut report);
+    report.finish(&result);
+    if let Err(e) = publish_report(&cli, &report) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = result {
         eprintln!("{}", e);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
 enum OperationMode {
     /// updates archive from WhatsApp folder
     #[clap(name = "backup")]
@@ -27,6 +58,34 @@ enum OperationMode {
     /// same as trim, but also restores files to WhatsApp folder (ONLY media)
     #[clap(name = "sync")]
     Sync,
+
+    /// prints a breakdown of the archive contents without changing any files
+    #[clap(name = "stats")]
+    Stats,
+
+    /// checks the archive contents against its manifest without changing any
+    /// files
+    #[clap(name = "verify")]
+    Verify,
+
+    /// checks that the WhatsApp and archive folders are valid without
+    /// changing any files
+    #[clap(name = "check")]
+    Check,
+
+    /// updates only the databases and backups in the archive, skipping media
+    #[clap(name = "db-only")]
+    #[serde(rename = "db-only")]
+    DbOnly,
+
+    /// copies the archived media to --export-dir in folders by date, without
+    /// changing the archive
+    #[clap(name = "export")]
+    Export,
+
+    /// prints the runs recorded with --history without changing any files
+    #[clap(name = "history")]
+    History,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -47,21 +106,343 @@ enum FileOrdering {
     /// tries to balance between newer and smaller
     #[clap(name = "smaller_newer")]
     SmallerNewer,
+
+    /// as smaller_newer, but values some kinds of media more than others
+    /// according to --category-weight
+    #[clap(name = "smaller_newer_weighted")]
+    SmallerNewerWeighted,
+
+    /// keep the most recently accessed (falls back to modification time if
+    /// access times are not recorded)
+    #[clap(name = "accessed")]
+    Accessed,
+
+    /// balances size and age according to --size-weight and --age-weight
+    #[clap(name = "weighted")]
+    Weighted,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum ExportFolders {
+    /// one folder per year
+    #[clap(name = "year")]
+    Year,
+
+    /// one folder per month
+    #[clap(name = "year-month")]
+    YearMonth,
+
+    /// one folder per day
+    #[clap(name = "year-month-day")]
+    YearMonthDay,
+}
+
+impl ExportFolders {
+    fn to_layout(self) -> ExportLayout {
+        match self {
+            ExportFolders::Year => ExportLayout::Year,
+            ExportFolders::YearMonth => ExportLayout::YearMonth,
+            ExportFolders::YearMonthDay => ExportLayout::YearMonthDay,
+        }
+    }
 }
 
-impl From<FileOrdering> for FileScore {
-    fn from(o: FileOrdering) -> FileScore {
-        match o {
+impl FileOrdering {
+    fn to_score(self, cli: &Cli) -> FileScore {
+        match self {
             FileOrdering::Newer => FileScore::Newer,
             FileOrdering::Smaller => FileScore::Smaller,
             FileOrdering::SmallerNewer => FileScore::SmallerNewer,
+            FileOrdering::SmallerNewerWeighted => {
+                let mut category_weights = FileScore::default_category_weights();
+                category_weights.extend(cli.category_weight.iter().copied());
+                FileScore::SmallerNewerWeighted { category_weights }
+            }
+            FileOrdering::Accessed => FileScore::RecentlyAccessed,
+            FileOrdering::Weighted => FileScore::Weighted {
+                size_weight: cli.size_weight,
+                age_weight: cli.age_weight,
+                category_weights: cli.category_weight.iter().copied().collect(),
+            },
+        }
+    }
+}
+
+/// Renders progress of file copies as an overall bar and a bar for the
+/// current file, printing messages above them
+#[cfg(feature = "progress")]
+#[derive(Debug)]
+struct ProgressBars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    current: Option<ProgressBar>,
+}
+
+#[cfg(feature = "progress")]
+impl ProgressBars {
+    fn new() -> ProgressBars {
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::hidden());
+        ProgressBars { multi, overall, current: None }
+    }
+
+    fn bar_style(template: &str) -> ProgressStyle {
+        ProgressStyle::with_template(template).expect("Invalid progress bar template").progress_chars("=> ")
+    }
+}
+
+#[cfg(feature = "progress")]
+impl Progress for ProgressBars {
+    fn mirror_started(&mut self, total_bytes: u64, total_files: usize) {
+        self.overall.set_length(total_bytes);
+        self.overall.set_position(0);
+        self.overall.set_style(Self::bar_style(
+            "{msg} [{bar:40}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, ETA {eta})",
+        ));
+        self.overall.set_message(format!("{} files", total_files));
+        self.overall.reset_eta();
+    }
+
+    fn file_started(&mut self, path: &Path, size: u64) {
+        let current = self.multi.add(ProgressBar::new(size));
+        current.set_style(Self::bar_style("{wide_msg} [{bar:40}] {binary_bytes}/{binary_total_bytes}"));
+        current.set_message(path.display().to_string());
+        self.current = Some(current);
+    }
+
+    fn bytes_copied(&mut self, count: u64) {
+        self.overall.inc(count);
+        if let Some(current) = &self.current {
+            current.inc(count);
+        }
+    }
+
+    fn file_finished(&mut self, _path: &Path) {
+        if let Some(current) = self.current.take() {
+            current.finish_and_clear();
+            self.multi.remove(&current);
+        }
+    }
+
+    fn mirror_finished(&mut self) {
+        // A file is left unfinished if the operation failed while copying it
+        self.file_finished(Path::new(""));
+        self.overall.finish_and_clear();
+    }
+
+    fn message(&mut self, message: &str) {
+        // Printing while the bars are drawn would garble them
+        self.multi.suspend(|| println!("{}", message));
+    }
+}
+
+/// Prints the messages describing each step taken
+#[derive(Debug)]
+struct Printer;
+
+impl Progress for Printer {
+    fn message(&mut self, message: &str) {
+        println!("{}", message);
+    }
+}
+
+/// Forwards progress notifications to each of several observers
+#[derive(Debug)]
+struct Observers(Vec<Box<dyn Progress>>);
+
+impl Progress for Observers {
+    fn mirror_started(&mut self, total_bytes: u64, total_files: usize) {
+        self.0.iter_mut().for_each(|o| o.mirror_started(total_bytes, total_files));
+    }
+
+    fn file_started(&mut self, path: &Path, size: u64) { self.0.iter_mut().for_each(|o| o.file_started(path, size)); }
+
+    fn bytes_copied(&mut self, count: u64) { self.0.iter_mut().for_each(|o| o.bytes_copied(count)); }
+
+    fn file_finished(&mut self, path: &Path) { self.0.iter_mut().for_each(|o| o.file_finished(path)); }
+
+    fn mirror_finished(&mut self) { self.0.iter_mut().for_each(|o| o.mirror_finished()); }
+
+    fn file_deleted(&mut self, path: &Path, size: u64) { self.0.iter_mut().for_each(|o| o.file_deleted(path, size)); }
+
+    fn file_too_large(&mut self, path: &Path, size: u64) {
+        self.0.iter_mut().for_each(|o| o.file_too_large(path, size));
+    }
+
+    fn message(&mut self, message: &str) { self.0.iter_mut().for_each(|o| o.message(message)); }
+}
+
+/// Where the events written by --events-jsonl go
+#[derive(Debug)]
+enum EventOutput {
+    Stdout(std::io::Stdout),
+    File(std::fs::File),
+}
+
+/// The destination of the events stream, shared by both indices. Writing
+/// stops with a warning after the first failure.
+#[derive(Debug)]
+struct EventSink {
+    path: PathBuf,
+    output: Option<EventOutput>,
+}
+
+impl EventSink {
+    /// Opens the file at `path`, or standard output if `path` is `-`
+    fn open(path: &Path) -> std::io::Result<EventSink> {
+        let output = if path == Path::new("-") {
+            EventOutput::Stdout(std::io::stdout())
+        } else {
+            EventOutput::File(std::fs::File::create(path)?)
+        };
+        Ok(EventSink { path: path.to_path_buf(), output: Some(output) })
+    }
+
+    /// Writes `event` as a line of JSON, flushing it so that it can be read
+    /// as soon as it happens
+    fn write(&mut self, event: &EventRecord) {
+        let Some(output) = &mut self.output else { return };
+        let writer: &mut dyn std::io::Write = match output {
+            EventOutput::Stdout(stdout) => stdout,
+            EventOutput::File(file) => file,
+        };
+        let mut line = serde_json::to_string(event).expect("Unable to serialize event");
+        line.push('\n');
+        if let Err(e) = writer.write_all(line.as_bytes()).and_then(|()| writer.flush()) {
+            eprintln!("Unable to write events to {}, no more will be written: {}", self.path.display(), e);
+            self.output = None;
+        }
+    }
+}
+
+/// One line of the events stream
+#[derive(Debug, Serialize)]
+struct EventRecord<'a> {
+    /// When the event happened, in seconds since the Unix epoch
+    timestamp: i64,
+
+    /// What happened: `copy`, `restore`, `delete` or `skip`
+    event: &'a str,
+
+    /// The folder the event happened in: `archive` or `whatsapp`
+    folder: &'a str,
+
+    /// The path of the file, relative to the folder except for entries
+    /// skipped while indexing
+    path: String,
+
+    /// The size of the file in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+
+    /// Why a file was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Writes an event for each file copied into, deleted from or skipped by one
+/// of the indices
+#[derive(Debug)]
+struct EventStream {
+    sink: Rc<RefCell<EventSink>>,
+    folder: &'static str,
+    copy_event: &'static str,
+    current_size: u64,
+}
+
+impl EventStream {
+    fn new(sink: Rc<RefCell<EventSink>>, folder: &'static str, copy_event: &'static str) -> EventStream {
+        EventStream { sink, folder, copy_event, current_size: 0 }
+    }
+
+    fn emit(&self, event: &str, path: &Path, bytes: Option<u64>, reason: Option<String>) {
+        let path = path.to_string_lossy().into_owned();
+        let timestamp = Utc::now().timestamp();
+        self.sink.borrow_mut().write(&EventRecord { timestamp, event, folder: self.folder, path, bytes, reason });
+    }
+
+    /// Writes a `skip` event for each entry left out when `index` was built
+    fn emit_skipped(&self, index: &FileIndex) {
+        for entry in index.skipped_entries() {
+            self.emit("skip", &entry.path, None, Some(entry.reason.to_string()));
         }
     }
 }
 
+impl Progress for EventStream {
+    fn file_started(&mut self, _path: &Path, size: u64) { self.current_size = size; }
+
+    fn file_finished(&mut self, path: &Path) { self.emit(self.copy_event, path, Some(self.current_size), None); }
+
+    fn file_deleted(&mut self, path: &Path, size: u64) { self.emit("delete", path, Some(size), None); }
+
+    fn file_too_large(&mut self, path: &Path, size: u64) {
+        self.emit("skip", path, Some(size), Some("larger than the maximum file size".to_string()));
+    }
+}
+
 // Using `bytefmt::parse` directly angers `clap`
 fn parse_byte_count(s: &str) -> Result<u64, &'static str> { bytefmt::parse(s) }
 
+/// Parses a media category and its weight, e.g. "voice-notes=2". Categories
+/// are named by their display name in lower case with hyphens for spaces.
+fn parse_category_weight(s: &str) -> Result<(MediaCategory, f64), String> {
+    let (name, weight) = s.split_once('=').ok_or("expected CATEGORY=WEIGHT")?;
+    let category = MediaCategory::ALL
+        .into_iter()
+        .find(|c| c.to_string().to_lowercase().replace(' ', "-") == name.trim().to_lowercase())
+        .ok_or_else(|| format!("unknown media category '{}'", name))?;
+    let weight = parse_weight(weight.trim()).map_err(|e| format!("invalid weight '{}': {}", weight, e))?;
+    Ok((category, weight))
+}
+
+/// Parses a weight, which must be a finite number
+fn parse_weight(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(weight) if weight.is_finite() => Ok(weight),
+        Ok(_) => Err("must be a finite number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a copy buffer size, which must be non-zero and at most 64MiB
+fn parse_chunk_size(s: &str) -> Result<usize, &'static str> {
+    match bytefmt::parse(s)? {
+        0 => Err("chunk size must be greater than zero"),
+        size if size > MAX_COPY_CHUNK_SIZE as u64 => Err("chunk size must be at most 64MiB"),
+        size => Ok(usize::try_from(size).expect("Chunk size checked against maximum")),
+    }
+}
+
+/// Parses a timezone given as `local`, `UTC` or an offset such as `+05:30`
+fn parse_timezone(s: &str) -> Result<Timezone, chrono::ParseError> {
+    match s {
+        "local" => Ok(Timezone::Local),
+        "UTC" | "utc" | "Z" => Ok(Timezone::Fixed(Utc.fix())),
+        _ => s.parse().map(Timezone::Fixed),
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime, chrono::ParseError> { NaiveTime::parse_from_str(s, "%H:%M") }
+
+fn parse_date(s: &str) -> Result<NaiveDate, chrono::ParseError> { NaiveDate::parse_from_str(s, "%Y-%m-%d") }
+
+/// Parses a fraction between 0 and 1 inclusive
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        Ok(_) => Err("must be between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a database pattern given as a prefix, optionally followed by `:`
+/// and the format of the dates of older backups
+fn parse_db_pattern(s: &str) -> Result<DbPattern, Error> {
+    let (prefix, date_format) = s.split_once(':').unwrap_or((s, DEFAULT_DB_DATE_FORMAT));
+    DbPattern::new(prefix, date_format)
+}
+
 #[derive(Debug, Parser)]
 #[clap(author, version, about = "WhatsApp Archiver")]
 struct Cli {
@@ -77,18 +458,131 @@ struct Cli {
     /// Limit on size of WhatsApp folder with suffix e.g. 512MiB
     size_limit: Option<u64>,
 
+    #[clap(long = "reserve", value_parser = parse_byte_count)]
+    /// Amount to stay below the size limit by, with suffix e.g. 500MiB
+    reserve: Option<u64>,
+
+    #[clap(long = "min-free-after", value_parser = parse_byte_count)]
+    /// Trim until at least this much space is free on the WhatsApp folder's
+    /// filesystem, with suffix e.g. 2GiB
+    min_free_after: Option<u64>,
+
+    #[clap(long = "safe-trim", action)]
+    /// Before deleting each file from the WhatsApp folder, check it has not
+    /// changed since the folder was indexed, and keep it if it has
+    safe_trim: bool,
+
+    #[clap(long = "parallel-delete", action)]
+    /// Delete files trimmed from the WhatsApp folder using the number of
+    /// threads given by --jobs, which is faster over slow connections such
+    /// as MTP. Has no effect with --safe-trim.
+    parallel_delete: bool,
+
+    #[clap(long = "compact", action)]
+    /// Remove folders from the archive which no longer hold any files, e.g.
+    /// after --mirror-delete or --archive-max-age
+    compact: bool,
+
     #[clap(short = 'n', long = "dry-run", action)]
     /// Print actions without modifying filesystem
     dry_run: bool,
 
+    #[clap(long = "diff", action, requires = "dry_run")]
+    /// In dry-run mode, finish by listing the files which would be added
+    /// (+), updated (~) and deleted (-) in each folder
+    diff: bool,
+
     #[clap(long = "keep-newer-than", value_parser = humantime::parse_duration)]
     /// Prioritise keeping files newer than this duration e.g. 7d
     keep_newer_than: Option<std::time::Duration>,
 
-    #[clap(value_enum, short='o', long="order", default_value_t = FileOrdering::SmallerNewer)]
+    #[clap(long = "restore-newer-than", value_parser = humantime::parse_duration)]
+    /// Only restore media newer than this duration to the WhatsApp folder in
+    /// sync mode, e.g. 30d. Older media stays in the archive
+    restore_newer_than: Option<std::time::Duration>,
+
+    #[clap(long = "restore-only")]
+    /// Only restore media within this folder (relative to the WhatsApp
+    /// folder) in sync mode. May be repeated
+    restore_only: Vec<PathBuf>,
+
+    #[clap(long = "archive-max-age", value_parser = humantime::parse_duration)]
+    /// Remove media older than this duration from the archive and never
+    /// archive it, e.g. 5years. Databases and backups are not affected
+    archive_max_age: Option<std::time::Duration>,
+
+    #[clap(long = "since", value_parser = parse_date)]
+    /// Only trim or restore media created on or after this date e.g. 2023-01-31
+    since: Option<NaiveDate>,
+
+    #[clap(long = "until", value_parser = parse_date)]
+    /// Only trim or restore media created on or before this date e.g.
+    /// 2023-12-31
+    until: Option<NaiveDate>,
+
+    #[clap(long = "incremental-since", visible_alias = "newer-only", value_parser = parse_date, num_args = 0..=1)]
+    /// Only copy media whose filename date is on or after this date, or the
+    /// newest such date in the archive if none is given. Faster, but misses
+    /// media which was renamed or backfilled with older names
+    #[allow(clippy::option_option)]
+    incremental_since: Option<Option<NaiveDate>>,
+
+    #[clap(long = "timezone", value_parser = parse_timezone, default_value = "UTC")]
+    /// Timezone of the phone, used to interpret the dates in media filenames
+    /// when computing ages. Either `local`, `UTC` or an offset e.g. +05:30
+    timezone: Timezone,
+
+    #[clap(long = "assumed-time", value_parser = parse_time, default_value = "12:00")]
+    /// Time of day assumed for media whose filename only records the date,
+    /// e.g. 12:00
+    assumed_time: NaiveTime,
+
+    #[clap(long = "pin")]
+    /// Path of a media file (relative to the WhatsApp folder) which must never
+    /// be trimmed. Can be specified multiple times
+    pin: Vec<PathBuf>,
+
+    #[clap(long = "pin-from")]
+    /// File containing paths of media files (one per line, relative to the
+    /// WhatsApp folder) which must never be trimmed
+    pin_from: Option<PathBuf>,
+
+    #[clap(long = "pin-glob")]
+    /// Glob matching paths of media files (relative to the WhatsApp folder)
+    /// which must never be trimmed e.g. "Media/WhatsApp Documents/*.pdf"
+    pin_glob: Vec<String>,
+
+    #[clap(long = "paths-from", value_name = "FILE|-")]
+    /// File containing paths of media files (one per line, relative to the
+    /// WhatsApp folder) to delete in trim mode or restore in sync mode,
+    /// instead of choosing them by size limit and order. Use "-" for
+    /// standard input
+    paths_from: Option<PathBuf>,
+
+    #[clap(long = "keep-latest-per-chat")]
+    /// Never trim the newest media file in each folder, so that no chat loses
+    /// all of its media
+    keep_latest_per_chat: bool,
+
+    #[clap(value_enum, short='o', long="order", default_value_t = FileOrdering::SmallerNewerWeighted)]
     /// Which files to try to keep on phone (ONLY media)
     order: FileOrdering,
 
+    #[clap(long = "size-weight", default_value_t = 1.0, value_parser = parse_weight)]
+    /// How strongly the weighted order penalizes larger files
+    size_weight: f64,
+
+    #[clap(long = "age-weight", default_value_t = 1.0, value_parser = parse_weight)]
+    /// How strongly the weighted order penalizes older files
+    age_weight: f64,
+
+    #[clap(long = "category-weight", value_parser = parse_category_weight, value_name = "CATEGORY=WEIGHT")]
+    /// Relative importance of a kind of media for the smaller_newer_weighted
+    /// and weighted orders e.g. "video=0.5". Categories are images, video,
+    /// audio, voice-notes, documents, stickers, animated-gifs,
+    /// profile-photos, statuses and other. Can be specified multiple times
+    category_weight: Vec<(MediaCategory, f64)>,
+
     #[clap(value_enum, short = 'M', long = "mode", default_value_t = OperationMode::Backup)]
     /// Mode of operation
     mode: OperationMode,
@@ -96,6 +590,320 @@ struct Cli {
     #[clap(short = 'k', long = "kept-dbs", default_value_t = 10)]
     /// Number of message database backups to retain in archive
     num_kept_dbs: usize,
+
+    #[clap(long = "db-pattern", value_parser = parse_db_pattern)]
+    /// Prefix of another database series in the Databases folder whose old
+    /// backups are removed like those of the message database, optionally
+    /// followed by `:` and the date format of older backups e.g.
+    /// "wa:%Y-%m-%d". Can be specified multiple times
+    db_pattern: Vec<DbPattern>,
+
+    #[clap(long = "preserve-newest-db-of-each-type", action)]
+    /// Keep the newest database backup of each file type (e.g. crypt14 and
+    /// crypt15) in the archive rather than only the newest overall
+    preserve_newest_db_of_each_type: bool,
+
+    #[clap(long = "keep-key-backups", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Number of versions of the backup encryption key to retain in archive
+    keep_key_backups: usize,
+
+    #[clap(long = "keep-settings-backups", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Number of versions of each chat settings backup to retain in archive
+    keep_settings_backups: usize,
+
+    #[clap(long = "keep-wallpaper-backups", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Number of versions of the wallpaper backup to retain in archive
+    keep_wallpaper_backups: usize,
+
+    #[clap(long = "keep-other-backups", default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    /// Number of versions of each other file in the Backups folder to retain
+    /// in archive
+    keep_other_backups: usize,
+
+    #[clap(long = "no-db-cleanup", action)]
+    /// Keep every message database backup in the archive, ignoring -k
+    no_db_cleanup: bool,
+
+    #[clap(long = "no-backup-cleanup", action)]
+    /// Keep every version of the files in the Backups folder in the archive
+    no_backup_cleanup: bool,
+
+    #[clap(long = "list-cleanup", action)]
+    /// Before removing old message databases and backups from the archive,
+    /// list them under their own headings. Combine with --dry-run to preview
+    /// retention decisions.
+    list_cleanup: bool,
+
+    #[cfg(feature = "progress")]
+    #[clap(long = "progress", action)]
+    /// Display progress bars while copying files
+    progress: bool,
+
+    #[clap(long = "compress", action)]
+    /// Store compressible files (e.g. text and unencrypted databases)
+    /// compressed in the archive
+    compress: bool,
+
+    #[clap(long = "key-file")]
+    /// File containing a key (32 bytes or 64 hex digits) used to encrypt files
+    /// in the archive. Alternatively, set WAA_KEY to 64 hex digits
+    key_file: Option<PathBuf>,
+
+    #[clap(long = "timeout", value_parser = humantime::parse_duration)]
+    /// Abort the run if it has not finished within this duration (e.g. 2h).
+    /// This is checked between files and while copying, so a hung IO call can
+    /// still overrun it
+    timeout: Option<std::time::Duration>,
+
+    #[clap(long = "seed")]
+    /// Seed for the random names of temporary files, so that runs can be
+    /// reproduced when debugging
+    seed: Option<u64>,
+
+    #[clap(long = "transactional", action)]
+    /// If copying fails, remove any files newly added by this run
+    transactional: bool,
+
+    #[clap(long = "resume-copies", action)]
+    /// Resume copies interrupted by an earlier run from the temporary file
+    /// they left behind if it matches the start of the file being copied
+    resume_copies: bool,
+
+    #[clap(long = "copy-chunk-size", value_parser = parse_chunk_size)]
+    /// Size of the buffer files are copied through, with suffix e.g. 1MiB
+    /// [default: 256KiB]
+    copy_chunk_size: Option<usize>,
+
+    #[clap(long = "verify-content", action)]
+    /// Compare the contents of all files already in the archive, copying any
+    /// that differ even if their size and modification time match (slow)
+    verify_content: bool,
+
+    #[clap(
+        long = "wait-for-quiescent",
+        value_parser = humantime::parse_duration,
+        num_args = 0..=1,
+        default_missing_value = "5m"
+    )]
+    /// Before copying, wait for at most this duration (default 5m) for
+    /// databases modified in the last few seconds to stop changing
+    wait_for_quiescent: Option<std::time::Duration>,
+
+    #[clap(long = "skip-newer-than", value_parser = humantime::parse_duration)]
+    /// Don't copy files modified within this duration, e.g. 10m, leaving them
+    /// for a later run in case they are still being written
+    skip_newer_than: Option<std::time::Duration>,
+
+    #[clap(long = "clean-temp", value_parser = humantime::parse_duration, num_args = 0..=1, default_missing_value = "1d")]
+    /// Remove temporary files left in the archive by an interrupted copy which
+    /// are older than this duration (default 1d)
+    clean_temp: Option<std::time::Duration>,
+
+    #[clap(long = "reverify", action)]
+    /// With --verify-content, also compare files which an interrupted run
+    /// already confirmed match the WhatsApp folder
+    reverify: bool,
+
+    #[clap(long = "max-deletion-fraction", value_parser = parse_fraction, default_value_t = 0.5)]
+    /// Abort if a run would delete more than this fraction of the files or
+    /// bytes in the archive
+    max_deletion_fraction: f64,
+
+    #[clap(long = "force", action)]
+    /// Proceed even if the archive appears to have insufficient free space or
+    /// a run would delete more of it than --max-deletion-fraction
+    force: bool,
+
+    #[clap(long = "checksum", action)]
+    /// Compare file contents before copying files whose timestamps differ
+    checksum: bool,
+
+    #[clap(long = "content-identity", action)]
+    /// Recognise media renamed in the WhatsApp folder by their contents, and
+    /// copy them within the archive rather than from the WhatsApp folder
+    content_identity: bool,
+
+    #[clap(long = "max-file-size", value_parser = parse_byte_count)]
+    /// Never archive files larger than this, with suffix e.g. 1GiB
+    max_file_size: Option<u64>,
+
+    #[clap(long = "skip-empty", action)]
+    /// Do not archive empty media files, which WhatsApp leaves as placeholders
+    skip_empty: bool,
+
+    #[clap(short = 'v', long = "verbose", action)]
+    /// Print additional detail, such as each entry skipped while indexing
+    verbose: bool,
+
+    #[clap(long = "follow-symlinks", action)]
+    /// Follow symbolic links to files and folders in both folders, which are
+    /// ignored by default
+    follow_symlinks: bool,
+
+    #[clap(long = "include-statuses", action)]
+    /// Archive viewed status updates, which are skipped by default
+    include_statuses: bool,
+
+    #[clap(long = "mirror-delete", action)]
+    /// Remove files from the archive which are no longer in the WhatsApp
+    /// folder, other than those trimmed by waa (backup mode only)
+    mirror_delete: bool,
+
+    #[clap(long = "export-dir", required_if_eq("mode", "export"))]
+    /// Folder to which media is copied in export mode
+    export_dir: Option<PathBuf>,
+
+    #[clap(long = "export-layout", value_enum, default_value = "year-month")]
+    /// How exported media is arranged into folders by its creation date
+    export_layout: ExportFolders,
+
+    #[clap(long = "history", action)]
+    /// Append a summary of the run to a history file in the archive folder,
+    /// which can later be printed in history mode
+    history: bool,
+
+    #[clap(long = "manifest", action)]
+    /// Record the content hashes of all archived files in a manifest in the
+    /// archive folder, which can later be checked in verify mode
+    manifest: bool,
+
+    #[clap(short = 'j', long = "jobs", default_value_t = 0)]
+    /// Number of threads used to hash files, and to delete them with
+    /// --parallel-delete (0 uses one per CPU)
+    jobs: usize,
+
+    #[clap(long = "no-canonicalize", action)]
+    /// Use folder paths as given rather than resolving them, which can fail on
+    /// MTP and some network mounts
+    no_canonicalize: bool,
+
+    #[clap(long = "tag-name", default_value = ".waa")]
+    /// Name of the file marking the archive folder as an archive
+    tag_name: String,
+
+    #[clap(long = "report")]
+    /// Write a JSON summary of the run to this file
+    report: Option<PathBuf>,
+
+    #[clap(long = "metrics-file")]
+    /// Write metrics for the Prometheus node exporter's textfile collector
+    /// to this file
+    metrics_file: Option<PathBuf>,
+
+    #[clap(long = "events-jsonl")]
+    /// Stream a JSON object per line to this file (or - for standard output)
+    /// for each file copied, restored, deleted or skipped, as it happens
+    events_jsonl: Option<PathBuf>,
+
+    #[cfg(feature = "notify")]
+    #[clap(long = "notify-url")]
+    /// POST a JSON summary of the run to this URL on completion
+    notify_url: Option<String>,
+}
+
+/// Outcome of a run
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RunStatus {
+    Success,
+    Failure,
+}
+
+/// Summary of a run, written with `--report` and posted with `--notify-url`
+#[derive(Debug, Serialize)]
+struct RunReport {
+    status: RunStatus,
+    error: Option<String>,
+    mode: OperationMode,
+    dry_run: bool,
+    #[serde(flatten)]
+    backup: BackupReport,
+}
+
+impl RunReport {
+    fn new(cli: &Cli) -> RunReport {
+        RunReport {
+            status: RunStatus::Success,
+            error: None,
+            mode: cli.mode,
+            dry_run: cli.dry_run,
+            backup: BackupReport::default(),
+        }
+    }
+
+    /// Records the result of the run
+    fn finish(&mut self, result: &Result<(), AppError>) {
+        if let Err(e) = result {
+            self.status = RunStatus::Failure;
+            self.error = Some(e.to_string());
+        }
+    }
+
+    /// Renders the report in the Prometheus text exposition format
+    fn to_prometheus(&self, timestamp: i64) -> String {
+        let mut metrics = String::new();
+        let mut metric = |name: &str, help: &str, samples: &[(&str, String)]| {
+            // Writing to a `String` cannot fail
+            let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+            for (labels, value) in samples {
+                let _ = writeln!(metrics, "{}{} {}", name, labels, value);
+            }
+        };
+        if let Some(bytes) = self.backup.archive_bytes_after.or(self.backup.archive_bytes_before) {
+            metric("waa_archive_bytes", "Size of the archive in bytes.", &[("", bytes.to_string())]);
+        }
+        metric(
+            "waa_files_copied",
+            "Number of files copied to the archive by the last run.",
+            &[("", self.backup.files_copied.to_string())],
+        );
+        metric(
+            "waa_files_deleted",
+            "Number of files deleted by the last run.",
+            &[
+                ("{location=\"whatsapp\"}", self.backup.files_trimmed.to_string()),
+                ("{location=\"archive\"}", self.backup.files_removed_from_archive.to_string()),
+            ],
+        );
+        metric("waa_last_run_timestamp_seconds", "Time the last run completed.", &[("", timestamp.to_string())]);
+        let success = u8::from(matches!(self.status, RunStatus::Success));
+        metric("waa_run_success", "Whether the last run succeeded.", &[("", success.to_string())]);
+        metrics
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` before renaming it
+/// into place, so that readers never see a partially written file
+fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().map(ToOwned::to_owned).unwrap_or_default();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    let result = std::fs::write(&temp_path, contents).and_then(|()| std::fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Writes the report to a file and posts it to a URL if requested. Failures
+/// here are reported separately so they do not mask the result of the run.
+fn publish_report(cli: &Cli, report: &RunReport) -> Result<(), AppError> {
+    let json = serde_json::to_string_pretty(report).expect("Unable to serialize report");
+    if let Some(path) = &cli.report {
+        std::fs::write(path, &json).map_err(|e| AppError::WriteReport(path.clone(), e))?;
+    }
+    if let Some(path) = &cli.metrics_file {
+        let metrics = report.to_prometheus(chrono::Utc::now().timestamp());
+        write_atomically(path, &metrics).map_err(|e| AppError::WriteReport(path.clone(), e))?;
+    }
+    #[cfg(feature = "notify")]
+    if let Some(url) = &cli.notify_url {
+        ureq::post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&json)
+            .map_err(|e| AppError::Notify(url.clone(), Box::new(e)))?;
+    }
+    Ok(())
 }
 
 #[derive(Debug, Error)]
@@ -104,38 +912,472 @@ enum AppError {
     #[error("Unable to build index of {0}: {1}")]
     BuildIndex(PathBuf, Error),
 
-    /// Failure during file mirroring to backup
-    #[error("Unable to mirror files to archive: {0}")]
-    MirrorToArchive(Error),
+    /// A folder failed validation
+    #[error("Folder {0} is not usable: {1}")]
+    InvalidFolder(PathBuf, Error),
+
+    /// Failure during a step of the backup pipeline
+    #[error("{0}")]
+    Backup(BackupError),
+
+    /// Invalid pin pattern
+    #[error("Unable to parse pin pattern: {0}")]
+    PinPattern(Error),
+
+    /// Failure reading the encryption key or applying it to the archive
+    #[error("Unable to use encryption key: {0}")]
+    EncryptionKey(Error),
+
+    /// Deleting archived files which are absent from the WhatsApp folder is
+    /// only supported when backing up
+    #[error("--mirror-delete can only be used in backup mode")]
+    MirrorDeleteMode,
+
+    /// Paths to act on can only be deleted or restored
+    #[error("--paths-from can only be used in trim or sync mode")]
+    PathsFromMode,
+
+    /// Failure reading the list of paths to act on
+    #[error("Unable to read list of paths from {0}: {1}")]
+    ReadPathList(PathBuf, std::io::Error),
+
+    /// Failure building, saving or loading the manifest
+    #[error("Unable to process manifest: {0}")]
+    Manifest(Error),
+
+    /// Failure exporting media from the archive
+    #[error("Unable to export media from archive: {0}")]
+    Export(Error),
+
+    /// Failure reading or writing the history of runs
+    #[error("Unable to process history: {0}")]
+    History(Error),
+
+    /// Files did not match the manifest
+    #[error("{0} files did not match the manifest")]
+    VerifyFailed(usize),
+
+    /// Failure writing the report of the run
+    #[error("Unable to write report to {0}: {1}")]
+    WriteReport(PathBuf, std::io::Error),
+
+    /// Failure posting the report of the run
+    #[cfg(feature = "notify")]
+    #[error("Unable to post report to {0}: {1}")]
+    Notify(String, Box<ureq::Error>),
 
-    /// Failure while trimming files from WhatsApp folder
-    #[error("Unable to trim files from WhatsApp folder: {0}")]
-    TrimWhatsApp(Error),
+    /// Failure reading the list of pinned files
+    #[error("Unable to read list of pinned files from {0}: {1}")]
+    ReadPinList(PathBuf, std::io::Error),
 
-    /// Failure while cleaning up archive
-    #[error("Unable to clean unnecessary files from archive folder: {0}")]
-    TidyArchive(Error),
+    /// Failure opening the events stream
+    #[error("Unable to write events to {0}: {1}")]
+    WriteEvents(PathBuf, std::io::Error),
 
-    /// Failure while restoring files to WhatsApp folder
-    #[error("Unable to restore files to WhatsApp folder: {0}")]
-    RestoreToWhatsApp(Error),
+    /// The run was stopped early by Ctrl-C
+    #[error("Interrupted by the user")]
+    Interrupted,
 }
 
-fn main_internal() -> Result<(), AppError> {
-    let cli = Cli::parse();
-    let wa_folder = cli.whatsapp_folder;
-    let archive_folder = cli.archive_folder;
+impl AppError {
+    /// The process exit code for this error, as classified by
+    /// `Error::exit_code`. Files failing verification exit with 1 and
+    /// interrupted runs with 130, as if killed by SIGINT.
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::BuildIndex(_, e)
+            | AppError::InvalidFolder(_, e)
+            | AppError::PinPattern(e)
+            | AppError::EncryptionKey(e)
+            | AppError::Manifest(e)
+            | AppError::Export(e)
+            | AppError::History(e) => e.exit_code(),
+            AppError::Backup(e) => e.exit_code(),
+            AppError::MirrorDeleteMode | AppError::PathsFromMode => 2,
+            AppError::WriteReport(_, _)
+            | AppError::ReadPinList(_, _)
+            | AppError::ReadPathList(_, _)
+            | AppError::WriteEvents(_, _) => 3,
+            #[cfg(feature = "notify")]
+            AppError::Notify(_, _) => 3,
+            AppError::VerifyFailed(_) => 1,
+            AppError::Interrupted => INTERRUPTED_EXIT_CODE,
+        }
+    }
+}
 
-    let limit = cli.size_limit.map_or(DataLimit::Infinite, DataLimit::from_bytes);
+/// Reads the encryption key from the supplied file or the environment
+fn read_encryption_key(key_file: Option<&Path>) -> Result<Option<EncryptionKey>, AppError> {
+    let key = match (key_file, std::env::var(KEY_VARIABLE)) {
+        (Some(key_file), _) => Some(EncryptionKey::from_file(key_file).map_err(AppError::EncryptionKey)?),
+        (None, Ok(hex)) => Some(EncryptionKey::from_hex(&hex).map_err(AppError::EncryptionKey)?),
+        (None, Err(_)) => None,
+    };
+    Ok(key)
+}
 
+/// Reads a list of paths, one per line, ignoring blank lines. A path of "-"
+/// reads the list from standard input.
+fn read_path_list(path: &Path) -> std::io::Result<Vec<PathBuf>> { read_path_list_from(path, std::io::stdin()) }
+
+/// Reads a list of paths as for `read_path_list`, taking `stdin` as standard
+/// input
+fn read_path_list_from(path: &Path, stdin: impl std::io::Read) -> std::io::Result<Vec<PathBuf>> {
+    let list = if path == Path::new("-") { std::io::read_to_string(stdin)? } else { std::fs::read_to_string(path)? };
+    Ok(list.lines().map(str::trim).filter(|l| !l.is_empty()).map(PathBuf::from).collect())
+}
+
+/// Builds the query used to choose which media to keep in the WhatsApp folder,
+/// excluding the data limit
+fn build_query(cli: &Cli) -> Result<FileQuery, AppError> {
     let priority = cli
         .keep_newer_than
         .map(|d| chrono::Duration::from_std(d).expect("Duration too large"))
         .map_or(FilePredicate::Constant(false), FilePredicate::AgeLessThan);
 
+    let scope = if cli.since.is_some() || cli.until.is_some() {
+        FilePredicate::CreatedBetween(cli.since.unwrap_or(NaiveDate::MIN), cli.until.unwrap_or(NaiveDate::MAX))
+    } else {
+        FilePredicate::all()
+    };
+
+    let mut query = FileQuery::default();
+    query.set_order(cli.order.to_score(cli));
+    query.set_priority(priority);
+    query.set_scope(scope);
+    query.pin(&cli.pin);
+    query.set_keep_latest_per_chat(cli.keep_latest_per_chat);
+    if let Some(pin_from) = &cli.pin_from {
+        query.pin(read_path_list(pin_from).map_err(|e| AppError::ReadPinList(pin_from.clone(), e))?);
+    }
+    for glob in &cli.pin_glob {
+        query.pin_glob(glob).map_err(AppError::PinPattern)?;
+    }
+    Ok(query)
+}
+
+/// The options for the backup pipeline run in backup, db-only, trim and sync
+/// modes
+fn backup_options(cli: &Cli) -> Result<BackupOptions, AppError> {
+    let mode = match cli.mode {
+        OperationMode::Backup => BackupMode::Backup,
+        OperationMode::DbOnly => BackupMode::DbOnly,
+        OperationMode::Trim => BackupMode::Trim,
+        OperationMode::Sync => BackupMode::Sync,
+        OperationMode::Stats
+        | OperationMode::Verify
+        | OperationMode::Check
+        | OperationMode::Export
+        | OperationMode::History => panic!("The backup pipeline should only be run in modes which change the archive"),
+    };
+    let mut query = build_query(cli)?;
+    query
+        .set_limit(cli.size_limit.map_or(DataLimit::Infinite, DataLimit::from_bytes).reserve(cli.reserve.unwrap_or(0)));
+    let paths = match &cli.paths_from {
+        Some(path) => Some(read_path_list(path).map_err(|e| AppError::ReadPathList(path.clone(), e))?),
+        None => None,
+    };
+    let to_duration = |d: std::time::Duration| chrono::Duration::from_std(d).expect("Duration too large");
+    Ok(BackupOptions {
+        mode,
+        query,
+        min_free_after: cli.min_free_after,
+        safe_trim: cli.safe_trim,
+        delete_jobs: cli.parallel_delete.then_some(cli.jobs),
+        paths,
+        incremental_since: cli.incremental_since,
+        include_statuses: cli.include_statuses,
+        skip_empty: cli.skip_empty,
+        force: cli.force,
+        db_quiet_period: DEFAULT_DB_QUIET_PERIOD,
+        wait_for_quiescent: cli.wait_for_quiescent,
+        skip_newer_than: cli.skip_newer_than,
+        archive_max_age: cli.archive_max_age.map(to_duration),
+        mirror_delete: cli.mirror_delete,
+        compact: cli.compact,
+        num_kept_dbs: cli.num_kept_dbs,
+        db_cleanup: !cli.no_db_cleanup,
+        backup_cleanup: !cli.no_backup_cleanup,
+        list_cleanup: cli.list_cleanup,
+        manifest_jobs: cli.manifest.then_some(cli.jobs),
+        restore_newer_than: cli.restore_newer_than.map(to_duration),
+        restore_only: cli.restore_only.clone(),
+    })
+}
+
+/// The options used to build both indices
+fn index_options(cli: &Cli) -> IndexOptions {
+    IndexOptions {
+        canonicalize: !cli.no_canonicalize,
+        tag_name: cli.tag_name.clone(),
+        clean_temp_older_than: cli.clean_temp,
+        timezone: cli.timezone,
+        assumed_time: cli.assumed_time,
+        follow_symlinks: cli.follow_symlinks,
+        databases_only: cli.mode == OperationMode::DbOnly,
+    }
+}
+
+/// Applies the options specified on the command line to both indices
+fn configure_indices(cli: &Cli, wa_index: &mut FileIndex, archive_index: &mut FileIndex) -> Result<(), AppError> {
+    archive_index.set_hashing(cli.checksum);
+    archive_index.set_content_identity(cli.content_identity);
+    wa_index.set_hashing(cli.checksum);
+    archive_index.set_content_verification(cli.verify_content);
+    archive_index.set_max_file_size(cli.max_file_size);
+    archive_index.set_reverify(cli.reverify);
+    if let (true, Some((phase, count))) = (cli.verify_content, archive_index.resume_state()) {
+        if cli.reverify {
+            println!("Ignoring {} files confirmed by an interrupted run", count);
+        } else {
+            println!("Resuming interrupted run (last completed phase: {}), skipping {} confirmed files", phase, count);
+        }
+    }
+    archive_index.set_compression(cli.compress);
+    archive_index.set_encryption_key(read_encryption_key(cli.key_file.as_deref())?).map_err(AppError::EncryptionKey)?;
+    archive_index.set_rollback(cli.transactional);
+    wa_index.set_rollback(cli.transactional);
+    archive_index.set_resume_copies(cli.resume_copies);
+    if let Some(chunk_size) = cli.copy_chunk_size {
+        archive_index.set_copy_chunk_size(chunk_size);
+        wa_index.set_copy_chunk_size(chunk_size);
+    }
+    archive_index.set_keep_db_of_each_type(cli.preserve_newest_db_of_each_type);
+    archive_index.set_backup_retention(BackupRetention {
+        key: cli.keep_key_backups,
+        settings: cli.keep_settings_backups,
+        wallpapers: cli.keep_wallpaper_backups,
+        other: cli.keep_other_backups,
+    });
+    archive_index
+        .set_db_patterns(std::iter::once(DbPattern::msgstore()).chain(cli.db_pattern.iter().cloned()).collect());
+    wa_index.set_resume_copies(cli.resume_copies);
+    archive_index.set_seed(cli.seed);
+    archive_index.set_max_deletion_fraction((!cli.force).then_some(cli.max_deletion_fraction));
+    wa_index.set_seed(cli.seed);
+    if cli.checksum && cli.mode == OperationMode::Sync {
+        // Restored files are checked against the archive's manifest, if any
+        match archive_index.read_manifest() {
+            Ok(manifest) => wa_index.set_source_manifest(Some(manifest)),
+            Err(e) if e.io_kind() == Some(std::io::ErrorKind::NotFound) => {
+                println!("No manifest in archive, so restored files cannot be verified");
+            }
+            Err(e) => return Err(AppError::Manifest(e)),
+        }
+    }
+    let cancel_token = install_interrupt_handler();
+    wa_index.set_cancel_token(cancel_token.clone());
+    archive_index.set_cancel_token(cancel_token);
+    set_observers(cli, wa_index, archive_index)
+}
+
+/// Installs a Ctrl-C handler which cancels the returned token, so the run
+/// stops once the file being copied is complete. A second Ctrl-C exits
+/// immediately.
+fn install_interrupt_handler() -> Option<CancelToken> {
+    let token = CancelToken::new();
+    let handler_token = token.clone();
+    let result = ctrlc::set_handler(move || {
+        if handler_token.is_cancelled() {
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!("Stopping after the current file, press Ctrl-C again to exit immediately");
+        handler_token.cancel();
+    });
+    match result {
+        Ok(()) => Some(token),
+        Err(e) => {
+            eprintln!("Unable to install Ctrl-C handler, interrupting will stop immediately: {}", e);
+            None
+        }
+    }
+}
+
+/// Attaches an observer printing messages to the indices, which also draws
+/// progress bars if requested, along with the events stream if requested
+fn set_observers(cli: &Cli, wa_index: &mut FileIndex, archive_index: &mut FileIndex) -> Result<(), AppError> {
+    let sink = match &cli.events_jsonl {
+        Some(path) => {
+            let sink = EventSink::open(path).map_err(|e| AppError::WriteEvents(path.clone(), e))?;
+            Some(Rc::new(RefCell::new(sink)))
+        }
+        None => None,
+    };
+    // Files are only copied into the WhatsApp folder when restoring
+    for (index, folder, copy_event) in [(archive_index, "archive", "copy"), (wa_index, "whatsapp", "restore")] {
+        #[cfg(feature = "progress")]
+        let printer: Box<dyn Progress> = if cli.progress { Box::new(ProgressBars::new()) } else { Box::new(Printer) };
+        #[cfg(not(feature = "progress"))]
+        let printer: Box<dyn Progress> = Box::new(Printer);
+        let mut observers = vec![printer];
+        if let Some(sink) = &sink {
+            let stream = EventStream::new(sink.clone(), folder, copy_event);
+            stream.emit_skipped(index);
+            observers.push(Box::new(stream));
+        }
+        index.set_progress(Box::new(Observers(observers)));
+    }
+    Ok(())
+}
+
+/// Prints how many entries were left out of an index, and each of them in
+/// verbose mode
+fn print_skipped(folder: &Path, index: &FileIndex, verbose: bool) {
+    let skipped = index.skipped_entries();
+    if skipped.is_empty() {
+        return;
+    }
+    println!("Skipped {} entries in {} which are not regular files or folders", skipped.len(), folder.display());
+    if verbose {
+        for entry in skipped {
+            println!("  {} ({})", entry.path.display(), entry.reason);
+        }
+    }
+}
+
+/// Prints the changes made to an index, grouped by kind and sorted by path
+fn print_diff(folder: &Path, index: &FileIndex) {
+    let changes = index.changes();
+    println!("\nChanges to {} ({} files):", folder.display(), changes.len());
+    print!("{}", changes);
+}
+
+/// Prints where the space in the archive is going
+fn print_stats(archive_index: &FileIndex) {
+    let format_date = |date: Option<NaiveDateTime>| date.map_or_else(|| "-".to_string(), |d| d.date().to_string());
+    let stats = archive_index.stats();
+    println!("Archive contains {} files totalling {}", stats.total_count(), bytefmt::format(stats.total_bytes()));
+    println!("  Media:     {:>8} files {:>10}", stats.media.count, bytefmt::format(stats.media.bytes));
+    println!("  Non-media: {:>8} files {:>10}", stats.non_media.count, bytefmt::format(stats.non_media.bytes));
+    println!();
+    println!("{:<16} {:>8} {:>10} {:>10} {:>10}", "Category", "Files", "Size", "Oldest", "Newest");
+    for (category, category_stats) in &stats.categories {
+        println!(
+            "{:<16} {:>8} {:>10} {:>10} {:>10}",
+            category.to_string(),
+            category_stats.count,
+            bytefmt::format(category_stats.bytes),
+            format_date(category_stats.oldest),
+            format_date(category_stats.newest)
+        );
+    }
+    println!(
+        "{:<16} {:>8} {:>10} {:>10} {:>10}",
+        "All media",
+        stats.media.count,
+        bytefmt::format(stats.media.bytes),
+        format_date(stats.media.oldest),
+        format_date(stats.media.newest)
+    );
+    let backups = archive_index.backup_files();
+    if !backups.is_empty() {
+        println!();
+        println!("{:<16} Path", "Backup");
+        for (path, kind) in backups {
+            println!("{:<16} {}", kind.to_string(), path.display());
+        }
+    }
+}
+
+/// Prints the runs recorded in the archive's history, oldest first
+fn print_history(archive_index: &FileIndex) -> Result<(), AppError> {
+    let history = archive_index.read_history().map_err(AppError::History)?;
+    if history.is_empty() {
+        println!("No runs have been recorded. Use --history to record them.");
+        return Ok(());
+    }
+    println!("{:<20} {:>10} {:>10} {:>10} {:>8}", "Time (UTC)", "Archive", "Added", "Pruned", "Deleted");
+    for record in &history {
+        let time = chrono::DateTime::from_timestamp(record.timestamp, 0)
+            .map_or_else(|| "-".to_string(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+        println!(
+            "{:<20} {:>10} {:>10} {:>10} {:>8}",
+            time,
+            bytefmt::format(record.archive_bytes),
+            bytefmt::format(record.bytes_added),
+            bytefmt::format(record.bytes_pruned),
+            record.files_deleted
+        );
+    }
+    Ok(())
+}
+
+/// Appends a summary of the run to the archive's history
+fn record_history(archive_index: &FileIndex, report: &RunReport) -> Result<(), AppError> {
+    let record = HistoryRecord {
+        timestamp: Utc::now().timestamp(),
+        archive_bytes: report.backup.archive_bytes_after.unwrap_or_default(),
+        bytes_added: report.backup.bytes_copied,
+        bytes_pruned: report.backup.bytes_removed_from_archive,
+        files_deleted: report.backup.files_trimmed + report.backup.files_removed_from_archive,
+    };
+    archive_index.append_history(&record).map_err(AppError::History)
+}
+
+/// Checks the contents of the archive against its manifest
+fn verify_archive(cli: &Cli, mut archive_index: FileIndex) -> Result<(), AppError> {
+    archive_index.set_encryption_key(read_encryption_key(cli.key_file.as_deref())?).map_err(AppError::EncryptionKey)?;
+    let manifest = archive_index.read_manifest().map_err(AppError::Manifest)?;
+    println!("Verifying {} files against manifest...", manifest.len());
+    let mismatches = archive_index.verify(&manifest, cli.jobs).map_err(AppError::Manifest)?;
+    for path in &mismatches {
+        println!("Mismatch: {}", path.display());
+    }
+    if !mismatches.is_empty() {
+        return Err(AppError::VerifyFailed(mismatches.len()));
+    }
+    println!("Done.");
+    Ok(())
+}
+
+/// Copies the archived media to the export folder
+fn export_archive(cli: &Cli, mut archive_index: FileIndex, action_type: ActionType) -> Result<(), AppError> {
+    archive_index.set_encryption_key(read_encryption_key(cli.key_file.as_deref())?).map_err(AppError::EncryptionKey)?;
+    if let Some(chunk_size) = cli.copy_chunk_size {
+        archive_index.set_copy_chunk_size(chunk_size);
+    }
+    let export_dir = cli.export_dir.as_deref().expect("Export folder missing in export mode");
+    println!("Exporting media from {} to {}...", cli.archive_folder.display(), export_dir.display());
+    let exported =
+        archive_index.export_media(export_dir, cli.export_layout.to_layout(), action_type).map_err(AppError::Export)?;
+    println!("Exported {} files", exported.len());
+    Ok(())
+}
+
+/// Prints what was skipped and the changes made if requested, and records the
+/// run in the history
+fn finish_run(cli: &Cli, wa_index: &FileIndex, archive_index: &FileIndex, report: &RunReport) -> Result<(), AppError> {
+    print_skipped(&cli.whatsapp_folder, wa_index, cli.verbose);
+    print_skipped(&cli.archive_folder, archive_index, cli.verbose);
+    if cli.diff {
+        print_diff(&cli.archive_folder, archive_index);
+        print_diff(&cli.whatsapp_folder, wa_index);
+    }
+    if cli.history {
+        record_history(archive_index, report)?;
+    }
+    Ok(())
+}
+
+/// Rejects options which have no meaning in the selected mode
+fn check_mode_options(cli: &Cli) -> Result<(), AppError> {
+    if cli.mirror_delete && cli.mode != OperationMode::Backup {
+        return Err(AppError::MirrorDeleteMode);
+    }
+    if cli.paths_from.is_some() && !matches!(cli.mode, OperationMode::Trim | OperationMode::Sync) {
+        return Err(AppError::PathsFromMode);
+    }
+    Ok(())
+}
+
+fn main_internal(cli: &Cli, report: &mut RunReport) -> Result<(), AppError> {
+    let wa_folder = &cli.whatsapp_folder;
+    let archive_folder = &cli.archive_folder;
+
     let mode = cli.mode;
-    let order: FileScore = cli.order.into();
-    let num_dbs_to_keep = cli.num_kept_dbs;
+    let deadline = cli.timeout.map(|timeout| Instant::now() + timeout);
+
+    check_mode_options(cli)?;
 
     let action_type = if cli.dry_run {
         println!("Running in dry-run mode. No files will be changed.");
@@ -144,66 +1386,269 @@ fn main_internal() -> Result<(), AppError> {
         ActionType::Real
     };
 
-    let mut wa_index = FileIndex::new(IndexType::Original, &wa_folder, action_type)
-        .map_err(|e| AppError::BuildIndex(wa_folder.clone(), e))?;
+    let index_options = index_options(cli);
+
+    if matches!(mode, OperationMode::Stats | OperationMode::Verify | OperationMode::Export | OperationMode::History) {
+        // These modes only read the archive
+        let mut archive_index =
+            FileIndex::new_with_options(IndexType::Archive, archive_folder, ActionType::Dry, &index_options)
+                .map_err(|e| AppError::BuildIndex(archive_folder.clone(), e))?;
+        archive_index.set_deadline(deadline);
+        archive_index.set_progress(Box::new(Printer));
+        return match mode {
+            OperationMode::Stats => {
+                print_stats(&archive_index);
+                Ok(())
+            }
+            OperationMode::Verify => verify_archive(cli, archive_index),
+            OperationMode::History => print_history(&archive_index),
+            _ => export_archive(cli, archive_index, action_type),
+        };
+    }
+
+    // Both folders are used from here on
+    FileIndex::check_not_overlapping(wa_folder, archive_folder)
+        .map_err(|e| AppError::InvalidFolder(archive_folder.clone(), e))?;
 
-    let mut archive_index = FileIndex::new(IndexType::Archive, &archive_folder, action_type)
+    if mode == OperationMode::Check {
+        FileIndex::validate_with_options(IndexType::Original, wa_folder, &index_options)
+            .map_err(|e| AppError::InvalidFolder(wa_folder.clone(), e))?;
+        FileIndex::validate_with_options(IndexType::Archive, archive_folder, &index_options)
+            .map_err(|e| AppError::InvalidFolder(archive_folder.clone(), e))?;
+        println!(
+            "{} is a WhatsApp folder and {} can be used as an archive",
+            wa_folder.display(),
+            archive_folder.display()
+        );
+        return Ok(());
+    }
+
+    let options = backup_options(cli)?;
+    let mut wa_index = FileIndex::new_with_options(IndexType::Original, wa_folder, action_type, &index_options)
         .map_err(|e| AppError::BuildIndex(wa_folder.clone(), e))?;
 
-    let archive_size = archive_index.size_bytes();
+    let mut archive_index =
+        FileIndex::new_with_options(IndexType::Archive, archive_folder, action_type, &index_options)
+            .map_err(|e| AppError::BuildIndex(archive_folder.clone(), e))?;
+    configure_indices(cli, &mut wa_index, &mut archive_index)?;
+    wa_index.set_deadline(deadline);
+    archive_index.set_deadline(deadline);
+
+    let (temp_bytes, temp_count) = archive_index.cleaned_temp_files();
+    if temp_count > 0 {
+        println!("Removed {} stale temporary files, reclaiming {}", temp_count, bytefmt::format(temp_bytes));
+    }
+
     println!("Mirroring new files from {} to {}...", wa_folder.display(), archive_folder.display());
-    println!("Archive size is currently {}", bytefmt::format(archive_size));
-
-    archive_index.mirror_all(&wa_index).map_err(AppError::MirrorToArchive)?;
-    archive_index.clean_old_backups().map_err(AppError::TidyArchive)?;
-    archive_index.clean_old_dbs(num_dbs_to_keep).map_err(AppError::TidyArchive)?;
-
-    let archive_size = archive_index.size_bytes();
-    println!("Archive size is now {}", bytefmt::format(archive_size));
-
-    if mode == OperationMode::Trim || mode == OperationMode::Sync {
-        println!("\nTrimming files from WhatsApp folder...");
-        let wa_folder_size = wa_index.size_bytes();
-        println!("WhatsApp folder size is currently {}", bytefmt::format(wa_folder_size));
-
-        let mut query = FileQuery::default();
-        query.set_order(order);
-        query.set_priority(priority);
-        let limit = limit.map(|bytes| {
-            // Reduce limit to account for non-media files in WhatsApp folder
-            let non_media_bytes = wa_index.non_media_size_bytes();
-            bytes.saturating_sub(non_media_bytes)
-        });
-        query.set_limit(limit);
-
-        let (delete_candidates, retain_candidates) = {
-            let deletion_source = match mode {
-                OperationMode::Trim => &wa_index,
-                OperationMode::Sync => &archive_index,
-                OperationMode::Backup => panic!("Delete/retain should never be hit in backup mode"),
-            };
-            deletion_source.get_delete_retain_candidates(&query)
-        };
-        let delete_candidates = wa_index.filter_existing(&delete_candidates);
-        println!("Deleting {} files from WhatsApp folder...", delete_candidates.len());
+    report.backup = run_backup(&mut wa_index, &mut archive_index, options).map_err(|mut e| {
+        report.backup = std::mem::take(&mut e.report);
+        AppError::Backup(e)
+    })?;
+    if report.backup.cancelled {
+        return Err(AppError::Interrupted);
+    }
+    finish_run(cli, &wa_index, &archive_index, report)?;
+    println!("Done.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn category_weights_are_parsed() {
+        assert_eq!(parse_category_weight("video=0.5").unwrap(), (MediaCategory::Video, 0.5));
+        assert_eq!(parse_category_weight(" Voice-Notes = 2 ").unwrap(), (MediaCategory::VoiceNotes, 2.0));
+        assert_eq!(parse_category_weight("stickers=-1").unwrap(), (MediaCategory::Stickers, -1.0));
+    }
+
+    #[test]
+    fn weights_must_be_finite() {
+        assert_eq!(parse_weight("0"), Ok(0.0));
+        assert_eq!(parse_weight("-1.5"), Ok(-1.5));
+        for arg in ["inf", "-inf", "infinity", "NaN", "1e400", "", "one"] {
+            assert!(parse_weight(arg).is_err(), "{}", arg);
+        }
+        assert!(Cli::try_parse_from(["waa", "-w", "wa", "-a", "archive", "--size-weight", "NaN"]).is_err());
+        assert!(Cli::try_parse_from(["waa", "-w", "wa", "-a", "archive", "--age-weight", "inf"]).is_err());
+    }
+
+    #[test]
+    fn invalid_category_weights_are_rejected() {
+        for arg in ["video", "films=1", "video=", "video=x", "video=inf", "video=-inf", "video=NaN"] {
+            assert!(parse_category_weight(arg).is_err(), "{}", arg);
+        }
+    }
+
+    /// Accepts a single HTTP request on `listener`, responding with an empty
+    /// success, and returns its request line and body
+    #[cfg(feature = "notify")]
+    fn receive_request(listener: &std::net::TcpListener) -> (String, String) {
+        use std::io::{BufRead, BufReader, Read, Write};
 
-        wa_index.remove_files(&delete_candidates).map_err(AppError::TrimWhatsApp)?;
-        if !delete_candidates.is_empty() {
-            let wa_folder_size = wa_index.size_bytes();
-            println!("WhatsApp folder size is now {}", bytefmt::format(wa_folder_size));
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
         }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+        (request_line.trim().to_string(), String::from_utf8(body).unwrap())
+    }
 
-        if mode == OperationMode::Sync {
-            let restore_candidates = wa_index.filter_missing(&retain_candidates);
-            println!("\nRestoring {} files to WhatsApp folder...", restore_candidates.len());
-            wa_index.mirror_specified(&archive_index, &restore_candidates).map_err(AppError::RestoreToWhatsApp)?;
+    #[cfg(feature = "notify")]
+    #[test]
+    fn report_is_posted_to_notify_url() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/runs", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || receive_request(&listener));
+        let cli = Cli::try_parse_from(["waa", "-w", "wa", "-a", "archive", "--dry-run", "--notify-url", &url]).unwrap();
+        let mut report = RunReport::new(&cli);
+        report.backup.files_copied = 3;
+        report.finish(&Err(AppError::Interrupted));
+        publish_report(&cli, &report).unwrap();
 
-            if !restore_candidates.is_empty() {
-                let wa_folder_size = wa_index.size_bytes();
-                println!("WhatsApp folder size is now {}", bytefmt::format(wa_folder_size));
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /runs HTTP/1.1");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["status"], "failure");
+        assert_eq!(json["error"], "Interrupted by the user");
+        assert_eq!(json["mode"], "backup");
+        assert_eq!(json["dry_run"], true);
+        assert_eq!(json["files_copied"], 3);
+        assert_eq!(json["files_trimmed"], 0);
+        assert!(json.get("backup").is_none());
+        assert!(json.get("cancelled").is_none());
+    }
+
+    #[test]
+    fn metrics_are_valid_prometheus_gauges() {
+        let cli = Cli::try_parse_from(["waa", "-w", "wa", "-a", "archive"]).unwrap();
+        let mut report = RunReport::new(&cli);
+        report.backup.archive_bytes_after = Some(4096);
+        report.backup.files_copied = 2;
+        report.backup.files_trimmed = 5;
+        report.backup.files_removed_from_archive = 1;
+        let metrics = report.to_prometheus(1_704_067_200);
+
+        let mut types = BTreeMap::new();
+        let mut samples = BTreeMap::new();
+        for line in metrics.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut fields = comment.splitn(3, ' ');
+                let (kind, name, rest) = (fields.next().unwrap(), fields.next().unwrap(), fields.next().unwrap());
+                match kind {
+                    "TYPE" => assert!(types.insert(name, rest).is_none(), "{} declared twice", name),
+                    "HELP" => assert!(!rest.is_empty()),
+                    _ => panic!("Unexpected comment: {}", line),
+                }
+            } else {
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                let name = series.split('{').next().unwrap();
+                assert!(types.contains_key(name), "{} has no type", name);
+                assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'), "{}", name);
+                if let Some(labels) = series.strip_prefix(name).filter(|l| !l.is_empty()) {
+                    assert!(labels.starts_with('{') && labels.ends_with('}'), "{}", series);
+                }
+                assert!(samples.insert(series, value.parse::<f64>().unwrap()).is_none(), "{} repeated", series);
             }
         }
+        for (name, kind) in &types {
+            assert_eq!(*kind, "gauge");
+            assert!(!name.ends_with("_total"), "Gauge {} has a counter suffix", name);
+        }
+        let expected = [
+            ("waa_archive_bytes", 4096.0),
+            ("waa_files_copied", 2.0),
+            ("waa_files_deleted{location=\"whatsapp\"}", 5.0),
+            ("waa_files_deleted{location=\"archive\"}", 1.0),
+            ("waa_last_run_timestamp_seconds", 1_704_067_200.0),
+            ("waa_run_success", 1.0),
+        ];
+        assert_eq!(samples, expected.into_iter().collect());
+    }
+
+    #[test]
+    fn timezones_are_parsed() {
+        assert_eq!(parse_timezone("local").unwrap(), Timezone::Local);
+        assert_eq!(parse_timezone("UTC").unwrap(), Timezone::default());
+        assert_eq!(parse_timezone("+05:30").unwrap(), Timezone::Fixed("+05:30".parse().unwrap()));
+        assert!(parse_timezone("Mars").is_err());
+    }
+    #[test]
+    fn events_are_written_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("waa-events-{}.jsonl", std::process::id()));
+        let sink = Rc::new(RefCell::new(EventSink::open(&path).unwrap()));
+        let mut archive = EventStream::new(sink.clone(), "archive", "copy");
+        let mut whatsapp = EventStream::new(sink, "whatsapp", "restore");
+        archive.file_started(Path::new("Media/a.jpg"), 10);
+        archive.file_finished(Path::new("Media/a.jpg"));
+        archive.file_too_large(Path::new("Media/b.mp4"), 2000);
+        whatsapp.file_started(Path::new("Media/c.jpg"), 30);
+        whatsapp.file_finished(Path::new("Media/c.jpg"));
+        whatsapp.file_deleted(Path::new("Media/d.jpg"), 40);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<serde_json::Value> = contents.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (e["event"].as_str().unwrap(), e["folder"].as_str().unwrap(), e["path"].as_str().unwrap(), &e["bytes"])
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("copy", "archive", "Media/a.jpg", &serde_json::json!(10)),
+                ("skip", "archive", "Media/b.mp4", &serde_json::json!(2000)),
+                ("restore", "whatsapp", "Media/c.jpg", &serde_json::json!(30)),
+                ("delete", "whatsapp", "Media/d.jpg", &serde_json::json!(40)),
+            ]
+        );
+        assert_eq!(events[1]["reason"], "larger than the maximum file size");
+        for event in &events {
+            assert!(event["timestamp"].as_i64().unwrap() > 0);
+            if event["event"] != "skip" {
+                assert!(event.get("reason").is_none());
+            }
+        }
+    }
+    #[test]
+    fn path_lists_are_read_from_stdin_or_file() {
+        let stdin = "Media/WhatsApp Images/a.jpg\n\n  Media/WhatsApp Video/b.mp4  \r\n";
+        let expected = [PathBuf::from("Media/WhatsApp Images/a.jpg"), PathBuf::from("Media/WhatsApp Video/b.mp4")];
+        assert_eq!(read_path_list_from(Path::new("-"), stdin.as_bytes()).unwrap(), expected);
+
+        let path = std::env::temp_dir().join(format!("waa-paths-{}.txt", std::process::id()));
+        std::fs::write(&path, stdin).unwrap();
+        let from_file = read_path_list_from(&path, "ignored".as_bytes());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_file.unwrap(), expected);
+    }
+
+    #[test]
+    fn paths_from_is_only_accepted_in_trim_or_sync_mode() {
+        for (mode, accepted) in [("trim", true), ("sync", true), ("backup", false), ("db-only", false)] {
+            let cli =
+                Cli::try_parse_from(["waa", "-w", "wa", "-a", "archive", "-M", mode, "--paths-from", "-"]).unwrap();
+            assert_eq!(check_mode_options(&cli).is_ok(), accepted, "{}", mode);
+        }
     }
-    println!("Done.");
-    Ok(())
 }
@@ -0,0 +1,35 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag which can be set from another thread or a signal handler to ask a
+/// long-running operation to stop. Clones share the same flag.
+///
+/// Operations only check the token between files, so a file being copied
+/// when it is cancelled is completed first.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Constructs a token which has not been cancelled
+    pub fn new() -> CancelToken { CancelToken::default() }
+
+    /// Asks operations using this token to stop at the next file boundary
+    pub fn cancel(&self) { self.0.store(true, Ordering::SeqCst); }
+
+    /// Returns `true` if `cancel` has been called on this token or a clone
+    pub fn is_cancelled(&self) -> bool { self.0.load(Ordering::SeqCst) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_cancellation() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!token.is_cancelled());
+        clone.cancel();
+        assert!(token.is_cancelled());
+    }
+}
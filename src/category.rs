@@ -0,0 +1,76 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::file_index::MEDIA_FOLDER;
+
+/// The kind of media a file contains, determined by the WhatsApp media folder
+/// it is stored in
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum MediaCategory {
+    Images,
+    Video,
+    Audio,
+    VoiceNotes,
+    Documents,
+    Stickers,
+    AnimatedGifs,
+    ProfilePhotos,
+
+    /// Status updates viewed by the user, which WhatsApp deletes after a day
+    Status,
+
+    /// Any folder not listed above, e.g. wallpapers
+    Other,
+}
+
+impl MediaCategory {
+    /// All categories, in the order they are displayed
+    pub const ALL: [MediaCategory; 10] = [
+        MediaCategory::Images,
+        MediaCategory::Video,
+        MediaCategory::Audio,
+        MediaCategory::VoiceNotes,
+        MediaCategory::Documents,
+        MediaCategory::Stickers,
+        MediaCategory::AnimatedGifs,
+        MediaCategory::ProfilePhotos,
+        MediaCategory::Status,
+        MediaCategory::Other,
+    ];
+
+    /// Classifies a media file from its path relative to the WhatsApp folder,
+    /// e.g. `Media/WhatsApp Images/Sent/IMG-20230105-WA0001.jpg`
+    pub fn from_path(path: &Path) -> MediaCategory {
+        let folder = path.strip_prefix(MEDIA_FOLDER).ok().and_then(|p| p.iter().next()).map(|f| f.to_string_lossy());
+        match folder.as_deref() {
+            Some("WhatsApp Images") => MediaCategory::Images,
+            Some("WhatsApp Video" | "WhatsApp Video Notes") => MediaCategory::Video,
+            Some("WhatsApp Audio") => MediaCategory::Audio,
+            Some("WhatsApp Voice Notes") => MediaCategory::VoiceNotes,
+            Some("WhatsApp Documents") => MediaCategory::Documents,
+            Some("WhatsApp Stickers" | "WhatsApp Backup Excluded Stickers") => MediaCategory::Stickers,
+            Some("WhatsApp Animated Gifs") => MediaCategory::AnimatedGifs,
+            Some("WhatsApp Profile Photos") => MediaCategory::ProfilePhotos,
+            Some(".Statuses") => MediaCategory::Status,
+            _ => MediaCategory::Other,
+        }
+    }
+}
+
+impl fmt::Display for MediaCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MediaCategory::Images => "Images",
+            MediaCategory::Video => "Video",
+            MediaCategory::Audio => "Audio",
+            MediaCategory::VoiceNotes => "Voice notes",
+            MediaCategory::Documents => "Documents",
+            MediaCategory::Stickers => "Stickers",
+            MediaCategory::AnimatedGifs => "Animated GIFs",
+            MediaCategory::ProfilePhotos => "Profile photos",
+            MediaCategory::Status => "Statuses",
+            MediaCategory::Other => "Other",
+        };
+        f.write_str(name)
+    }
+}
@@ -0,0 +1,27 @@
+use std::fmt::Debug;
+
+use chrono::{DateTime, Utc};
+
+/// A source of the current time, used to compute the ages of files when
+/// evaluating a query
+pub trait Clock: Debug + Send + Sync {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// A clock reading the system time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> { Utc::now() }
+}
+
+/// A clock which always reads the same instant, so that age-based decisions
+/// are reproducible
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> { self.0 }
+}
@@ -0,0 +1,209 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::crypto::{DecryptingReader, EncryptingWriter, Header};
+use crate::filename::last_extension;
+use crate::EncryptionKey;
+
+/// Suffix appended to the names of files stored compressed
+pub(crate) const COMPRESSED_SUFFIX: &str = ".waa.zst";
+
+/// Suffix appended to the names of files stored encrypted (and possibly also
+/// compressed)
+pub(crate) const ENCRYPTED_SUFFIX: &str = ".waa.enc";
+
+/// Extensions of files which are worth compressing. Media such as images and
+/// video are already compressed, as are WhatsApp's encrypted databases, so
+/// these are always stored verbatim.
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &["csv", "db", "html", "json", "log", "txt", "vcf", "xml"];
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Maximum size of a zstd frame header
+const ZSTD_FRAME_HEADER_SIZE_MAX: usize = 18;
+
+/// How the contents of a file are stored on disk
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Encoding {
+    /// The file is zstd compressed
+    pub compressed: bool,
+
+    /// The file is encrypted
+    pub encrypted: bool,
+}
+
+impl Encoding {
+    /// Files stored verbatim
+    pub const PLAIN: Encoding = Encoding { compressed: false, encrypted: false };
+
+    /// Determines the encoding of a stored file from its name, returning the
+    /// name of the file it represents along with the encoding. Whether an
+    /// encrypted file is also compressed can only be determined by `inspect`.
+    pub(crate) fn from_stored_path(path: &Path) -> (PathBuf, Encoding) {
+        let filename = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default();
+        let encoding = if filename.ends_with(ENCRYPTED_SUFFIX) {
+            Encoding { compressed: false, encrypted: true }
+        } else if filename.ends_with(COMPRESSED_SUFFIX) {
+            Encoding { compressed: true, encrypted: false }
+        } else {
+            return (path.to_path_buf(), Encoding::PLAIN);
+        };
+        // Both suffixes consist of two extensions. Removing them from the path
+        // rather than the lossy filename preserves names which are not UTF-8.
+        let logical_path = path.with_extension("").with_extension("");
+        (logical_path, encoding)
+    }
+
+    /// Chooses how to store the file at `path`
+    pub(crate) fn for_path(path: &Path, compression: bool, encryption: bool) -> Encoding {
+        let filename = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default();
+        // Only the last extension matters, e.g. `msgstore.db` is compressible
+        // but `msgstore.db.crypt14` is not
+        let compressible = last_extension(&filename)
+            .is_some_and(|e| COMPRESSIBLE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+        Encoding { compressed: compression && compressible, encrypted: encryption }
+    }
+
+    /// The path at which a file with this encoding is stored
+    pub(crate) fn stored_path(self, path: &Path) -> PathBuf {
+        let suffix = match (self.compressed, self.encrypted) {
+            (false, false) => return path.to_path_buf(),
+            (true, false) => COMPRESSED_SUFFIX,
+            (_, true) => ENCRYPTED_SUFFIX,
+        };
+        let mut name = path.file_name().map_or_else(OsString::new, ToOwned::to_owned);
+        name.push(suffix);
+        path.with_file_name(name)
+    }
+
+    /// Determines the complete encoding and original size of the file stored
+    /// at `path`
+    pub(crate) fn inspect(self, path: &Path) -> io::Result<(Encoding, u64)> {
+        if self.encrypted {
+            let header = Header::read(&mut File::open(path)?)?;
+            Ok((Encoding { compressed: header.compressed, encrypted: true }, header.size))
+        } else if self.compressed {
+            let mut header = [0u8; ZSTD_FRAME_HEADER_SIZE_MAX];
+            let count = File::open(path)?.read(&mut header)?;
+            match zstd::zstd_safe::get_frame_content_size(&header[..count]) {
+                Ok(Some(size)) => Ok((self, size)),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unable to determine size of compressed file")),
+            }
+        } else {
+            Ok((self, path.metadata()?.len()))
+        }
+    }
+
+    /// Opens the file stored at `path`, decoding its contents
+    pub(crate) fn open(self, path: &Path, key: Option<&EncryptionKey>) -> io::Result<Box<dyn Read>> {
+        let file = File::open(path)?;
+        let reader: Box<dyn Read> = if self.encrypted {
+            let (header, reader) = DecryptingReader::new(file, key.ok_or_else(missing_key)?)?;
+            if header.compressed {
+                Box::new(zstd::Decoder::new(reader)?)
+            } else {
+                Box::new(BufReader::new(reader))
+            }
+        } else if self.compressed {
+            Box::new(zstd::Decoder::new(file)?)
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        Ok(reader)
+    }
+
+    /// Creates a file at `path` which encodes data written to it. The number
+    /// of bytes which will be written must be known in advance. Fails if a
+    /// file already exists at `path`.
+    pub(crate) fn create(self, path: &Path, size: u64, key: Option<&EncryptionKey>) -> io::Result<EncodedWriter> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        let writer = match (self.compressed, self.encrypted) {
+            (false, false) => EncodedWriter::Plain(file),
+            (true, false) => EncodedWriter::Compressed(Self::compressor(file, size)?),
+            (compressed, true) => {
+                let encryptor = EncryptingWriter::new(file, key.ok_or_else(missing_key)?, compressed, size)?;
+                if compressed {
+                    EncodedWriter::CompressedEncrypted(Self::compressor(encryptor, size)?)
+                } else {
+                    EncodedWriter::Encrypted(encryptor)
+                }
+            }
+        };
+        Ok(writer)
+    }
+
+    fn compressor<W: Write>(writer: W, size: u64) -> io::Result<zstd::Encoder<'static, W>> {
+        let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL)?;
+        encoder.include_contentsize(true)?;
+        encoder.set_pledged_src_size(Some(size))?;
+        Ok(encoder)
+    }
+}
+
+fn missing_key() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "An encryption key is required to access encrypted files")
+}
+
+/// A writer which encodes data written to a file
+pub(crate) enum EncodedWriter {
+    Plain(File),
+    Compressed(zstd::Encoder<'static, File>),
+    Encrypted(EncryptingWriter<File>),
+    CompressedEncrypted(zstd::Encoder<'static, EncryptingWriter<File>>),
+}
+
+impl EncodedWriter {
+    /// Flushes all encoded data and syncs the underlying file
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let file = match self {
+            EncodedWriter::Plain(file) => file,
+            EncodedWriter::Compressed(encoder) => encoder.finish()?,
+            EncodedWriter::Encrypted(encryptor) => encryptor.finish()?,
+            EncodedWriter::CompressedEncrypted(encoder) => encoder.finish()?.finish()?,
+        };
+        file.sync_all()
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            EncodedWriter::Plain(file) => file,
+            EncodedWriter::Compressed(encoder) => encoder,
+            EncodedWriter::Encrypted(encryptor) => encryptor,
+            EncodedWriter::CompressedEncrypted(encoder) => encoder,
+        }
+    }
+}
+
+impl Write for EncodedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.inner().write(buf) }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner().flush() }
+}
+
+/// A file on disk along with what is needed to decode or encode it
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StoredFile<'a> {
+    pub path: &'a Path,
+    pub encoding: Encoding,
+    pub key: Option<&'a EncryptionKey>,
+}
+
+impl<'a> StoredFile<'a> {
+    /// The same encoding applied to a file at a different path
+    pub(crate) fn with_path<'b>(&self, path: &'b Path) -> StoredFile<'b>
+    where
+        'a: 'b,
+    {
+        StoredFile { path, encoding: self.encoding, key: self.key }
+    }
+
+    /// Opens the file, decoding its contents
+    pub(crate) fn open(&self) -> io::Result<Box<dyn Read>> { self.encoding.open(self.path, self.key) }
+
+    /// Creates the file, encoding data written to it
+    pub(crate) fn create(&self, size: u64) -> io::Result<EncodedWriter> {
+        self.encoding.create(self.path, size, self.key)
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+use crate::{ContentHash, Progress};
+
+/// Default size of the buffer used to copy files. Larger chunks make fewer
+/// system calls at the cost of memory, which matters little beyond this size.
+pub const DEFAULT_COPY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Something notified of each chunk of data as it is copied, so that e.g.
+/// progress reporting and hashing happen in the same pass as the copy
+pub(crate) trait CopyObserver {
+    /// Called after `chunk` has been written. Returning an error stops the
+    /// copy.
+    fn chunk_copied(&mut self, chunk: &[u8]) -> io::Result<()>;
+}
+
+/// Stops a copy with `io::ErrorKind::TimedOut` once a deadline has passed
+pub(crate) struct Deadline(pub Option<Instant>);
+
+impl CopyObserver for Deadline {
+    fn chunk_copied(&mut self, _chunk: &[u8]) -> io::Result<()> {
+        if self.0.is_some_and(|deadline| Instant::now() >= deadline) {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed during copy"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reports the size of each chunk copied to a `Progress`
+pub(crate) struct ReportProgress<'a>(pub &'a mut dyn Progress);
+
+impl CopyObserver for ReportProgress<'_> {
+    fn chunk_copied(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.0.bytes_copied(chunk.len() as u64);
+        Ok(())
+    }
+}
+
+/// Settings for copying the contents of a single file
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct CopyOptions<'a> {
+    /// Size of the buffer data is copied through
+    pub chunk_size: usize,
+
+    /// Time after which the copy fails with `io::ErrorKind::TimedOut`
+    pub deadline: Option<Instant>,
+
+    /// The hash the decoded source contents must have for the copy to be
+    /// moved into place
+    pub expected_hash: Option<&'a ContentHash>,
+}
+
+/// Copies everything remaining in `reader` to `writer` through a buffer of
+/// `chunk_size` bytes, notifying each observer of every chunk written.
+/// Returns the number of bytes copied.
+pub(crate) fn copy_with(
+    reader: &mut dyn Read, writer: &mut dyn Write, chunk_size: usize, observers: &mut [&mut dyn CopyObserver],
+) -> io::Result<u64> {
+    let mut buffer = vec![0u8; chunk_size.max(1)];
+    let mut total = 0;
+    loop {
+        let count = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(total),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..count])?;
+        total += count as u64;
+        for observer in observers.iter_mut() {
+            observer.chunk_copied(&buffer[..count])?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the size of each chunk
+    #[derive(Default)]
+    struct Chunks(Vec<usize>);
+
+    impl CopyObserver for Chunks {
+        fn chunk_copied(&mut self, chunk: &[u8]) -> io::Result<()> {
+            self.0.push(chunk.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chunks_are_at_most_the_chunk_size() {
+        let contents: Vec<u8> = (0..10).collect();
+        for (chunk_size, expected) in [(3, vec![3, 3, 3, 1]), (10, vec![10]), (64, vec![10]), (0, vec![1; 10])] {
+            let mut output = Vec::new();
+            let mut chunks = Chunks::default();
+            let copied = copy_with(&mut contents.as_slice(), &mut output, chunk_size, &mut [&mut chunks]).unwrap();
+            assert_eq!(copied, 10);
+            assert_eq!(output, contents);
+            assert_eq!(chunks.0, expected, "{}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn passed_deadline_stops_copy() {
+        let mut deadline = Deadline(Some(Instant::now()));
+        let result = copy_with(&mut [1u8, 2, 3].as_slice(), &mut Vec::new(), 1, &mut [&mut deadline]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
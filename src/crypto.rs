@@ -0,0 +1,305 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::XChaCha20Poly1305;
+use rand::RngCore;
+
+use crate::Error;
+
+/// Identifies an encrypted file and the version of its format
+const MAGIC: &[u8; 8] = b"WAAENC1\0";
+
+/// Size of the nonce prefix used by the STREAM construction
+const NONCE_PREFIX_SIZE: usize = 19;
+
+/// Size of the header: magic, flags, plaintext size and nonce prefix
+pub(crate) const HEADER_SIZE: usize = MAGIC.len() + 1 + 8 + NONCE_PREFIX_SIZE;
+
+/// Size of each encrypted chunk of plaintext
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Size of the authentication tag appended to each chunk
+const TAG_SIZE: usize = 16;
+
+/// Header flag indicating the plaintext is compressed
+const FLAG_COMPRESSED: u8 = 1;
+
+/// A key used to encrypt files at rest
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Parses a key from 64 hexadecimal digits
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey, Error> {
+        let hex = hex.trim();
+        let invalid = || Error::InvalidKey("expected 64 hexadecimal digits".to_string());
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; 32];
+        for (idx, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(EncryptionKey(key))
+    }
+
+    /// Reads a key from a file containing either 32 raw bytes or 64
+    /// hexadecimal digits
+    pub fn from_file(path: &Path) -> Result<EncryptionKey, Error> {
+        let contents = std::fs::read(path).map_err(|e| (e, path))?;
+        if let Ok(key) = <[u8; 32]>::try_from(contents.as_slice()) {
+            return Ok(EncryptionKey(key));
+        }
+        let hex = String::from_utf8(contents).map_err(|_| Error::InvalidKey(path.display().to_string()))?;
+        Self::from_hex(&hex)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("EncryptionKey(..)") }
+}
+
+/// The unencrypted header at the start of every encrypted file
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Header {
+    pub compressed: bool,
+    pub size: u64,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl Header {
+    fn new(compressed: bool, size: u64) -> Header {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce_prefix);
+        Header { compressed, size, nonce_prefix }
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[..8].copy_from_slice(MAGIC);
+        bytes[8] = if self.compressed { FLAG_COMPRESSED } else { 0 };
+        bytes[9..17].copy_from_slice(&self.size.to_le_bytes());
+        bytes[17..].copy_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    /// Reads and validates the header at the start of `reader`
+    pub(crate) fn read<R: Read>(reader: &mut R) -> io::Result<Header> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        if &bytes[..8] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an encrypted archive file"));
+        }
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&bytes[9..17]);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        nonce_prefix.copy_from_slice(&bytes[17..]);
+        Ok(Header { compressed: bytes[8] & FLAG_COMPRESSED != 0, size: u64::from_le_bytes(size), nonce_prefix })
+    }
+}
+
+fn crypto_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Encrypted data could not be authenticated (wrong key or corrupt file)")
+}
+
+/// Encrypts data written to it in fixed-size chunks
+pub(crate) struct EncryptingWriter<W: Write> {
+    inner: W,
+    header: [u8; HEADER_SIZE],
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Writes the header to `inner` and returns a writer which will encrypt
+    /// `size` bytes of (optionally compressed) plaintext
+    pub(crate) fn new(mut inner: W, key: &EncryptionKey, compressed: bool, size: u64) -> io::Result<Self> {
+        let header = Header::new(compressed, size);
+        let encryptor =
+            EncryptorBE32::new(GenericArray::from_slice(&key.0), GenericArray::from_slice(&header.nonce_prefix));
+        let header = header.to_bytes();
+        inner.write_all(&header)?;
+        Ok(EncryptingWriter { inner, header, encryptor, buffer: Vec::with_capacity(CHUNK_SIZE) })
+    }
+
+    /// Encrypts any remaining data and returns the underlying writer
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let chunk = self
+            .encryptor
+            .encrypt_last(Payload { msg: &self.buffer, aad: &self.header })
+            .map_err(|_| crypto_error())?;
+        self.inner.write_all(&chunk)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full buffer is only encrypted once more data arrives, since the
+        // final chunk must be encrypted differently
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            let chunk = self
+                .encryptor
+                .encrypt_next(Payload { msg: &self.buffer, aad: &self.header })
+                .map_err(|_| crypto_error())?;
+            self.inner.write_all(&chunk)?;
+            self.buffer.clear();
+        }
+        let count = std::cmp::min(buf.len(), CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+
+/// Decrypts data written by an `EncryptingWriter`
+pub(crate) struct DecryptingReader<R: Read> {
+    inner: R,
+    header: [u8; HEADER_SIZE],
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Reads the header from `inner` and returns it along with a reader of the
+    /// decrypted contents
+    pub(crate) fn new(mut inner: R, key: &EncryptionKey) -> io::Result<(Header, Self)> {
+        let header = Header::read(&mut inner)?;
+        let decryptor =
+            DecryptorBE32::new(GenericArray::from_slice(&key.0), GenericArray::from_slice(&header.nonce_prefix));
+        let reader = DecryptingReader {
+            inner,
+            header: header.to_bytes(),
+            decryptor: Some(decryptor),
+            ciphertext: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE + 1),
+            plaintext: Vec::new(),
+            position: 0,
+        };
+        Ok((header, reader))
+    }
+
+    /// Decrypts the next chunk into the plaintext buffer
+    fn decrypt_chunk(&mut self) -> io::Result<()> {
+        // Read one byte more than a full chunk to determine if this is the last
+        let wanted = CHUNK_SIZE + TAG_SIZE + 1;
+        while self.ciphertext.len() < wanted {
+            let start = self.ciphertext.len();
+            self.ciphertext.resize(wanted, 0);
+            let count = self.inner.read(&mut self.ciphertext[start..])?;
+            self.ciphertext.truncate(start + count);
+            if count == 0 {
+                break;
+            }
+        }
+        let Some(decryptor) = self.decryptor.as_mut() else {
+            self.plaintext.clear();
+            return Ok(());
+        };
+        if self.ciphertext.len() == wanted {
+            let chunk: Vec<u8> = self.ciphertext.drain(..wanted - 1).collect();
+            self.plaintext =
+                decryptor.decrypt_next(Payload { msg: &chunk, aad: &self.header }).map_err(|_| crypto_error())?;
+        } else {
+            let decryptor = self.decryptor.take().expect("Decryptor unexpectedly missing");
+            self.plaintext = decryptor
+                .decrypt_last(Payload { msg: &self.ciphertext, aad: &self.header })
+                .map_err(|_| crypto_error())?;
+            self.ciphertext.clear();
+        }
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.decrypt_chunk()?;
+        }
+        let count = std::cmp::min(buf.len(), self.plaintext.len() - self.position);
+        buf[..count].copy_from_slice(&self.plaintext[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> EncryptionKey { EncryptionKey([byte; 32]) }
+
+    fn encrypt(key: &EncryptionKey, plaintext: &[u8]) -> Vec<u8> {
+        let size = u64::try_from(plaintext.len()).unwrap();
+        let mut writer = EncryptingWriter::new(Vec::new(), key, false, size).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &EncryptionKey, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let (header, mut reader) = DecryptingReader::new(ciphertext, key)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        assert_eq!(header.size, u64::try_from(plaintext.len()).unwrap());
+        Ok(plaintext)
+    }
+
+    /// Contents spanning several chunks, ending part way through one
+    fn contents() -> Vec<u8> { (0..=u8::MAX).cycle().take(CHUNK_SIZE * 2 + 100).collect() }
+
+    #[test]
+    fn round_trip() {
+        for plaintext in [Vec::new(), b"short".to_vec(), vec![7; CHUNK_SIZE], contents()] {
+            let ciphertext = encrypt(&key(1), &plaintext);
+            assert_eq!(
+                ciphertext.len(),
+                HEADER_SIZE + plaintext.len() + TAG_SIZE * plaintext.len().div_ceil(CHUNK_SIZE).max(1)
+            );
+            assert_eq!(decrypt(&key(1), &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let ciphertext = encrypt(&key(1), &contents());
+        let error = decrypt(&key(2), &ciphertext).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let ciphertext = encrypt(&key(1), &contents());
+        for idx in [HEADER_SIZE - 1, HEADER_SIZE + 10, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[idx] ^= 1;
+            let error = decrypt(&key(1), &tampered).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        let truncated = &ciphertext[..ciphertext.len() - CHUNK_SIZE];
+        assert_eq!(decrypt(&key(1), truncated).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn non_encrypted_data_is_rejected() {
+        let error = decrypt(&key(1), &[0u8; HEADER_SIZE]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn keys_from_hex() {
+        let hex = "00".repeat(31) + "ff";
+        assert_eq!(EncryptionKey::from_hex(&hex).unwrap().0[31], 0xff);
+        assert!(EncryptionKey::from_hex("00").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+    }
+}
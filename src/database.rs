@@ -0,0 +1,127 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::NaiveDate;
+use regex::Regex;
+
+use crate::filename::split_extension;
+use crate::Error;
+
+/// Prefix of WhatsApp's message database backups
+const MSGSTORE_PREFIX: &str = "msgstore";
+
+/// Format of the dates in the names of WhatsApp's older database backups
+pub const DEFAULT_DB_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// How a series of database backups in the `Databases` folder is named.
+///
+/// The current backup is named `<prefix>.db.<extension>`, e.g.
+/// `msgstore.db.crypt14`, while older backups are named
+/// `<prefix>-<date>.<n>.db.<extension>`, e.g.
+/// `msgstore-2023-01-31.1.db.crypt14`. Incremental backups insert
+/// `-increment-<n>` after the prefix.
+#[derive(Clone, Debug)]
+pub struct DbPattern {
+    prefix: String,
+    date_format: String,
+    current: Regex,
+    previous: Regex,
+}
+
+impl DbPattern {
+    /// Constructs a pattern for backups whose names start with `prefix` and
+    /// whose older backups are dated using the `chrono` format `date_format`,
+    /// e.g. `%Y-%m-%d`. Neither the prefix nor the date format can contain
+    /// `.` since this separates the base name from the extension.
+    pub fn new(prefix: &str, date_format: &str) -> Result<DbPattern, Error> {
+        let invalid = |reason: &str| Error::InvalidDbPattern(prefix.to_string(), reason.to_string());
+        if prefix.is_empty() {
+            return Err(invalid("the prefix is empty"));
+        }
+        if prefix.contains('.') {
+            return Err(invalid("the prefix cannot contain `.`"));
+        }
+        if date_format.contains('.') {
+            return Err(invalid("the date format cannot contain `.`"));
+        }
+        if StrftimeItems::new(date_format).any(|item| matches!(item, Item::Error)) {
+            return Err(invalid("the date format is invalid"));
+        }
+        let prefix_regex = regex::escape(prefix);
+        // Both are matched against the base name, i.e. up to the first `.`
+        let current = Regex::new(&format!(r"^{}(?P<incremental>-increment-\d+)?$", prefix_regex))
+            .expect("Invalid database name regex");
+        let previous = Regex::new(&format!(r"^{}(?P<incremental>-increment-\d+)?-(?P<date>.+)$", prefix_regex))
+            .expect("Invalid database name regex");
+        Ok(DbPattern { prefix: prefix.to_string(), date_format: date_format.to_string(), current, previous })
+    }
+
+    /// The pattern of WhatsApp's message database backups, `msgstore`
+    pub fn msgstore() -> DbPattern {
+        Self::new(MSGSTORE_PREFIX, DEFAULT_DB_DATE_FORMAT).expect("Invalid built-in database pattern")
+    }
+
+    /// The prefix of the names of the backups
+    pub fn prefix(&self) -> &str { &self.prefix }
+
+    /// The format of the dates in the names of older backups
+    pub fn date_format(&self) -> &str { &self.date_format }
+
+    /// If `filename` is the current backup, returns whether it is incremental
+    /// along with its extension following `db.`, e.g. `crypt14`
+    pub(crate) fn match_current<'a>(&self, filename: &'a str) -> Option<(bool, &'a str)> {
+        let (base_name, extension) = split_extension(filename);
+        let extension = extension?.strip_prefix("db.")?;
+        let captures = self.current.captures(base_name)?;
+        Some((captures.name("incremental").is_some(), extension))
+    }
+
+    /// If `filename` is an older backup, returns whether it is incremental
+    /// along with its date
+    pub(crate) fn match_previous(&self, filename: &str) -> Option<(bool, NaiveDate)> {
+        let (base_name, Some(_)) = split_extension(filename) else { return None };
+        let captures = self.previous.captures(base_name)?;
+        let date = NaiveDate::parse_from_str(captures.name("date")?.as_str(), &self.date_format).ok()?;
+        Some((captures.name("incremental").is_some(), date))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate { NaiveDate::from_ymd_opt(year, month, day).unwrap() }
+
+    #[test]
+    fn msgstore_names() {
+        let pattern = DbPattern::msgstore();
+        assert_eq!(pattern.match_current("msgstore.db.crypt14"), Some((false, "crypt14")));
+        assert_eq!(pattern.match_current("msgstore-increment-1.db.crypt15"), Some((true, "crypt15")));
+        assert_eq!(pattern.match_previous("msgstore-2023-01-31.1.db.crypt14"), Some((false, date(2023, 1, 31))));
+        assert_eq!(
+            pattern.match_previous("msgstore-increment-2-2023-01-31.1.db.crypt14"),
+            Some((true, date(2023, 1, 31)))
+        );
+        assert_eq!(pattern.match_current("msgstore-2023-01-31.1.db.crypt14"), None);
+        assert_eq!(pattern.match_previous("msgstore.db.crypt14"), None);
+        assert_eq!(pattern.match_current("wa.db.crypt14"), None);
+    }
+
+    #[test]
+    fn custom_series_names() {
+        let pattern = DbPattern::new("wa", "%Y%m%d").unwrap();
+        assert_eq!(pattern.match_current("wa.db.crypt14"), Some((false, "crypt14")));
+        assert_eq!(pattern.match_previous("wa-20230131.1.db.crypt14"), Some((false, date(2023, 1, 31))));
+        // Dates must follow the series' own format
+        assert_eq!(pattern.match_previous("wa-2023-01-31.1.db.crypt14"), None);
+        // Another series sharing a prefix is not matched
+        assert_eq!(pattern.match_current("wallpaper.db.crypt14"), None);
+        assert_eq!(pattern.match_previous("msgstore-20230131.1.db.crypt14"), None);
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for (prefix, date_format) in [("", "%Y"), ("wa.db", "%Y"), ("wa", "%Y.%m"), ("wa", "%Q")] {
+            let result = DbPattern::new(prefix, date_format);
+            assert!(matches!(result, Err(Error::InvalidDbPattern(_, _))), "{} {}", prefix, date_format);
+        }
+    }
+}
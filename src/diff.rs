@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A change made to a single file in an index
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// The file was added, with the given size in bytes
+    Added(u64),
+
+    /// The file's contents or metadata were replaced
+    Updated,
+
+    /// The file was removed, with the given size in bytes
+    Removed(u64),
+}
+
+impl Change {
+    fn symbol(self) -> char {
+        match self {
+            Change::Added(_) => '+',
+            Change::Updated => '~',
+            Change::Removed(_) => '-',
+        }
+    }
+}
+
+/// The net changes made to the files of an index, keyed by path relative to
+/// the index root.
+///
+/// When displayed, additions are listed first, followed by updates and then
+/// removals, each sorted by path, one per line in the form `+ path (size)`,
+/// `~ path` or `- path (size)`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Diff {
+    changes: BTreeMap<PathBuf, Change>,
+}
+
+impl Diff {
+    /// Records that the file at `path` was added
+    pub(crate) fn record_added(&mut self, path: &Path, size: u64) {
+        let change = match self.changes.get(path) {
+            // A file which was removed and added back has been replaced
+            Some(Change::Removed(_)) => Change::Updated,
+            _ => Change::Added(size),
+        };
+        self.changes.insert(path.to_path_buf(), change);
+    }
+
+    /// Records that the contents or metadata of the file at `path` were
+    /// replaced
+    pub(crate) fn record_updated(&mut self, path: &Path) {
+        self.changes.entry(path.to_path_buf()).or_insert(Change::Updated);
+    }
+
+    /// Records that the file at `path` was removed
+    pub(crate) fn record_removed(&mut self, path: &Path, size: u64) {
+        if let Some(Change::Added(_)) = self.changes.get(path) {
+            // A file which was added and removed again is unchanged overall
+            self.changes.remove(path);
+        } else {
+            self.changes.insert(path.to_path_buf(), Change::Removed(size));
+        }
+    }
+
+    /// Gets the change recorded for the file at `path`, if any
+    pub fn get(&self, path: &Path) -> Option<Change> { self.changes.get(path).copied() }
+
+    /// Iterates over changed paths and their changes, in path order
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, Change)> {
+        self.changes.iter().map(|(path, change)| (path.as_path(), *change))
+    }
+
+    /// The number of changed files
+    pub fn len(&self) -> usize { self.changes.len() }
+
+    /// Returns `true` if no files were changed
+    pub fn is_empty(&self) -> bool { self.changes.is_empty() }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in ['+', '~', '-'] {
+            for (path, change) in self.iter().filter(|(_, change)| change.symbol() == symbol) {
+                match change {
+                    Change::Added(size) | Change::Removed(size) => {
+                        writeln!(f, "{} {} ({})", symbol, path.display(), bytefmt::format(size))?;
+                    }
+                    Change::Updated => writeln!(f, "{} {}", symbol, path.display())?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_combined() {
+        let mut diff = Diff::default();
+        let (a, b, c) = (Path::new("a"), Path::new("b"), Path::new("c"));
+        diff.record_added(a, 1);
+        diff.record_updated(a);
+        diff.record_removed(b, 2);
+        diff.record_added(b, 3);
+        diff.record_added(c, 4);
+        diff.record_removed(c, 4);
+        assert_eq!(diff.get(a), Some(Change::Added(1)));
+        assert_eq!(diff.get(b), Some(Change::Updated));
+        assert_eq!(diff.get(c), None);
+        assert_eq!(diff.len(), 2);
+    }
+
+    #[test]
+    fn changes_are_listed_by_kind_then_path() {
+        let mut diff = Diff::default();
+        diff.record_removed(Path::new("Media/a.jpg"), 2048);
+        diff.record_updated(Path::new("Media/c.jpg"));
+        diff.record_added(Path::new("Media/d.jpg"), 10);
+        diff.record_updated(Path::new("Media/b.jpg"));
+        diff.record_added(Path::new("Databases/msgstore.db.crypt14"), 512);
+        let expected = format!(
+            "+ Databases/msgstore.db.crypt14 ({})\n+ Media/d.jpg ({})\n~ Media/b.jpg\n~ Media/c.jpg\n- Media/a.jpg ({})\n",
+            bytefmt::format(512),
+            bytefmt::format(10),
+            bytefmt::format(2048)
+        );
+        assert_eq!(diff.to_string(), expected);
+        assert_eq!(Diff::default().to_string(), "");
+    }
+}
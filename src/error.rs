@@ -7,15 +7,15 @@ use thiserror::Error;
 pub enum Error {
     /// An IO error involving a path
     #[error("An IO error occurred involving {1}: {0}")]
-    Io(io::Error, PathBuf),
+    Io(#[source] io::Error, PathBuf),
 
     /// An IO error encountered during a file copy
     #[error("An IO error occurred while copying: {0}\nSource: {1}\nTarget:{2}")]
-    Cp(io::Error, PathBuf, PathBuf),
+    Cp(#[source] io::Error, PathBuf, PathBuf),
 
     /// An IO error encountered during a file rename
     #[error("An IO error occurred while renaming: {0}\nSource: {1}\nTarget:{2}")]
-    Mv(io::Error, PathBuf, PathBuf),
+    Mv(#[source] io::Error, PathBuf, PathBuf),
 
     /// The supplied folder was not a WhatsApp data folder
     #[error("The supplied folder was not a WhatsApp folder: {0}")]
@@ -26,19 +26,210 @@ pub enum Error {
     #[error("The supplied folder was not an archive folder but not empty: {0}")]
     NewArchiveFolderNotEmpty(PathBuf),
 
-    /// Failed to copy file metadata
-    #[error("After a copy operation, the metadata of the two files did not match:\nSource: {0}\nTarget: {1}")]
+    /// The archive was written by a newer version of `waa`
+    #[error("The archive uses format version {0}, which is newer than this version of waa supports")]
+    UnsupportedArchiveVersion(u32),
+
+    /// Failed to copy file metadata, or the contents of a copied file did not
+    /// match its recorded hash
+    #[error(
+        "After a copy operation, the metadata or contents of the two files did not match:\nSource: {0}\nTarget: {1}"
+    )]
     FileMismatch(PathBuf, PathBuf),
 
     /// File not found
     #[error("A file was unexpectedly missing: {0}")]
     FileMissing(PathBuf),
 
+    /// A glob pattern could not be parsed
+    #[error("Invalid glob pattern `{0}`: {1}")]
+    InvalidGlob(String, #[source] glob::PatternError),
+
+    /// A duration could not be parsed
+    #[error("Invalid duration `{0}`: {1}")]
+    DurationParse(String, String),
+
+    /// A database naming pattern was invalid
+    #[error("Invalid database pattern `{0}`: {1}")]
+    InvalidDbPattern(String, String),
+
+    /// An encryption key could not be parsed
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    /// The index holds encrypted files but no key was supplied
+    #[error("The folder {0} contains encrypted files but no encryption key was supplied")]
+    EncryptionKeyRequired(PathBuf),
+
+    /// There is not enough free space to copy the required files
+    #[error("Insufficient free space: {needed} bytes are needed but only {available} bytes are available")]
+    InsufficientSpace { needed: u64, available: u64 },
+
+    /// Deleting files would remove more of an index than permitted
+    #[error(
+        "Refusing to delete {files} of {total_files} files ({bytes} of {total_bytes} bytes) present before this run, \
+         which exceeds the permitted fraction"
+    )]
+    ExcessiveDeletion { files: usize, total_files: usize, bytes: u64, total_bytes: u64 },
+
+    /// Some of the files in a parallel deletion could not be deleted, each
+    /// with the error encountered
+    #[error("{}", format_failed_deletions(.0))]
+    DeletionsFailed(Vec<(PathBuf, io::Error)>),
+
+    /// The WhatsApp folder and archive are the same folder, or one is inside
+    /// the other, so mirroring would copy a folder into itself
+    #[error("The WhatsApp folder {0} and archive {1} overlap, so one cannot be mirrored into the other")]
+    OverlappingPaths(PathBuf, PathBuf),
+
+    /// A path found while indexing was not within the indexed folder
+    #[error("A path was unexpectedly outside the indexed folder: {0}")]
+    PathOutsideIndex(PathBuf),
+
+    /// A pool of worker threads could not be created
+    #[error("Unable to create worker threads: {0}")]
+    ThreadPool(#[source] rayon::ThreadPoolBuildError),
+
+    /// The index was built from a listing and has no files on disk to read or
+    /// change
+    #[error("The index was read from the listing {0} and its files are not available")]
+    ListingOnly(PathBuf),
+
+    /// The deadline for the operation passed before it completed
+    #[error("The operation did not complete within the time limit")]
+    TimedOut,
+
     /// An entry in the file index was unexpectedly missing
     #[error("An entry was unexpectedly missing from the file index (probably a bug)")]
     IndexEntryMissing,
 }
 
+impl Error {
+    /// A stable process exit code classifying this error. 2 indicates a
+    /// problem with the supplied folders or options, 3 an IO error, lack of
+    /// space or timeout, and 4 a bug in `waa`.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NotWhatsAppFolder(_)
+            | Error::NewArchiveFolderNotEmpty(_)
+            | Error::UnsupportedArchiveVersion(_)
+            | Error::InvalidGlob(_, _)
+            | Error::DurationParse(_, _)
+            | Error::InvalidDbPattern(_, _)
+            | Error::InvalidKey(_)
+            | Error::EncryptionKeyRequired(_)
+            | Error::ListingOnly(_)
+            | Error::OverlappingPaths(_, _)
+            | Error::ExcessiveDeletion { .. } => 2,
+            Error::Io(_, _)
+            | Error::Cp(_, _, _)
+            | Error::Mv(_, _, _)
+            | Error::DeletionsFailed(_)
+            | Error::FileMismatch(_, _)
+            | Error::FileMissing(_)
+            | Error::InsufficientSpace { .. }
+            | Error::ThreadPool(_)
+            | Error::TimedOut => 3,
+            Error::PathOutsideIndex(_) | Error::IndexEntryMissing => 4,
+        }
+    }
+
+    /// The kind of the underlying IO error, if this error was caused by one
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Error::Io(e, _) | Error::Cp(e, _, _) | Error::Mv(e, _, _) => Some(e.kind()),
+            _ => None,
+        }
+    }
+}
+
+/// Lists each file which could not be deleted along with its error
+fn format_failed_deletions(failures: &[(PathBuf, io::Error)]) -> String {
+    let details: Vec<String> = failures.iter().map(|(path, e)| format!("  {}: {}", path.display(), e)).collect();
+    format!("Unable to delete {} files:\n{}", failures.len(), details.join("\n"))
+}
+
 impl<P: AsRef<Path>> From<(io::Error, P)> for Error {
     fn from(err: (io::Error, P)) -> Self { Error::Io(err.0, err.1.as_ref().to_owned()) }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+    use crate::FileInfo;
+
+    #[test]
+    fn io_errors_are_exposed_as_source() {
+        let error = Error::from((io::Error::new(io::ErrorKind::PermissionDenied, "denied"), "file.txt"));
+        let source = error.source().expect("IO error has no source");
+        let io_error = source.downcast_ref::<io::Error>().expect("Source is not an IO error");
+        assert_eq!(io_error.kind(), io::ErrorKind::PermissionDenied);
+
+        let copy = Error::Cp(io::Error::other("failed"), PathBuf::from("a"), PathBuf::from("b"));
+        assert!(copy.source().and_then(|source| source.downcast_ref::<io::Error>()).is_some());
+        assert!(Error::TimedOut.source().is_none());
+    }
+
+    #[test]
+    fn io_kind_of_missing_file() {
+        let error = FileInfo::new(Path::new("/nonexistent/waa/file.txt")).unwrap_err();
+        assert_eq!(error.io_kind(), Some(io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn io_kind_of_non_io_errors() {
+        assert_eq!(Error::TimedOut.io_kind(), None);
+        assert_eq!(Error::FileMissing(PathBuf::from("file.txt")).io_kind(), None);
+    }
+
+    #[test]
+    fn usage_errors_exit_with_2() {
+        let path = || PathBuf::from("folder");
+        let glob_error = glob::Pattern::new("[").unwrap_err();
+        let errors = [
+            Error::NotWhatsAppFolder(path()),
+            Error::NewArchiveFolderNotEmpty(path()),
+            Error::UnsupportedArchiveVersion(u32::MAX),
+            Error::InvalidGlob("[".into(), glob_error),
+            Error::DurationParse("1x".into(), "unknown unit".into()),
+            Error::InvalidDbPattern("*".into(), "no prefix".into()),
+            Error::InvalidKey("00".into()),
+            Error::EncryptionKeyRequired(path()),
+            Error::ListingOnly(path()),
+            Error::OverlappingPaths(path(), path()),
+            Error::ExcessiveDeletion { files: 2, total_files: 3, bytes: 2, total_bytes: 3 },
+        ];
+        for error in errors {
+            assert_eq!(error.exit_code(), 2, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn io_errors_exit_with_3() {
+        let path = || PathBuf::from("file.txt");
+        let io_error = || io::Error::other("failed");
+        let pool_error = rayon::ThreadPoolBuilder::new().spawn_handler(|_| Err(io_error())).build().unwrap_err();
+        let errors = [
+            Error::Io(io_error(), path()),
+            Error::Cp(io_error(), path(), path()),
+            Error::Mv(io_error(), path(), path()),
+            Error::DeletionsFailed(vec![(path(), io_error())]),
+            Error::FileMismatch(path(), path()),
+            Error::FileMissing(path()),
+            Error::InsufficientSpace { needed: 2, available: 1 },
+            Error::ThreadPool(pool_error),
+            Error::TimedOut,
+        ];
+        for error in errors {
+            assert_eq!(error.exit_code(), 3, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn bugs_exit_with_4() {
+        assert_eq!(Error::PathOutsideIndex(PathBuf::from("/elsewhere")).exit_code(), 4);
+        assert_eq!(Error::IndexEntryMissing.exit_code(), 4);
+    }
+}
@@ -0,0 +1,73 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDateTime};
+
+/// How exported media is arranged into folders by creation date
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ExportLayout {
+    /// One folder per year, e.g. `2023`
+    Year,
+
+    /// One folder per month within a folder per year, e.g. `2023/01`
+    #[default]
+    YearMonth,
+
+    /// One folder per day within folders per year and month, e.g.
+    /// `2023/01/05`
+    YearMonthDay,
+}
+
+impl ExportLayout {
+    /// The folder, relative to the export root, for media created at `date`
+    pub(crate) fn folder(self, date: NaiveDateTime) -> PathBuf {
+        let mut folder = PathBuf::from(format!("{:04}", date.year()));
+        if self != ExportLayout::Year {
+            folder.push(format!("{:02}", date.month()));
+        }
+        if self == ExportLayout::YearMonthDay {
+            folder.push(format!("{:02}", date.day()));
+        }
+        folder
+    }
+}
+
+/// Returns `filename` with ` (n)` inserted before its extension to avoid a
+/// collision with another file of the same name, or `filename` unchanged if
+/// `n` is zero
+pub(crate) fn numbered_filename(filename: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        return filename.to_path_buf();
+    }
+    let mut numbered: OsString = filename.file_stem().unwrap_or(filename.as_os_str()).to_owned();
+    numbered.push(format!(" ({})", n));
+    if let Some(extension) = filename.extension() {
+        numbered.push(".");
+        numbered.push(extension);
+    }
+    PathBuf::from(numbered)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    #[test]
+    fn folders_follow_layout() {
+        let date = NaiveDate::from_ymd_opt(2023, 1, 5).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        assert_eq!(ExportLayout::Year.folder(date), Path::new("2023"));
+        assert_eq!(ExportLayout::YearMonth.folder(date), Path::new("2023/01"));
+        assert_eq!(ExportLayout::YearMonthDay.folder(date), Path::new("2023/01/05"));
+    }
+
+    #[test]
+    fn numbers_are_inserted_before_extension() {
+        let numbered = |name: &str, n| numbered_filename(Path::new(name), n);
+        assert_eq!(numbered("IMG-20230105-WA0001.jpg", 0), Path::new("IMG-20230105-WA0001.jpg"));
+        assert_eq!(numbered("IMG-20230105-WA0001.jpg", 2), Path::new("IMG-20230105-WA0001 (2).jpg"));
+        assert_eq!(numbered("notes", 1), Path::new("notes (1)"));
+        assert_eq!(numbered("archive.tar.gz", 1), Path::new("archive.tar (1).gz"));
+    }
+}
@@ -1,16 +1,68 @@
 use std::borrow::ToOwned;
-use std::collections::{hash_map, BTreeSet, HashMap, HashSet, VecDeque};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use filetime::FileTime;
 use log::warn;
-use rand::Rng;
-use regex::Regex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
 
-use crate::{DataLimit, Error, FileInfo, FileQuery};
+use crate::codec::{EncodedWriter, StoredFile};
+use crate::copy::{copy_with, CopyObserver, CopyOptions, Deadline, ReportProgress};
+use crate::export::numbered_filename;
+use crate::file_info::DEFAULT_ASSUMED_TIME;
+use crate::filename::split_extension;
+use crate::hash::ContentHasher;
+use crate::history::HISTORY_NAME;
+use crate::listing::{ListedFile, Listing};
+use crate::manifest::MANIFEST_NAME;
+use crate::resume::{ResumeMarker, ResumeWriter, RESUME_NAME};
+use crate::tag::{ArchiveTag, ARCHIVE_VERSION};
+use crate::trimmed::{self, TRIMMED_NAME};
+use crate::{
+    BackupKind, BackupRetention, CancelToken, Change, ContentHash, DataLimit, DbPattern, Diff, Encoding, EncryptionKey,
+    Error, ExportLayout, FileInfo, FileQuery, HistoryRecord, IndexStats, Manifest, MediaCategory, MirrorPlan,
+    MirrorReport, NoProgress, Progress, ResumePhase, SkipReason, SkippedEntry, Timezone, DEFAULT_COPY_CHUNK_SIZE,
+};
 
-const TAG_NAME: &str = ".waa";
+/// Default name of the file marking a folder as an archive
+const DEFAULT_TAG_NAME: &str = ".waa";
+
+/// Suffix of the temporary files written while copying, which are renamed
+/// into place once complete
+pub(crate) const TEMP_SUFFIX: &str = ".waa.tmp";
+
+/// The encodings a file in an archive may be stored with, each with a
+/// different suffix. An encrypted file may also be compressed.
+const STORED_ENCODINGS: [Encoding; 3] =
+    [Encoding::PLAIN, Encoding { compressed: true, encrypted: false }, Encoding { compressed: false, encrypted: true }];
+
+/// Number of times a new temporary file name is tried if one already exists
+const TEMP_FILE_ATTEMPTS: usize = 16;
+
+/// Folder of the WhatsApp folder holding media, which is the only content
+/// which is ever trimmed or restored
+pub(crate) const MEDIA_FOLDER: &str = "Media";
+
+/// Folder of the WhatsApp folder holding the message databases
+const DATABASES_FOLDER: &str = "Databases";
+
+/// Folder of the WhatsApp folder holding backups of settings and other data
+const BACKUPS_FOLDER: &str = "Backups";
+
+/// How often databases are checked while waiting for WhatsApp to finish
+/// writing them
+const QUIESCENCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Name of the marker file which hides a folder from Android's media scanner
+const NOMEDIA_NAME: &str = ".nomedia";
 
 /// What the file index is constructed over
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -28,15 +80,113 @@ pub enum ActionType {
     Dry,
 }
 
+/// Options controlling how a `FileIndex` is constructed
+#[derive(Clone, Debug)]
+pub struct IndexOptions {
+    /// Resolve the path of the index to an absolute path without symbolic
+    /// links. This can fail or be slow on MTP mounts and some network
+    /// filesystems, in which case it should be disabled.
+    pub canonicalize: bool,
+
+    /// Name of the file in the root of an archive which marks it as one.
+    /// Files with this name are never indexed.
+    pub tag_name: String,
+
+    /// When opening an archive in real mode, remove temporary files left by
+    /// an interrupted copy which were last modified longer ago than this.
+    /// Newer temporary files may belong to a copy still in progress.
+    pub clean_temp_older_than: Option<Duration>,
+
+    /// The timezone in which dates are interpreted, which should be that of
+    /// the phone. WhatsApp names media using the phone's local date, so this
+    /// affects when a file is considered to be a day old.
+    pub timezone: Timezone,
+
+    /// The time of day assumed for files whose names record only the date
+    /// they were created
+    pub assumed_time: NaiveTime,
+
+    /// Follow symbolic links to files and folders while indexing, rather than
+    /// ignoring them. Links leading to a folder which has already been indexed
+    /// are not followed, so loops are safe.
+    pub follow_symlinks: bool,
+
+    /// Only index the `Databases` and `Backups` folders of a WhatsApp folder,
+    /// e.g. when only the message databases are to be archived. Archives are
+    /// always indexed in full.
+    pub databases_only: bool,
+}
+
+impl Default for IndexOptions {
+    fn default() -> IndexOptions {
+        IndexOptions {
+            canonicalize: true,
+            tag_name: DEFAULT_TAG_NAME.to_string(),
+            clean_temp_older_than: None,
+            timezone: Timezone::default(),
+            assumed_time: DEFAULT_ASSUMED_TIME,
+            follow_symlinks: false,
+            databases_only: false,
+        }
+    }
+}
+
 /// A file index for a directory tree
 #[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct FileIndex {
-    _index_type: IndexType,
+    index_type: IndexType,
     action_type: ActionType,
     path: PathBuf,
+    tag_name: String,
+    timezone: Timezone,
+    assumed_time: NaiveTime,
+    follow_symlinks: bool,
+    databases_only: bool,
+    archive_version: u32,
     entries: HashMap<PathBuf, FileInfo>,
+    temp_files: Vec<PathBuf>,
+    skipped: Vec<SkippedEntry>,
+    cleaned_temp: (u64, usize),
+    hashing: bool,
+    content_identity: bool,
+    verify_content: bool,
+    reverify: bool,
+    max_file_size: Option<u64>,
+    resume: Option<ResumeMarker>,
+    compression: bool,
+    key: Option<EncryptionKey>,
+    rollback: bool,
+    resume_copies: bool,
+    db_patterns: Vec<DbPattern>,
+    keep_db_of_each_type: bool,
+    backup_retention: BackupRetention,
+    listing_only: bool,
+    deadline: Option<Instant>,
+    copy_chunk_size: usize,
+    cancel_token: Option<CancelToken>,
+    source_manifest: Option<Manifest>,
+    temp_rng: Mutex<Option<StdRng>>,
+    deletion_guard: Option<DeletionGuard>,
+    changes: Diff,
+    progress: RefCell<Box<dyn Progress>>,
 }
 
+/// Limits the fraction of the files present in an index when the limit was
+/// set which can subsequently be deleted
+#[derive(Debug)]
+struct DeletionGuard {
+    max_fraction: f64,
+    remaining: HashMap<PathBuf, u64>,
+    total_files: usize,
+    total_bytes: u64,
+    deleted_files: usize,
+    deleted_bytes: u64,
+}
+
+/// A media file considered for deletion or retention
+type Candidate<'a> = (&'a Path, &'a FileInfo);
+
 #[derive(Debug)]
 struct DbInfo {
     pub is_incremental: bool,
@@ -47,105 +197,757 @@ struct DbInfo {
 impl FileIndex {
     /// Constructs a new index of the files at the specified path.
     pub fn new<P: AsRef<Path>>(index_type: IndexType, path: P, action_type: ActionType) -> Result<FileIndex, Error> {
+        Self::new_with_options(index_type, path, action_type, &IndexOptions::default())
+    }
+
+    /// Constructs a new index of the files at the specified path using the
+    /// supplied options.
+    pub fn new_with_options<P: AsRef<Path>>(
+        index_type: IndexType, path: P, action_type: ActionType, options: &IndexOptions,
+    ) -> Result<FileIndex, Error> {
         let path = path.as_ref();
         let mut new = false;
+        let mut archive_version = ARCHIVE_VERSION;
         match index_type {
-            IndexType::Original => {
-                let mut found_db = false;
-                for suffix in &["crypt14", "crypt15"] {
-                    let db_path = path.join("Databases").join(format!("msgstore.db.{}", suffix));
-                    if db_path.exists() {
-                        found_db = true;
-                        break;
-                    }
-                }
-                let tag_path = path.join(TAG_NAME);
-                // We check for presence of a DB and that this is not a backup folder
-                if !found_db || tag_path.exists() {
-                    return Err(Error::NotWhatsAppFolder(path.to_owned()));
-                }
-            }
+            IndexType::Original => Self::validate_with_options(index_type, path, options)?,
             IndexType::Archive => {
                 if !path.exists() && action_type == ActionType::Real {
                     std::fs::create_dir_all(path).map_err(|e| (e, path))?;
                 }
-                let tag_path = path.join(TAG_NAME);
-                if !tag_path.exists() {
-                    if action_type == ActionType::Real {
-                        let num_entries = path.read_dir().map_err(|e| (e, path))?.count();
-                        if num_entries == 0 {
-                            std::fs::write(&tag_path, []).map_err(|e| (e, &tag_path))?;
-                        } else {
-                            return Err(Error::NewArchiveFolderNotEmpty(path.to_owned()));
-                        }
+                let tag_path = path.join(&options.tag_name);
+                if tag_path.exists() {
+                    archive_version = ArchiveTag::read(&tag_path)?.version;
+                } else if action_type == ActionType::Real {
+                    let num_entries = path.read_dir().map_err(|e| (e, path))?.count();
+                    if num_entries == 0 {
+                        ArchiveTag::write_current(&tag_path)?;
                     } else {
-                        new = true;
+                        return Err(Error::NewArchiveFolderNotEmpty(path.to_owned()));
                     }
+                } else {
+                    new = true;
                 }
             }
-        };
-        let path = if action_type == ActionType::Real {
+        }
+        let path = if !options.canonicalize {
+            // The path is used as given, but must still exist unless this is a
+            // new archive in dry-run mode
+            if !new {
+                std::fs::metadata(path).map_err(|e| (e, path))?;
+            }
+            path.to_path_buf()
+        } else if action_type == ActionType::Real {
             path.canonicalize().map_err(|e| (e, path))?
-        } else if let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) {
-            let parent = parent.canonicalize().map_err(|e| (e, parent))?;
-            parent.join(file_name)
         } else {
-            path.to_path_buf()
+            // The folder might not exist yet, so only its parent is resolved.
+            // Making the path absolute first ensures inputs like `.` or a bare
+            // folder name have a parent.
+            let path = std::path::absolute(path).map_err(|e| (e, path))?;
+            if let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) {
+                parent.canonicalize().map_err(|e| (e, parent))?.join(file_name)
+            } else {
+                path
+            }
         };
-        let mut result = FileIndex { _index_type: index_type, path, entries: HashMap::new(), action_type };
+        let mut result = Self::empty(index_type, path, action_type, options);
+        result.archive_version = archive_version;
         // So that dry-run mode doesn't error when a new folder hasn't been created
         if !new {
             result.rebuild_index()?;
+            if index_type == IndexType::Archive {
+                result.resume = ResumeMarker::load(&result.path.join(RESUME_NAME))?;
+                if let (ActionType::Real, Some(max_age)) = (action_type, options.clean_temp_older_than) {
+                    result.cleaned_temp = result.clean_temp_files(max_age)?;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Constructs an index of the files recorded in a listing previously
+    /// written by `save_listing`, without the files needing to be present.
+    /// Queries and size accounting work as for the original index, e.g. to
+    /// plan trimming on another machine, but any operation which would read
+    /// or change the files fails with `Error::ListingOnly`.
+    pub fn from_listing<P: AsRef<Path>>(path: P) -> Result<FileIndex, Error> {
+        Self::from_listing_with_options(path, &IndexOptions::default())
+    }
+
+    /// Constructs an index of the files recorded in a listing using the
+    /// supplied options, which should match those the listed index was built
+    /// with so that dates are interpreted the same way
+    pub fn from_listing_with_options<P: AsRef<Path>>(path: P, options: &IndexOptions) -> Result<FileIndex, Error> {
+        let path = path.as_ref();
+        let listing = Listing::load(path)?;
+        let index_type = if listing.archive { IndexType::Archive } else { IndexType::Original };
+        let mut result = Self::empty(index_type, path.to_path_buf(), ActionType::Dry, options);
+        result.listing_only = true;
+        for file in listing.files {
+            let rel_path = PathBuf::from(file.path);
+            let modification_time = FileTime::from_unix_time(file.modified, file.modified_nanos);
+            let encoding = Encoding { compressed: file.compressed, encrypted: file.encrypted };
+            let info = FileInfo::from_listing(&rel_path, file.size, file.stored_size, modification_time, encoding);
+            let info = result.localize(&info);
+            result.entries.insert(rel_path, info);
         }
         Ok(result)
     }
 
-    /// Strips the location of the index from an absolute path
-    fn get_relative_path(&self, path: &Path) -> PathBuf {
-        path.strip_prefix(&self.path).expect("Unable to strip prefix").to_owned()
+    /// Saves the metadata of every file in the index to `path`, so that the
+    /// index can be rebuilt elsewhere with `from_listing`
+    pub fn save_listing(&self, path: &Path) -> Result<(), Error> {
+        let mut files: Vec<ListedFile> = self
+            .entries
+            .iter()
+            .map(|(rel_path, info)| ListedFile {
+                path: rel_path.to_string_lossy().into_owned(),
+                size: info.get_size(),
+                stored_size: info.get_stored_size(),
+                modified: info.get_modification_time().unix_seconds(),
+                modified_nanos: info.get_modification_time().nanoseconds(),
+                compressed: info.get_encoding().compressed,
+                encrypted: info.get_encoding().encrypted,
+            })
+            .collect();
+        files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        let listing = Listing { archive: self.index_type == IndexType::Archive, files };
+        listing.save(path)
+    }
+
+    /// Constructs an index at `path` with no entries
+    fn empty(index_type: IndexType, path: PathBuf, action_type: ActionType, options: &IndexOptions) -> FileIndex {
+        FileIndex {
+            index_type,
+            path,
+            tag_name: options.tag_name.clone(),
+            timezone: options.timezone,
+            assumed_time: options.assumed_time,
+            follow_symlinks: options.follow_symlinks,
+            databases_only: options.databases_only && index_type == IndexType::Original,
+            archive_version: ARCHIVE_VERSION,
+            entries: HashMap::new(),
+            temp_files: Vec::new(),
+            skipped: Vec::new(),
+            cleaned_temp: (0, 0),
+            action_type,
+            hashing: false,
+            content_identity: false,
+            verify_content: false,
+            reverify: false,
+            max_file_size: None,
+            resume: None,
+            compression: false,
+            key: None,
+            rollback: false,
+            resume_copies: false,
+            db_patterns: vec![DbPattern::msgstore()],
+            keep_db_of_each_type: false,
+            backup_retention: BackupRetention::default(),
+            listing_only: false,
+            deadline: None,
+            copy_chunk_size: DEFAULT_COPY_CHUNK_SIZE,
+            cancel_token: None,
+            source_manifest: None,
+            temp_rng: Mutex::new(None),
+            deletion_guard: None,
+            changes: Diff::default(),
+            progress: RefCell::new(Box::new(NoProgress)),
+        }
+    }
+
+    /// Fails if the index was built from a listing, so its files cannot be
+    /// read or changed
+    fn ensure_files_present(&self) -> Result<(), Error> {
+        if self.listing_only {
+            Err(Error::ListingOnly(self.path.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that an index of the specified type could be constructed at
+    /// `path` without building the index or changing anything on disk. A
+    /// WhatsApp folder must contain a message database and must not be an
+    /// archive. An archive folder must either be an existing archive, empty or
+    /// not yet exist.
+    pub fn validate<P: AsRef<Path>>(index_type: IndexType, path: P) -> Result<(), Error> {
+        Self::validate_with_options(index_type, path, &IndexOptions::default())
+    }
+
+    /// Checks that an index of the specified type could be constructed at
+    /// `path` using the supplied options, as for `validate`.
+    pub fn validate_with_options<P: AsRef<Path>>(
+        index_type: IndexType, path: P, options: &IndexOptions,
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tag_path = path.join(&options.tag_name);
+        match index_type {
+            IndexType::Original => {
+                let found_db = ["crypt14", "crypt15"]
+                    .iter()
+                    .any(|suffix| path.join(DATABASES_FOLDER).join(format!("msgstore.db.{}", suffix)).exists());
+                // We check for presence of a DB and that this is not a backup folder
+                if !found_db || tag_path.exists() {
+                    return Err(Error::NotWhatsAppFolder(path.to_owned()));
+                }
+            }
+            IndexType::Archive => {
+                if tag_path.exists() {
+                    ArchiveTag::read(&tag_path)?;
+                } else if path.exists() {
+                    let num_entries = path.read_dir().map_err(|e| (e, path))?.count();
+                    if num_entries != 0 {
+                        return Err(Error::NewArchiveFolderNotEmpty(path.to_owned()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the WhatsApp folder and archive are distinct and that
+    /// neither is inside the other, since mirroring one into the other would
+    /// copy a folder into itself. Symbolic links are resolved before the
+    /// paths are compared, so different paths to the same folder are
+    /// detected. The archive need not exist yet.
+    pub fn check_not_overlapping<P: AsRef<Path>, Q: AsRef<Path>>(wa_folder: P, archive_folder: Q) -> Result<(), Error> {
+        let (wa_folder, archive_folder) = (wa_folder.as_ref(), archive_folder.as_ref());
+        let (resolved_wa, resolved_archive) = (Self::resolve_folder(wa_folder), Self::resolve_folder(archive_folder));
+        if resolved_wa.starts_with(&resolved_archive) || resolved_archive.starts_with(&resolved_wa) {
+            return Err(Error::OverlappingPaths(wa_folder.to_owned(), archive_folder.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Resolves symbolic links in `path` as far as its nearest existing
+    /// ancestor, e.g. for an archive folder which will be created
+    fn resolve_folder(path: &Path) -> PathBuf {
+        let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+        for ancestor in absolute.ancestors() {
+            if let Ok(canonical) = ancestor.canonicalize() {
+                let rest = absolute.strip_prefix(ancestor).expect("Ancestor was not a prefix of path");
+                return canonical.join(rest);
+            }
+        }
+        absolute
+    }
+
+    /// The root of the folder indexed
+    pub fn path(&self) -> &Path { &self.path }
+
+    /// Enables comparing file contents by hash when deciding whether a file
+    /// whose modification time differs actually needs to be copied
+    pub fn set_hashing(&mut self, enabled: bool) { self.hashing = enabled; }
+
+    /// Enables recognising files by their contents as well as their paths
+    /// when mirroring. A file missing from this index whose contents match
+    /// media here which is no longer in the source is treated as renamed, and
+    /// is copied from the existing file rather than from the source. The
+    /// existing file is kept, since it may have been trimmed rather than
+    /// renamed.
+    pub fn set_content_identity(&mut self, enabled: bool) { self.content_identity = enabled; }
+
+    /// Enables comparing the contents of files whose metadata matches, so that
+    /// files modified without a change in size or modification time are still
+    /// copied. This reads every file in both indices.
+    pub fn set_content_verification(&mut self, enabled: bool) { self.verify_content = enabled; }
+
+    /// Enables verifying the contents of files which an interrupted mirror
+    /// operation already confirmed match the source, rather than trusting its
+    /// resume marker. Only relevant if content verification is enabled.
+    pub fn set_reverify(&mut self, enabled: bool) { self.reverify = enabled; }
+
+    /// The last phase reached by an interrupted mirror operation and the
+    /// number of files it confirmed, if this index has a resume marker
+    pub fn resume_state(&self) -> Option<(ResumePhase, usize)> {
+        self.resume.as_ref().map(|marker| (marker.phase(), marker.len()))
+    }
+
+    /// Sets the size in bytes above which files are never copied into this
+    /// index by a mirror operation. Files already present are not affected.
+    pub fn set_max_file_size(&mut self, max_size: Option<u64>) { self.max_file_size = max_size; }
+
+    /// Enables compressing files of compressible types as they are imported.
+    /// Files already present are not affected until they are next updated.
+    pub fn set_compression(&mut self, enabled: bool) { self.compression = enabled; }
+
+    /// Sets the key used to encrypt files as they are imported and to decrypt
+    /// encrypted files already in the index. Files already present are not
+    /// encrypted until they are next updated. Fails without changing the key
+    /// if `key` is `None` but the index holds encrypted files, since new files
+    /// would otherwise be silently stored unencrypted.
+    pub fn set_encryption_key(&mut self, key: Option<EncryptionKey>) -> Result<(), Error> {
+        if key.is_none() && self.entries.values().any(|info| info.get_encoding().encrypted) {
+            return Err(Error::EncryptionKeyRequired(self.path.clone()));
+        }
+        self.key = key;
+        Ok(())
+    }
+
+    /// Enables removing files newly added by a mirror operation if it fails
+    pub fn set_rollback(&mut self, enabled: bool) { self.rollback = enabled; }
+
+    /// Enables resuming copies into this index which were interrupted, by
+    /// appending to the temporary file they left behind if its contents match
+    /// the start of the source. Only files stored verbatim can be resumed.
+    pub fn set_resume_copies(&mut self, enabled: bool) { self.resume_copies = enabled; }
+
+    /// Sets the naming patterns of the database series whose old backups are
+    /// removed by `clean_old_dbs`. By default, only WhatsApp's message
+    /// database series, `msgstore`, is cleaned.
+    pub fn set_db_patterns(&mut self, patterns: Vec<DbPattern>) { self.db_patterns = patterns; }
+
+    /// Enables keeping the newest full backup of each database file type
+    /// (e.g. one `.crypt14` and one `.crypt15`) when cleaning old databases,
+    /// rather than only the newest overall. The older format may be needed
+    /// to restore to an older version of WhatsApp.
+    pub fn set_keep_db_of_each_type(&mut self, enabled: bool) { self.keep_db_of_each_type = enabled; }
+
+    /// Sets how many versions of each kind of backup in the `Backups` folder
+    /// are kept when cleaning old backups. By default, only the newest is
+    /// kept.
+    pub fn set_backup_retention(&mut self, retention: BackupRetention) { self.backup_retention = retention; }
+
+    /// Sets a time after which operations changing this index fail with
+    /// `Error::TimedOut`. The deadline is checked before each file is copied,
+    /// updated or removed and between the chunks of each copy, so a single IO
+    /// call which hangs can still overrun it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) { self.deadline = deadline; }
+
+    /// Sets the size of the buffer files are copied through. Defaults to
+    /// `DEFAULT_COPY_CHUNK_SIZE`.
+    pub fn set_copy_chunk_size(&mut self, chunk_size: usize) { self.copy_chunk_size = chunk_size; }
+
+    /// Sets a token which stops mirroring at the next file boundary when
+    /// cancelled. The file being copied is always completed, and the report
+    /// returned has `cancelled` set.
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) { self.cancel_token = token; }
+
+    /// Returns `true` if the cancellation token has been cancelled
+    pub fn is_cancelled(&self) -> bool { self.cancel_token.as_ref().is_some_and(CancelToken::is_cancelled) }
+
+    /// Fails with `Error::TimedOut` if the deadline has passed
+    fn check_deadline(&self) -> Result<(), Error> {
+        if deadline_passed(self.deadline) {
+            Err(Error::TimedOut)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sets the manifest of the index files are mirrored from, e.g. the
+    /// archive when restoring media to the WhatsApp folder. Files it lists
+    /// whose size and modification time are unchanged since they were hashed
+    /// have their contents checked against it before and after copying, so
+    /// that a corrupt source fails with `Error::FileMismatch` rather than
+    /// being copied.
+    pub fn set_source_manifest(&mut self, manifest: Option<Manifest>) { self.source_manifest = manifest; }
+
+    /// Seeds the random number generator used to name temporary files, so
+    /// that runs can be reproduced when debugging. By default, names are drawn
+    /// from the thread's entropy-seeded generator. Encryption never uses the
+    /// seeded generator.
+    pub fn set_seed(&mut self, seed: Option<u64>) { self.temp_rng = Mutex::new(seed.map(StdRng::seed_from_u64)); }
+
+    /// Draws a random number for naming a temporary file from `rng`, or the
+    /// thread's generator if it is not seeded
+    fn temp_random(rng: &Mutex<Option<StdRng>>) -> u32 {
+        match rng.lock().expect("Temporary file name generator lock poisoned").as_mut() {
+            Some(rng) => rng.gen(),
+            None => rand::thread_rng().gen(),
+        }
+    }
+
+    /// Limits the files which can be deleted from the index to `max_fraction`
+    /// of the number and total size of those present now, e.g. to guard
+    /// against an archive folder being given by mistake. Deleting more fails
+    /// with `Error::ExcessiveDeletion`. Files added later can always be
+    /// deleted.
+    pub fn set_max_deletion_fraction(&mut self, max_fraction: Option<f64>) {
+        self.deletion_guard = max_fraction.map(|max_fraction| {
+            let remaining: HashMap<_, _> =
+                self.entries.iter().map(|(path, info)| (path.clone(), info.get_size())).collect();
+            DeletionGuard {
+                max_fraction,
+                total_files: remaining.len(),
+                total_bytes: remaining.values().sum(),
+                remaining,
+                deleted_files: 0,
+                deleted_bytes: 0,
+            }
+        });
+    }
+
+    /// Fails with `Error::ExcessiveDeletion` if deleting `paths` in addition
+    /// to the files already deleted would exceed the deletion limit
+    fn check_deletion<'a, I: IntoIterator<Item = &'a Path>>(&self, paths: I) -> Result<(), Error> {
+        let Some(guard) = &self.deletion_guard else { return Ok(()) };
+        let (mut files, mut bytes) = (guard.deleted_files, guard.deleted_bytes);
+        for size in paths.into_iter().filter_map(|path| guard.remaining.get(path)) {
+            files += 1;
+            bytes += size;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let exceeds = |deleted: u64, total: u64| deleted as f64 > guard.max_fraction * total as f64;
+        if exceeds(files as u64, guard.total_files as u64) || exceeds(bytes, guard.total_bytes) {
+            return Err(Error::ExcessiveDeletion {
+                files,
+                total_files: guard.total_files,
+                bytes,
+                total_bytes: guard.total_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets the observer notified as files are copied into this index, and
+    /// passed the messages describing each step taken
+    pub fn set_progress(&mut self, progress: Box<dyn Progress>) { self.progress = RefCell::new(progress); }
+
+    /// Passes `message` describing a step taken to the observer
+    pub fn message(&self, message: &str) { self.progress.borrow_mut().message(message); }
+
+    /// The number of bytes and files reclaimed by removing stale temporary
+    /// files when the index was opened
+    pub fn cleaned_temp_files(&self) -> (u64, usize) { self.cleaned_temp }
+
+    /// The files added, updated and removed by this index since it was
+    /// built. In dry-run mode, these are the changes which would have been
+    /// made.
+    pub fn changes(&self) -> &Diff { &self.changes }
+
+    /// Returns true if the file at `path` is a temporary file written while
+    /// copying
+    fn is_temp_file(path: &Path) -> bool {
+        path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(TEMP_SUFFIX))
+    }
+
+    /// Removes temporary files found while indexing which were last modified
+    /// longer ago than `max_age`, returning the number of bytes and files
+    /// removed. No observer is attached while indexing, so these are only
+    /// reported by `cleaned_temp_files`.
+    fn clean_temp_files(&mut self, max_age: Duration) -> Result<(u64, usize), Error> {
+        let now = SystemTime::now();
+        let mut bytes = 0;
+        let mut count = 0;
+        let mut kept = Vec::new();
+        for path in std::mem::take(&mut self.temp_files) {
+            let metadata = path.metadata().map_err(|e| (e, &path))?;
+            let modified = metadata.modified().map_err(|e| (e, &path))?;
+            if now.duration_since(modified).is_ok_and(|age| age > max_age) {
+                std::fs::remove_file(&path).map_err(|e| (e, &path))?;
+                bytes += metadata.len();
+                count += 1;
+            } else {
+                kept.push(path);
+            }
+        }
+        self.temp_files = kept;
+        Ok((bytes, count))
+    }
+
+    /// Interprets the dates of `info` using the timezone and assumed time of
+    /// day of this index
+    fn localize(&self, info: &FileInfo) -> FileInfo {
+        info.with_timezone(self.timezone).with_assumed_time(self.assumed_time)
     }
 
-    /// Traverses the directory structure and builds the index
+    /// Traverses the directory structure and builds the index.
+    ///
+    /// The path of each entry relative to the index root is built up from the
+    /// names of the folders traversed to reach it, rather than derived from
+    /// its full path, so it is unaffected by how the root was reached, e.g.
+    /// through symbolic links or a path which was not canonicalized.
     fn rebuild_index(&mut self) -> Result<(), Error> {
         let mut remaining = VecDeque::new();
-        remaining.push_back(self.path.clone());
+        remaining.push_back((self.path.clone(), PathBuf::new()));
         self.entries.clear();
-        while let Some(path) = remaining.pop_front() {
+        self.temp_files.clear();
+        self.skipped.clear();
+        // Canonical paths of the folders traversed, so that symbolic links
+        // leading back to a folder already indexed are not followed again
+        let mut visited = HashSet::new();
+        if self.follow_symlinks {
+            visited.insert(self.path.canonicalize().map_err(|e| (e, &self.path))?);
+        }
+        while let Some((path, rel_folder)) = remaining.pop_front() {
             for entry in path.read_dir().map_err(|e| (e, &path))? {
                 let entry = entry.map_err(|e| (e, &path))?;
-                if entry.path().file_name().map_or(false, |n| n == TAG_NAME) {
+                if entry.path().file_name().is_some_and(|name| self.is_reserved_name(name)) {
                     continue;
                 }
-                let ftype = entry.file_type().map_err(|e| (e, entry.path()))?;
+                if self.databases_only
+                    && rel_folder.as_os_str().is_empty()
+                    && entry.file_name() != DATABASES_FOLDER
+                    && entry.file_name() != BACKUPS_FOLDER
+                {
+                    continue;
+                }
+                let mut ftype = entry.file_type().map_err(|e| (e, entry.path()))?;
+                if ftype.is_symlink() && self.follow_symlinks {
+                    match entry.path().metadata() {
+                        Ok(metadata) => ftype = metadata.file_type(),
+                        Err(e) => {
+                            warn!("Ignoring broken symbolic link {}: {}", entry.path().display(), e);
+                            self.skip(entry.path(), SkipReason::BrokenSymlink);
+                            continue;
+                        }
+                    }
+                }
                 if ftype.is_file() {
                     let path = entry.path();
-                    let info = FileInfo::new(&path)?;
-                    let rel_path = self.get_relative_path(&path);
+                    // Left by an interrupted copy, or one still in progress
+                    if Self::is_temp_file(&path) {
+                        self.temp_files.push(path);
+                        continue;
+                    }
+                    let rel_stored_path = rel_folder.join(entry.file_name());
+                    let (rel_path, encoding) = match self.index_type {
+                        IndexType::Original => (rel_stored_path, Encoding::PLAIN),
+                        IndexType::Archive => Encoding::from_stored_path(&rel_stored_path),
+                    };
+                    let info = self.localize(&FileInfo::new_stored(&path, encoding)?);
                     self.entries.insert(rel_path, info);
                 } else if ftype.is_dir() {
-                    remaining.push_back(entry.path());
+                    if self.follow_symlinks {
+                        let canonical = entry.path().canonicalize().map_err(|e| (e, entry.path()))?;
+                        if !visited.insert(canonical) {
+                            warn!(
+                                "Not following symbolic link to a folder already indexed: {}",
+                                entry.path().display()
+                            );
+                            self.skip(entry.path(), SkipReason::SymlinkLoop);
+                            continue;
+                        }
+                    }
+                    remaining.push_back((entry.path(), rel_folder.join(entry.file_name())));
                 } else {
                     warn!("Ignoring unexpected directory entry: {:?}", entry);
+                    let reason = if ftype.is_symlink() { SkipReason::Symlink } else { SkipReason::Special };
+                    self.skip(entry.path(), reason);
                 }
             }
         }
         Ok(())
     }
 
+    /// Returns `true` if files with this name hold `waa`'s own data, e.g. the
+    /// archive tag, and so are never indexed
+    fn is_reserved_name(&self, name: &OsStr) -> bool {
+        name == OsStr::new(&self.tag_name)
+            || name == MANIFEST_NAME
+            || name == RESUME_NAME
+            || name == HISTORY_NAME
+            || name == TRIMMED_NAME
+    }
+
+    /// Updates the entries for the files at `paths`, relative to the index
+    /// root, from the filesystem rather than rebuilding the whole index.
+    /// Entries for files which no longer exist are removed and files not yet
+    /// indexed are added, so the entries for these paths match those a full
+    /// rebuild would produce. All other entries are left untouched.
+    pub fn refresh_paths(&mut self, paths: &[PathBuf]) -> Result<(), Error> {
+        self.ensure_files_present()?;
+        for rel_path in paths {
+            match self.read_entry(rel_path)? {
+                Some(info) => self.entries.insert(rel_path.clone(), info),
+                None => self.entries.remove(rel_path),
+            };
+        }
+        Ok(())
+    }
+
+    /// Reads the metadata of the regular file stored for `rel_path`, trying
+    /// the encoding it is indexed with first, or returns `None` if there is
+    /// no such file
+    fn read_entry(&self, rel_path: &Path) -> Result<Option<FileInfo>, Error> {
+        if rel_path.file_name().is_none_or(|name| self.is_reserved_name(name)) {
+            return Ok(None);
+        }
+        let indexed = self.entries.get(rel_path).map(FileInfo::get_encoding);
+        let encodings = match self.index_type {
+            IndexType::Original => &STORED_ENCODINGS[..1],
+            IndexType::Archive => &STORED_ENCODINGS[..],
+        };
+        for encoding in indexed.into_iter().chain(encodings.iter().copied()) {
+            let path = encoding.stored_path(&self.path.join(rel_path));
+            let metadata = if self.follow_symlinks { path.metadata() } else { path.symlink_metadata() };
+            if metadata.is_ok_and(|m| m.is_file()) && !Self::is_temp_file(&path) {
+                return Ok(Some(self.localize(&FileInfo::new_stored(&path, encoding)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn skip(&mut self, path: PathBuf, reason: SkipReason) { self.skipped.push(SkippedEntry { path, reason }); }
+
+    /// The directory entries left out when the index was built, such as
+    /// symbolic links which were not followed or FIFOs
+    pub fn skipped_entries(&self) -> &[SkippedEntry] { &self.skipped }
+
+    /// The path at which the file described by `info` is stored
+    fn stored_path(&self, relative_path: &Path, info: &FileInfo) -> PathBuf {
+        info.get_encoding().stored_path(&self.path.join(relative_path))
+    }
+
+    /// Copies the contents of `source` to `writer`, decoding as necessary and
+    /// reporting each chunk copied to `progress`. The decoded contents are
+    /// also passed to `hasher`, if any.
+    fn copy_contents(
+        source: &StoredFile, mut writer: EncodedWriter, options: &CopyOptions, progress: &mut dyn Progress,
+        hasher: Option<&mut ContentHasher>,
+    ) -> io::Result<()> {
+        let mut reader = source.open()?;
+        Self::copy_remaining(&mut reader, &mut writer, options, progress, hasher)?;
+        writer.finish()
+    }
+
+    /// Copies everything remaining in `reader` to `writer`, reporting each
+    /// chunk copied to `progress` and passing it to `hasher`, if any, in the
+    /// same pass. Fails with `io::ErrorKind::TimedOut` if the deadline passes
+    /// before the copy completes.
+    fn copy_remaining(
+        reader: &mut dyn Read, writer: &mut dyn Write, options: &CopyOptions, progress: &mut dyn Progress,
+        hasher: Option<&mut ContentHasher>,
+    ) -> io::Result<()> {
+        let mut deadline = Deadline(options.deadline);
+        let mut progress = ReportProgress(progress);
+        let mut observers: Vec<&mut dyn CopyObserver> = vec![&mut deadline, &mut progress];
+        if let Some(hasher) = hasher {
+            observers.push(hasher);
+        }
+        copy_with(reader, writer, options.chunk_size, &mut observers)?;
+        Ok(())
+    }
+
+    /// Removes and returns the largest temporary file found while indexing
+    /// which was left by an interrupted copy to `dest_path`
+    fn take_partial_copy(&mut self, dest_path: &Path) -> Option<PathBuf> {
+        let prefix = format!("{}.", dest_path.file_name()?.to_string_lossy());
+        let is_partial = |path: &Path| {
+            path.parent() == dest_path.parent()
+                && path.file_name().map(OsStr::to_string_lossy).is_some_and(|name| {
+                    name.strip_prefix(&prefix)
+                        .and_then(|rest| rest.strip_suffix(TEMP_SUFFIX))
+                        .is_some_and(|random| !random.is_empty() && random.chars().all(|c| c.is_ascii_hexdigit()))
+                })
+        };
+        let (idx, _) = self
+            .temp_files
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| is_partial(path))
+            .max_by_key(|(_, path)| path.metadata().map_or(0, |m| m.len()))?;
+        Some(self.temp_files.swap_remove(idx))
+    }
+
+    /// Attempts to complete an interrupted copy of `source` to `dest_path`
+    /// by appending to `partial`. Returns `false` without changing anything
+    /// if `partial` is not a prefix of the source, in which case the file
+    /// must be copied in full. Fails with `io::ErrorKind::InvalidData`,
+    /// leaving `partial` in place, if the source does not have the expected
+    /// hash.
+    fn resume_copy(
+        source: &StoredFile, dest_path: &Path, partial: &Path, size: u64, options: &CopyOptions,
+        progress: &mut dyn Progress,
+    ) -> io::Result<bool> {
+        let existing = partial.metadata()?.len();
+        if existing == 0 || existing > size {
+            return Ok(false);
+        }
+        let mut reader = source.open()?;
+        let mut prefix_hasher = ContentHasher::default();
+        copy_with(&mut (&mut reader).take(existing), &mut io::sink(), options.chunk_size, &mut [&mut prefix_hasher])?;
+        // The hash of the whole source continues from that of the prefix
+        let mut hasher = options.expected_hash.map(|_| prefix_hasher.clone());
+        let partial_hash = ContentHash::from_reader(io::BufReader::new(std::fs::File::open(partial)?))?;
+        if prefix_hasher.finish() != partial_hash {
+            return Ok(false);
+        }
+        progress.message(&format!("Resuming interrupted copy from {} bytes", existing));
+        progress.bytes_copied(existing);
+        let mut file = std::fs::File::options().append(true).open(partial)?;
+        Self::copy_remaining(&mut reader, &mut file, options, progress, hasher.as_mut())?;
+        if let (Some(hasher), Some(expected)) = (hasher, options.expected_hash) {
+            if hasher.finish() != *expected {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "source does not match its expected hash"));
+            }
+        }
+        file.sync_all()?;
+        std::fs::rename(partial, dest_path)?;
+        Ok(true)
+    }
+
+    /// Creates a temporary file next to `dest` to copy into, returning its
+    /// path and a writer. Names are generated from `random` until one is found
+    /// which does not already exist, so an existing file is never clobbered.
+    fn create_temp_file(
+        dest: &StoredFile, size: u64, mut random: impl FnMut() -> u32,
+    ) -> Result<(PathBuf, EncodedWriter), (io::Error, PathBuf)> {
+        let filename = dest.path.file_name().expect("Unable to determine destination filename");
+        let parent = dest.path.parent().expect("Unable to determine parent folder of destination file");
+        let mut attempts = 0;
+        loop {
+            let mut temp_filename = filename.to_owned();
+            temp_filename.push(format!(".{:x}{}", random(), TEMP_SUFFIX));
+            let temp_path = parent.join(temp_filename);
+            match dest.with_path(&temp_path).create(size) {
+                Ok(writer) => return Ok((temp_path, writer)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < TEMP_FILE_ATTEMPTS => attempts += 1,
+                Err(e) => return Err((e, temp_path)),
+            }
+        }
+    }
+
     /// Attempts to copy a file in a way that minimizes the chance that a
     /// partially written file ends up at the destination path if an IO
     /// error occurs.
-    fn safer_copy(source_path: &Path, dest_path: &Path) -> Result<(), Error> {
-        let dest_path_temp = {
-            let filename = dest_path.file_name().expect("Unable to determine destination filename");
-            let parent = dest_path.parent().expect("Unable to determine parent folder of destination file");
-            let random: u32 = rand::thread_rng().gen();
-            let temp_filename = format!("{}.{:x}.waa.tmp", filename.to_string_lossy(), random);
-            parent.join(temp_filename)
-        };
-        if let Err(e) = std::fs::copy(source_path, &dest_path_temp)
-            .map_err(|e| Error::Cp(e, source_path.to_owned(), dest_path_temp.clone()))
-            .and_then(|_| {
+    ///
+    /// If `partial` is the temporary file left by an interrupted copy, the copy
+    /// is resumed from it where possible. Otherwise, or if resuming fails, it
+    /// is removed and the file is copied in full.
+    ///
+    /// Fails with `Error::TimedOut` if the deadline passes during the copy, in
+    /// which case a resumed copy keeps what was appended to `partial`. If an
+    /// expected hash is supplied, the source is hashed as it is copied and
+    /// the copy fails with `Error::FileMismatch` rather than being moved into
+    /// place if the hash differs. The temporary file is named using numbers
+    /// drawn from `random`.
+    fn safer_copy(
+        source: &StoredFile, dest: &StoredFile, size: u64, partial: Option<&Path>, options: &CopyOptions,
+        random: &mut dyn FnMut() -> u32, progress: &mut dyn Progress,
+    ) -> Result<(), Error> {
+        let (dest_path, deadline) = (dest.path, options.deadline);
+        if let Some(partial) = partial {
+            match Self::resume_copy(source, dest_path, partial, size, options, progress) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut && deadline_passed(deadline) => {
+                    return Err(Error::TimedOut)
+                }
+                Err(e) => warn!("Unable to resume copy from {}: {}", partial.display(), e),
+            }
+            let _ = std::fs::remove_file(partial);
+        }
+        let (dest_path_temp, writer) = Self::create_temp_file(dest, size, random)
+            .map_err(|(e, temp_path)| Error::Cp(e, source.path.to_owned(), temp_path))?;
+        let mut hasher = options.expected_hash.map(|_| ContentHasher::default());
+        if let Err(e) = Self::copy_contents(source, writer, options, progress, hasher.as_mut())
+            .map_err(|e| {
+                if e.kind() == io::ErrorKind::TimedOut && deadline_passed(deadline) {
+                    Error::TimedOut
+                } else {
+                    Error::Cp(e, source.path.to_owned(), dest_path_temp.clone())
+                }
+            })
+            .and_then(|()| {
+                if hasher.zip(options.expected_hash).is_some_and(|(hasher, expected)| hasher.finish() != *expected) {
+                    Err(Error::FileMismatch(source.path.to_owned(), dest_path.to_owned()))
+                } else {
+                    Ok(())
+                }
+            })
+            .and_then(|()| {
                 std::fs::rename(&dest_path_temp, dest_path)
                     .map_err(|e| Error::Mv(e, dest_path_temp.clone(), dest_path.to_owned()))
             })
@@ -156,37 +958,89 @@ impl FileIndex {
         Ok(())
     }
 
-    /// Imports the file at `path` into the index at `relative_path` optionally
+    /// Imports the file `source` into the index at `relative_path` optionally
     /// overriding metadata with the supplied
     fn import_file_maybe_metadata(
-        &mut self, relative_path: &Path, source: &Path, info: Option<&FileInfo>,
+        &mut self, relative_path: &Path, source: &StoredFile, info: Option<&FileInfo>,
     ) -> Result<(), Error> {
-        let dest_path = self.path.join(relative_path);
+        self.ensure_files_present()?;
+        if self.action_type == ActionType::Real {
+            // Create destination folder
+            if let Some(parent) = self.path.join(relative_path).parent() {
+                std::fs::create_dir_all(parent).map_err(|e| (e, parent))?;
+            }
+        }
+        self.import_into_existing_folder(relative_path, source, info, None)
+    }
+
+    /// Imports the file `source` into the index at `relative_path` as for
+    /// `import_file_maybe_metadata`, assuming the destination folder exists.
+    /// If `expected_hash` is supplied, the source is checked against it while
+    /// being copied.
+    fn import_into_existing_folder(
+        &mut self, relative_path: &Path, source: &StoredFile, info: Option<&FileInfo>,
+        expected_hash: Option<&ContentHash>,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        let dest_encoding = Encoding::for_path(relative_path, self.compression, self.key.is_some());
+        if dest_encoding != Encoding::PLAIN {
+            self.upgrade_archive_version()?;
+        }
+        let dest_path = dest_encoding.stored_path(&self.path.join(relative_path));
+        let key = self.key.clone();
+        let dest = StoredFile { path: &dest_path, encoding: dest_encoding, key: key.as_ref() };
+        let previous_path = self.entries.get(relative_path).map(|previous| self.stored_path(relative_path, previous));
+        // An existing file being updated is never removed on failure, since
+        // it either still holds its previous contents or has already been
+        // replaced by the new copy
+        let dest_existed = dest_path.symlink_metadata().is_ok();
+        let partial = if self.resume_copies && self.action_type == ActionType::Real && dest_encoding == Encoding::PLAIN
+        {
+            self.take_partial_copy(&dest_path)
+        } else {
+            None
+        };
         let mut do_copy = || {
             assert!(relative_path.is_relative());
             if self.action_type == ActionType::Real {
-                // Create destination folder
-                if let Some(parent) = dest_path.parent() {
-                    std::fs::create_dir_all(parent).map_err(|e| (e, parent))?;
+                let size = info.map_or_else(|| source.path.metadata().map_or(0, |m| m.len()), FileInfo::get_size);
+                self.progress.get_mut().file_started(relative_path, size);
+                let mut random = || Self::temp_random(&self.temp_rng);
+                let partial = partial.as_deref();
+                let options = CopyOptions { chunk_size: self.copy_chunk_size, deadline: self.deadline, expected_hash };
+                Self::safer_copy(
+                    source,
+                    &dest,
+                    size,
+                    partial,
+                    &options,
+                    &mut random,
+                    self.progress.get_mut().as_mut(),
+                )?;
+                self.progress.get_mut().file_finished(relative_path);
+                if let Some(info) = info {
+                    // Update modification time on filesystem
+                    info.set_modification_time(&dest_path)?;
+                    let actual_metadata = self.localize(&FileInfo::new_stored(&dest_path, dest_encoding)?);
+                    // Check that other metadata matches (e.g. file size)
+                    if actual_metadata != *info {
+                        return Err(Error::FileMismatch(source.path.to_owned(), dest_path.clone()));
+                    }
+                    self.entries.insert(relative_path.to_path_buf(), actual_metadata);
                 }
-                Self::safer_copy(source, &dest_path)?;
-                match info {
-                    None => Ok(()),
-                    Some(info) => {
-                        // Update modification time on filesystem
-                        info.set_modification_time(&dest_path)?;
-                        let actual_metadata = FileInfo::new(&dest_path)?;
-                        // Check that other metadata matches (e.g. file size)
-                        if actual_metadata == *info {
-                            self.entries.insert(relative_path.to_path_buf(), actual_metadata);
-                            Ok(())
-                        } else {
-                            Err(Error::FileMismatch(source.to_owned(), dest_path.clone()))
-                        }
+                // The previous version is only removed once the new copy has
+                // been checked, if it was stored under a different name. The
+                // new copy is already in place, so failing to remove it is
+                // only logged.
+                if let Some(previous_path) = previous_path.as_ref().filter(|p| **p != dest_path) {
+                    if let Err(e) = std::fs::remove_file(previous_path) {
+                        warn!("Unable to remove previous version {}: {}", previous_path.display(), e);
                     }
                 }
+                Ok(())
             } else {
-                let actual_metadata = FileInfo::new(source)?;
+                let actual_metadata =
+                    self.localize(&FileInfo::new_stored(source.path, source.encoding)?.with_encoding(dest_encoding));
                 self.entries.insert(relative_path.to_path_buf(), actual_metadata);
                 Ok(())
             }
@@ -194,10 +1048,13 @@ impl FileIndex {
         match do_copy() {
             Ok(()) => Ok(()),
             Err(e) => {
-                if self.action_type == ActionType::Real {
-                    //TODO: no need to error if this file doesn't exist
-                    let _ = std::fs::remove_file(&dest_path)
-                        .map_err(|e| eprintln!("Additional error during delete of incompletely copied file: {:?}", e));
+                if self.action_type == ActionType::Real && !dest_existed {
+                    match std::fs::remove_file(&dest_path) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            eprintln!("Additional error during delete of incompletely copied file: {:?}", e);
+                        }
+                        _ => {}
+                    }
                 }
                 Err(e)
             }
@@ -206,7 +1063,8 @@ impl FileIndex {
 
     /// Imports the file at `path` into the index at `relative_path`
     pub fn import_file(&mut self, relative_path: &Path, source: &Path) -> Result<(), Error> {
-        self.import_file_maybe_metadata(relative_path, source, None)
+        let source = StoredFile { path: source, encoding: Encoding::PLAIN, key: None };
+        self.import_file_maybe_metadata(relative_path, &source, None)
     }
 
     /// Imports the file at `path` into the index at `relative_path` with the
@@ -214,221 +1072,1079 @@ impl FileIndex {
     pub fn import_file_with_metadata(
         &mut self, relative_path: &Path, source: &Path, info: &FileInfo,
     ) -> Result<(), Error> {
-        self.import_file_maybe_metadata(relative_path, source, Some(info))
+        let source = StoredFile { path: source, encoding: info.get_encoding(), key: None };
+        self.import_file_maybe_metadata(relative_path, &source, Some(info))
     }
 
-    /// Removes a file from the index and the filesystem
-    pub fn remove_file(&mut self, path: &Path) -> Result<(), Error> {
-        if let hash_map::Entry::Occupied(entry) = self.entries.entry(path.to_path_buf()) {
-            let path = self.path.join(path);
-            println!("Deleting {}", path.to_string_lossy());
-            if self.action_type == ActionType::Real {
-                std::fs::remove_file(&path).map_err(|e| (e, path))?;
+    /// Imports the file at `relative_path` in `source_index` described by
+    /// `info` into this index, assuming the destination folder exists
+    fn import_from_index(
+        &mut self, relative_path: &Path, source_index: &FileIndex, info: &FileInfo,
+    ) -> Result<(), Error> {
+        let source_path = source_index.stored_path(relative_path, info);
+        let source = StoredFile { path: &source_path, encoding: info.get_encoding(), key: source_index.key.as_ref() };
+        let expected = self
+            .source_manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get_unchanged(relative_path, info.get_size(), info.get_modification_time()))
+            .copied();
+        let Some(expected) = expected else {
+            return self.import_into_existing_folder(relative_path, &source, Some(info), None);
+        };
+        let dest_path = self.path.join(relative_path);
+        let mismatch = || Error::FileMismatch(source_path.clone(), dest_path.clone());
+        // A real copy hashes the source as it is read, but nothing is read
+        // in a dry run
+        if self.action_type != ActionType::Real && source_index.hash_entry(relative_path, info)? != expected {
+            return Err(mismatch());
+        }
+        let existed = self.entries.contains_key(relative_path);
+        self.import_into_existing_folder(relative_path, &source, Some(info), Some(&expected))?;
+        if self.action_type == ActionType::Real {
+            let copied = self.entries.get(relative_path).ok_or(Error::IndexEntryMissing)?;
+            if self.hash_entry(relative_path, copied)? != expected {
+                let error = mismatch();
+                // A corrupt copy of a file which was not previously present
+                // is not left behind
+                if !existed {
+                    let stored_path = self.stored_path(relative_path, copied);
+                    std::fs::remove_file(&stored_path).map_err(|e| (e, &stored_path))?;
+                    self.entries.remove(relative_path);
+                }
+                return Err(error);
             }
-            entry.remove_entry();
-            Ok(())
-        } else {
-            Err(Error::FileMissing(path.to_owned()))
         }
+        Ok(())
     }
 
-    /// Parses the supplied string as a WhatsApp intra-filename date or panics.
-    fn parse_date_or_fail(date: &str) -> NaiveDate {
-        NaiveDate::parse_from_str(date, "%Y-%m-%d")
-            .unwrap_or_else(|e| panic!("Unable to parse `{}` as date: {}", date, e))
+    /// Imports the files at `paths` in `source_index` into this index in a
+    /// single pass. Each destination folder is created once, rather than once
+    /// per file. Returns the result of importing each file, in the same order
+    /// as `paths`. A failure to import one file does not stop the others
+    /// being imported.
+    pub fn import_files(&mut self, source_index: &FileIndex, paths: &[PathBuf]) -> Vec<Result<(), Error>> {
+        if let Some(listing) = [&*self, source_index].into_iter().find(|index| index.listing_only) {
+            return paths.iter().map(|_| Err(Error::ListingOnly(listing.path.clone()))).collect();
+        }
+        let sources: Vec<_> = paths.iter().map(|path| source_index.entries.get(path)).collect();
+        if self.action_type == ActionType::Real {
+            let folders: BTreeSet<_> =
+                paths.iter().zip(&sources).filter(|(_, info)| info.is_some()).filter_map(|(p, _)| p.parent()).collect();
+            for folder in folders {
+                // Copies into a folder which could not be created fail
+                // individually, so this is only logged
+                let folder = self.path.join(folder);
+                if let Err(e) = std::fs::create_dir_all(&folder) {
+                    warn!("Unable to create {}: {}", folder.display(), e);
+                }
+            }
+        }
+        let total = sources.iter().flatten().map(|info| info.get_size()).sum();
+        self.progress.get_mut().mirror_started(total, sources.iter().flatten().count());
+        let results = paths
+            .iter()
+            .zip(sources)
+            .map(|(rel_path, info)| {
+                let info = info.ok_or_else(|| Error::FileMissing(source_index.path.join(rel_path)))?;
+                let source_path = source_index.stored_path(rel_path, info);
+                let source =
+                    StoredFile { path: &source_path, encoding: info.get_encoding(), key: source_index.key.as_ref() };
+                self.import_into_existing_folder(rel_path, &source, Some(info), None)
+            })
+            .collect();
+        self.progress.get_mut().mirror_finished();
+        results
     }
 
-    /// Gets the filename prefix for a path
-    fn determine_filename_prefix(path: &Path) -> String {
-        let filename =
-            path.file_name().unwrap_or_else(|| panic!("Unable to determine filename of file: {}", path.display()));
-        let filename = filename.to_str().unwrap_or_else(|| panic!("Filename is invalid UTF8: {:?}", filename));
-        let prefix = filename.split('.').next().unwrap_or(filename).to_string();
-        prefix
+    /// Overwrites the stored modification time of an existing file with the one
+    /// in `info` without copying any data
+    fn update_metadata(&mut self, relative_path: &Path, info: &FileInfo) -> Result<(), Error> {
+        self.check_deadline()?;
+        self.ensure_files_present()?;
+        let existing = self.entries.get(relative_path).ok_or(Error::IndexEntryMissing)?;
+        let actual_metadata = if self.action_type == ActionType::Real {
+            let dest_path = self.stored_path(relative_path, existing);
+            info.set_modification_time(&dest_path)?;
+            self.localize(&FileInfo::new_stored(&dest_path, existing.get_encoding())?)
+        } else {
+            self.localize(&info.with_encoding(existing.get_encoding()))
+        };
+        self.entries.insert(relative_path.to_path_buf(), actual_metadata);
+        Ok(())
     }
 
-    /// Removes old files from the `Backups` folder.
-    ///
-    /// This should correctly handle the case where the file extension changes
-    /// since only the most recent file for a given prefix is kept. It won't
-    /// handle the case where WhatsApp removes or changes the name
-    /// (excluding file extension) of a backup file.
-    pub fn clean_old_backups(&mut self) -> Result<(), Error> {
-        // Get top-level files in `Backup`
-        let backup_files_and_info: Vec<(PathBuf, FileInfo)> = self
-            .entries
-            .iter()
-            .map(|(path, info)| (path.clone(), info.clone()))
-            .filter(|(path, _)| {
-                path.starts_with("Backups")
-                    && path.components().count() == 2
-                    && !path.file_name().and_then(|f| f.to_str()).map_or(true, |f| f.starts_with('.'))
-            })
-            .collect();
-        // For each file prefix, determine the latest modified time.
-        let mut latest: HashMap<String, FileTime> = HashMap::new();
-        for (path, info) in &backup_files_and_info {
-            let prefix = Self::determine_filename_prefix(path);
-            let modification_time = info.get_modification_time();
-            latest.entry(prefix).and_modify(|m| *m = std::cmp::max(*m, modification_time)).or_insert(modification_time);
-        }
-        // Delete all older files for each prefix
-        for (path, info) in &backup_files_and_info {
-            let modification_time = info.get_modification_time();
-            let prefix = Self::determine_filename_prefix(path);
-            let latest_modification_time =
-                latest.get(&prefix).expect("Could not find latest modification time for prefix");
-            if modification_time < *latest_modification_time {
-                self.remove_file(path)?;
-            }
+    /// Marks an archive created by an older version of `waa` as using the
+    /// current format, so that versions unable to read compressed or
+    /// encrypted files refuse to open it once it holds any
+    fn upgrade_archive_version(&mut self) -> Result<(), Error> {
+        if self.archive_version < ARCHIVE_VERSION && self.action_type == ActionType::Real {
+            ArchiveTag::write_current(&self.path.join(&self.tag_name))?;
+            self.archive_version = ARCHIVE_VERSION;
         }
         Ok(())
     }
 
-    fn clean_previous_dbs(&mut self, keep: usize) -> Result<(), Error> {
-        let db_regex = Regex::new(r"msgstore(?P<incremental>-increment-\d+)?-(<?P<date>\d{4}-\d{2}-\d{2})\.")
-            .expect("Invalid database name regex");
-        let path_dates: Vec<(PathBuf, NaiveDate)> = self
-            .entries
-            .keys()
-            .filter(|p| p.starts_with("Databases"))
-            .filter_map(|p| {
-                db_regex.captures(&p.to_string_lossy()).map(|captures| {
-                    (
-                        p.clone(),
-                        Self::parse_date_or_fail(captures.name("date").expect("Date regex capture missing").as_str()),
-                    )
-                })
-            })
-            .collect();
-        let unique_dates: BTreeSet<_> = path_dates.iter().map(|(_, date)| std::cmp::Reverse(*date)).collect();
-        if unique_dates.len() <= keep {
-            return Ok(());
+    /// Copies the file at `from` to `to` within the index, giving the copy
+    /// the metadata `info`. The file at `from` is left in place.
+    fn copy_entry(&mut self, from: &Path, to: &Path, info: &FileInfo) -> Result<(), Error> {
+        self.ensure_files_present()?;
+        let existing = self.entries.get(from).ok_or(Error::IndexEntryMissing)?;
+        let from_path = self.stored_path(from, existing);
+        let key = self.key.clone();
+        let source = StoredFile { path: &from_path, encoding: existing.get_encoding(), key: key.as_ref() };
+        self.import_into_existing_folder(to, &source, Some(info), None)?;
+        self.changes.record_added(to, info.get_size());
+        Ok(())
+    }
+
+    /// Hashes the contents of the file at `relative_path`
+    fn hash_entry(&self, relative_path: &Path, info: &FileInfo) -> Result<ContentHash, Error> {
+        self.ensure_files_present()?;
+        Self::hash_stored_file(&self.path, self.key.as_ref(), relative_path, info)
+    }
+
+    /// Hashes the decoded contents of a file in the index rooted at `root`.
+    /// This does not borrow the index so that it can be called from multiple
+    /// threads.
+    fn hash_stored_file(
+        root: &Path, key: Option<&EncryptionKey>, relative_path: &Path, info: &FileInfo,
+    ) -> Result<ContentHash, Error> {
+        let path = info.get_encoding().stored_path(&root.join(relative_path));
+        let reader = info.get_encoding().open(&path, key).map_err(|e| (e, &path))?;
+        let hash = ContentHash::from_reader(reader).map_err(|e| (e, &path))?;
+        Ok(hash)
+    }
+
+    /// Hashes the specified files using `jobs` threads, or one per CPU if
+    /// `jobs` is zero
+    fn hash_files(&self, files: &[(&Path, &FileInfo)], jobs: usize) -> Result<Manifest, Error> {
+        self.ensure_files_present()?;
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build().map_err(Error::ThreadPool)?;
+        let root = self.path.as_path();
+        let key = self.key.as_ref();
+        let hashes: Vec<(&Path, &FileInfo, ContentHash)> = pool.install(|| {
+            files
+                .par_iter()
+                .map(|(path, info)| Ok((*path, *info, Self::hash_stored_file(root, key, path, info)?)))
+                .collect::<Result<_, Error>>()
+        })?;
+        let mut manifest = Manifest::default();
+        for (path, info, hash) in hashes {
+            manifest.insert_stamped(path, hash, info.get_size(), info.get_modification_time());
+        }
+        Ok(manifest)
+    }
+
+    /// Hashes every file in the index using `jobs` threads, or one per CPU if
+    /// `jobs` is zero. The manifest is the same regardless of the number of
+    /// threads.
+    pub fn build_manifest(&self, jobs: usize) -> Result<Manifest, Error> {
+        let files: Vec<_> = self.entries.iter().map(|(path, info)| (path.as_path(), info)).collect();
+        self.hash_files(&files, jobs)
+    }
+
+    /// Updates the manifest saved in the root of the index and saves it,
+    /// returning it. Only files which are new or whose size or modification
+    /// time differ from when they were last hashed are hashed again, and
+    /// files no longer in the index are dropped. If there is no usable saved
+    /// manifest, every file is hashed.
+    pub fn write_manifest(&self, jobs: usize) -> Result<Manifest, Error> {
+        let manifest_path = self.path.join(MANIFEST_NAME);
+        let previous = if manifest_path.exists() {
+            Manifest::load(&manifest_path)
+                .inspect_err(|e| warn!("Unable to read existing manifest, so hashing every file: {}", e))
+                .unwrap_or_default()
+        } else {
+            Manifest::default()
+        };
+        let mut unchanged = Vec::new();
+        let mut changed = Vec::new();
+        for (path, info) in &self.entries {
+            match previous.get_unchanged(path, info.get_size(), info.get_modification_time()) {
+                Some(hash) => unchanged.push((path, info, *hash)),
+                None => changed.push((path.as_path(), info)),
+            }
+        }
+        self.message(&format!("Hashing {} new or changed files for manifest", changed.len()));
+        let mut manifest = self.hash_files(&changed, jobs)?;
+        for (path, info, hash) in unchanged {
+            manifest.insert_stamped(path, hash, info.get_size(), info.get_modification_time());
+        }
+        if self.action_type == ActionType::Real {
+            manifest.save(&manifest_path)?;
         }
-        let oldest_date_to_keep = unique_dates.into_iter().map(|d| d.0).take(keep).last().unwrap_or(NaiveDate::MAX);
-        let to_delete: Vec<_> =
-            path_dates.iter().filter(|(_, date)| *date < oldest_date_to_keep).map(|(path, _)| path).collect();
-        for db in to_delete {
-            self.remove_file(db)?;
+        Ok(manifest)
+    }
+
+    /// Appends a summary of a run to the history saved in the root of the
+    /// index
+    pub fn append_history(&self, record: &HistoryRecord) -> Result<(), Error> {
+        self.ensure_files_present()?;
+        if self.action_type == ActionType::Real {
+            record.append(&self.path.join(HISTORY_NAME))?;
         }
         Ok(())
     }
 
-    fn clean_current_db(&mut self) -> Result<(), Error> {
-        // Matches the current database backup, including incrementals.
-        let db_regex = Regex::new(r"msgstore(?P<incremental>-increment-\d+)?\.db\.(?P<extension>.*)")
-            .expect("Invalid database name regex");
+    /// Loads the history saved in the root of the index, oldest run first.
+    /// An index without a history has an empty one.
+    pub fn read_history(&self) -> Result<Vec<HistoryRecord>, Error> {
+        let path = self.path.join(HISTORY_NAME);
+        if path.exists() {
+            HistoryRecord::load_all(&path)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Loads the paths of files recorded as trimmed from the WhatsApp folder
+    /// while held in this index
+    pub fn read_trimmed(&self) -> Result<BTreeSet<PathBuf>, Error> { trimmed::load(&self.path.join(TRIMMED_NAME)) }
 
-        // Collect info for all database files
-        let db_infos: Vec<(PathBuf, DbInfo)> = self
-            .entries
+    /// Records that `trimmed` were deleted from the WhatsApp folder, and that
+    /// `restored` were copied back to it, so only the copies in this index
+    /// remain of those recorded. Paths no longer in the index are dropped
+    /// from the record.
+    pub fn update_trimmed(&self, trimmed: &[PathBuf], restored: &[PathBuf]) -> Result<(), Error> {
+        self.ensure_files_present()?;
+        let previous = self.read_trimmed()?;
+        let mut record = previous.clone();
+        record.extend(trimmed.iter().cloned());
+        for path in restored {
+            record.remove(path);
+        }
+        record.retain(|path| self.entries.contains_key(path));
+        // The record is only rewritten if it changed, so that a run with
+        // nothing to trim leaves the archive untouched
+        if self.action_type == ActionType::Real && record != previous {
+            trimmed::save(&self.path.join(TRIMMED_NAME), &record)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the manifest saved in the root of the index
+    pub fn read_manifest(&self) -> Result<Manifest, Error> { Manifest::load(&self.path.join(MANIFEST_NAME)) }
+
+    /// Compares the contents of files against `manifest` using `jobs` threads,
+    /// or one per CPU if `jobs` is zero. Returns the paths of files whose
+    /// contents differ along with those of files in the manifest which are
+    /// missing from the index, in path order. Files absent from the manifest
+    /// are not checked.
+    pub fn verify(&self, manifest: &Manifest, jobs: usize) -> Result<Vec<PathBuf>, Error> {
+        let files: Vec<_> = manifest
             .iter()
-            .map(|p| (p.0.clone(), p.1.clone()))
-            .filter_map(|(path, file_info)| {
-                if !path.starts_with("Databases") {
-                    return None;
-                }
-                let capture =
-                    path.file_name().and_then(|name| name.to_str()).and_then(|filename| db_regex.captures(filename));
-                capture.map(|capture| {
-                    (
-                        path.clone(),
-                        DbInfo {
-                            last_modified: file_info.get_modification_time(),
-                            file_extension: capture
-                                .name("extension")
-                                .expect("file extension unexpectedly missing")
-                                .as_str()
-                                .to_string(),
-                            is_incremental: capture.name("incremental").is_some(),
-                        },
-                    )
-                })
+            .filter_map(|(path, _)| self.entries.get_key_value(path))
+            .map(|(path, info)| (path.as_path(), info))
+            .collect();
+        let actual = self.hash_files(&files, jobs)?;
+        let mismatches =
+            manifest.iter().filter(|(path, hash)| actual.get(path) != Some(*hash)).map(|(path, _)| path.to_path_buf());
+        Ok(mismatches.collect())
+    }
+
+    /// Determines whether a file has the same contents as the corresponding
+    /// file in `source_index` by comparing their hashes
+    fn has_identical_content(
+        &self, relative_path: &Path, info: &FileInfo, source_index: &FileIndex, source_info: &FileInfo,
+    ) -> Result<bool, Error> {
+        if info.get_size() != source_info.get_size() {
+            return Ok(false);
+        }
+        Ok(self.hash_entry(relative_path, info)? == source_index.hash_entry(relative_path, source_info)?)
+    }
+
+    /// Removes a file from the index and the filesystem
+    pub fn remove_file(&mut self, path: &Path) -> Result<(), Error> {
+        self.check_deadline()?;
+        self.ensure_files_present()?;
+        self.check_deletion([path])?;
+        let info = self.entries.get(path).ok_or_else(|| Error::FileMissing(path.to_owned()))?;
+        let stored_path = self.stored_path(path, info);
+        self.message(&format!("Deleting {}", stored_path.to_string_lossy()));
+        if self.action_type == ActionType::Real {
+            std::fs::remove_file(&stored_path).map_err(|e| (e, stored_path))?;
+        }
+        self.forget_deleted(path);
+        Ok(())
+    }
+
+    /// Removes the entry for a file which has been deleted, recording the
+    /// change
+    fn forget_deleted(&mut self, path: &Path) {
+        let Some(info) = self.entries.remove(path) else { return };
+        self.changes.record_removed(path, info.get_size());
+        self.progress.get_mut().file_deleted(path, info.get_size());
+        if let Some(guard) = &mut self.deletion_guard {
+            if let Some(size) = guard.remaining.remove(path) {
+                guard.deleted_files += 1;
+                guard.deleted_bytes += size;
+            }
+        }
+    }
+
+    /// Gets the filename prefix for a path, i.e. its base name as for
+    /// `split_extension`. Filenames which are not valid UTF-8 are compared
+    /// using their lossy conversion.
+    fn determine_filename_prefix(path: &Path) -> String {
+        let filename = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default();
+        split_extension(&filename).0.to_string()
+    }
+
+    /// Gets the group a backup file belongs to: its folder along with its
+    /// filename prefix
+    fn determine_backup_group(path: &Path) -> PathBuf { path.with_file_name(Self::determine_filename_prefix(path)) }
+
+    /// Returns the paths of the files in the `Backups` folder and any folders
+    /// within it, in path order, along with the kind of backup each holds
+    pub fn backup_files(&self) -> Vec<(PathBuf, BackupKind)> { Self::backup_files_in(&self.entries) }
+
+    /// Returns the paths of the backups among `entries`, as for
+    /// `backup_files`
+    fn backup_files_in(entries: &HashMap<PathBuf, FileInfo>) -> Vec<(PathBuf, BackupKind)> {
+        let mut backups: Vec<_> = entries
+            .keys()
+            .filter(|path| {
+                path.starts_with(BACKUPS_FOLDER)
+                    && !path.file_name().is_none_or(|f| f.to_string_lossy().starts_with('.'))
             })
+            .map(|path| (path.clone(), BackupKind::from_path(path)))
+            .collect();
+        backups.sort();
+        backups
+    }
+
+    /// Removes old files from the `Backups` folder and any folders within it,
+    /// namely those returned by `plan_backup_cleanup`
+    pub fn clean_old_backups(&mut self) -> Result<(), Error> {
+        let to_delete = self.plan_backup_cleanup();
+        self.remove_files(&to_delete)
+    }
+
+    /// Returns the paths of the old files in the `Backups` folder and any
+    /// folders within it which `clean_old_backups` would remove, in path
+    /// order, without removing them.
+    ///
+    /// This should correctly handle the case where the file extension changes
+    /// since only the most recent files for a given prefix within each folder
+    /// are kept, as many as the retention count for their kind of backup. It
+    /// won't handle the case where WhatsApp removes or changes the name
+    /// (excluding file extension) of a backup file.
+    pub fn plan_backup_cleanup(&self) -> Vec<PathBuf> { self.plan_backup_cleanup_in(&self.entries) }
+
+    /// Returns the paths of the backups among `entries` which
+    /// `clean_old_backups` would remove if they were the entries of this
+    /// index
+    fn plan_backup_cleanup_in(&self, entries: &HashMap<PathBuf, FileInfo>) -> Vec<PathBuf> {
+        // For each folder and file prefix, collect the modification times
+        let mut groups: HashMap<PathBuf, Vec<FileTime>> = HashMap::new();
+        let backups = Self::backup_files_in(entries);
+        for (path, _) in &backups {
+            let modification_time = entries[path].get_modification_time();
+            groups.entry(Self::determine_backup_group(path)).or_default().push(modification_time);
+        }
+        // Delete files older than the newest ones kept for each prefix
+        let mut to_delete = Vec::new();
+        for (path, kind) in backups {
+            let times = &groups[&Self::determine_backup_group(&path)];
+            let keep = self.backup_retention.keep(kind);
+            let newer = times.iter().filter(|t| **t > entries[&path].get_modification_time()).count();
+            if newer >= keep {
+                to_delete.push(path);
+            }
+        }
+        to_delete
+    }
+
+    /// Returns the paths of the superseded backups of the database series
+    /// named by `pattern`.
+    ///
+    /// Older full backups from the `keep` most recent dates in their names
+    /// are kept, along with the current full backup. Any other current backup
+    /// not in the format of the latest one is removed unless the newest backup
+    /// of each format is kept. Incremental backups, whether dated or current,
+    /// only have meaning on top of a full backup, so they are kept if they are
+    /// no older than the oldest full backup kept.
+    fn plan_db_series(
+        &self, entries: &HashMap<PathBuf, FileInfo>, pattern: &DbPattern, keep: usize,
+    ) -> BTreeSet<PathBuf> {
+        let mut previous: Vec<(PathBuf, NaiveDate, DbInfo)> = Vec::new();
+        let mut current: Vec<(PathBuf, DbInfo)> = Vec::new();
+        for (path, file_info) in entries.iter().filter(|(path, _)| path.starts_with(DATABASES_FOLDER)) {
+            let Some(filename) = path.file_name().map(OsStr::to_string_lossy) else { continue };
+            let last_modified = file_info.get_modification_time();
+            if let Some((is_incremental, extension)) = pattern.match_current(&filename) {
+                let file_extension = extension.to_string();
+                current.push((path.clone(), DbInfo { is_incremental, file_extension, last_modified }));
+            } else if let Some((is_incremental, date)) = pattern.match_previous(&filename) {
+                let info = DbInfo { is_incremental, file_extension: String::new(), last_modified };
+                previous.push((path.clone(), date, info));
+            }
+        }
+
+        // Older full backups are kept by date, since WhatsApp may make several
+        // on the same day
+        let full_dates: BTreeSet<_> = previous
+            .iter()
+            .filter(|(_, _, info)| !info.is_incremental)
+            .map(|(_, date, _)| std::cmp::Reverse(*date))
             .collect();
+        let oldest_date_to_keep = if full_dates.len() <= keep {
+            NaiveDate::MIN
+        } else {
+            full_dates.into_iter().map(|d| d.0).take(keep).next_back().unwrap_or(NaiveDate::MAX)
+        };
 
         // Determine the most recent full backup (there might be multiple DBs with
-        // different file extensions)
-        let latest_db_info = db_infos
+        // different file extensions). Series other than the message database
+        // need not have a current backup. Ties are broken by extension so the
+        // same backup is chosen on every run.
+        let latest_extension = current
             .iter()
             .map(|(_, info)| info)
             .filter(|info| !info.is_incremental)
-            .max_by_key(|i| i.last_modified)
-            .expect("Unable to find current database");
-        let file_extension = latest_db_info.file_extension.clone();
-        let last_modified = latest_db_info.last_modified;
+            .max_by(|a, b| (a.last_modified, &a.file_extension).cmp(&(b.last_modified, &b.file_extension)))
+            .map(|info| info.file_extension.clone());
+        let incorrect_db_type = |info: &DbInfo| {
+            !self.keep_db_of_each_type && latest_extension.as_ref().is_some_and(|ext| info.file_extension != *ext)
+        };
 
-        // Delete any DBs not in the currently used format, or incremental backups that
-        // are older than the last full backup.
-        for (path, info) in db_infos {
-            let incorrect_db_type = info.file_extension != file_extension;
-            let outdated_increment = info.is_incremental && info.last_modified < last_modified;
-            if incorrect_db_type || outdated_increment {
-                self.remove_file(&path)?;
+        let mut to_delete = BTreeSet::new();
+        let mut oldest_full_kept: Option<FileTime> = None;
+        for (path, date, info) in &previous {
+            if info.is_incremental {
+                continue;
+            }
+            if *date < oldest_date_to_keep {
+                to_delete.insert(path);
+            } else {
+                oldest_full_kept = Some(oldest_full_kept.map_or(info.last_modified, |t| t.min(info.last_modified)));
             }
         }
-        Ok(())
+        for (path, info) in &current {
+            if incorrect_db_type(info) {
+                to_delete.insert(path);
+            } else if !info.is_incremental {
+                oldest_full_kept = Some(oldest_full_kept.map_or(info.last_modified, |t| t.min(info.last_modified)));
+            }
+        }
+        // Without any full backup, there is nothing to judge increments by
+        if let Some(oldest_full_kept) = oldest_full_kept {
+            let increments =
+                previous.iter().map(|(path, _, info)| (path, info)).chain(current.iter().map(|(p, i)| (p, i)));
+            for (path, info) in increments {
+                if info.is_incremental && info.last_modified < oldest_full_kept {
+                    to_delete.insert(path);
+                }
+            }
+        }
+        to_delete.into_iter().cloned().collect()
     }
 
-    /// Removes all but the last `keep` full WhatsApp backup databases
+    /// Removes all but the last `keep` dates of full backups of each database
+    /// series named by the configured patterns, along with superseded current
+    /// backups and incremental backups older than every full backup kept,
+    /// namely those returned by `plan_db_cleanup`
     pub fn clean_old_dbs(&mut self, keep: usize) -> Result<(), Error> {
-        self.clean_previous_dbs(keep)?;
-        self.clean_current_db()?;
-        Ok(())
+        let to_delete = self.plan_db_cleanup(keep);
+        self.remove_files(&to_delete)
     }
 
-    /// Mirrors the specified files from the supplied index into this one
-    pub fn mirror_specified<I: IntoIterator<Item = impl AsRef<Path>>>(
-        &mut self, source_index: &FileIndex, files: I,
-    ) -> Result<(), Error> {
-        let files: HashSet<PathBuf> = files.into_iter().map(|p| p.as_ref().to_path_buf()).collect();
-        let source: HashMap<PathBuf, FileInfo> = source_index
+    /// Returns the paths of the databases which `clean_old_dbs` would remove
+    /// when keeping `keep` dates of full backups, in path order, without
+    /// removing them
+    pub fn plan_db_cleanup(&self, keep: usize) -> Vec<PathBuf> { self.plan_db_cleanup_in(&self.entries, keep) }
+
+    /// Returns the paths of the databases among `entries` which
+    /// `clean_old_dbs` would remove if they were the entries of this index
+    fn plan_db_cleanup_in(&self, entries: &HashMap<PathBuf, FileInfo>, keep: usize) -> Vec<PathBuf> {
+        let to_delete: BTreeSet<PathBuf> =
+            self.db_patterns.iter().flat_map(|pattern| self.plan_db_series(entries, pattern, keep)).collect();
+        to_delete.into_iter().collect()
+    }
+
+    /// Returns the paths of the databases and backups in `source_index` which
+    /// would be removed from this index by `clean_old_dbs`, keeping
+    /// `keep_dbs` dates if given, and by `clean_old_backups` if
+    /// `clean_backups` is set, straight after being mirrored into it.
+    /// Copying these would be wasted, since they would be copied and removed
+    /// again on every run.
+    pub fn superseded_by_cleanup(
+        &self, source_index: &FileIndex, keep_dbs: Option<usize>, clean_backups: bool,
+    ) -> Vec<PathBuf> {
+        let is_managed = |path: &PathBuf| path.starts_with(DATABASES_FOLDER) || path.starts_with(BACKUPS_FOLDER);
+        let mut merged: HashMap<PathBuf, FileInfo> =
+            self.entries.iter().filter(|(path, _)| is_managed(path)).map(|(p, i)| (p.clone(), i.clone())).collect();
+        merged.extend(
+            source_index.entries.iter().filter(|(path, _)| is_managed(path)).map(|(p, i)| (p.clone(), i.clone())),
+        );
+        let mut superseded = Vec::new();
+        if let Some(keep) = keep_dbs {
+            superseded.extend(self.plan_db_cleanup_in(&merged, keep));
+        }
+        if clean_backups {
+            superseded.extend(self.plan_backup_cleanup_in(&merged));
+        }
+        superseded.retain(|path| source_index.entries.contains_key(path));
+        superseded.sort();
+        superseded
+    }
+
+    /// Removes files which are not present in `source_index`, returning the
+    /// paths of the removed files. Files in the `Databases` and `Backups`
+    /// folders are left alone since old generations of these are managed by
+    /// `clean_old_dbs` and `clean_old_backups`, as are files recorded as
+    /// trimmed from `source_index`, since the archive holds their only copy.
+    pub fn remove_orphans(&mut self, source_index: &FileIndex) -> Result<Vec<PathBuf>, Error> {
+        let trimmed = self.read_trimmed()?;
+        let orphans: Vec<PathBuf> = self
             .entries
-            .iter()
-            .filter(|(p, _)| files.contains(p.as_path()))
-            .map(|p| (p.0.clone(), p.1.clone()))
+            .keys()
+            .filter(|path| !path.starts_with(DATABASES_FOLDER) && !path.starts_with(BACKUPS_FOLDER))
+            .filter(|path| !source_index.entries.contains_key(*path) && !trimmed.contains(*path))
+            .cloned()
             .collect();
-        if files.len() != source.len() {
+        self.remove_files(&orphans)?;
+        Ok(orphans)
+    }
+
+    /// Returns the paths of media files whose estimated age at `now` exceeds
+    /// `max_age`. Databases, backups and `.nomedia` markers are never
+    /// included.
+    pub fn media_older_than(&self, max_age: chrono::Duration, now: DateTime<Utc>) -> Vec<PathBuf> {
+        self.media_files()
+            .filter(|(_, info)| info.estimate_age_at(now) > max_age)
+            .map(|(p, _)| p.to_path_buf())
+            .collect()
+    }
+
+    /// Removes media files whose estimated age at `now` exceeds `max_age` from
+    /// the index and filesystem, returning their paths. Unlike trimming,
+    /// this applies to the archive regardless of size and the files are not
+    /// kept anywhere else. Databases, backups and `.nomedia` markers are
+    /// never removed.
+    pub fn prune_media_older_than(
+        &mut self, max_age: chrono::Duration, now: DateTime<Utc>,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let old = self.media_older_than(max_age, now);
+        self.remove_files(&old)?;
+        Ok(old)
+    }
+
+    /// Removes folders which hold no files, deepest first, returning their
+    /// paths relative to the index root. A folder whose only contents are
+    /// other empty folders is itself removed. The index root is never
+    /// removed, and symbolic links and other entries which are not regular
+    /// files or folders count as contents. In a dry run, files already
+    /// deleted from the index count as absent, so the folders reported are
+    /// those which a real run would remove.
+    pub fn remove_empty_dirs(&mut self) -> Result<Vec<PathBuf>, Error> {
+        self.ensure_files_present()?;
+        let mut removed = Vec::new();
+        self.remove_empty_dirs_in(&self.path.clone(), Path::new(""), &mut removed)?;
+        Ok(removed)
+    }
+
+    /// Removes the empty folders within `path`, followed by `path` itself if
+    /// it is then empty and not the index root. Returns `true` if `path` was
+    /// considered empty.
+    fn remove_empty_dirs_in(&self, path: &Path, rel_folder: &Path, removed: &mut Vec<PathBuf>) -> Result<bool, Error> {
+        let mut empty = true;
+        for entry in path.read_dir().map_err(|e| (e, path))? {
+            let entry = entry.map_err(|e| (e, path))?;
+            let ftype = entry.file_type().map_err(|e| (e, entry.path()))?;
+            let rel_stored_path = rel_folder.join(entry.file_name());
+            if ftype.is_dir() {
+                empty &= self.remove_empty_dirs_in(&entry.path(), &rel_stored_path, removed)?;
+            } else if !ftype.is_file() || !self.is_deleted(&rel_stored_path) {
+                empty = false;
+            }
+        }
+        if empty && !rel_folder.as_os_str().is_empty() {
+            self.message(&format!("Removing empty folder {}", path.display()));
+            if self.action_type == ActionType::Real {
+                std::fs::remove_dir(path).map_err(|e| (e, path))?;
+            }
+            removed.push(rel_folder.to_path_buf());
+        }
+        Ok(empty)
+    }
+
+    /// Returns `true` if the file stored at `rel_stored_path` has been deleted
+    /// from the index but, since this is a dry run, is still present on disk
+    fn is_deleted(&self, rel_stored_path: &Path) -> bool {
+        if self.action_type == ActionType::Real {
+            return false;
+        }
+        let rel_path = match self.index_type {
+            IndexType::Original => rel_stored_path.to_path_buf(),
+            IndexType::Archive => Encoding::from_stored_path(rel_stored_path).0,
+        };
+        !self.entries.contains_key(&rel_path) && matches!(self.changes.get(&rel_path), Some(Change::Removed(_)))
+    }
+
+    /// Determines what needs to be done to mirror the specified files from the
+    /// supplied index into this one. If hashing is enabled, files which differ
+    /// only in metadata will have their contents compared. If content
+    /// verification is enabled, files with matching metadata will also have
+    /// their contents compared. If a maximum file size is set, larger files
+    /// are excluded from copying. The files in each part of the plan are
+    /// listed, and so copied, in path order.
+    pub fn plan_mirror<I: IntoIterator<Item = impl AsRef<Path>>>(
+        &self, source_index: &FileIndex, files: I,
+    ) -> Result<MirrorPlan, Error> {
+        let mut plan = MirrorPlan::default();
+        let mut found = 0;
+        let files: HashSet<PathBuf> = files.into_iter().map(|p| p.as_ref().to_path_buf()).collect();
+        for (rel_path, other) in source_index.entries.iter().filter(|(p, _)| files.contains(p.as_path())) {
+            found += 1;
+            let entry = (rel_path.clone(), other.clone());
+            match self.entries.get(rel_path) {
+                None => plan.missing.push(entry),
+                Some(value) if value == other => {
+                    // Metadata can match even though the contents differ, e.g. if
+                    // the timestamp resolution hides a modification
+                    if self.verify_content {
+                        if self.is_confirmed(rel_path)
+                            || self.has_identical_content(rel_path, value, source_index, other)?
+                        {
+                            plan.verified.push(rel_path.clone());
+                        } else {
+                            plan.changed.push(entry);
+                        }
+                    }
+                }
+                Some(value) => {
+                    if self.hashing && self.has_identical_content(rel_path, value, source_index, other)? {
+                        plan.metadata_only.push(entry);
+                    } else {
+                        plan.changed.push(entry);
+                    }
+                }
+            }
+        }
+        if files.len() != found {
             return Err(Error::IndexEntryMissing);
         }
-        // Check common files match in terms of metadata
-        {
-            let mut common = self.entries.clone();
-            common.retain(|k, _| source.contains_key(k.as_path()));
-            for (rel_path, value) in &common {
-                let other = source.get(rel_path).unwrap();
-                if value != other {
-                    println!("Updating changed file {:?}", rel_path);
-                    let source_path = source_index.path.join(rel_path);
-                    self.import_file_with_metadata(rel_path, &source_path, other)?;
+        // Files which are too large are never copied, though they may still
+        // have their metadata updated if their contents are unchanged
+        if let Some(max_size) = self.max_file_size {
+            for entries in [&mut plan.missing, &mut plan.changed] {
+                let (too_large, kept): (Vec<_>, Vec<_>) =
+                    std::mem::take(entries).into_iter().partition(|(_, info)| info.get_size() > max_size);
+                *entries = kept;
+                plan.too_large.extend(too_large);
+            }
+        }
+        // Files are copied in path order so that runs with the same inputs,
+        // e.g. with a seeded generator for temporary file names, are
+        // reproducible
+        for entries in [&mut plan.missing, &mut plan.changed, &mut plan.metadata_only, &mut plan.too_large] {
+            entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        if self.content_identity {
+            self.find_renamed(source_index, &mut plan)?;
+        }
+        let new_paths = plan.missing.iter().map(|(path, _)| path).chain(plan.renamed.iter().map(|(_, path, _)| path));
+        for rel_path in new_paths {
+            let folders = rel_path.ancestors().skip(1).filter(|f| !f.as_os_str().is_empty());
+            for folder in folders {
+                if plan.new_folders.contains(folder) || self.path.join(folder).is_dir() {
+                    break;
                 }
+                plan.new_folders.insert(folder.to_path_buf());
             }
         }
+        Ok(plan)
+    }
 
-        // Copy missing files to archive
-        {
-            let mut missing = source.clone();
-            missing.retain(|k, _| !self.entries.contains_key(k.as_path()));
-            for (rel_path, value) in &missing {
-                println!("Copying missing file: {:?}", rel_path);
-                let source_path = source_index.path.join(rel_path);
-                self.import_file_with_metadata(rel_path, &source_path, value)?;
+    /// Returns `true` if an interrupted mirror operation confirmed that the
+    /// file at `rel_path` matches the source and re-verification is disabled
+    fn is_confirmed(&self, rel_path: &Path) -> bool {
+        !self.reverify && self.resume.as_ref().is_some_and(|marker| marker.contains(rel_path))
+    }
+
+    /// Returns the number of bytes and files which would be copied to mirror
+    /// all files from the supplied index into this one
+    pub fn mirror_cost(&self, source_index: &FileIndex) -> Result<(u64, usize), Error> {
+        let plan = self.plan_mirror(source_index, source_index.entries.keys())?;
+        Ok((plan.bytes(), plan.file_count()))
+    }
+
+    /// Moves files planned as missing to `plan.renamed` if this index holds
+    /// media with the same contents which is no longer in `source_index`.
+    /// Only files of the same size are hashed. Other files, such as old
+    /// databases and backups, are never used as the source of a copy.
+    fn find_renamed(&self, source_index: &FileIndex, plan: &mut MirrorPlan) -> Result<(), Error> {
+        let mut orphans_by_size: HashMap<u64, Vec<&Path>> = HashMap::new();
+        let orphans = self
+            .entries
+            .iter()
+            .filter(|(path, info)| Self::is_media_file(path, info) && !source_index.entries.contains_key(*path));
+        for (path, info) in orphans {
+            orphans_by_size.entry(info.get_size()).or_default().push(path);
+        }
+        for orphans in orphans_by_size.values_mut() {
+            orphans.sort_unstable();
+        }
+        let mut orphan_hashes: HashMap<&Path, ContentHash> = HashMap::new();
+        let mut missing = Vec::new();
+        for (rel_path, info) in std::mem::take(&mut plan.missing) {
+            let Some(orphans) = orphans_by_size.get(&info.get_size()) else {
+                missing.push((rel_path, info));
+                continue;
+            };
+            let hash = source_index.hash_entry(&rel_path, &info)?;
+            let mut matched = None;
+            for orphan in orphans {
+                let orphan_hash = if let Some(orphan_hash) = orphan_hashes.get(orphan) {
+                    *orphan_hash
+                } else {
+                    let orphan_hash = self.hash_entry(orphan, &self.entries[*orphan])?;
+                    orphan_hashes.insert(orphan, orphan_hash);
+                    orphan_hash
+                };
+                if orphan_hash == hash {
+                    matched = Some(*orphan);
+                    break;
+                }
+            }
+            match matched {
+                Some(orphan) => plan.renamed.push((orphan.to_path_buf(), rel_path, info)),
+                None => missing.push((rel_path, info)),
             }
         }
+        plan.missing = missing;
         Ok(())
     }
 
+    /// Mirrors the specified files from the supplied index into this one
+    pub fn mirror_specified<I: IntoIterator<Item = impl AsRef<Path>>>(
+        &mut self, source_index: &FileIndex, files: I,
+    ) -> Result<MirrorReport, Error> {
+        let plan = self.plan_mirror(source_index, files)?;
+        self.execute_plan(source_index, &plan)
+    }
+
+    /// Applies a plan produced by `plan_mirror` to this index.
+    ///
+    /// If rollback is enabled and an error occurs, files newly added by this
+    /// call are removed again before the error is returned. Files which were
+    /// updated in place keep their new contents or metadata since their
+    /// previous contents no longer exist.
+    pub fn execute_plan(&mut self, source_index: &FileIndex, plan: &MirrorPlan) -> Result<MirrorReport, Error> {
+        self.ensure_files_present()?;
+        source_index.ensure_files_present()?;
+        let mut created = Vec::new();
+        let result = self.execute_plan_inner(source_index, plan, &mut created);
+        if result.is_err() && self.rollback {
+            self.roll_back(&created);
+        }
+        result
+    }
+
+    fn execute_plan_inner(
+        &mut self, source_index: &FileIndex, plan: &MirrorPlan, created: &mut Vec<PathBuf>,
+    ) -> Result<MirrorReport, Error> {
+        let mut report = MirrorReport::default();
+        // Verifying contents is slow, so progress is recorded in case this
+        // operation is interrupted
+        let marker_path = self.path.join(RESUME_NAME);
+        let mut marker = if self.verify_content && self.action_type == ActionType::Real {
+            let verified = plan.verified.iter().map(PathBuf::as_path);
+            Some(ResumeWriter::create(&marker_path, ResumePhase::Verified, verified)?)
+        } else {
+            None
+        };
+        self.progress.get_mut().mirror_started(plan.bytes(), plan.file_count());
+        for (rel_path, info) in plan.too_large() {
+            self.message(&format!(
+                "Skipping file too large to copy: {} ({} bytes)",
+                rel_path.display(),
+                info.get_size()
+            ));
+            self.progress.get_mut().file_too_large(rel_path, info.get_size());
+            report.files_too_large += 1;
+        }
+        let cancelled = self.execute_plan_files(source_index, plan, created, &mut marker, &mut report);
+        // Observers are told the operation stopped even if it failed, so e.g.
+        // progress bars are removed
+        self.progress.get_mut().mirror_finished();
+        report.cancelled = cancelled?;
+        if report.cancelled {
+            // The resume marker is kept so verified files can be skipped when
+            // the operation is next run
+            self.message("Stopped mirroring early as the operation was cancelled");
+            return Ok(report);
+        }
+        if let Some(marker) = marker {
+            marker.finish()?;
+        } else if self.resume.is_some() && self.action_type == ActionType::Real {
+            // A marker left by an earlier operation could be stale by the
+            // time content verification is next enabled
+            std::fs::remove_file(&marker_path).map_err(|e| (e, &marker_path))?;
+        }
+        self.resume = None;
+        Ok(report)
+    }
+
+    /// Copies, updates and moves the files of `plan`, checking the
+    /// cancellation token before each one. Returns `true` if the token was
+    /// cancelled before every file was processed.
+    fn execute_plan_files(
+        &mut self, source_index: &FileIndex, plan: &MirrorPlan, created: &mut Vec<PathBuf>,
+        marker: &mut Option<ResumeWriter>, report: &mut MirrorReport,
+    ) -> Result<bool, Error> {
+        for (rel_path, info) in plan.metadata_only() {
+            if self.is_cancelled() {
+                return Ok(true);
+            }
+            self.message(&format!("Updating metadata of unchanged file {}", rel_path.display()));
+            self.update_metadata(rel_path, info)?;
+            self.changes.record_updated(rel_path);
+            report.metadata_updated += 1;
+            if let Some(marker) = marker.as_mut() {
+                marker.confirm(rel_path)?;
+            }
+        }
+        if let Some(marker) = marker.as_mut() {
+            marker.phase(ResumePhase::MetadataUpdated)?;
+        }
+        for (rel_path, info) in plan.changed() {
+            if self.is_cancelled() {
+                return Ok(true);
+            }
+            self.message(&format!("Updating changed file {}", rel_path.display()));
+            self.import_from_index(rel_path, source_index, info)?;
+            self.changes.record_updated(rel_path);
+            report.files_updated += 1;
+            report.bytes_transferred += info.get_size();
+            if let Some(marker) = marker.as_mut() {
+                marker.confirm(rel_path)?;
+            }
+        }
+        // Every folder needed by the missing files is created once up front,
+        // parents first, rather than as each file is copied
+        for folder in plan.new_folders() {
+            self.message(&format!("Creating folder: {}", folder.display()));
+            if self.action_type == ActionType::Real {
+                let path = self.path.join(folder);
+                match std::fs::create_dir(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err((e, path).into()),
+                    _ => {}
+                }
+            }
+            report.folders_created += 1;
+        }
+        for (from, to, info) in plan.renamed() {
+            if self.is_cancelled() {
+                return Ok(true);
+            }
+            self.message(&format!("Copying renamed file {} from {}", to.display(), from.display()));
+            self.copy_entry(from, to, info)?;
+            report.files_renamed += 1;
+        }
+        for (rel_path, info) in plan.missing() {
+            if self.is_cancelled() {
+                return Ok(true);
+            }
+            self.message(&format!("Copying missing file: {}", rel_path.display()));
+            self.import_from_index(rel_path, source_index, info)?;
+            self.changes.record_added(rel_path, info.get_size());
+            created.push(rel_path.to_path_buf());
+            report.files_added += 1;
+            report.bytes_transferred += info.get_size();
+            if let Some(marker) = marker.as_mut() {
+                marker.confirm(rel_path)?;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Removes files added by a failed operation
+    fn roll_back(&mut self, created: &[PathBuf]) {
+        self.message(&format!("Rolling back {} newly added files", created.len()));
+        for rel_path in created {
+            if let Some(info) = self.entries.remove(rel_path) {
+                self.changes.record_removed(rel_path, info.get_size());
+                let path = self.stored_path(rel_path, &info);
+                if self.action_type == ActionType::Real {
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        eprintln!("Unable to remove {} during rollback: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the free space available to the index in bytes, or `None` if
+    /// it cannot be determined
+    pub fn available_space(&self) -> Option<u64> {
+        if self.listing_only {
+            return None;
+        }
+        // In dry-run mode the folder may not exist yet
+        let existing = self.path.ancestors().find(|p| p.exists())?;
+        match fs2::available_space(existing) {
+            Ok(available) => Some(available),
+            Err(e) => {
+                warn!("Unable to determine free space of {}: {}", existing.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Checks that there is enough free space to execute `plan`. If the free
+    /// space cannot be determined, the check passes.
+    pub fn check_space_for(&self, plan: &MirrorPlan) -> Result<(), Error> {
+        Self::ensure_space(plan.bytes(), self.available_space())
+    }
+
+    fn ensure_space(needed: u64, available: Option<u64>) -> Result<(), Error> {
+        match available {
+            Some(available) if available < needed => Err(Error::InsufficientSpace { needed, available }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the paths of the message databases and backups in the index,
+    /// i.e. all files in the `Databases` and `Backups` folders
+    pub fn database_paths(&self) -> Vec<PathBuf> {
+        self.entries
+            .keys()
+            .filter(|p| p.starts_with(DATABASES_FOLDER) || p.starts_with(BACKUPS_FOLDER))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the databases and backups whose modification time on disk is
+    /// within `quiet_period` of now, suggesting WhatsApp may still be writing
+    /// them. This is only a heuristic: WhatsApp can start writing again after
+    /// a database has been quiet for longer than this.
+    pub fn busy_databases(&self, quiet_period: Duration) -> Result<Vec<PathBuf>, Error> {
+        self.ensure_files_present()?;
+        let now = SystemTime::now();
+        let mut busy = Vec::new();
+        for (rel_path, info) in &self.entries {
+            if !(rel_path.starts_with(DATABASES_FOLDER) || rel_path.starts_with(BACKUPS_FOLDER)) {
+                continue;
+            }
+            let path = self.stored_path(rel_path, info);
+            let modified = match path.metadata().and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err((e, path).into()),
+            };
+            // A modification time in the future also counts as recent
+            if now.duration_since(modified).map_or(true, |age| age < quiet_period) {
+                busy.push(rel_path.clone());
+            }
+        }
+        busy.sort_unstable();
+        Ok(busy)
+    }
+
+    /// Waits until `busy_databases` finds no database or backup modified
+    /// within `quiet_period`, polling every second for at most `max_wait`.
+    /// The metadata of the databases and backups is then refreshed so that
+    /// the settled versions are copied. Returns those still modified recently
+    /// when waiting was abandoned.
+    pub fn wait_for_quiescent_databases(
+        &mut self, quiet_period: Duration, max_wait: Duration,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let started = Instant::now();
+        let mut busy = self.busy_databases(quiet_period)?;
+        if !busy.is_empty() {
+            self.message(&format!("Waiting for {} recently modified databases to stop changing", busy.len()));
+        }
+        while !busy.is_empty() && started.elapsed() < max_wait {
+            self.check_deadline()?;
+            std::thread::sleep(QUIESCENCE_POLL_INTERVAL);
+            busy = self.busy_databases(quiet_period)?;
+        }
+        self.refresh_paths(&self.database_paths())?;
+        Ok(busy)
+    }
+
+    /// Mirrors only the message databases and backups from the supplied index
+    /// into this one, leaving media untouched
+    pub fn mirror_databases(&mut self, source_index: &FileIndex) -> Result<MirrorReport, Error> {
+        self.mirror_specified(source_index, source_index.database_paths())
+    }
+
     /// Mirrors all files from the supplied index into this one
-    pub fn mirror_all(&mut self, source_index: &FileIndex) -> Result<(), Error> {
+    pub fn mirror_all(&mut self, source_index: &FileIndex) -> Result<MirrorReport, Error> {
         self.mirror_specified(source_index, source_index.entries.keys())
     }
 
     /// The total size of all files in the index in bytes
     pub fn size_bytes(&self) -> u64 { self.entries.values().map(FileInfo::get_size).sum() }
 
-    /// Returns true if this is a media file
+    /// The total number of bytes the files in the index occupy on disk. This
+    /// is smaller than `size_bytes` if files are stored compressed.
+    pub fn stored_size_bytes(&self) -> u64 { self.entries.values().map(FileInfo::get_stored_size).sum() }
+
+    /// Returns true if this is a media file. Only media files are ever
+    /// trimmed or restored. Everything else, including the databases and
+    /// backups, is protected.
     fn is_media_file(path: &Path, _file_info: &FileInfo) -> bool {
-        path.starts_with("Media") && path.file_name().map_or(true, |e| e != ".nomedia")
+        path.starts_with(MEDIA_FOLDER) && !Self::is_nomedia_marker(path)
+    }
+
+    /// Returns the paths, relative to the index root, of files which are
+    /// never trimmed or restored regardless of the query or size limit. These
+    /// are all files other than media, such as the message databases in
+    /// `Databases`, the contents of `Backups` and `.nomedia` markers, along
+    /// with the tag marking an archive.
+    pub fn protected_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.non_media_files().map(|(path, _)| path.to_path_buf()).collect();
+        if self.index_type == IndexType::Archive && self.path.join(&self.tag_name).exists() {
+            paths.push(PathBuf::from(&self.tag_name));
+        }
+        paths
+    }
+
+    /// Returns true if this is a `.nomedia` marker. These tell Android's
+    /// media scanner to skip a folder and are part of the folder structure
+    /// rather than content, so they are never trimmed.
+    fn is_nomedia_marker(path: &Path) -> bool { path.file_name().is_some_and(|name| name == NOMEDIA_NAME) }
+
+    /// Returns true if WhatsApp hides this media folder from the gallery. This
+    /// covers the `Sent` and `Private` folders for each media type as well as
+    /// hidden folders such as `.Statuses`.
+    fn is_hidden_media_folder(folder: &Path) -> bool {
+        folder.starts_with(MEDIA_FOLDER)
+            && folder.file_name().map(OsStr::to_string_lossy).is_some_and(|name| {
+                name == "Sent" || name == "Private" || (name.starts_with('.') && name != NOMEDIA_NAME)
+            })
+    }
+
+    /// Creates any `.nomedia` markers missing from the folders containing
+    /// `paths` which WhatsApp hides from the gallery. Without these, the
+    /// gallery would show restored media the user never saw there before,
+    /// e.g. every sent photo. Returns the paths of the markers created.
+    pub fn restore_nomedia_markers(&mut self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+        self.ensure_files_present()?;
+        let folders: BTreeSet<&Path> =
+            paths.iter().filter_map(|p| p.parent()).filter(|f| Self::is_hidden_media_folder(f)).collect();
+        let mut created = Vec::new();
+        for folder in folders {
+            let rel_path = folder.join(NOMEDIA_NAME);
+            if self.entries.contains_key(&rel_path) {
+                continue;
+            }
+            self.message(&format!("Creating missing marker {}", rel_path.display()));
+            if self.action_type == ActionType::Real {
+                let path = self.path.join(&rel_path);
+                let parent = self.path.join(folder);
+                std::fs::create_dir_all(&parent).map_err(|e| (e, &parent))?;
+                std::fs::File::create(&path).map_err(|e| (e, &path))?;
+                let info = self.localize(&FileInfo::new(&path)?);
+                self.entries.insert(rel_path.clone(), info);
+            }
+            self.changes.record_added(&rel_path, 0);
+            created.push(rel_path);
+        }
+        Ok(created)
     }
 
     /// Iterator over all media files
@@ -441,74 +2157,2512 @@ impl FileIndex {
         self.entries.iter().filter(|(p, fi)| !Self::is_media_file(p, fi)).map(|(p, fi)| (p.as_path(), fi))
     }
 
-    /// Size of all media files in the index
+    /// Size of all media files in the index, i.e. files in the `Media` folder
+    /// other than `.nomedia` markers
     pub fn media_size_bytes(&self) -> u64 { self.media_files().map(|(_p, fi)| fi.get_size()).sum() }
 
-    /// Size of all non-media files in the index
+    /// Size of all non-media files in the index. This is the sum of
+    /// `database_size_bytes`, `backup_size_bytes` and `other_size_bytes`.
     pub fn non_media_size_bytes(&self) -> u64 { self.non_media_files().map(|(_p, fi)| fi.get_size()).sum() }
 
-    /// Returns which files should be added and removed to satisfy the query
-    pub fn get_delete_retain_candidates(&self, query: &FileQuery) -> (Vec<PathBuf>, Vec<PathBuf>) {
-        // Construct list of media files
-        let mut media_entries: Vec<(PathBuf, FileInfo)> =
-            self.media_files().map(|(k, v)| (k.to_path_buf(), v.clone())).collect();
-        let calculate_priority = |file_info: &FileInfo| -> (i32, f64) {
-            // We assign a higher class to the files the user specifically requested we keep
-            let class = i32::from(query.priority.matches(file_info));
-            let value = query.order.evaluate(file_info);
-            (class, value)
-        };
-        media_entries.sort_unstable_by(|(_, a), (_, b)| {
-            calculate_priority(a).partial_cmp(&calculate_priority(b)).expect("Unable to compute ordering")
-        });
-        let (to_delete, to_retain) = match query.data_limit {
-            DataLimit::Infinite => (Vec::new(), media_entries),
-            DataLimit::Bytes(limit) => {
-                let mut total: u64 = self.media_size_bytes();
-                let mut count = 0;
-                for (idx, (_, entry)) in media_entries.iter().enumerate() {
-                    count = idx;
-                    if total <= limit {
-                        break;
-                    }
-                    total = total.saturating_sub(entry.get_size());
-                }
-                let to_retain = media_entries.split_off(count);
-                let to_delete = media_entries;
-                (to_delete, to_retain)
-            }
-        };
-        (to_delete.into_iter().map(|(p, _)| p).collect(), to_retain.into_iter().map(|(p, _)| p).collect())
-    }
+    /// Size of all files in the `Databases` folder
+    pub fn database_size_bytes(&self) -> u64 { self.folder_size_bytes(DATABASES_FOLDER) }
 
-    /// Returns all paths present in the index
-    pub fn get_all_paths(&self) -> Vec<PathBuf> { self.entries.keys().cloned().collect() }
+    /// Size of all files in the `Backups` folder
+    pub fn backup_size_bytes(&self) -> u64 { self.folder_size_bytes(BACKUPS_FOLDER) }
 
-    /// Returns only the files which should be removed to satisfy the query
-    pub fn get_delete_candidates(&self, query: &FileQuery) -> Vec<PathBuf> {
-        self.get_delete_retain_candidates(query).0
+    /// Size of all files which are neither media, databases nor backups, such
+    /// as `.nomedia` markers and files outside WhatsApp's standard folders
+    pub fn other_size_bytes(&self) -> u64 {
+        self.non_media_files()
+            .filter(|(p, _)| !p.starts_with(DATABASES_FOLDER) && !p.starts_with(BACKUPS_FOLDER))
+            .map(|(_p, fi)| fi.get_size())
+            .sum()
     }
 
-    /// Returns only the files which should be kept to satisfy the query
-    pub fn get_retain_candidates(&self, query: &FileQuery) -> Vec<PathBuf> {
-        self.get_delete_retain_candidates(query).1
+    /// Size of all files in `folder`, relative to the index root
+    fn folder_size_bytes(&self, folder: &str) -> u64 {
+        self.entries.iter().filter(|(p, _)| p.starts_with(folder)).map(|(_p, fi)| fi.get_size()).sum()
     }
 
-    /// Returns all files in `list` which are present in the index
-    pub fn filter_existing(&self, list: &[PathBuf]) -> Vec<PathBuf> {
-        list.iter().filter(|p| self.entries.contains_key(p.as_path())).cloned().collect()
-    }
+    /// Summarizes the files in the index by media category
+    pub fn stats(&self) -> IndexStats { Self::stats_of(&self.entries) }
 
-    /// Returns all files in `list` which are not in the index
-    pub fn filter_missing(&self, list: &[PathBuf]) -> Vec<PathBuf> {
-        list.iter().filter(|p| !self.entries.contains_key(p.as_path())).cloned().collect()
+    /// Computes the same breakdown as `stats`, but only over the files at
+    /// `paths`, e.g. to summarize the files chosen for deletion. Paths absent
+    /// from the index are ignored.
+    pub fn stats_for<I: IntoIterator<Item = impl AsRef<Path>>>(&self, paths: I) -> IndexStats {
+        let entries = paths.into_iter().filter_map(|path| self.entries.get_key_value(path.as_ref()));
+        Self::stats_of(entries)
     }
 
-    /// Removes files from the index and filesystem
+    fn stats_of<'a, I: IntoIterator<Item = (&'a PathBuf, &'a FileInfo)>>(entries: I) -> IndexStats {
+        let mut stats = IndexStats::default();
+        for (path, info) in entries {
+            if Self::is_media_file(path, info) {
+                stats.media.add(info);
+                stats.categories.entry(MediaCategory::from_path(path)).or_default().add(info);
+            } else {
+                stats.non_media.add(info);
+            }
+        }
+        stats
+    }
+
+    /// Returns the newest media file in each folder directly containing media,
+    /// which is taken to be a chat. Ties are broken by path so the result is
+    /// deterministic.
+    fn latest_per_chat(&self) -> HashSet<&Path> {
+        let mut latest: HashMap<&Path, (&Path, &FileInfo)> = HashMap::new();
+        for (path, info) in self.media_files() {
+            let Some(folder) = path.parent() else { continue };
+            let newest = latest.entry(folder).or_insert((path, info));
+            if (info.estimate_creation_date(), path) > (newest.1.estimate_creation_date(), newest.0) {
+                *newest = (path, info);
+            }
+        }
+        latest.into_values().map(|(path, _)| path).collect()
+    }
+
+    /// Returns which files should be added and removed to satisfy the query
+    pub fn get_delete_retain_candidates(&self, query: &FileQuery) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let (to_delete, to_retain) = self.select_candidates(query);
+        let to_retain = to_retain.into_iter().map(|(p, _)| p.to_path_buf());
+        // `.nomedia` markers are always retained so that they are restored
+        // along with the media in their folders
+        let markers = self.entries.keys().filter(|p| Self::is_nomedia_marker(p)).cloned();
+        (to_delete.into_iter().map(|(p, _)| p.to_path_buf()).collect(), to_retain.chain(markers).collect())
+    }
+
+    /// Returns the number of files which would be deleted to satisfy the query
+    /// and their total size in bytes, without building the list of paths
+    pub fn delete_candidate_stats(&self, query: &FileQuery) -> (usize, u64) {
+        let (to_delete, _) = self.select_candidates(query);
+        (to_delete.len(), to_delete.iter().map(|(_, info)| info.get_size()).sum())
+    }
+
+    /// Returns the paths of the media files which would be deleted to
+    /// satisfy the query, in the order they would be deleted. Unlike
+    /// `get_delete_retain_candidates`, the paths are borrowed from the index
+    /// and no list of retained files is built, though the media files are
+    /// still buffered in order to rank them.
+    pub fn delete_candidates_iter(&self, query: &FileQuery) -> impl Iterator<Item = &Path> + '_ {
+        let (_, ranked) = self.rank_candidates(query);
+        let count = self.deletion_count(&ranked, query.data_limit);
+        ranked.into_iter().take(count).map(|(path, _)| path)
+    }
+
+    /// Selects the media files to delete and retain to satisfy the query,
+    /// borrowing them from the index
+    fn select_candidates(&self, query: &FileQuery) -> (Vec<Candidate<'_>>, Vec<Candidate<'_>>) {
+        let (pinned, mut ranked) = self.rank_candidates(query);
+        let count = self.deletion_count(&ranked, query.data_limit);
+        let mut to_retain = ranked.split_off(count);
+        // Pinned files are always retained, though they still count towards the limit
+        to_retain.extend(pinned);
+        (ranked, to_retain)
+    }
+
+    /// Ranks the media files within the scope of the query, returning those
+    /// which can never be deleted followed by the rest in the order they
+    /// should be deleted
+    fn rank_candidates(&self, query: &FileQuery) -> (Vec<Candidate<'_>>, Vec<Candidate<'_>>) {
+        // Construct list of media files. Files outside the query scope are never
+        // candidates but still count towards the limit. The time is read once
+        // so that every file is judged against the same instant.
+        let now = query.clock.now();
+        let latest_per_chat = if query.keep_latest_per_chat { self.latest_per_chat() } else { HashSet::new() };
+        let (pinned, mut media_entries): (Vec<_>, Vec<_>) = self
+            .media_files()
+            .filter(|(_, v)| query.scope.matches_at(v, now))
+            .partition(|(k, _)| query.is_pinned(k) || latest_per_chat.contains(k));
+        let calculate_priority = |path: &Path, file_info: &FileInfo| -> (i32, f64) {
+            // We assign a higher class to the files the user specifically requested we keep
+            let class = i32::from(query.priority.keep_at(path, file_info, now));
+            let value = query.order.score_at(path, file_info, now);
+            (class, value)
+        };
+        media_entries.sort_unstable_by(|(a_path, a), (b_path, b)| {
+            let (a_class, a_value) = calculate_priority(a_path, a);
+            let (b_class, b_value) = calculate_priority(b_path, b);
+            a_class.cmp(&b_class).then(a_value.total_cmp(&b_value))
+        });
+        (pinned, media_entries)
+    }
+
+    /// Returns how many of the ranked candidates must be deleted, in order,
+    /// for the media in the index to fit within `data_limit`
+    fn deletion_count(&self, ranked: &[Candidate<'_>], data_limit: DataLimit) -> usize {
+        let DataLimit::Bytes(limit) = data_limit else { return 0 };
+        let mut total: u64 = self.media_size_bytes();
+        // If the limit is never reached, every candidate is deleted
+        let mut count = ranked.len();
+        for (idx, (_, entry)) in ranked.iter().enumerate() {
+            if total <= limit {
+                count = idx;
+                break;
+            }
+            total = total.saturating_sub(entry.get_size());
+        }
+        count
+    }
+
+    /// Returns the number of bytes of media which would be retained and
+    /// deleted by applying `query`, without changing anything. Media outside
+    /// the scope of the query counts as retained, so the two figures always
+    /// sum to `media_size_bytes`.
+    pub fn projected_media_bytes(&self, query: &FileQuery) -> (u64, u64) {
+        let (_, deleted) = self.delete_candidate_stats(query);
+        (self.media_size_bytes() - deleted, deleted)
+    }
+
+    /// Copies every media file in the index into folders under `dest` named
+    /// by its estimated creation date according to `layout`, e.g. for import
+    /// into a photo library. Files are decoded as they are copied and keep
+    /// their modification times. If a different file with the same name is
+    /// already in a folder, a number is added to the name, while a file which
+    /// was already exported with the same size and modification time is
+    /// skipped. The index itself is never changed, so it can be opened in
+    /// dry-run mode, while `action_type` controls whether files are actually
+    /// written to `dest`. Returns the paths of the media exported and where
+    /// each was copied to.
+    pub fn export_media(
+        &self, dest: &Path, layout: ExportLayout, action_type: ActionType,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        self.ensure_files_present()?;
+        let mut media: Vec<_> = self.media_files().collect();
+        media.sort_unstable_by_key(|(path, _)| *path);
+        let mut assigned = HashSet::new();
+        let mut exported = Vec::new();
+        for (rel_path, info) in media {
+            let Some(filename) = rel_path.file_name() else { continue };
+            let folder = dest.join(layout.folder(info.estimate_creation_date()));
+            let mut n = 0;
+            let (target, already_exported) = loop {
+                let candidate = folder.join(numbered_filename(Path::new(filename), n));
+                n += 1;
+                if assigned.contains(&candidate) {
+                    continue;
+                }
+                match candidate.metadata() {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => break (candidate, false),
+                    Err(e) => return Err((e, candidate).into()),
+                    Ok(metadata) => {
+                        let modified = FileTime::from_last_modification_time(&metadata);
+                        if metadata.len() == info.get_size() && modified == info.get_modification_time() {
+                            break (candidate, true);
+                        }
+                    }
+                }
+            };
+            assigned.insert(target.clone());
+            if already_exported {
+                continue;
+            }
+            self.check_deadline()?;
+            self.message(&format!("Exporting {} to {}", rel_path.display(), target.display()));
+            if action_type == ActionType::Real {
+                std::fs::create_dir_all(&folder).map_err(|e| (e, &folder))?;
+                let source_path = self.stored_path(rel_path, info);
+                let source = StoredFile { path: &source_path, encoding: info.get_encoding(), key: self.key.as_ref() };
+                let dest = StoredFile { path: &target, encoding: Encoding::PLAIN, key: None };
+                let mut random = || Self::temp_random(&self.temp_rng);
+                let options =
+                    CopyOptions { chunk_size: self.copy_chunk_size, deadline: self.deadline, expected_hash: None };
+                Self::safer_copy(&source, &dest, info.get_size(), None, &options, &mut random, &mut NoProgress)?;
+                info.set_modification_time(&target)?;
+            }
+            exported.push((rel_path.to_path_buf(), target));
+        }
+        Ok(exported)
+    }
+
+    /// Returns all paths present in the index
+    pub fn get_all_paths(&self) -> Vec<PathBuf> { self.entries.keys().cloned().collect() }
+
+    /// Returns the paths of files whose recorded modification time is within
+    /// `window` of now, e.g. because they may still be being written. A
+    /// modification time in the future also counts as recent.
+    pub fn modified_within(&self, window: Duration) -> Vec<PathBuf> {
+        // A window too large to represent includes every file
+        let cutoff = SystemTime::now().checked_sub(window).map(FileTime::from_system_time);
+        let mut recent: Vec<PathBuf> = self
+            .entries
+            .iter()
+            .filter(|(_, info)| cutoff.is_none_or(|cutoff| info.get_modification_time() > cutoff))
+            .map(|(path, _)| path.clone())
+            .collect();
+        recent.sort_unstable();
+        recent
+    }
+
+    /// Returns the paths of media files with no contents. WhatsApp sometimes
+    /// leaves these as placeholders for media which was never downloaded.
+    /// Empty `.nomedia` markers are not media and so are never included.
+    pub fn empty_files(&self) -> Vec<PathBuf> {
+        self.media_files().filter(|(_, info)| info.get_size() == 0).map(|(path, _)| path.to_path_buf()).collect()
+    }
+
+    /// Returns true if the name of the file embeds its creation date
+    fn has_dated_name(path: &Path) -> bool {
+        path.file_name().is_some_and(|name| FileInfo::creation_date_from_filename(&name.to_string_lossy()).is_some())
+    }
+
+    /// The latest creation date embedded in the name of a file in the index,
+    /// if any file follows WhatsApp's naming convention
+    pub fn latest_named_date(&self) -> Option<NaiveDate> {
+        self.entries
+            .iter()
+            .filter(|(path, _)| Self::has_dated_name(path))
+            .map(|(_, info)| info.estimate_creation_date().date())
+            .max()
+    }
+
+    /// Returns the latest estimated creation date of any media file, or `None`
+    /// if the index holds no media
+    pub fn newest_media_date(&self) -> Option<NaiveDateTime> {
+        self.media_files().map(|(_, info)| info.estimate_creation_date()).max()
+    }
+
+    /// Returns the paths of files which may have been added on or after
+    /// `date`, trusting that WhatsApp names new media with the current date.
+    /// These are the files whose names embed a date no earlier than `date`,
+    /// along with all files whose names do not embed a date, such as
+    /// databases. Files which were renamed or given older names, e.g. media
+    /// restored from another device, are missed.
+    pub fn new_files_since(&self, date: NaiveDate) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .filter(|(path, info)| !Self::has_dated_name(path) || info.estimate_creation_date().date() >= date)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Returns only the files which should be removed to satisfy the query
+    pub fn get_delete_candidates(&self, query: &FileQuery) -> Vec<PathBuf> {
+        self.delete_candidates_iter(query).map(Path::to_path_buf).collect()
+    }
+
+    /// Returns only the files which should be kept to satisfy the query
+    pub fn get_retain_candidates(&self, query: &FileQuery) -> Vec<PathBuf> {
+        self.get_delete_retain_candidates(query).1
+    }
+
+    /// Returns all files in `list` which are present in the index
+    pub fn filter_existing(&self, list: &[PathBuf]) -> Vec<PathBuf> {
+        list.iter().filter(|p| self.entries.contains_key(p.as_path())).cloned().collect()
+    }
+
+    /// Returns all files in `list` which are present in both this index and
+    /// `other` with the same metadata, e.g. those whose archived copy is up to
+    /// date
+    pub fn filter_matching(&self, other: &FileIndex, list: &[PathBuf]) -> Vec<PathBuf> {
+        list.iter()
+            .filter(|p| self.entries.get(p.as_path()).is_some_and(|info| other.entries.get(p.as_path()) == Some(info)))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns all files in `list` which are within any of the folders
+    /// `prefixes`. Paths are compared by component, so `Media/WhatsApp Video`
+    /// does not match `Media/WhatsApp Video Notes`.
+    pub fn filter_by_prefix(list: &[PathBuf], prefixes: &[PathBuf]) -> Vec<PathBuf> {
+        list.iter().filter(|p| prefixes.iter().any(|prefix| p.starts_with(prefix))).cloned().collect()
+    }
+
+    /// Returns all files in `list` which are not in the index
+    pub fn filter_missing(&self, list: &[PathBuf]) -> Vec<PathBuf> {
+        list.iter().filter(|p| !self.entries.contains_key(p.as_path())).cloned().collect()
+    }
+
+    /// Removes files from the index and filesystem
     pub fn remove_files<I: IntoIterator<Item = impl AsRef<Path>>>(&mut self, files: I) -> Result<(), Error> {
+        let files: Vec<_> = files.into_iter().collect();
+        // Nothing is deleted if the whole batch would exceed the limit
+        self.check_deletion(files.iter().map(AsRef::as_ref))?;
         for file in files {
             self.remove_file(file.as_ref())?;
         }
         Ok(())
     }
+
+    /// Removes files from the index and filesystem as for `remove_files`, but
+    /// deletes them using `jobs` threads, or one per CPU if `jobs` is zero.
+    /// This is much faster when each deletion is slow, e.g. over MTP. Every
+    /// file is attempted even if some fail. Those deleted are removed from the
+    /// index and the rest are reported together as `Error::DeletionsFailed`.
+    pub fn remove_files_parallel<I: IntoIterator<Item = impl AsRef<Path>>>(
+        &mut self, files: I, jobs: usize,
+    ) -> Result<(), Error> {
+        self.check_deadline()?;
+        self.ensure_files_present()?;
+        let files: BTreeSet<PathBuf> = files.into_iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self.check_deletion(files.iter().map(PathBuf::as_path))?;
+        let stored_paths = files
+            .iter()
+            .map(|path| {
+                let info = self.entries.get(path).ok_or_else(|| Error::FileMissing(path.clone()))?;
+                Ok(self.stored_path(path, info))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build().map_err(Error::ThreadPool)?;
+        let real = self.action_type == ActionType::Real;
+        for stored_path in &stored_paths {
+            self.message(&format!("Deleting {}", stored_path.to_string_lossy()));
+        }
+        let results: Vec<io::Result<()>> = pool.install(|| {
+            stored_paths
+                .par_iter()
+                .map(|stored_path| if real { std::fs::remove_file(stored_path) } else { Ok(()) })
+                .collect()
+        });
+        // The index is only updated once all deletions have been attempted
+        let mut failures = Vec::new();
+        for ((path, stored_path), result) in files.iter().zip(stored_paths).zip(results) {
+            match result {
+                Ok(()) => self.forget_deleted(path),
+                Err(e) => failures.push((stored_path, e)),
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::DeletionsFailed(failures))
+        }
+    }
+
+    /// Removes files from the index and filesystem as for `remove_files`,
+    /// except that immediately before each file is deleted its size and
+    /// modification time on disk are compared with those recorded in the
+    /// index. Files which have changed or disappeared since the index was
+    /// built, e.g. because WhatsApp wrote to them in the meantime, are not
+    /// deleted and are returned instead.
+    pub fn remove_unchanged_files<I: IntoIterator<Item = impl AsRef<Path>>>(
+        &mut self, files: I,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let files: Vec<_> = files.into_iter().collect();
+        self.check_deletion(files.iter().map(AsRef::as_ref))?;
+        let mut changed = Vec::new();
+        for file in files {
+            let file = file.as_ref();
+            if self.is_unchanged_on_disk(file)? {
+                self.remove_file(file)?;
+            } else {
+                self.message(&format!("Not deleting {} as it changed after it was indexed", file.display()));
+                changed.push(file.to_path_buf());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Returns `true` if the file at `path` still has the size and
+    /// modification time recorded in the index, or `false` if it has changed
+    /// or no longer exists
+    fn is_unchanged_on_disk(&self, path: &Path) -> Result<bool, Error> {
+        let info = self.entries.get(path).ok_or_else(|| Error::FileMissing(path.to_owned()))?;
+        let stored_path = self.stored_path(path, info);
+        match FileInfo::new_stored(&stored_path, info.get_encoding()) {
+            Ok(current) => Ok(current.get_size() == info.get_size()
+                && current.get_modification_time() == info.get_modification_time()),
+            Err(e) if e.io_kind() == Some(io::ErrorKind::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Returns `true` if `deadline` is set and has passed
+fn deadline_passed(deadline: Option<Instant>) -> bool { deadline.is_some_and(|deadline| Instant::now() >= deadline) }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use chrono::FixedOffset;
+
+    use super::*;
+    use crate::codec::COMPRESSED_SUFFIX;
+    use crate::test_util::{in_current_dir, indices, whatsapp_folder, write_file, TempDir, TEST_MTIME};
+    use crate::{Clock, FilePredicate, FileScore, FixedClock, KeepFn, ScoreFn};
+
+    const IMAGE: &str = "Media/WhatsApp Images/IMG-20240101-WA0001.jpg";
+    const NOTES: &str = "Media/WhatsApp Documents/notes.txt";
+
+    /// Counts the files whose contents are copied
+    #[derive(Debug, Default)]
+    struct CopyCounter(Arc<AtomicUsize>);
+
+    impl Progress for CopyCounter {
+        fn file_started(&mut self, _path: &Path, _size: u64) { self.0.fetch_add(1, Ordering::Relaxed); }
+    }
+
+    /// Records the mirror operations started and stopped
+    #[derive(Debug, Default)]
+    struct MirrorEvents(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Progress for MirrorEvents {
+        fn mirror_started(&mut self, _total_bytes: u64, _total_files: usize) { self.0.lock().unwrap().push("started"); }
+
+        fn mirror_finished(&mut self) { self.0.lock().unwrap().push("finished"); }
+    }
+
+    fn set_mtime(path: &Path, mtime: i64) {
+        filetime::set_file_mtime(path, FileTime::from_unix_time(mtime, 0)).unwrap();
+    }
+
+    fn mtime_of(path: &Path) -> i64 { FileTime::from_last_modification_time(&path.metadata().unwrap()).unix_seconds() }
+
+    #[test]
+    fn identical_file_with_new_mtime_only_updates_metadata() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        let new_mtime = TEST_MTIME + 3600;
+        set_mtime(&wa.join(IMAGE), new_mtime);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let copies = Arc::new(AtomicUsize::new(0));
+        archive_index.set_progress(Box::new(CopyCounter(copies.clone())));
+        archive_index.set_hashing(true);
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+
+        assert_eq!(report.metadata_updated, 1);
+        assert_eq!(report.files_copied(), 0);
+        assert_eq!(report.bytes_transferred, 0);
+        assert_eq!(copies.load(Ordering::Relaxed), 0);
+        assert_eq!(mtime_of(&archive_index.path().join(IMAGE)), new_mtime);
+        assert_eq!(archive_index.entries[Path::new(IMAGE)], wa_index.entries[Path::new(IMAGE)]);
+    }
+
+    #[test]
+    fn changed_file_with_new_mtime_is_copied() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        write_file(&wa, IMAGE, b"other contents", TEST_MTIME + 3600);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.set_hashing(true);
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+
+        assert_eq!(report.metadata_updated, 0);
+        assert_eq!(report.files_updated, 1);
+        assert_eq!(std::fs::read(archive_index.path().join(IMAGE)).unwrap(), b"other contents");
+    }
+
+    /// Mirrors four images and the message database to the archive and
+    /// returns the archive index with the names of the images
+    fn archive_with_images(temp: &TempDir) -> (FileIndex, Vec<String>) {
+        let wa = whatsapp_folder(temp.path());
+        let images: Vec<_> = (1..=4).map(|i| format!("Media/WhatsApp Images/IMG-20240101-WA000{}.jpg", i)).collect();
+        for image in &images {
+            write_file(&wa, image, b"image contents", TEST_MTIME);
+        }
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        (archive_index, images)
+    }
+
+    #[test]
+    fn excessive_deletion_is_refused() {
+        let temp = TempDir::new();
+        let (mut archive_index, images) = archive_with_images(&temp);
+        archive_index.set_max_deletion_fraction(Some(0.5));
+
+        let result = archive_index.remove_files(&images);
+        assert!(matches!(result, Err(Error::ExcessiveDeletion { files: 4, total_files: 5, .. })));
+        for image in &images {
+            assert!(archive_index.path().join(image).exists());
+            assert!(archive_index.entries.contains_key(Path::new(image)));
+        }
+
+        // Deletions count towards the limit across calls
+        archive_index.remove_files(&images[..2]).unwrap();
+        let result = archive_index.remove_file(Path::new(&images[2]));
+        assert!(matches!(result, Err(Error::ExcessiveDeletion { files: 3, .. })));
+        assert!(archive_index.path().join(&images[2]).exists());
+    }
+
+    #[test]
+    fn deletion_is_unlimited_without_a_fraction() {
+        let temp = TempDir::new();
+        let (mut archive_index, images) = archive_with_images(&temp);
+        archive_index.set_max_deletion_fraction(Some(0.5));
+        archive_index.set_max_deletion_fraction(None);
+
+        archive_index.remove_files(&images).unwrap();
+        for image in &images {
+            assert!(!archive_index.path().join(image).exists());
+        }
+    }
+
+    #[test]
+    fn parallel_deletion_reports_failures_and_removes_the_rest() {
+        let temp = TempDir::new();
+        let (mut archive_index, images) = archive_with_images(&temp);
+        // One file disappears behind the index's back, so cannot be deleted
+        std::fs::remove_file(archive_index.path().join(&images[0])).unwrap();
+
+        let result = archive_index.remove_files_parallel(&images, 2);
+        let Err(Error::DeletionsFailed(failures)) = result else { panic!("Unexpected result {:?}", result) };
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, archive_index.path().join(&images[0]));
+        assert_eq!(failures[0].1.kind(), io::ErrorKind::NotFound);
+        assert!(archive_index.entries.contains_key(Path::new(&images[0])));
+        for image in &images[1..] {
+            assert!(!archive_index.path().join(image).exists());
+            assert!(!archive_index.entries.contains_key(Path::new(image)));
+        }
+    }
+
+    /// Plans mirroring every file in `wa_index` into `archive_index` with
+    /// content identity enabled, checks the plan and performs it
+    fn mirror_by_content(wa_index: &FileIndex, archive_index: &mut FileIndex, renamed: &[(&str, &str)]) {
+        archive_index.set_content_identity(true);
+        let plan = archive_index.plan_mirror(wa_index, wa_index.entries.keys()).unwrap();
+        let planned: Vec<_> = plan.renamed().map(|(from, to, _)| (from.to_path_buf(), to.to_path_buf())).collect();
+        let expected: Vec<_> = renamed.iter().map(|(from, to)| (PathBuf::from(from), PathBuf::from(to))).collect();
+        assert_eq!(planned, expected);
+        let report = archive_index.mirror_all(wa_index).unwrap();
+        assert_eq!(report.files_renamed, renamed.len());
+    }
+
+    #[test]
+    fn renamed_identical_file_is_treated_as_existing() {
+        const RENAMED: &str = "Media/WhatsApp Images/IMG-20240101-WA0002.jpg";
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        std::fs::rename(wa.join(IMAGE), wa.join(RENAMED)).unwrap();
+        let (wa_index, mut archive_index) = indices(temp.path());
+        assert_eq!(archive_index.mirror_cost(&wa_index).unwrap().1, 1);
+        archive_index.set_content_identity(true);
+        assert_eq!(archive_index.mirror_cost(&wa_index).unwrap(), (0, 0));
+        mirror_by_content(&wa_index, &mut archive_index, &[(IMAGE, RENAMED)]);
+
+        let archive = archive_index.path();
+        assert_eq!(std::fs::read(archive.join(RENAMED)).unwrap(), b"image contents");
+        assert_eq!(archive_index.entries[Path::new(RENAMED)], wa_index.entries[Path::new(RENAMED)]);
+        // The existing file is never removed by a match on its contents
+        assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), b"image contents");
+        assert!(archive_index.entries.contains_key(Path::new(IMAGE)));
+    }
+
+    #[test]
+    fn trimmed_file_is_kept_when_its_contents_reappear() {
+        let resent = ["Media/WhatsApp Images/IMG-20240102-WA0001.jpg", "Media/WhatsApp Images/IMG-20240103-WA0001.jpg"];
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        // The image is trimmed, then the same image is received twice more
+        wa_index.remove_file(Path::new(IMAGE)).unwrap();
+        for name in resent {
+            write_file(&wa, name, b"image contents", TEST_MTIME + 86400);
+        }
+        let (wa_index, mut archive_index) = indices(temp.path());
+        mirror_by_content(&wa_index, &mut archive_index, &[(IMAGE, resent[0]), (IMAGE, resent[1])]);
+
+        let archive = archive_index.path();
+        assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), b"image contents");
+        assert_eq!(mtime_of(&archive.join(IMAGE)), TEST_MTIME);
+        for name in resent {
+            assert_eq!(std::fs::read(archive.join(name)).unwrap(), b"image contents");
+            assert_eq!(mtime_of(&archive.join(name)), TEST_MTIME + 86400);
+        }
+    }
+
+    #[test]
+    fn databases_are_not_matched_by_content() {
+        const OLD_DB: &str = "Databases/msgstore-2024-01-01.1.db.crypt14";
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, OLD_DB, b"image contents", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        wa_index.remove_file(Path::new(OLD_DB)).unwrap();
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        mirror_by_content(&wa_index, &mut archive_index, &[]);
+
+        let archive = archive_index.path();
+        assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), b"image contents");
+        assert_eq!(std::fs::read(archive.join(OLD_DB)).unwrap(), b"image contents");
+    }
+
+    /// Archives a WhatsApp folder holding the message database and `files`,
+    /// each with its modification time, returning the archive index
+    fn archive_of(temp: &TempDir, files: &[(&str, i64)]) -> FileIndex {
+        let wa = whatsapp_folder(temp.path());
+        for (name, mtime) in files {
+            write_file(&wa, name, name.as_bytes(), *mtime);
+        }
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        archive_index
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> { names.iter().map(PathBuf::from).collect() }
+
+    #[test]
+    fn dated_msgstore_backups_beyond_kept_dates_are_removed() {
+        let temp = TempDir::new();
+        let mut archive_index = archive_of(
+            &temp,
+            &[
+                ("Databases/msgstore-2024-01-01.1.db.crypt14", TEST_MTIME),
+                ("Databases/msgstore-2024-01-02.1.db.crypt14", TEST_MTIME + 86400),
+                ("Databases/msgstore-2024-01-03.1.db.crypt14", TEST_MTIME + 2 * 86400),
+                ("Databases/msgstore-2024-01-04.1.db.crypt14", TEST_MTIME + 3 * 86400),
+                ("Databases/msgstore-2024-01-04.2.db.crypt14", TEST_MTIME + 3 * 86400),
+            ],
+        );
+        let removed =
+            paths(&["Databases/msgstore-2024-01-01.1.db.crypt14", "Databases/msgstore-2024-01-02.1.db.crypt14"]);
+        assert_eq!(archive_index.plan_db_cleanup(2), removed);
+        archive_index.clean_old_dbs(2).unwrap();
+
+        // Both backups from the newest date count as one of the dates kept
+        let kept = [
+            "Databases/msgstore.db.crypt14",
+            "Databases/msgstore-2024-01-03.1.db.crypt14",
+            "Databases/msgstore-2024-01-04.1.db.crypt14",
+            "Databases/msgstore-2024-01-04.2.db.crypt14",
+        ];
+        for name in kept {
+            assert!(archive_index.path().join(name).exists(), "{}", name);
+        }
+        for path in &removed {
+            assert!(!archive_index.path().join(path).exists());
+            assert!(!archive_index.entries.contains_key(path));
+        }
+    }
+
+    #[test]
+    fn custom_database_series_is_cleaned() {
+        let temp = TempDir::new();
+        let mut archive_index = archive_of(
+            &temp,
+            &[
+                ("Databases/wa.db.crypt14", TEST_MTIME + 3 * 86400),
+                ("Databases/wa-20240101.1.db.crypt14", TEST_MTIME),
+                ("Databases/wa-20240102.1.db.crypt14", TEST_MTIME + 86400),
+                ("Databases/wa-20240103.1.db.crypt14", TEST_MTIME + 2 * 86400),
+                ("Databases/chatsettings-20240101.1.db.crypt14", TEST_MTIME),
+            ],
+        );
+        // Only the message database series is cleaned by default
+        assert!(archive_index.plan_db_cleanup(1).is_empty());
+
+        archive_index.set_db_patterns(vec![DbPattern::msgstore(), DbPattern::new("wa", "%Y%m%d").unwrap()]);
+        let removed = paths(&["Databases/wa-20240101.1.db.crypt14", "Databases/wa-20240102.1.db.crypt14"]);
+        assert_eq!(archive_index.plan_db_cleanup(1), removed);
+        archive_index.clean_old_dbs(1).unwrap();
+        for path in &removed {
+            assert!(!archive_index.path().join(path).exists());
+        }
+        for name in ["wa.db.crypt14", "wa-20240103.1.db.crypt14", "chatsettings-20240101.1.db.crypt14"] {
+            assert!(archive_index.path().join(DATABASES_FOLDER).join(name).exists(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn compressed_file_restores_identically() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let contents = "Compressible text which repeats. ".repeat(1000).into_bytes();
+        write_file(&wa, NOTES, &contents, TEST_MTIME);
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.set_compression(true);
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        let archive = archive_index.path().to_path_buf();
+        let compressed = archive.join(format!("{}{}", NOTES, COMPRESSED_SUFFIX));
+        assert!(compressed.exists());
+        assert!(!archive.join(NOTES).exists());
+        assert!(compressed.metadata().unwrap().len() < contents.len() as u64);
+        // Already compressed media is stored verbatim
+        assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), b"image contents");
+
+        std::fs::remove_file(wa.join(NOTES)).unwrap();
+        let (mut wa_index, archive_index) = indices(temp.path());
+        assert_eq!(archive_index.entries[Path::new(NOTES)].get_size(), contents.len() as u64);
+        wa_index.mirror_specified(&archive_index, [NOTES]).unwrap();
+        assert_eq!(std::fs::read(wa.join(NOTES)).unwrap(), contents);
+        assert_eq!(mtime_of(&wa.join(NOTES)), TEST_MTIME);
+    }
+
+    /// Updates the archived copy of `NOTES` with metadata whose creation date
+    /// cannot be derived from the stored file, so the copy succeeds but the
+    /// update fails the metadata check
+    fn failing_update(temp: &TempDir, archive_index: &mut FileIndex) -> Result<(), Error> {
+        let contents = b"updated notes";
+        let update = write_file(temp.path(), "update.txt", contents, TEST_MTIME + 60);
+        let date = FileInfo::creation_date_from_filename("IMG-20240101-WA0001.jpg").unwrap();
+        let info = FileInfo::from_parts(contents.len() as u64, FileTime::from_unix_time(TEST_MTIME + 60, 0), date);
+        archive_index.import_file_with_metadata(Path::new(NOTES), &update, &info)
+    }
+
+    #[test]
+    fn failed_update_keeps_existing_file() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, NOTES, b"original notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        let result = failing_update(&temp, &mut archive_index);
+        assert!(matches!(result, Err(Error::FileMismatch(_, _))));
+        assert!(archive_index.path().join(NOTES).exists());
+        assert!(archive_index.entries.contains_key(Path::new(NOTES)));
+    }
+
+    #[test]
+    fn failed_update_with_new_encoding_keeps_previous_file() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, NOTES, b"original notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        // The update would be stored compressed, under a different name
+        archive_index.set_compression(true);
+        let result = failing_update(&temp, &mut archive_index);
+        assert!(matches!(result, Err(Error::FileMismatch(_, _))));
+        let archive = archive_index.path();
+        assert_eq!(std::fs::read(archive.join(NOTES)).unwrap(), b"original notes");
+        assert!(!archive.join(format!("{}{}", NOTES, COMPRESSED_SUFFIX)).exists());
+        let entry = &archive_index.entries[Path::new(NOTES)];
+        assert_eq!(entry.get_encoding(), Encoding::PLAIN);
+        assert_eq!(entry.get_modification_time(), FileTime::from_unix_time(TEST_MTIME, 0));
+    }
+
+    #[test]
+    fn parallel_and_serial_manifests_are_identical() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        for i in 0..50 {
+            let name = format!("Media/WhatsApp Images/IMG-20240101-WA{:04}.jpg", i);
+            write_file(&wa, &name, format!("image {}", i).as_bytes(), TEST_MTIME);
+        }
+        let (wa_index, _) = indices(temp.path());
+        let write = |manifest: &Manifest| {
+            let mut bytes = Vec::new();
+            manifest.write(&mut bytes).unwrap();
+            bytes
+        };
+        let serial = write(&wa_index.build_manifest(1).unwrap());
+        for jobs in [0, 2, 8] {
+            assert_eq!(write(&wa_index.build_manifest(jobs).unwrap()), serial);
+        }
+    }
+
+    #[test]
+    fn encrypted_archive_requires_key() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, NOTES, b"secret notes", TEST_MTIME);
+        let key = || Some(EncryptionKey::from_hex(&"ab".repeat(32)).unwrap());
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.set_encryption_key(key()).unwrap();
+        archive_index.mirror_all(&wa_index).unwrap();
+        let stored = archive_index.stored_path(Path::new(NOTES), &archive_index.entries[Path::new(NOTES)]);
+        assert!(!std::fs::read(stored).unwrap().windows(6).any(|w| w == b"secret"));
+
+        let (_, mut archive_index) = indices(temp.path());
+        let error = archive_index.set_encryption_key(None).unwrap_err();
+        assert!(matches!(error, Error::EncryptionKeyRequired(_)));
+        archive_index.set_encryption_key(key()).unwrap();
+        let exported = archive_index.export_media(&temp.path().join("Export"), ExportLayout::Year, ActionType::Real);
+        let exported = exported.unwrap();
+        assert_eq!(exported.len(), 1);
+        assert_eq!(std::fs::read(&exported[0].1).unwrap(), b"secret notes");
+    }
+
+    #[test]
+    fn gif_is_trimmed_before_photo_of_same_size_and_age() {
+        const GIF: &str = "Media/WhatsApp Animated Gifs/VID-20240101-WA0001.mp4";
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"contents", TEST_MTIME);
+        write_file(&wa, GIF, b"contents", TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        let mut query = FileQuery::default();
+        query.set_order(FileScore::SmallerNewerWeighted { category_weights: FileScore::default_category_weights() });
+        query.set_limit(DataLimit::from_bytes(wa_index.media_size_bytes() - 1));
+        let (to_delete, to_retain) = wa_index.get_delete_retain_candidates(&query);
+        assert_eq!(to_delete, paths(&[GIF]));
+        assert_eq!(to_retain, paths(&[IMAGE]));
+    }
+
+    #[test]
+    fn non_finite_weights_do_not_break_ranking() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        for weight in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut query = FileQuery::default();
+            let category_weights = BTreeMap::from([(MediaCategory::Images, weight)]);
+            query.set_order(FileScore::Weighted { size_weight: weight, age_weight: weight, category_weights });
+            query.set_limit(DataLimit::from_bytes(0));
+            let (to_delete, to_retain) = wa_index.get_delete_retain_candidates(&query);
+            assert_eq!(to_delete.len(), 2);
+            assert!(to_retain.is_empty());
+        }
+    }
+
+    #[test]
+    fn failed_mirror_is_reported_as_finished() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(MirrorEvents(events.clone())));
+        std::fs::remove_file(wa.join(IMAGE)).unwrap();
+
+        assert!(archive_index.mirror_all(&wa_index).is_err());
+        assert_eq!(*events.lock().unwrap(), ["started", "finished"]);
+    }
+
+    #[test]
+    fn listing_round_trip() {
+        const UNDATED: &str = "Media/WhatsApp Documents/report.pdf";
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, &[b'a'; 4096], TEST_MTIME);
+        write_file(&wa, UNDATED, b"report", TEST_MTIME + 1);
+        let options = IndexOptions {
+            timezone: FixedOffset::east_opt(10 * 60 * 60).unwrap().into(),
+            assumed_time: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            ..IndexOptions::default()
+        };
+        let wa_index = FileIndex::new_with_options(IndexType::Original, &wa, ActionType::Real, &options).unwrap();
+        let archive_path = temp.path().join("Archive");
+        let mut archive_index =
+            FileIndex::new_with_options(IndexType::Archive, &archive_path, ActionType::Real, &options).unwrap();
+        archive_index.set_compression(true);
+        archive_index.mirror_all(&wa_index).unwrap();
+        let listing_path = temp.path().join("listing.json");
+        archive_index.save_listing(&listing_path).unwrap();
+
+        let listed = FileIndex::from_listing_with_options(&listing_path, &options).unwrap();
+        assert_eq!(listed.index_type, IndexType::Archive);
+        assert_eq!(listed.entries, archive_index.entries);
+        for (path, info) in &archive_index.entries {
+            let listed_info = &listed.entries[path];
+            assert_eq!(listed_info.get_stored_size(), info.get_stored_size(), "{}", path.display());
+            assert_eq!(listed_info.get_encoding(), info.get_encoding(), "{}", path.display());
+            assert_eq!(listed_info.get_timezone(), info.get_timezone(), "{}", path.display());
+        }
+        assert!(listed.entries[Path::new(NOTES)].get_encoding().compressed);
+        assert_eq!(listed.size_bytes(), archive_index.size_bytes());
+        assert_eq!(listed.stored_size_bytes(), archive_index.stored_size_bytes());
+
+        // Without the options, the default time of day is assumed for dated
+        // names and dates from metadata are interpreted as UTC
+        let utc = FileIndex::from_listing(&listing_path).unwrap();
+        let date = |index: &FileIndex, path: &str| index.entries[Path::new(path)].estimate_creation_date();
+        assert_eq!(date(&listed, IMAGE).time(), options.assumed_time);
+        assert_ne!(date(&utc, IMAGE), date(&listed, IMAGE));
+        assert_eq!(date(&listed, UNDATED) - date(&utc, UNDATED), chrono::Duration::hours(10));
+        assert!(matches!(
+            utc.export_media(temp.path(), ExportLayout::Year, ActionType::Dry),
+            Err(Error::ListingOnly(_))
+        ));
+    }
+
+    #[test]
+    fn media_age_is_measured_at_the_given_time() {
+        let temp = TempDir::new();
+        let archive_index = archive_of(&temp, &[(IMAGE, TEST_MTIME), (NOTES, TEST_MTIME + 200 * 24 * 60 * 60)]);
+        let clock = |date: &str| FixedClock(format!("{}T00:00:00Z", date).parse().unwrap());
+        let max_age = chrono::Duration::days(365);
+        let older = |date| archive_index.media_older_than(max_age, clock(date).now());
+        assert!(older("2024-06-01").is_empty());
+        assert_eq!(older("2025-03-01"), paths(&[IMAGE]));
+        let mut both = older("2026-01-01");
+        both.sort();
+        assert_eq!(both, paths(&[NOTES, IMAGE]));
+    }
+
+    #[test]
+    fn databases_only_index_skips_media() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, "Backups/wa.db.crypt14", b"backup", TEST_MTIME);
+        write_file(&wa, "Databases/Nested/extra.db", b"nested", TEST_MTIME);
+        let options = IndexOptions { databases_only: true, ..IndexOptions::default() };
+        let wa_index = FileIndex::new_with_options(IndexType::Original, &wa, ActionType::Real, &options).unwrap();
+        let mut indexed = wa_index.get_all_paths();
+        indexed.sort();
+        assert_eq!(
+            indexed,
+            paths(&["Backups/wa.db.crypt14", "Databases/Nested/extra.db", "Databases/msgstore.db.crypt14"])
+        );
+
+        // Archives are always indexed in full
+        let archive_path = temp.path().join("Archive");
+        let mut archive_index = FileIndex::new(IndexType::Archive, &archive_path, ActionType::Real).unwrap();
+        archive_index.mirror_all(&FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap()).unwrap();
+        let archive_index =
+            FileIndex::new_with_options(IndexType::Archive, &archive_path, ActionType::Real, &options).unwrap();
+        assert!(archive_index.get_all_paths().contains(&PathBuf::from(IMAGE)));
+    }
+
+    #[test]
+    fn modified_within_includes_recent_and_future_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let now = FileTime::now().unix_seconds();
+        write_file(&wa, "Media/recent.jpg", b"recent", now - 10);
+        write_file(&wa, "Media/future.jpg", b"future", now + 60 * 60);
+        write_file(&wa, "Media/hours.jpg", b"hours", now - 2 * 60 * 60);
+        let wa_index = FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap();
+        assert_eq!(wa_index.modified_within(Duration::from_hours(1)), paths(&["Media/future.jpg", "Media/recent.jpg"]));
+        assert_eq!(wa_index.modified_within(Duration::MAX).len(), 4);
+    }
+
+    #[test]
+    fn passed_deadline_times_out_without_partial_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, &[b'a'; 4096], TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let passed = Instant::now().checked_sub(Duration::from_secs(1));
+        archive_index.set_deadline(passed);
+        assert!(matches!(archive_index.mirror_all(&wa_index), Err(Error::TimedOut)));
+        let archive_index = FileIndex::new(IndexType::Archive, temp.path().join("Archive"), ActionType::Real).unwrap();
+        assert!(archive_index.get_all_paths().is_empty());
+        assert!(archive_index.temp_files.is_empty());
+
+        // A copy whose deadline passes once started removes its temporary file
+        let source = StoredFile { path: &wa.join(IMAGE), encoding: Encoding::PLAIN, key: None };
+        let dest_path = temp.path().join("copy.jpg");
+        let dest = StoredFile { path: &dest_path, encoding: Encoding::PLAIN, key: None };
+        let options = CopyOptions { chunk_size: 1024, deadline: passed, expected_hash: None };
+        let result = FileIndex::safer_copy(&source, &dest, 4096, None, &options, &mut || 1, &mut NoProgress);
+        assert!(matches!(result, Err(Error::TimedOut)));
+        let names: Vec<_> = temp.path().read_dir().unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(!dest_path.exists());
+    }
+
+    #[test]
+    fn older_archive_is_upgraded_when_storing_encoded_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, archive_index) = indices(temp.path());
+        drop(archive_index);
+        let tag_path = temp.path().join("Archive").join(DEFAULT_TAG_NAME);
+        std::fs::write(&tag_path, r#"{"version":1}"#).unwrap();
+
+        // Storing files verbatim keeps the archive readable by older versions
+        let open_archive =
+            || FileIndex::new(IndexType::Archive, temp.path().join("Archive"), ActionType::Real).unwrap();
+        open_archive().mirror_all(&wa_index).unwrap();
+        assert_eq!(ArchiveTag::read(&tag_path).unwrap().version, 1);
+
+        write_file(&wa, NOTES, &[b'a'; 4096], TEST_MTIME);
+        let wa_index = FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap();
+        let mut archive_index = open_archive();
+        archive_index.set_compression(true);
+        archive_index.mirror_all(&wa_index).unwrap();
+        assert!(archive_index.entries[Path::new(NOTES)].get_encoding().compressed);
+        assert_eq!(ArchiveTag::read(&tag_path).unwrap().version, ARCHIVE_VERSION);
+    }
+
+    #[test]
+    fn corrupt_archived_file_fails_restore_verification() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        archive_index.write_manifest(1).unwrap();
+        std::fs::remove_file(wa.join(IMAGE)).unwrap();
+        std::fs::remove_file(wa.join(NOTES)).unwrap();
+
+        // Corruption which leaves the size and modification time unchanged
+        // is only detectable using the manifest
+        let archived = temp.path().join("Archive").join(IMAGE);
+        write_file(&temp.path().join("Archive"), IMAGE, b"IMAGE CONTENTS", TEST_MTIME);
+        assert_eq!(mtime_of(&archived), TEST_MTIME);
+
+        let (mut wa_index, archive_index) = indices(temp.path());
+        wa_index.set_source_manifest(Some(archive_index.read_manifest().unwrap()));
+        assert!(matches!(wa_index.mirror_specified(&archive_index, [IMAGE]), Err(Error::FileMismatch(_, _))));
+        assert!(!wa.join(IMAGE).exists());
+        assert!(!wa_index.entries.contains_key(Path::new(IMAGE)));
+        assert!(FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap().temp_files.is_empty());
+
+        wa_index.mirror_specified(&archive_index, [NOTES]).unwrap();
+        assert_eq!(std::fs::read(wa.join(NOTES)).unwrap(), b"notes contents");
+    }
+
+    /// Records the names of temporary files in the archive while they are
+    /// being copied into
+    #[derive(Debug)]
+    struct TempNames {
+        root: PathBuf,
+        names: Arc<Mutex<BTreeSet<PathBuf>>>,
+    }
+
+    impl Progress for TempNames {
+        fn bytes_copied(&mut self, _count: u64) {
+            let mut folders = vec![self.root.clone()];
+            while let Some(folder) = folders.pop() {
+                for entry in folder.read_dir().unwrap() {
+                    let path = entry.unwrap().path();
+                    if path.is_dir() {
+                        folders.push(path);
+                    } else if path.to_string_lossy().ends_with(TEMP_SUFFIX) {
+                        self.names.lock().unwrap().insert(path.strip_prefix(&self.root).unwrap().to_path_buf());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_runs_use_identical_temp_names() {
+        let temp_names = |seed| {
+            let temp = TempDir::new();
+            let wa = whatsapp_folder(temp.path());
+            for day in 1..=5 {
+                write_file(&wa, &format!("Media/WhatsApp Images/IMG-2024010{}-WA0001.jpg", day), b"image", TEST_MTIME);
+            }
+            let (wa_index, mut archive_index) = indices(temp.path());
+            let names = Arc::new(Mutex::new(BTreeSet::new()));
+            archive_index.set_progress(Box::new(TempNames { root: temp.path().join("Archive"), names: names.clone() }));
+            archive_index.set_seed(seed);
+            archive_index.mirror_all(&wa_index).unwrap();
+            let names = names.lock().unwrap().clone();
+            names
+        };
+        let names = temp_names(Some(42));
+        assert_eq!(names.len(), 6);
+        assert_eq!(temp_names(Some(42)), names);
+        assert_ne!(temp_names(Some(43)), names);
+    }
+
+    #[test]
+    fn mirror_plan_is_in_path_order() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let names: Vec<String> =
+            (0..20).map(|i| format!("Media/WhatsApp Images/IMG-20240101-WA{:04}.jpg", i)).collect();
+        for name in &names {
+            write_file(&wa, name, b"image", TEST_MTIME);
+        }
+        let (wa_index, archive_index) = indices(temp.path());
+        let plan = archive_index.plan_mirror(&wa_index, wa_index.get_all_paths()).unwrap();
+        let planned: Vec<&Path> = plan.missing().map(|(path, _)| path).collect();
+        let mut sorted = planned.clone();
+        sorted.sort_unstable();
+        assert_eq!(planned.len(), names.len() + 1);
+        assert_eq!(planned, sorted);
+    }
+
+    /// Cancels a token once a file has been copied
+    #[derive(Debug)]
+    struct CancelAfterFile(CancelToken);
+
+    impl Progress for CancelAfterFile {
+        fn file_finished(&mut self, _path: &Path) { self.0.cancel(); }
+    }
+
+    #[test]
+    fn cancelled_mirror_copies_nothing() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let token = CancelToken::new();
+        token.cancel();
+        archive_index.set_cancel_token(Some(token));
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.files_copied(), 0);
+        let (_, archive_index) = indices(temp.path());
+        assert!(archive_index.get_all_paths().is_empty());
+    }
+
+    #[test]
+    fn mirror_cancelled_during_copy_stops_after_that_file() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let token = CancelToken::new();
+        archive_index.set_cancel_token(Some(token.clone()));
+        archive_index.set_progress(Box::new(CancelAfterFile(token)));
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert!(report.cancelled);
+        assert_eq!(report.files_copied(), 1);
+        let (_, archive_index) = indices(temp.path());
+        assert_eq!(archive_index.get_all_paths().len(), 1);
+        assert!(archive_index.temp_files.is_empty());
+    }
+
+    #[test]
+    fn overlapping_folders_are_rejected() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let overlapping = |wa: &Path, archive: &Path| {
+            matches!(FileIndex::check_not_overlapping(wa, archive), Err(Error::OverlappingPaths(_, _)))
+        };
+        assert!(overlapping(&wa, &wa));
+        assert!(overlapping(&wa, &temp.path().join("WhatsApp/./")));
+        assert!(overlapping(&wa, &wa.join("Archive")));
+        assert!(overlapping(&wa.join("Media"), temp.path()));
+        FileIndex::check_not_overlapping(&wa, temp.path().join("Archive")).unwrap();
+        FileIndex::check_not_overlapping(&wa, temp.path().join("WhatsApp Archive")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overlapping_folders_are_found_through_symlinks() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let link = temp.path().join("Link");
+        std::os::unix::fs::symlink(&wa, &link).unwrap();
+        for archive in [link.clone(), link.join("Archive"), link.join("New/Archive")] {
+            assert!(
+                matches!(FileIndex::check_not_overlapping(&wa, &archive), Err(Error::OverlappingPaths(_, _))),
+                "{}",
+                archive.display()
+            );
+        }
+        FileIndex::check_not_overlapping(&link, temp.path().join("Archive")).unwrap();
+    }
+
+    /// Returns the paths of every file under `root`, relative to it
+    fn files_under(root: &Path) -> BTreeSet<PathBuf> {
+        let mut files = BTreeSet::new();
+        let mut folders = vec![root.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            for entry in folder.read_dir().unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    folders.push(path);
+                } else {
+                    files.insert(path.strip_prefix(root).unwrap().to_path_buf());
+                }
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn planned_cleanup_matches_files_removed() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        for day in 1..=6 {
+            let mtime = TEST_MTIME + i64::from(day) * 86_400;
+            write_file(&wa, &format!("Databases/msgstore-2024-01-{:02}.1.db.crypt14", day), b"database", mtime);
+            write_file(&wa, &format!("Databases/msgstore-increment-2024-01-{:02}.1.db.crypt14", day), b"inc", mtime);
+        }
+        for (extension, age) in [("crypt1", 2), ("crypt12", 1), ("crypt14", 0)] {
+            for prefix in ["chatsettingsbackup", "wallpapers", "stickers"] {
+                write_file(&wa, &format!("Backups/{}.db.{}", prefix, extension), b"backup", TEST_MTIME - age);
+            }
+        }
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        let archive = temp.path().join("Archive");
+
+        let planned_dbs = archive_index.plan_db_cleanup(2);
+        assert!(!planned_dbs.is_empty());
+        let before = files_under(&archive);
+        archive_index.clean_old_dbs(2).unwrap();
+        let removed: Vec<PathBuf> = before.difference(&files_under(&archive)).cloned().collect();
+        assert_eq!(removed, planned_dbs);
+
+        let planned_backups = archive_index.plan_backup_cleanup();
+        assert!(!planned_backups.is_empty());
+        let before = files_under(&archive);
+        archive_index.clean_old_backups().unwrap();
+        let removed: Vec<PathBuf> = before.difference(&files_under(&archive)).cloned().collect();
+        assert_eq!(removed, planned_backups);
+        assert!(archive_index.plan_db_cleanup(2).is_empty());
+        assert!(archive_index.plan_backup_cleanup().is_empty());
+    }
+
+    #[test]
+    fn temp_name_collision_never_clobbers_existing_file() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        // The names a copy seeded with 7 tries first and second
+        let mut rng = StdRng::seed_from_u64(7);
+        let (first, second) = (rng.gen::<u32>(), rng.gen::<u32>());
+        let taken = format!("{}.{:x}{}", IMAGE, first, TEMP_SUFFIX);
+        let archive = temp.path().join("Archive");
+        write_file(&archive, &taken, b"unrelated", TEST_MTIME);
+
+        let mut archive_index = FileIndex::new(IndexType::Archive, &archive, ActionType::Real).unwrap();
+        archive_index.set_seed(Some(7));
+        let names = Arc::new(Mutex::new(BTreeSet::new()));
+        archive_index.set_progress(Box::new(TempNames { root: archive.clone(), names: names.clone() }));
+        archive_index.mirror_all(&wa_index).unwrap();
+        let used = PathBuf::from(format!("{}.{:x}{}", IMAGE, second, TEMP_SUFFIX));
+        assert!(names.lock().unwrap().contains(&used));
+        assert_eq!(std::fs::read(archive.join(&taken)).unwrap(), b"unrelated");
+        assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), b"image contents");
+
+        // Giving up once every attempt collides
+        let dest_path = archive.join(IMAGE);
+        let dest = StoredFile { path: &dest_path, encoding: Encoding::PLAIN, key: None };
+        let result = FileIndex::create_temp_file(&dest, 0, || first);
+        assert!(
+            matches!(result, Err((e, path)) if e.kind() == io::ErrorKind::AlreadyExists && path == archive.join(&taken))
+        );
+    }
+
+    /// Records the messages sent to an observer
+    #[derive(Debug)]
+    struct RecordMessages(Arc<Mutex<Vec<String>>>);
+
+    impl Progress for RecordMessages {
+        fn message(&mut self, message: &str) { self.0.lock().unwrap().push(message.to_string()); }
+    }
+
+    #[test]
+    fn truncated_temp_file_is_resumed() {
+        let contents: Vec<u8> = (0..=u8::MAX).cycle().take(10_000).collect();
+        for (partial, resumed) in [(&contents[..4000], true), (&b"something else"[..], false)] {
+            let temp = TempDir::new();
+            let wa = whatsapp_folder(temp.path());
+            write_file(&wa, IMAGE, &contents, TEST_MTIME);
+            let (wa_index, _) = indices(temp.path());
+            let archive = temp.path().join("Archive");
+            let partial_name = format!("{}.1f{}", IMAGE, TEMP_SUFFIX);
+            write_file(&archive, &partial_name, partial, TEST_MTIME);
+
+            let mut archive_index = FileIndex::new(IndexType::Archive, &archive, ActionType::Real).unwrap();
+            archive_index.set_resume_copies(true);
+            let messages = Arc::new(Mutex::new(Vec::new()));
+            archive_index.set_progress(Box::new(RecordMessages(messages.clone())));
+            archive_index.mirror_all(&wa_index).unwrap();
+            assert_eq!(std::fs::read(archive.join(IMAGE)).unwrap(), contents);
+            assert!(!archive.join(&partial_name).exists());
+            let resume_message = "Resuming interrupted copy from 4000 bytes".to_string();
+            assert_eq!(messages.lock().unwrap().contains(&resume_message), resumed);
+        }
+    }
+
+    #[test]
+    fn dry_run_changes_are_listed_as_a_diff() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        write_file(&wa, NOTES, b"new notes", TEST_MTIME + 60);
+        write_file(&wa, "Media/new.jpg", b"new", TEST_MTIME);
+
+        let wa_index = FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap();
+        let archive = temp.path().join("Archive");
+        let mut archive_index = FileIndex::new(IndexType::Archive, &archive, ActionType::Dry).unwrap();
+        archive_index.mirror_all(&wa_index).unwrap();
+        archive_index.remove_files([IMAGE]).unwrap();
+        let expected =
+            format!("+ Media/new.jpg ({})\n~ {}\n- {} ({})\n", bytefmt::format(3), NOTES, IMAGE, bytefmt::format(14));
+        assert_eq!(archive_index.changes().to_string(), expected);
+        // Nothing was changed on disk
+        assert_eq!(std::fs::read(archive.join(NOTES)).unwrap(), b"notes");
+        assert!(archive.join(IMAGE).exists());
+    }
+
+    #[test]
+    fn history_is_appended_after_each_real_run() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let record = |index: &FileIndex, timestamp| HistoryRecord {
+            timestamp,
+            archive_bytes: index.stats().total_bytes(),
+            bytes_added: 0,
+            bytes_pruned: 0,
+            files_deleted: 0,
+        };
+        let mut records = Vec::new();
+        for timestamp in [TEST_MTIME, TEST_MTIME + 60] {
+            let (wa_index, mut archive_index) = indices(temp.path());
+            archive_index.mirror_all(&wa_index).unwrap();
+            records.push(record(&archive_index, timestamp));
+            archive_index.append_history(records.last().unwrap()).unwrap();
+        }
+        // Dry runs change nothing, including the history
+        let archive = temp.path().join("Archive");
+        let dry_index = FileIndex::new(IndexType::Archive, &archive, ActionType::Dry).unwrap();
+        dry_index.append_history(&record(&dry_index, TEST_MTIME + 120)).unwrap();
+
+        let lines = std::fs::read_to_string(archive.join(HISTORY_NAME)).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        assert_eq!(dry_index.read_history().unwrap(), records);
+        // The history is never indexed as a file in the archive
+        let mut indexed = dry_index.get_all_paths();
+        indexed.sort();
+        assert_eq!(indexed, paths(&["Databases/msgstore.db.crypt14", IMAGE]));
+    }
+
+    #[test]
+    fn planned_folders_are_created_once_parents_first() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        for name in ["Media/A/B/c.jpg", "Media/A/B/d.jpg", "Media/A/e.jpg", "Media/F/g.jpg"] {
+            write_file(&wa, name, name.as_bytes(), TEST_MTIME);
+        }
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let plan = archive_index.plan_mirror(&wa_index, wa_index.get_all_paths()).unwrap();
+        let folders: Vec<&Path> = plan.new_folders().collect();
+        assert_eq!(folders, paths(&["Databases", "Media", "Media/A", "Media/A/B", "Media/F"]));
+
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(RecordMessages(messages.clone())));
+        let report = archive_index.execute_plan(&wa_index, &plan).unwrap();
+        assert_eq!(report.folders_created, folders.len());
+        let created: Vec<PathBuf> = messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|m| m.strip_prefix("Creating folder: ").map(PathBuf::from))
+            .collect();
+        assert_eq!(created, folders);
+        for folder in folders {
+            assert!(temp.path().join("Archive").join(folder).is_dir());
+        }
+    }
+
+    #[test]
+    fn files_created_outside_date_window_are_left_untouched() {
+        let temp = TempDir::new();
+        let archive_index = archive_of(
+            &temp,
+            &[
+                ("Media/WhatsApp Images/IMG-20240101-WA0001.jpg", TEST_MTIME),
+                ("Media/WhatsApp Images/IMG-20240105-WA0001.jpg", TEST_MTIME),
+                ("Media/WhatsApp Images/IMG-20240110-WA0001.jpg", TEST_MTIME),
+            ],
+        );
+        let mut query = FileQuery::default();
+        let date = |day| NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        query.set_scope(FilePredicate::CreatedBetween(date(2), date(5)));
+        query.set_limit(DataLimit::from_bytes(0));
+        let (to_delete, to_retain) = archive_index.get_delete_retain_candidates(&query);
+        assert_eq!(to_delete, paths(&["Media/WhatsApp Images/IMG-20240105-WA0001.jpg"]));
+        assert!(to_retain.is_empty());
+
+        // Files outside the window still count towards the limit
+        let size = archive_index.media_size_bytes();
+        query.set_limit(DataLimit::from_bytes(size - 1));
+        let (to_delete, _) = archive_index.get_delete_retain_candidates(&query);
+        assert_eq!(to_delete, paths(&["Media/WhatsApp Images/IMG-20240105-WA0001.jpg"]));
+        query.set_limit(DataLimit::from_bytes(size));
+        let (to_delete, to_retain) = archive_index.get_delete_retain_candidates(&query);
+        assert!(to_delete.is_empty());
+        assert_eq!(to_retain, paths(&["Media/WhatsApp Images/IMG-20240105-WA0001.jpg"]));
+    }
+
+    #[test]
+    fn pinned_files_are_kept_but_count_towards_limit() {
+        let temp = TempDir::new();
+        let (archive_index, images) = archive_with_images(&temp);
+        let mut query = FileQuery::default();
+        query.pin([&images[0], &images[2]]);
+        query.set_limit(DataLimit::from_bytes(0));
+        let (to_delete, mut to_retain) = archive_index.get_delete_retain_candidates(&query);
+        to_retain.sort();
+        assert_eq!(to_delete.len(), 2);
+        assert!(to_delete.iter().all(|p| !to_retain.contains(p)));
+        assert_eq!(to_retain, paths(&[&images[0], &images[2]]));
+
+        // The two pinned files alone fill the limit, so both others are deleted
+        let size = b"image contents".len() as u64;
+        query.set_limit(DataLimit::from_bytes(2 * size));
+        assert_eq!(archive_index.delete_candidate_stats(&query), (2, 2 * size));
+        query.set_limit(DataLimit::from_bytes(3 * size));
+        assert_eq!(archive_index.delete_candidate_stats(&query), (1, size));
+    }
+
+    /// Records the totals announced when a mirror starts, followed by the
+    /// bytes and files actually copied
+    #[derive(Debug, Default)]
+    struct CopyTotals(Arc<Mutex<[u64; 4]>>);
+
+    impl Progress for CopyTotals {
+        fn mirror_started(&mut self, total_bytes: u64, total_files: usize) {
+            let mut totals = self.0.lock().unwrap();
+            totals[0] = total_bytes;
+            totals[1] = total_files as u64;
+        }
+
+        fn file_started(&mut self, _path: &Path, size: u64) {
+            let mut totals = self.0.lock().unwrap();
+            totals[2] += size;
+            totals[3] += 1;
+        }
+    }
+
+    #[test]
+    fn mirror_announces_total_work_up_front() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        // Only the new files are counted, not the one already archived
+        write_file(&wa, "Media/WhatsApp Images/IMG-20240101-WA0002.jpg", b"new image", TEST_MTIME);
+        write_file(&wa, NOTES, b"some notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let totals = Arc::new(Mutex::new([0; 4]));
+        archive_index.set_progress(Box::new(CopyTotals(totals.clone())));
+        archive_index.mirror_all(&wa_index).unwrap();
+        let totals = *totals.lock().unwrap();
+        assert_eq!(totals, [19, 2, 19, 2]);
+    }
+
+    #[test]
+    fn failed_mirror_removes_newly_added_files_when_rolling_back() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        let archived = files_under(archive_index.path());
+
+        // The second file vanishes after indexing, so copying it fails
+        let first = "Media/WhatsApp Documents/a.txt";
+        let second = "Media/WhatsApp Documents/b.txt";
+        write_file(&wa, first, b"first", TEST_MTIME);
+        write_file(&wa, second, b"second", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        std::fs::remove_file(wa.join(second)).unwrap();
+        archive_index.set_rollback(true);
+        assert!(archive_index.mirror_all(&wa_index).is_err());
+        assert!(!archive_index.entries.contains_key(Path::new(first)));
+        assert!(archive_index.entries.contains_key(Path::new(IMAGE)));
+        assert_eq!(files_under(archive_index.path()), archived);
+    }
+
+    #[test]
+    fn space_check_compares_plan_with_free_space() {
+        assert!(FileIndex::ensure_space(100, Some(100)).is_ok());
+        assert!(FileIndex::ensure_space(100, None).is_ok());
+        assert!(matches!(
+            FileIndex::ensure_space(101, Some(100)),
+            Err(Error::InsufficientSpace { needed: 101, available: 100 })
+        ));
+
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, archive_index) = indices(temp.path());
+        let plan = archive_index.plan_mirror(&wa_index, wa_index.get_all_paths()).unwrap();
+        assert!(archive_index.check_space_for(&plan).is_ok());
+    }
+
+    #[test]
+    fn content_verification_reimports_file_changed_in_place() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        // Same size and modification time, so only the contents reveal the change
+        write_file(&wa, IMAGE, b"edited content", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        assert_eq!(archive_index.mirror_all(&wa_index).unwrap().files_copied(), 0);
+        assert_eq!(std::fs::read(archive_index.path().join(IMAGE)).unwrap(), b"image contents");
+
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.set_content_verification(true);
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!(report.files_updated, 1);
+        assert_eq!(std::fs::read(archive_index.path().join(IMAGE)).unwrap(), b"edited content");
+        assert_eq!(mtime_of(&archive_index.path().join(IMAGE)), TEST_MTIME);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_filenames_are_indexed_and_copied() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let name = OsString::from_vec(b"IMG-20240101-WA0001-\xff.jpg".to_vec());
+        let rel_path = Path::new("Media/WhatsApp Images").join(&name);
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        std::fs::write(wa.join(&rel_path), b"odd name").unwrap();
+        set_mtime(&wa.join(&rel_path), TEST_MTIME);
+
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let info = &wa_index.entries[&rel_path];
+        assert!(FileIndex::is_media_file(&rel_path, info));
+        assert_eq!(info.estimate_creation_date().date(), NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!(std::fs::read(archive_index.path().join(&rel_path)).unwrap(), b"odd name");
+        let (_, archive_index) = indices(temp.path());
+        assert!(archive_index.entries.contains_key(&rel_path));
+    }
+
+    #[test]
+    fn stats_break_down_media_by_category() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, &[0; 10], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Images/IMG-20240301-WA0001.jpg", &[0; 20], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Video/VID-20240201-WA0001.mp4", &[0; 30], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Images/Sent/.nomedia", b"", TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        let stats = wa_index.stats();
+
+        let date = |month| NaiveDate::from_ymd_opt(2024, month, 1).unwrap();
+        assert_eq!((stats.total_count(), stats.total_bytes()), (5, 68));
+        assert_eq!((stats.media.count, stats.media.bytes), (3, 60));
+        assert_eq!((stats.non_media.count, stats.non_media.bytes), (2, 8));
+        assert_eq!(stats.categories.keys().copied().collect::<Vec<_>>(), [MediaCategory::Images, MediaCategory::Video]);
+        let images = stats.categories[&MediaCategory::Images];
+        assert_eq!((images.count, images.bytes), (2, 30));
+        assert_eq!(images.oldest.map(|d| d.date()), Some(date(1)));
+        assert_eq!(images.newest.map(|d| d.date()), Some(date(3)));
+        assert_eq!(stats.media.newest.map(|d| d.date()), Some(date(3)));
+        assert_eq!(stats.categories[&MediaCategory::Video].bytes, 30);
+        assert_eq!(wa_index.stats_for([IMAGE, "missing.jpg"]).total_bytes(), 10);
+    }
+
+    #[test]
+    fn reserve_reduces_retained_files() {
+        let temp = TempDir::new();
+        let (archive_index, _) = archive_with_images(&temp);
+        let size = archive_index.media_size_bytes();
+        let mut query = FileQuery::default();
+        query.set_limit(DataLimit::from_bytes(size));
+        assert_eq!(archive_index.get_delete_retain_candidates(&query).1.len(), 4);
+        query.set_limit(DataLimit::from_bytes(size).reserve(1));
+        assert_eq!(archive_index.get_delete_retain_candidates(&query).1.len(), 3);
+        query.set_limit(DataLimit::from_bytes(size).reserve(size + 1));
+        assert!(archive_index.get_delete_retain_candidates(&query).1.is_empty());
+    }
+
+    /// Scores PNG files above all others, and otherwise prefers larger files
+    #[derive(Debug)]
+    struct PreferPng;
+
+    impl ScoreFn for PreferPng {
+        #[allow(clippy::cast_precision_loss)]
+        fn score(&self, path: &Path, info: &FileInfo) -> f64 {
+            let bonus = if path.extension().is_some_and(|e| e == "png") { 1e6 } else { 0.0 };
+            bonus + info.get_size() as f64
+        }
+    }
+
+    #[test]
+    fn custom_score_fn_orders_deletion() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let small_png = "Media/WhatsApp Images/IMG-20240101-WA0001.png";
+        let large_png = "Media/WhatsApp Images/IMG-20240101-WA0002.png";
+        let small_jpg = "Media/WhatsApp Images/IMG-20240101-WA0003.jpg";
+        let large_jpg = "Media/WhatsApp Images/IMG-20240101-WA0004.jpg";
+        for (name, size) in [(small_png, 10), (large_png, 20), (small_jpg, 30), (large_jpg, 40)] {
+            write_file(&wa, name, &vec![0; size], TEST_MTIME);
+        }
+        let (wa_index, _) = indices(temp.path());
+        let mut query = FileQuery::default();
+        query.set_score_fn(Box::new(PreferPng));
+        query.set_limit(DataLimit::from_bytes(0));
+        let (to_delete, _) = wa_index.get_delete_retain_candidates(&query);
+        assert_eq!(to_delete, paths(&[small_jpg, large_jpg, small_png, large_png]));
+        query.set_limit(DataLimit::from_bytes(30));
+        let (_, mut to_retain) = wa_index.get_delete_retain_candidates(&query);
+        to_retain.sort();
+        assert_eq!(to_retain, paths(&[small_png, large_png]));
+    }
+
+    /// Keeps the files in one folder
+    #[derive(Debug)]
+    struct KeepFolder(&'static str);
+
+    impl KeepFn for KeepFolder {
+        fn keep(&self, path: &Path, _info: &FileInfo) -> bool { path.starts_with(self.0) }
+    }
+
+    #[test]
+    fn custom_keep_fn_ranks_matching_files_last_for_deletion() {
+        let temp = TempDir::new();
+        let family = "Media/WhatsApp Images/Family/IMG-20240110-WA0001.jpg";
+        let older = "Media/WhatsApp Images/IMG-20240101-WA0001.jpg";
+        let newer = "Media/WhatsApp Images/IMG-20240105-WA0001.jpg";
+        let archive_index = archive_of(&temp, &[(family, TEST_MTIME), (older, TEST_MTIME), (newer, TEST_MTIME)]);
+        let mut query = FileQuery::default();
+        query.set_limit(DataLimit::from_bytes(0));
+        assert_eq!(archive_index.get_delete_retain_candidates(&query).0, paths(&[family, newer, older]));
+        query.set_keep_fn(Box::new(KeepFolder("Media/WhatsApp Images/Family")));
+        assert_eq!(archive_index.get_delete_retain_candidates(&query).0, paths(&[newer, older, family]));
+    }
+
+    #[test]
+    fn old_nested_backups_are_removed() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, "Backups/Nested/wa.db.crypt14", b"old", TEST_MTIME);
+        write_file(&wa, "Backups/Nested/wa.db.crypt15", b"new", TEST_MTIME + 60);
+        write_file(&wa, "Backups/Nested/.hidden", b"hidden", TEST_MTIME);
+        write_file(&wa, "Backups/Nested/Deeper/wa.db.crypt14", b"old", TEST_MTIME);
+        write_file(&wa, "Backups/Nested/Deeper/wa.db.crypt15", b"new", TEST_MTIME + 60);
+        write_file(&wa, "Backups/wa.db.crypt14", b"only", TEST_MTIME);
+        let (mut wa_index, _) = indices(temp.path());
+        assert_eq!(
+            wa_index.plan_backup_cleanup(),
+            paths(&["Backups/Nested/Deeper/wa.db.crypt14", "Backups/Nested/wa.db.crypt14"])
+        );
+        wa_index.clean_old_backups().unwrap();
+        assert!(!wa.join("Backups/Nested/wa.db.crypt14").exists());
+        assert!(!wa.join("Backups/Nested/Deeper/wa.db.crypt14").exists());
+        for kept in ["Nested/wa.db.crypt15", "Nested/.hidden", "Nested/Deeper/wa.db.crypt15", "wa.db.crypt14"] {
+            assert!(wa.join("Backups").join(kept).exists());
+        }
+    }
+
+    #[test]
+    fn relative_path_is_used_as_given_without_canonicalization() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let options = IndexOptions { canonicalize: false, ..Default::default() };
+        let index = in_current_dir(temp.path(), || {
+            FileIndex::new_with_options(IndexType::Original, "WhatsApp", ActionType::Real, &options).unwrap()
+        });
+        assert_eq!(index.path(), Path::new("WhatsApp"));
+        assert_eq!(index.stats().media.count, 1);
+        assert!(in_current_dir(temp.path(), || {
+            FileIndex::new_with_options(IndexType::Archive, "Missing", ActionType::Dry, &options).is_ok()
+        }));
+        assert!(FileIndex::new_with_options(
+            IndexType::Original,
+            temp.path().join("Missing"),
+            ActionType::Real,
+            &options
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn dry_run_paths_without_parents_are_resolved() {
+        let temp = TempDir::new();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("Archive")).unwrap();
+        let dry_run = |path: &str| FileIndex::new(IndexType::Archive, path, ActionType::Dry).unwrap();
+        let index = in_current_dir(&root.join("Archive"), || dry_run("."));
+        assert_eq!(index.path(), root.join("Archive"));
+        let index = in_current_dir(&root, || dry_run("NewArchive"));
+        assert_eq!(index.path(), root.join("NewArchive"));
+        assert!(!root.join("NewArchive").exists());
+    }
+
+    #[test]
+    fn validation_detects_whatsapp_folders_without_side_effects() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        assert!(FileIndex::validate(IndexType::Original, &wa).is_ok());
+
+        let no_db = temp.path().join("NoDatabase");
+        write_file(&no_db, IMAGE, b"image contents", TEST_MTIME);
+        assert!(matches!(FileIndex::validate(IndexType::Original, &no_db), Err(Error::NotWhatsAppFolder(_))));
+
+        // An archive contains a database but is tagged
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        let archive = archive_index.path();
+        assert!(matches!(FileIndex::validate(IndexType::Original, archive), Err(Error::NotWhatsAppFolder(_))));
+        assert!(FileIndex::validate(IndexType::Archive, archive).is_ok());
+        assert!(matches!(FileIndex::validate(IndexType::Archive, &wa), Err(Error::NewArchiveFolderNotEmpty(_))));
+
+        let missing = temp.path().join("Missing");
+        assert!(FileIndex::validate(IndexType::Archive, &missing).is_ok());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn custom_tag_name_marks_archive() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let archive = temp.path().join("Custom");
+        let options = IndexOptions { tag_name: ".custom".to_string(), ..Default::default() };
+        let open = |options: &IndexOptions| {
+            FileIndex::new_with_options(IndexType::Archive, &archive, ActionType::Real, options)
+        };
+        let wa_index = FileIndex::new(IndexType::Original, &wa, ActionType::Real).unwrap();
+        open(&options).unwrap().mirror_all(&wa_index).unwrap();
+        assert!(archive.join(".custom").exists());
+        assert!(!archive.join(".waa").exists());
+
+        let archive_index = open(&options).unwrap();
+        assert_eq!(archive_index.get_all_paths(), vec![PathBuf::from("Databases/msgstore.db.crypt14")]);
+        assert!(matches!(open(&IndexOptions::default()), Err(Error::NewArchiveFolderNotEmpty(_))));
+        assert!(FileIndex::validate_with_options(IndexType::Original, &wa, &options).is_ok());
+        assert!(FileIndex::validate_with_options(IndexType::Original, &archive, &options).is_err());
+    }
+
+    #[test]
+    fn bytes_transferred_sum_copied_and_updated_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!(report.bytes_transferred, 8 + 14 + 5);
+
+        // Unchanged files transfer nothing
+        write_file(&wa, IMAGE, b"edited image", TEST_MTIME + 60);
+        write_file(&wa, "Media/WhatsApp Images/IMG-20240101-WA0002.jpg", b"new", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!((report.files_added, report.files_updated), (1, 1));
+        assert_eq!(report.bytes_transferred, 12 + 3);
+    }
+
+    #[test]
+    fn interrupted_verified_mirror_resumes_without_reverifying() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        let other = "Media/WhatsApp Documents/other.txt";
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        write_file(&wa, other, b"other", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let token = CancelToken::new();
+        archive_index.set_content_verification(true);
+        archive_index.set_cancel_token(Some(token.clone()));
+        archive_index.set_progress(Box::new(CancelAfterFile(token)));
+        assert!(archive_index.mirror_all(&wa_index).unwrap().cancelled);
+
+        // The archived image was confirmed before the interruption, so damage
+        // which leaves its metadata intact goes unnoticed when resuming
+        let archived_image = write_file(&temp.path().join("Archive"), IMAGE, b"damaged image!", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        assert_eq!(archive_index.resume_state(), Some((ResumePhase::MetadataUpdated, 3)));
+        archive_index.set_content_verification(true);
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!(report.files_copied(), 1);
+        assert!(temp.path().join("Archive").join(other).exists());
+        assert_eq!(std::fs::read(&archived_image).unwrap(), b"damaged image!");
+
+        let (wa_index, mut archive_index) = indices(temp.path());
+        assert_eq!(archive_index.resume_state(), None);
+        archive_index.set_content_verification(true);
+        assert_eq!(archive_index.mirror_all(&wa_index).unwrap().files_updated, 1);
+        assert_eq!(std::fs::read(&archived_image).unwrap(), b"image contents");
+    }
+
+    #[test]
+    fn only_newer_dated_files_are_new() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let older = "Media/WhatsApp Images/IMG-20240104-WA0001.jpg";
+        let same_day = "Media/WhatsApp Images/IMG-20240105-WA0001.jpg";
+        let newer = "Media/WhatsApp Video/VID-20240110-WA0001.mp4";
+        for name in [older, same_day, newer, NOTES] {
+            write_file(&wa, name, b"contents", TEST_MTIME);
+        }
+        let (wa_index, _) = indices(temp.path());
+        let mut new_files = wa_index.new_files_since(NaiveDate::from_ymd_opt(2024, 1, 5).unwrap());
+        new_files.sort();
+        // Files without a date in their names are always included
+        assert_eq!(new_files, paths(&["Databases/msgstore.db.crypt14", NOTES, same_day, newer]));
+    }
+
+    #[test]
+    fn stale_temp_files_are_removed_on_open() {
+        let temp = TempDir::new();
+        whatsapp_folder(temp.path());
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        let archive = archive_index.path().to_path_buf();
+        let stale = write_file(&archive, "Media/WhatsApp Images/.IMG.jpg.1.waa.tmp", b"stale", TEST_MTIME);
+        let in_use = archive.join("Media/WhatsApp Images/.IMG.jpg.2.waa.tmp");
+        std::fs::write(&in_use, b"in use").unwrap();
+
+        // Temporary files are only removed when requested
+        let (_, archive_index) = indices(temp.path());
+        assert_eq!(archive_index.cleaned_temp_files(), (0, 0));
+        assert!(stale.exists());
+
+        let options = IndexOptions { clean_temp_older_than: Some(Duration::from_hours(1)), ..Default::default() };
+        let dry_run = FileIndex::new_with_options(IndexType::Archive, &archive, ActionType::Dry, &options).unwrap();
+        assert_eq!(dry_run.cleaned_temp_files(), (0, 0));
+        assert!(stale.exists());
+        let archive_index =
+            FileIndex::new_with_options(IndexType::Archive, &archive, ActionType::Real, &options).unwrap();
+        assert_eq!(archive_index.cleaned_temp_files(), (5, 1));
+        assert!(!stale.exists());
+        assert!(in_use.exists());
+    }
+
+    #[test]
+    fn batch_import_reports_each_file() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let video = "Media/WhatsApp Video/VID-20240101-WA0001.mp4";
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, video, b"video contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let results = archive_index.import_files(&wa_index, &paths(&[video, "Media/missing.jpg", IMAGE]));
+
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::FileMissing(_))));
+        assert!(results[2].is_ok());
+        let mut imported = archive_index.get_all_paths();
+        imported.sort();
+        assert_eq!(imported, paths(&[IMAGE, video]));
+        assert_eq!(archive_index.entries[Path::new(IMAGE)], wa_index.entries[Path::new(IMAGE)]);
+        assert_eq!(std::fs::read(archive_index.path().join(video)).unwrap(), b"video contents");
+        assert!(!archive_index.path().join(NOTES).exists());
+    }
+
+    /// Records the files reported as too large to copy
+    #[derive(Debug, Default)]
+    struct TooLarge(Arc<Mutex<Vec<(PathBuf, u64)>>>);
+
+    impl Progress for TooLarge {
+        fn file_too_large(&mut self, path: &Path, size: u64) {
+            self.0.lock().unwrap().push((path.to_path_buf(), size));
+        }
+    }
+
+    #[test]
+    fn files_larger_than_maximum_are_skipped_and_reported() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let video = "Media/WhatsApp Video/VID-20240101-WA0001.mp4";
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, video, &[0; 100], TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let too_large = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(TooLarge(too_large.clone())));
+        archive_index.set_max_file_size(Some(99));
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+
+        assert_eq!(report.files_too_large, 1);
+        assert_eq!(report.files_copied(), 2);
+        assert_eq!(*too_large.lock().unwrap(), vec![(PathBuf::from(video), 100)]);
+        assert!(archive_index.path().join(IMAGE).exists());
+        assert!(!archive_index.path().join(video).exists());
+
+        // A file exactly at the maximum is copied
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.set_max_file_size(Some(100));
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+        assert_eq!((report.files_too_large, report.files_copied()), (0, 1));
+    }
+
+    #[test]
+    fn projected_media_bytes_sum_to_media_size() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        for day in 1..=5_u8 {
+            write_file(
+                &wa,
+                &format!("Media/WhatsApp Images/IMG-202401{:02}-WA0001.jpg", day),
+                &vec![0; 10 * usize::from(day)],
+                TEST_MTIME,
+            );
+        }
+        let (wa_index, _) = indices(temp.path());
+        let total = wa_index.media_size_bytes();
+        assert_eq!(total, 150);
+        let mut query = FileQuery::default();
+        for (limit, expected) in
+            [(None, (150, 0)), (Some(150), (150, 0)), (Some(100), (100, 50)), (Some(99), (60, 90)), (Some(0), (0, 150))]
+        {
+            query.set_limit(limit.map_or(DataLimit::Infinite, DataLimit::from_bytes));
+            let (retained, deleted) = wa_index.projected_media_bytes(&query);
+            assert_eq!(retained + deleted, total);
+            assert_eq!((retained, deleted), expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_only_if_requested_and_loops_are_skipped() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let target = write_file(temp.path(), "Elsewhere/photo.jpg", b"linked photo", TEST_MTIME);
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let linked = "Media/WhatsApp Images/IMG-20240101-WA0002.jpg";
+        symlink(&target, wa.join(linked)).unwrap();
+        symlink(&wa, wa.join("Media/Loop")).unwrap();
+
+        let (wa_index, _) = indices(temp.path());
+        assert!(!wa_index.entries.contains_key(Path::new(linked)));
+        let reasons: Vec<_> = wa_index.skipped_entries().iter().map(|e| e.reason).collect();
+        assert_eq!(reasons, [SkipReason::Symlink, SkipReason::Symlink]);
+
+        let options = IndexOptions { follow_symlinks: true, ..Default::default() };
+        let wa_index = FileIndex::new_with_options(IndexType::Original, &wa, ActionType::Real, &options).unwrap();
+        assert_eq!(wa_index.entries[Path::new(linked)].get_size(), 12);
+        assert_eq!(wa_index.get_all_paths().len(), 3);
+        let loop_entry =
+            SkippedEntry { path: wa.canonicalize().unwrap().join("Media/Loop"), reason: SkipReason::SymlinkLoop };
+        assert_eq!(wa_index.skipped_entries(), [loop_entry]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fifo_is_listed_as_skipped() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let fifo = wa.canonicalize().unwrap().join("Media/WhatsApp Images/pipe");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status().unwrap();
+        assert!(status.success());
+
+        let (wa_index, _) = indices(temp.path());
+        assert_eq!(wa_index.skipped_entries(), [SkippedEntry { path: fifo, reason: SkipReason::Special }]);
+        assert_eq!(wa_index.get_all_paths().len(), 2);
+    }
+
+    #[test]
+    fn databases_and_backups_are_never_deletion_candidates() {
+        let temp = TempDir::new();
+        let archive_index = archive_of(
+            &temp,
+            &[
+                (IMAGE, TEST_MTIME),
+                ("Databases/msgstore-2024-01-01.1.db.crypt14", TEST_MTIME),
+                ("Backups/wa.db.crypt14", TEST_MTIME),
+                ("Media/WhatsApp Images/Sent/.nomedia", TEST_MTIME),
+            ],
+        );
+        let mut query = FileQuery::default();
+        query.set_limit(DataLimit::from_bytes(0));
+        let (to_delete, to_retain) = archive_index.get_delete_retain_candidates(&query);
+        assert_eq!(to_delete, paths(&[IMAGE]));
+        assert_eq!(to_retain, paths(&["Media/WhatsApp Images/Sent/.nomedia"]));
+
+        let mut protected = archive_index.protected_paths();
+        protected.sort();
+        let expected = [
+            ".waa",
+            "Backups/wa.db.crypt14",
+            "Databases/msgstore-2024-01-01.1.db.crypt14",
+            "Databases/msgstore.db.crypt14",
+            "Media/WhatsApp Images/Sent/.nomedia",
+        ];
+        assert_eq!(protected, paths(&expected));
+        assert_eq!(archive_index.media_size_bytes(), IMAGE.len() as u64);
+    }
+
+    #[test]
+    fn sizes_are_accounted_by_bucket_without_temp_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, &[0; 100], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Images/Sent/.nomedia", b"x", TEST_MTIME);
+        write_file(&wa, "Databases/wa.db", &[0; 20], TEST_MTIME);
+        write_file(&wa, "Backups/wa.db.crypt14", &[0; 30], TEST_MTIME);
+        write_file(&wa, "Other/readme.txt", &[0; 4], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Images/.IMG.jpg.1.waa.tmp", &[0; 1000], TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+
+        assert_eq!(wa_index.media_size_bytes(), 100);
+        assert_eq!(wa_index.database_size_bytes(), 8 + 20);
+        assert_eq!(wa_index.backup_size_bytes(), 30);
+        assert_eq!(wa_index.other_size_bytes(), 1 + 4);
+        assert_eq!(wa_index.non_media_size_bytes(), 28 + 30 + 5);
+        assert_eq!(wa_index.size_bytes(), 163);
+    }
+
+    #[test]
+    fn age_based_decisions_follow_the_query_clock() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let old = "Media/WhatsApp Images/IMG-20240101-WA0001.jpg";
+        let new = "Media/WhatsApp Images/IMG-20240301-WA0001.jpg";
+        write_file(&wa, old, &[0; 10], TEST_MTIME);
+        write_file(&wa, new, &[0; 40], TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        let mut query = FileQuery::default();
+        query.set_order(FileScore::Smaller);
+        query.set_priority(FilePredicate::AgeLessThan(chrono::Duration::days(30)));
+        query.set_limit(DataLimit::from_bytes(40));
+        let mut deleted_at = |date: &str| {
+            query.set_clock(Box::new(FixedClock(format!("{}T00:00:00Z", date).parse().unwrap())));
+            wa_index.get_delete_retain_candidates(&query).0
+        };
+        // Only the newer file is recent, so the older one goes despite being smaller
+        assert_eq!(deleted_at("2024-03-10"), paths(&[old]));
+        // Both or neither are recent, so the larger goes
+        assert_eq!(deleted_at("2024-01-10"), paths(&[new]));
+        assert_eq!(deleted_at("2024-06-01"), paths(&[new]));
+    }
+
+    #[test]
+    fn latest_media_of_each_chat_is_kept() {
+        let temp = TempDir::new();
+        let family_old = "Media/WhatsApp Images/Family/IMG-20240101-WA0001.jpg";
+        let family_new = "Media/WhatsApp Images/Family/IMG-20240201-WA0001.jpg";
+        let work = "Media/WhatsApp Documents/Work/DOC-20240101-WA0001.pdf";
+        let sent_old = "Media/WhatsApp Images/Sent/IMG-20240101-WA0002.jpg";
+        let sent_new = "Media/WhatsApp Images/Sent/IMG-20240301-WA0002.jpg";
+        let archive_index = archive_of(
+            &temp,
+            &[
+                (family_old, TEST_MTIME),
+                (family_new, TEST_MTIME),
+                (work, TEST_MTIME),
+                (sent_old, TEST_MTIME),
+                (sent_new, TEST_MTIME),
+            ],
+        );
+        let mut query = FileQuery::default();
+        query.set_limit(DataLimit::from_bytes(0));
+        query.set_keep_latest_per_chat(true);
+        let (mut to_delete, mut to_retain) = archive_index.get_delete_retain_candidates(&query);
+        to_delete.sort();
+        to_retain.sort();
+        assert_eq!(to_delete, paths(&[family_old, sent_old]));
+        assert_eq!(to_retain, paths(&[work, family_new, sent_new]));
+    }
+
+    #[test]
+    fn dry_run_reports_folders_it_would_create() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        write_file(&wa, "Media/WhatsApp Images/Sent/IMG-20240101-WA0002.jpg", b"sent", TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Video/Private/VID-20240101-WA0001.mp4", b"video", TEST_MTIME);
+        let (wa_index, _) = indices(temp.path());
+        let archive = temp.path().join("Archive");
+        let mut archive_index = FileIndex::new(IndexType::Archive, &archive, ActionType::Dry).unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(RecordMessages(messages.clone())));
+        let report = archive_index.mirror_all(&wa_index).unwrap();
+
+        let expected = ["Media/WhatsApp Images/Sent", "Media/WhatsApp Video", "Media/WhatsApp Video/Private"];
+        assert_eq!(report.folders_created, expected.len());
+        let messages = messages.lock().unwrap();
+        let planned: Vec<&str> = messages.iter().filter_map(|m| m.strip_prefix("Creating folder: ")).collect();
+        assert_eq!(planned, expected);
+        assert!(expected.iter().all(|folder| !archive.join(folder).exists()));
+    }
+
+    #[test]
+    fn delete_candidate_stats_match_candidates() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        for day in 1..=6_u8 {
+            let name = format!("Media/WhatsApp Images/IMG-202401{:02}-WA0001.jpg", day);
+            write_file(&wa, &name, &vec![0; 7 * usize::from(day)], TEST_MTIME);
+        }
+        let (wa_index, _) = indices(temp.path());
+        let mut query = FileQuery::default();
+        query.set_order(FileScore::Smaller);
+        for limit in [None, Some(147), Some(100), Some(42), Some(0)] {
+            query.set_limit(limit.map_or(DataLimit::Infinite, DataLimit::from_bytes));
+            let candidates = wa_index.get_delete_candidates(&query);
+            let size = candidates.iter().map(|p| wa_index.entries[p].get_size()).sum();
+            assert_eq!(wa_index.delete_candidate_stats(&query), (candidates.len(), size));
+        }
+        query.set_limit(DataLimit::from_bytes(100));
+        assert_eq!(wa_index.delete_candidate_stats(&query), (2, 42 + 35));
+    }
+
+    #[test]
+    fn newest_database_of_each_type_is_kept_if_requested() {
+        let temp = TempDir::new();
+        let mut archive_index = archive_of(
+            &temp,
+            &[
+                ("Databases/msgstore.db.crypt15", TEST_MTIME + 60),
+                ("Databases/msgstore-2024-01-01.1.db.crypt14", TEST_MTIME - 86400),
+            ],
+        );
+        assert_eq!(archive_index.plan_db_cleanup(1), paths(&["Databases/msgstore.db.crypt14"]));
+
+        archive_index.set_keep_db_of_each_type(true);
+        assert!(archive_index.plan_db_cleanup(1).is_empty());
+        archive_index.clean_old_dbs(1).unwrap();
+        for name in ["msgstore.db.crypt14", "msgstore.db.crypt15", "msgstore-2024-01-01.1.db.crypt14"] {
+            assert!(archive_index.path().join("Databases").join(name).exists());
+        }
+    }
+
+    #[test]
+    fn manifest_update_only_hashes_changed_files() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, NOTES, b"notes", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        let first = archive_index.write_manifest(1).unwrap();
+        assert_eq!(first.len(), 3);
+
+        write_file(&wa, NOTES, b"more notes", TEST_MTIME + 60);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        archive_index.mirror_all(&wa_index).unwrap();
+        // Damage which leaves the metadata intact shows the image is trusted
+        // rather than hashed again
+        write_file(archive_index.path(), IMAGE, b"image damaged!", TEST_MTIME);
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(RecordMessages(messages.clone())));
+        let second = archive_index.write_manifest(1).unwrap();
+
+        assert!(messages.lock().unwrap().contains(&"Hashing 1 new or changed files for manifest".to_string()));
+        assert_eq!(second.get(Path::new(IMAGE)), first.get(Path::new(IMAGE)));
+        assert_ne!(second.get(Path::new(NOTES)), first.get(Path::new(NOTES)));
+        assert_eq!(second.get(Path::new(NOTES)), archive_index.build_manifest(1).unwrap().get(Path::new(NOTES)));
+        assert_eq!(archive_index.read_manifest().unwrap().len(), 3);
+
+        // Entries for files no longer in the index are dropped
+        let (_, mut archive_index) = indices(temp.path());
+        archive_index.remove_files([NOTES]).unwrap();
+        let third = archive_index.write_manifest(1).unwrap();
+        assert_eq!(third.len(), 2);
+        assert!(third.get(Path::new(NOTES)).is_none());
+    }
+
+    #[test]
+    fn media_is_exported_by_year_and_month_with_numbered_collisions() {
+        let temp = TempDir::new();
+        let march = "Media/WhatsApp Images/IMG-20240315-WA0001.jpg";
+        let march_sent = "Media/WhatsApp Images/Sent/IMG-20240315-WA0001.jpg";
+        let february = "Media/WhatsApp Video/VID-20240201-WA0001.mp4";
+        let archive_index = archive_of(&temp, &[(march, TEST_MTIME), (march_sent, TEST_MTIME), (february, TEST_MTIME)]);
+        let export = temp.path().join("Export");
+        // A different file already in the export takes the first free name
+        write_file(&export, "2024/03/IMG-20240315-WA0001.jpg", b"unrelated", TEST_MTIME);
+
+        let exported = archive_index.export_media(&export, ExportLayout::YearMonth, ActionType::Real).unwrap();
+        let expected = [
+            (march, "2024/03/IMG-20240315-WA0001 (1).jpg"),
+            (march_sent, "2024/03/IMG-20240315-WA0001 (2).jpg"),
+            (february, "2024/02/VID-20240201-WA0001.mp4"),
+        ];
+        let expected: Vec<_> = expected.iter().map(|(from, to)| (PathBuf::from(from), export.join(to))).collect();
+        assert_eq!(exported, expected);
+        for (from, to) in &expected {
+            assert_eq!(std::fs::read(to).unwrap(), from.as_os_str().as_encoded_bytes());
+            assert_eq!(mtime_of(to), TEST_MTIME);
+        }
+        assert_eq!(std::fs::read(export.join("2024/03/IMG-20240315-WA0001.jpg")).unwrap(), b"unrelated");
+        assert!(!export.join("Databases").exists());
+
+        // Exporting again finds every file already exported
+        assert!(archive_index.export_media(&export, ExportLayout::YearMonth, ActionType::Real).unwrap().is_empty());
+    }
+
+    fn now_seconds() -> i64 {
+        let elapsed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        i64::try_from(elapsed.as_secs()).unwrap()
+    }
+
+    #[test]
+    fn databases_are_waited_for_until_quiet() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", now_seconds());
+        write_file(&wa, "Backups/wa.db.crypt14", b"backup", TEST_MTIME);
+        let (mut wa_index, _) = indices(temp.path());
+        assert!(wa_index.busy_databases(Duration::from_secs(10)).unwrap().is_empty());
+
+        // The database is rewritten after indexing and settles two seconds later
+        write_file(&wa, "Databases/msgstore.db.crypt14", b"newer database", now_seconds() - 1);
+        let database = paths(&["Databases/msgstore.db.crypt14"]);
+        assert_eq!(wa_index.busy_databases(Duration::from_secs(3)).unwrap(), database);
+        let started = Instant::now();
+        let busy = wa_index.wait_for_quiescent_databases(Duration::from_secs(3), Duration::from_secs(30)).unwrap();
+        assert!(busy.is_empty());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(wa_index.entries[&database[0]].get_size(), 14);
+
+        // Waiting is abandoned after the maximum wait
+        let busy = wa_index.wait_for_quiescent_databases(Duration::from_hours(1), Duration::ZERO).unwrap();
+        assert_eq!(busy, database);
+    }
+
+    #[test]
+    fn paths_are_filtered_by_folder_prefix() {
+        let list = paths(&[
+            "Media/WhatsApp Video/VID-20240101-WA0001.mp4",
+            "Media/WhatsApp Video Notes/VID-20240101-WA0002.mp4",
+            "Media/WhatsApp Images/Family/IMG-20240101-WA0001.jpg",
+            "Media/WhatsApp Images/IMG-20240101-WA0002.jpg",
+        ]);
+        let filtered =
+            FileIndex::filter_by_prefix(&list, &paths(&["Media/WhatsApp Video", "Media/WhatsApp Images/Family"]));
+        assert_eq!(filtered, [list[0].clone(), list[2].clone()]);
+        assert!(FileIndex::filter_by_prefix(&list, &[]).is_empty());
+    }
+    #[test]
+    fn increments_interleaved_with_full_backups_are_kept_by_time() {
+        const HOUR: i64 = 3600;
+        const DAY: i64 = 86_400;
+        let temp = TempDir::new();
+        let archive_index = archive_of(
+            &temp,
+            &[
+                ("Databases/msgstore-2024-01-01.1.db.crypt14", TEST_MTIME + DAY),
+                ("Databases/msgstore-increment-1-2024-01-01.1.db.crypt14", TEST_MTIME + DAY + HOUR),
+                ("Databases/msgstore-increment-1-2024-01-02.1.db.crypt14", TEST_MTIME + 2 * DAY - HOUR),
+                ("Databases/msgstore-2024-01-02.1.db.crypt14", TEST_MTIME + 2 * DAY),
+                ("Databases/msgstore-increment-2-2024-01-02.1.db.crypt14", TEST_MTIME + 2 * DAY + HOUR),
+                ("Databases/msgstore-2024-01-03.1.db.crypt14", TEST_MTIME + 3 * DAY),
+                ("Databases/msgstore-increment-1-2024-01-03.1.db.crypt14", TEST_MTIME + 3 * DAY + HOUR),
+                ("Databases/msgstore.db.crypt14", TEST_MTIME + 4 * DAY),
+                ("Databases/msgstore-increment-1.db.crypt14", TEST_MTIME + 4 * DAY + HOUR),
+            ],
+        );
+        // Keeping two dates leaves the full backup of the second day as the
+        // oldest kept, so only increments made before it are removed, even
+        // one dated the same day
+        assert_eq!(
+            archive_index.plan_db_cleanup(2),
+            paths(&[
+                "Databases/msgstore-2024-01-01.1.db.crypt14",
+                "Databases/msgstore-increment-1-2024-01-01.1.db.crypt14",
+                "Databases/msgstore-increment-1-2024-01-02.1.db.crypt14",
+            ])
+        );
+        // Keeping every date keeps every increment made after the oldest full
+        // backup
+        assert!(archive_index.plan_db_cleanup(3).is_empty());
+        // Keeping no dates leaves only the current backups
+        assert_eq!(archive_index.plan_db_cleanup(0).len(), 7);
+    }
+    #[test]
+    fn backup_versions_are_kept_per_kind() {
+        let temp = TempDir::new();
+        let mut files = Vec::new();
+        for (extension, age) in [("crypt1", 2), ("crypt12", 1), ("crypt14", 0)] {
+            for prefix in ["chatsettingsbackup", "stickers"] {
+                files.push((format!("Backups/{}.db.{}", prefix, extension), TEST_MTIME - age));
+            }
+        }
+        let files: Vec<(&str, i64)> = files.iter().map(|(name, mtime)| (name.as_str(), *mtime)).collect();
+        let mut archive_index = archive_of(&temp, &files);
+        archive_index.set_backup_retention(BackupRetention { settings: 2, other: 1, ..BackupRetention::default() });
+        assert_eq!(
+            archive_index.plan_backup_cleanup(),
+            paths(&[
+                "Backups/chatsettingsbackup.db.crypt1",
+                "Backups/stickers.db.crypt1",
+                "Backups/stickers.db.crypt12"
+            ])
+        );
+        archive_index.set_backup_retention(BackupRetention { settings: 3, other: 2, ..BackupRetention::default() });
+        assert_eq!(archive_index.plan_backup_cleanup(), paths(&["Backups/stickers.db.crypt1"]));
+    }
+    #[test]
+    fn delete_candidates_iter_matches_delete_candidates() {
+        let temp = TempDir::new();
+        let archive_index = archive_of(
+            &temp,
+            &[
+                ("Media/WhatsApp Images/IMG-20240101-WA0001.jpg", TEST_MTIME),
+                ("Media/WhatsApp Images/Family/IMG-20240102-WA0001.jpg", TEST_MTIME + 86_400),
+                ("Media/WhatsApp Video/VID-20240103-WA0001.mp4", TEST_MTIME + 2 * 86_400),
+                ("Media/WhatsApp Voice Notes/202401/PTT-20240104-WA0001.opus", TEST_MTIME + 3 * 86_400),
+                ("Media/WhatsApp Documents/notes.txt", TEST_MTIME + 4 * 86_400),
+            ],
+        );
+        for order in [FileScore::Smaller, FileScore::Newer, FileScore::SmallerNewer] {
+            for limit in [0, 60, 120, u64::MAX] {
+                let mut query = FileQuery::default();
+                query.set_order(order.clone());
+                query.set_limit(DataLimit::from_bytes(limit));
+                query.pin(["Media/WhatsApp Video/VID-20240103-WA0001.mp4"]);
+                query.set_clock(Box::new(FixedClock(DateTime::from_timestamp(TEST_MTIME + 10 * 86_400, 0).unwrap())));
+                let streamed: Vec<PathBuf> =
+                    archive_index.delete_candidates_iter(&query).map(Path::to_path_buf).collect();
+                assert_eq!(streamed, archive_index.get_delete_retain_candidates(&query).0, "{:?} {}", order, limit);
+            }
+        }
+    }
+    #[cfg(unix)]
+    #[test]
+    fn folder_reached_through_symlink_gives_clean_relative_paths() {
+        use std::os::unix::fs::symlink;
+
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(temp.path(), "Elsewhere/VID-20240101-WA0001.mp4", b"video", TEST_MTIME);
+        symlink(temp.path().join("Elsewhere"), wa.join("Media/WhatsApp Video")).unwrap();
+        let link = temp.path().join("Link");
+        symlink(&wa, &link).unwrap();
+
+        let expected = paths(&["Databases/msgstore.db.crypt14", IMAGE, "Media/WhatsApp Video/VID-20240101-WA0001.mp4"]);
+        for (root, canonicalize) in [(link.clone(), true), (link.clone(), false), (link.join("Media/.."), false)] {
+            let options = IndexOptions { canonicalize, follow_symlinks: true, ..Default::default() };
+            let wa_index = FileIndex::new_with_options(IndexType::Original, &root, ActionType::Real, &options).unwrap();
+            let mut indexed = wa_index.get_all_paths();
+            indexed.sort();
+            assert_eq!(indexed, expected, "{:?}", root);
+        }
+    }
+    #[test]
+    fn newest_media_date_ignores_databases() {
+        let temp = TempDir::new();
+        let archive_index = archive_of(&temp, &[("Databases/msgstore-2024-02-01.1.db.crypt14", TEST_MTIME)]);
+        assert_eq!(archive_index.newest_media_date(), None);
+
+        let temp = TempDir::new();
+        let archive_index = archive_of(
+            &temp,
+            &[
+                ("Databases/msgstore-2024-02-01.1.db.crypt14", TEST_MTIME),
+                ("Media/WhatsApp Images/IMG-20240101-WA0001.jpg", TEST_MTIME),
+                ("Media/WhatsApp Images/IMG-20240105-WA0001.jpg", TEST_MTIME),
+                (NOTES, TEST_MTIME),
+            ],
+        );
+        let newest = archive_index.newest_media_date().unwrap();
+        assert_eq!(newest.date(), NaiveDate::from_ymd_opt(2024, 1, 5).unwrap());
+    }
+    /// Records the size of each chunk copied
+    #[derive(Debug, Default)]
+    struct ChunkSizes(Arc<Mutex<Vec<u64>>>);
+
+    impl Progress for ChunkSizes {
+        fn bytes_copied(&mut self, count: u64) { self.0.lock().unwrap().push(count); }
+    }
+
+    #[test]
+    fn files_are_copied_in_chunks_of_configured_size() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (wa_index, mut archive_index) = indices(temp.path());
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        archive_index.set_progress(Box::new(ChunkSizes(chunks.clone())));
+        archive_index.set_copy_chunk_size(4);
+        archive_index.mirror_all(&wa_index).unwrap();
+
+        let mut chunks = chunks.lock().unwrap().clone();
+        chunks.sort_unstable();
+        // The 8 byte database and the 14 byte image
+        assert_eq!(chunks, [2, 4, 4, 4, 4, 4]);
+        assert_eq!(std::fs::read(archive_index.path().join(IMAGE)).unwrap(), b"image contents");
+    }
+    #[test]
+    fn files_changed_after_indexing_are_not_deleted() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let names: Vec<_> = (1..=4).map(|i| format!("Media/WhatsApp Images/IMG-20240101-WA000{}.jpg", i)).collect();
+        for name in &names {
+            write_file(&wa, name, b"image contents", TEST_MTIME);
+        }
+        let (mut wa_index, _) = indices(temp.path());
+        // Rewritten with a new size, touched, and removed after indexing
+        write_file(&wa, &names[1], b"new image contents", TEST_MTIME);
+        write_file(&wa, &names[2], b"image contents", TEST_MTIME + 60);
+        std::fs::remove_file(wa.join(&names[3])).unwrap();
+
+        let changed = wa_index.remove_unchanged_files(&names).unwrap();
+        assert_eq!(changed, paths(&[&names[1], &names[2], &names[3]]));
+        assert!(!wa.join(&names[0]).exists());
+        assert!(!wa_index.entries.contains_key(Path::new(&names[0])));
+        assert_eq!(std::fs::read(wa.join(&names[1])).unwrap(), b"new image contents");
+        assert!(wa.join(&names[2]).exists());
+        assert!(wa_index.entries.contains_key(Path::new(&names[2])));
+    }
+    #[test]
+    fn refreshed_entries_match_full_rebuild() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let names: Vec<_> = (1..=3).map(|i| format!("Media/WhatsApp Images/IMG-20240101-WA000{}.jpg", i)).collect();
+        write_file(&wa, &names[0], b"image contents", TEST_MTIME);
+        write_file(&wa, &names[1], b"image contents", TEST_MTIME);
+        let (mut wa_index, _) = indices(temp.path());
+
+        // One file rewritten, one removed and one added, along with a path
+        // that never existed
+        write_file(&wa, &names[0], b"new image contents", TEST_MTIME + 60);
+        std::fs::remove_file(wa.join(&names[1])).unwrap();
+        write_file(&wa, &names[2], b"image contents", TEST_MTIME);
+        let mut refreshed = paths(&[&names[0], &names[1], &names[2]]);
+        refreshed.push(PathBuf::from("Media/WhatsApp Images/missing.jpg"));
+        wa_index.refresh_paths(&refreshed).unwrap();
+
+        let (rebuilt, _) = indices(temp.path());
+        assert_eq!(wa_index.entries, rebuilt.entries);
+        for (path, info) in &rebuilt.entries {
+            assert_eq!(wa_index.entries[path].get_encoding(), info.get_encoding());
+        }
+    }
+    #[test]
+    fn nested_empty_folders_are_removed_but_not_the_root() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        std::fs::create_dir_all(wa.join("Media/WhatsApp Video/Sent/Old")).unwrap();
+        std::fs::create_dir_all(wa.join("Media/WhatsApp Images/Private")).unwrap();
+        let (mut wa_index, _) = indices(temp.path());
+
+        let removed = wa_index.remove_empty_dirs().unwrap();
+        let mut sorted = removed.clone();
+        sorted.sort();
+        assert_eq!(
+            sorted,
+            paths(&[
+                "Media/WhatsApp Images/Private",
+                "Media/WhatsApp Video",
+                "Media/WhatsApp Video/Sent",
+                "Media/WhatsApp Video/Sent/Old",
+            ])
+        );
+        // Deeper folders are removed before their parents
+        let position = |p: &str| removed.iter().position(|r| r == Path::new(p)).unwrap();
+        assert!(position("Media/WhatsApp Video/Sent/Old") < position("Media/WhatsApp Video/Sent"));
+        assert!(position("Media/WhatsApp Video/Sent") < position("Media/WhatsApp Video"));
+        assert!(!wa.join("Media/WhatsApp Video").exists());
+        assert!(wa.join(IMAGE).exists());
+
+        // Once every file is gone, everything but the root is removed
+        wa_index.remove_files(paths(&["Databases/msgstore.db.crypt14", IMAGE])).unwrap();
+        let mut removed = wa_index.remove_empty_dirs().unwrap();
+        removed.sort();
+        assert_eq!(removed, paths(&["Databases", "Media", "Media/WhatsApp Images"]));
+        assert!(wa.is_dir());
+        assert_eq!(wa.read_dir().unwrap().count(), 0);
+    }
 }
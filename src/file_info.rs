@@ -5,32 +5,122 @@ use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use filetime::FileTime;
 use regex::Regex;
 
-use crate::Error;
+use crate::{Encoding, Error, Timezone};
+
+/// The time of day assumed for files whose names record only the date they
+/// were created. Midday halves the worst-case error compared with midnight.
+pub const DEFAULT_ASSUMED_TIME: NaiveTime = match NaiveTime::from_hms_opt(12, 0, 0) {
+    Some(time) => time,
+    None => panic!("Invalid time"),
+};
+
+/// Where the estimated creation date of a file came from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum DateSource {
+    /// The filesystem modification time
+    Metadata,
+
+    /// A filename recording only the date, with an assumed time of day
+    NameDate,
+
+    /// A filename recording both the date and time
+    NameDateTime,
+}
 
 /// Represents file metadata
-#[derive(Clone, Debug, Eq, PartialEq)]
+///
+/// Equality ignores the access time since it changes whenever a file is read
+/// and is not preserved when copying. It also ignores how the file is stored,
+/// so a compressed copy of a file is considered equal to the original.
+#[derive(Clone, Debug)]
 pub struct FileInfo {
     modification_time: FileTime,
+    access_time: FileTime,
     estimated_creation_date: NaiveDateTime,
+    date_source: DateSource,
+    timezone: Timezone,
     size: u64,
+    stored_size: u64,
+    encoding: Encoding,
+}
+
+impl PartialEq for FileInfo {
+    fn eq(&self, other: &FileInfo) -> bool {
+        self.modification_time == other.modification_time
+            && self.estimated_creation_date == other.estimated_creation_date
+            && self.size == other.size
+    }
 }
 
+impl Eq for FileInfo {}
+
 impl FileInfo {
     /// Constructs a new `FileInfo` representing the metadata of the specified
     /// file
     pub fn new(path: &Path) -> Result<FileInfo, Error> {
-        let filename = path.file_name().expect("Unable to get filename from path");
         let metadata = path.metadata().map_err(|e| (e, path))?;
         let modification_time = FileTime::from_last_modification_time(&metadata);
-        let estimated_creation_date = Self::creation_date_from_name(filename.as_ref()).unwrap_or_else(|| {
-            DateTime::<Utc>::from_timestamp(modification_time.unix_seconds(), modification_time.nanoseconds())
-                .expect("Timestamp conversion falure")
-                .naive_utc()
-        });
-        let result = FileInfo { modification_time, estimated_creation_date, size: metadata.len() };
+        let access_time = FileTime::from_last_access_time(&metadata);
+        let timezone = Timezone::default();
+        let (estimated_creation_date, date_source) = path
+            .file_name()
+            .and_then(|filename| Self::creation_date_from_name(filename.as_ref()))
+            .unwrap_or_else(|| (Self::local_time(modification_time, timezone), DateSource::Metadata));
+        let size = metadata.len();
+        let result = FileInfo {
+            modification_time,
+            access_time,
+            estimated_creation_date,
+            date_source,
+            timezone,
+            size,
+            stored_size: size,
+            encoding: Encoding::PLAIN,
+        };
         Ok(result)
     }
 
+    /// Constructs a `FileInfo` from explicit metadata without reference to
+    /// any file, e.g. for testing scoring functions. The access time is taken
+    /// to be the modification time. The estimated creation date is treated as
+    /// exact, so it is unaffected by `with_timezone` and `with_assumed_time`.
+    pub fn from_parts(size: u64, modification_time: FileTime, estimated_creation_date: NaiveDateTime) -> FileInfo {
+        FileInfo {
+            modification_time,
+            access_time: modification_time,
+            estimated_creation_date,
+            date_source: DateSource::NameDateTime,
+            timezone: Timezone::default(),
+            size,
+            stored_size: size,
+            encoding: Encoding::PLAIN,
+        }
+    }
+
+    /// Constructs a `FileInfo` for the file at `path` from previously recorded
+    /// metadata, as if the file were present. The access time is taken to be
+    /// the modification time. As for `new`, dates are interpreted as UTC until
+    /// `with_timezone` and `with_assumed_time` are applied.
+    pub(crate) fn from_listing(
+        path: &Path, size: u64, stored_size: u64, modification_time: FileTime, encoding: Encoding,
+    ) -> FileInfo {
+        let timezone = Timezone::default();
+        let (estimated_creation_date, date_source) = path
+            .file_name()
+            .and_then(|filename| Self::creation_date_from_name(filename.as_ref()))
+            .unwrap_or_else(|| (Self::local_time(modification_time, timezone), DateSource::Metadata));
+        FileInfo {
+            modification_time,
+            access_time: modification_time,
+            estimated_creation_date,
+            date_source,
+            timezone,
+            size,
+            stored_size,
+            encoding,
+        }
+    }
+
     /// Alters the modification time of the file at `path` to the one stored in
     /// the `FileInfo`.
     pub fn set_modification_time(&self, path: &Path) -> Result<(), Error> {
@@ -42,23 +132,255 @@ impl FileInfo {
     /// Gets the modification time.
     pub fn get_modification_time(&self) -> FileTime { self.modification_time }
 
+    /// Gets the access time.
+    pub fn get_access_time(&self) -> FileTime { self.access_time }
+
+    /// Estimates when the file was last used. This is the access time, unless
+    /// it is older than the modification time, which happens on filesystems
+    /// mounted with `noatime` or platforms which do not track access times.
+    pub fn estimate_last_used_time(&self) -> FileTime { std::cmp::max(self.access_time, self.modification_time) }
+
     /// Attempts to estimate the creation date of a file based on WhatsApp's
-    /// media file naming convention
-    fn creation_date_from_name(filename: &Path) -> Option<NaiveDateTime> {
-        let day_regex = Regex::new(r"^.*-(\d{8})-WA[0-9]{4}\..+$").unwrap();
-        let filename = filename.to_string_lossy();
-        day_regex.captures(&filename).and_then(|c| c.get(1)).and_then(|capture| {
-            let date_time = NaiveDate::parse_from_str(capture.as_str(), "%Y%m%d")
-                .map(|date| NaiveDateTime::new(date, NaiveTime::MIN));
-            date_time.ok()
+    /// media file naming conventions, assuming the default time of day if the
+    /// name only records the date
+    fn creation_date_from_name(filename: &Path) -> Option<(NaiveDateTime, DateSource)> {
+        Self::parse_filename(&filename.to_string_lossy()).map(|(date, time)| match time {
+            Some(time) => (date.and_time(time), DateSource::NameDateTime),
+            None => (date.and_time(DEFAULT_ASSUMED_TIME), DateSource::NameDate),
         })
     }
 
+    /// Parses the creation date embedded in a filename following one of
+    /// WhatsApp's media file naming conventions. Returns `None` if the
+    /// filename follows neither convention. The time is only present if the
+    /// filename records it.
+    fn parse_filename(filename: &str) -> Option<(NaiveDate, Option<NaiveTime>)> {
+        // Names used by WhatsApp Web and desktop downloads, e.g. `WhatsApp
+        // Image 2023-01-05 at 10.15.30.jpeg`, with a 12-hour time in some
+        // locales
+        let spaced_regex =
+            Regex::new(r"^WhatsApp .+ (\d{4}-\d{2}-\d{2}) at (\d{1,2}\.\d{2}\.\d{2})( [AP]M)?[ .(]").unwrap();
+        if let Some(captures) = spaced_regex.captures(filename) {
+            let date = NaiveDate::parse_from_str(&captures[1], "%Y-%m-%d").ok()?;
+            let time = match captures.get(3) {
+                Some(suffix) => {
+                    NaiveTime::parse_from_str(&format!("{}{}", &captures[2], suffix.as_str()), "%I.%M.%S %p")
+                }
+                None => NaiveTime::parse_from_str(&captures[2], "%H.%M.%S"),
+            };
+            return Some((date, time.ok()));
+        }
+        let day_regex = Regex::new(r"^.*-(\d{8})-WA[0-9]{4}\..+$").unwrap();
+        let captures = day_regex.captures(filename)?;
+        let date = NaiveDate::parse_from_str(&captures[1], "%Y%m%d").ok()?;
+        Some((date, None))
+    }
+
+    /// Parses the creation date embedded in a filename following WhatsApp's
+    /// media file naming conventions (e.g. `IMG-20230105-WA0001.jpg` or
+    /// `WhatsApp Image 2023-01-05 at 10.15.30.jpeg`). If the filename only
+    /// records the date, `DEFAULT_ASSUMED_TIME` is used as the time of day.
+    /// Returns `None` if the filename does not follow a convention.
+    pub fn creation_date_from_filename(filename: &str) -> Option<NaiveDateTime> {
+        Self::creation_date_from_name(Path::new(filename)).map(|(date, _)| date)
+    }
+
     /// Estimate when this file was created. This will attempt to infer the
     /// creation time from WhatsApp's naming convention, otherwise will use
-    /// the filesystem metadata.
+    /// the filesystem metadata. The result is a local time in the timezone of
+    /// this `FileInfo`.
     pub fn estimate_creation_date(&self) -> NaiveDateTime { self.estimated_creation_date }
 
+    /// Estimates how long ago this file was created, interpreting the
+    /// estimated creation date as a local time in the timezone of this
+    /// `FileInfo`
+    pub fn estimate_age(&self) -> chrono::Duration { self.estimate_age_at(Utc::now()) }
+
+    /// Estimates how old this file was at `now`, as for `estimate_age`
+    pub fn estimate_age_at(&self, now: DateTime<Utc>) -> chrono::Duration {
+        now.signed_duration_since(self.timezone.to_utc(self.estimated_creation_date))
+    }
+
+    /// The timezone in which dates are interpreted. This is UTC unless changed
+    /// with `with_timezone`.
+    pub fn get_timezone(&self) -> Timezone { self.timezone }
+
+    /// Returns a copy of this `FileInfo` interpreting dates in `timezone`.
+    /// Dates from filenames are taken to already be local to the timezone,
+    /// since WhatsApp names files using the phone's local date, while dates
+    /// from the filesystem metadata are converted to it.
+    #[must_use]
+    pub fn with_timezone(&self, timezone: Timezone) -> FileInfo {
+        let mut result = FileInfo { timezone, ..self.clone() };
+        if self.date_source == DateSource::Metadata {
+            result.estimated_creation_date = Self::local_time(self.modification_time, timezone);
+        }
+        result
+    }
+
+    /// Returns a copy of this `FileInfo` assuming `time` as the time of day it
+    /// was created if its filename records only the date. Estimates from
+    /// filenames recording the time or from filesystem metadata are
+    /// unaffected.
+    #[must_use]
+    pub fn with_assumed_time(&self, time: NaiveTime) -> FileInfo {
+        let mut result = self.clone();
+        if self.date_source == DateSource::NameDate {
+            result.estimated_creation_date = self.estimated_creation_date.date().and_time(time);
+        }
+        result
+    }
+
+    /// Converts a filesystem timestamp to a local time in `timezone`
+    fn local_time(time: FileTime, timezone: Timezone) -> NaiveDateTime {
+        timezone.local_time(
+            DateTime::<Utc>::from_timestamp(time.unix_seconds(), time.nanoseconds())
+                .expect("Timestamp conversion falure"),
+        )
+    }
+
+    /// Constructs a `FileInfo` for a file stored with the specified encoding
+    /// at `stored_path`
+    pub(crate) fn new_stored(stored_path: &Path, encoding: Encoding) -> Result<FileInfo, Error> {
+        let mut result = Self::new(stored_path)?;
+        if encoding != Encoding::PLAIN {
+            let (logical_path, _) = Encoding::from_stored_path(stored_path);
+            if let Some((date, source)) =
+                logical_path.file_name().and_then(|f| Self::creation_date_from_name(f.as_ref()))
+            {
+                result.estimated_creation_date = date;
+                result.date_source = source;
+            }
+            let (encoding, size) = encoding.inspect(stored_path).map_err(|e| (e, stored_path))?;
+            result.size = size;
+            result.encoding = encoding;
+        }
+        Ok(result)
+    }
+
+    /// Returns a copy of this `FileInfo` describing the same file stored with
+    /// a different encoding. The stored size is only an estimate.
+    pub(crate) fn with_encoding(&self, encoding: Encoding) -> FileInfo {
+        FileInfo { encoding, stored_size: self.size, ..self.clone() }
+    }
+
     /// The size of the file in bytes
     pub fn get_size(&self) -> u64 { self.size }
+
+    /// The number of bytes the file occupies on disk, which differs from the
+    /// size if the file is stored compressed or encrypted
+    pub fn get_stored_size(&self) -> u64 { self.stored_size }
+
+    /// How the file is stored on disk
+    pub fn get_encoding(&self) -> Encoding { self.encoding }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_time(year: i32, month: u32, day: u32, time: NaiveTime) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap().and_time(time)
+    }
+
+    fn hms(hour: u32, min: u32, sec: u32) -> NaiveTime { NaiveTime::from_hms_opt(hour, min, sec).unwrap() }
+
+    #[test]
+    fn creation_date_from_dated_media_names() {
+        for name in [
+            "IMG-20230105-WA0001.jpg",
+            "VID-20230105-WA0012.mp4",
+            "PTT-20230105-WA0003.opus",
+            "DOC-20230105-WA0004.pdf",
+            "STK-20230105-WA0005.webp",
+        ] {
+            assert_eq!(
+                FileInfo::creation_date_from_filename(name),
+                Some(date_time(2023, 1, 5, DEFAULT_ASSUMED_TIME)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn creation_date_from_timestamped_names() {
+        assert_eq!(
+            FileInfo::creation_date_from_filename("WhatsApp Image 2023-01-05 at 10.15.30.jpeg"),
+            Some(date_time(2023, 1, 5, hms(10, 15, 30)))
+        );
+        assert_eq!(
+            FileInfo::creation_date_from_filename("WhatsApp Video 2023-01-05 at 9.05.07 PM.mp4"),
+            Some(date_time(2023, 1, 5, hms(21, 5, 7)))
+        );
+        assert_eq!(
+            FileInfo::creation_date_from_filename("WhatsApp Image 2023-01-05 at 10.15.30 (1).jpeg"),
+            Some(date_time(2023, 1, 5, hms(10, 15, 30)))
+        );
+    }
+
+    #[test]
+    fn creation_date_from_unconventional_names() {
+        for name in [
+            "",
+            "holiday.jpg",
+            "IMG-20230105.jpg",
+            "IMG-20231305-WA0001.jpg",
+            "IMG-20230105-WA0001",
+            "IMG-2023015-WA0001.jpg",
+            "msgstore-2023-01-05.1.db.crypt14",
+            "WhatsApp Image 2023-02-30 at 10.15.30.jpeg",
+        ] {
+            assert_eq!(FileInfo::creation_date_from_filename(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn local_timezone_converts_each_time_with_its_own_offset() {
+        crate::test_util::use_sydney_time();
+        let at = |time: DateTime<Utc>| FileTime::from_unix_time(time.timestamp(), 0);
+        let summer = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().to_utc();
+        let winter = DateTime::parse_from_rfc3339("2024-07-01T00:00:00Z").unwrap().to_utc();
+        let info = |time| FileInfo::from_listing(Path::new("notes.txt"), 1, 1, at(time), Encoding::PLAIN);
+
+        // Daylight saving time is in effect in January but not July
+        let summer_info = info(summer).with_timezone(Timezone::Local);
+        let winter_info = info(winter).with_timezone(Timezone::Local);
+        assert_eq!(summer_info.estimate_creation_date(), date_time(2024, 1, 1, hms(11, 0, 0)));
+        assert_eq!(winter_info.estimate_creation_date(), date_time(2024, 7, 1, hms(10, 0, 0)));
+        assert_eq!(summer_info.estimate_age_at(summer), chrono::Duration::zero());
+        assert_eq!(winter_info.estimate_age_at(winter), chrono::Duration::zero());
+
+        // Local times skip an hour when daylight saving time starts at 02:00
+        // on 6 October, but ages are measured in elapsed time
+        let before = DateTime::parse_from_rfc3339("2024-10-05T15:30:00Z").unwrap().to_utc();
+        let after = DateTime::parse_from_rfc3339("2024-10-05T16:30:00Z").unwrap().to_utc();
+        let before_info = info(before).with_timezone(Timezone::Local);
+        assert_eq!(before_info.estimate_creation_date(), date_time(2024, 10, 6, hms(1, 30, 0)));
+        assert_eq!(
+            info(after).with_timezone(Timezone::Local).estimate_creation_date(),
+            date_time(2024, 10, 6, hms(3, 30, 0))
+        );
+        assert_eq!(before_info.estimate_age_at(after), chrono::Duration::hours(1));
+    }
+    #[test]
+    fn info_from_parts_keeps_given_metadata() {
+        let mtime = FileTime::from_unix_time(1_704_067_200, 0);
+        let created = date_time(2023, 12, 25, hms(9, 30, 0));
+        let info = FileInfo::from_parts(1234, mtime, created);
+        assert_eq!(info.get_size(), 1234);
+        assert_eq!(info.get_stored_size(), 1234);
+        assert_eq!(info.get_modification_time(), mtime);
+        assert_eq!(info.get_access_time(), mtime);
+        assert_eq!(info.get_encoding(), Encoding::PLAIN);
+        assert_eq!(info.estimate_creation_date(), created);
+
+        // The creation date is exact, so neither adjustment changes it
+        let offset = Timezone::Fixed("+05:30".parse().unwrap());
+        let adjusted = info.with_timezone(offset).with_assumed_time(hms(12, 0, 0));
+        assert_eq!(adjusted.estimate_creation_date(), created);
+        let now = DateTime::parse_from_rfc3339("2023-12-25T09:30:00Z").unwrap().to_utc();
+        assert_eq!(info.estimate_age_at(now), chrono::Duration::zero());
+        assert_eq!(adjusted.estimate_age_at(now), chrono::Duration::minutes(330));
+    }
 }
@@ -0,0 +1,76 @@
+/// Splits a filename into its base name and extension as WhatsApp names its
+/// databases and backups. Unlike `Path::extension`, which is only the part
+/// after the last `.`, the extension is everything after the first `.`, so
+/// `msgstore.db.crypt15` has the base name `msgstore` and the extension
+/// `db.crypt15`. As with `Path::extension`, a leading dot belongs to the base
+/// name, so `.nomedia` has no extension.
+///
+/// Media names can contain dots which do not start an extension, e.g.
+/// `WhatsApp Image 2023-01-05 at 10.15.30.jpeg`, so `last_extension` suits
+/// them better.
+pub fn split_extension(filename: &str) -> (&str, Option<&str>) {
+    let leading_dot = usize::from(filename.starts_with('.'));
+    match filename[leading_dot..].find('.') {
+        Some(dot) => (&filename[..leading_dot + dot], Some(&filename[leading_dot + dot + 1..])),
+        None => (filename, None),
+    }
+}
+
+/// The extension of a filename in WhatsApp's sense, e.g. `db.crypt15` for
+/// `msgstore.db.crypt15`, as for `split_extension`
+pub fn extension(filename: &str) -> Option<&str> { split_extension(filename).1 }
+
+/// The last extension of a filename, e.g. `crypt15` for
+/// `msgstore.db.crypt15`. This is the same as `Path::extension`.
+pub fn last_extension(filename: &str) -> Option<&str> {
+    extension(filename).map(|extension| extension.rsplit('.').next().unwrap_or(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn single_extension() {
+        assert_eq!(split_extension("msgstore.crypt14"), ("msgstore", Some("crypt14")));
+        assert_eq!(last_extension("msgstore.crypt14"), Some("crypt14"));
+    }
+
+    #[test]
+    fn double_extension() {
+        assert_eq!(split_extension("msgstore.db.crypt15"), ("msgstore", Some("db.crypt15")));
+        assert_eq!(extension("msgstore.db.crypt15"), Some("db.crypt15"));
+        assert_eq!(last_extension("msgstore.db.crypt15"), Some("crypt15"));
+    }
+
+    #[test]
+    fn no_extension() {
+        assert_eq!(split_extension("msgstore"), ("msgstore", None));
+        assert_eq!(last_extension("msgstore"), None);
+        assert_eq!(split_extension(""), ("", None));
+    }
+
+    #[test]
+    fn leading_dot_is_part_of_base_name() {
+        assert_eq!(split_extension(".nomedia"), (".nomedia", None));
+        assert_eq!(last_extension(".nomedia"), None);
+        assert_eq!(split_extension(".hidden.txt"), (".hidden", Some("txt")));
+    }
+
+    #[test]
+    fn dots_within_media_names() {
+        let name = "WhatsApp Image 2023-01-05 at 10.15.30.jpeg";
+        assert_eq!(split_extension(name), ("WhatsApp Image 2023-01-05 at 10", Some("15.30.jpeg")));
+        assert_eq!(last_extension(name), Some("jpeg"));
+    }
+
+    #[test]
+    fn last_extension_matches_path_extension() {
+        for name in ["msgstore.db.crypt15", "msgstore", ".nomedia", "..hidden", "trailing.", "a.b.c.d"] {
+            let expected = Path::new(name).extension().map(|e| e.to_str().unwrap());
+            assert_eq!(last_extension(name), expected, "{}", name);
+        }
+    }
+}
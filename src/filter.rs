@@ -1,39 +1,146 @@
-use chrono::Utc;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
 
-use crate::FileInfo;
+use chrono::{DateTime, NaiveDate, Utc};
+use regex::Regex;
+
+use crate::{Clock, Error, FileInfo, MediaCategory, SystemClock};
 
 /// A query for files
 #[derive(Debug)]
 pub struct FileQuery {
     /// Function used to score each file for ordering
-    pub(crate) order: FileScore,
+    pub(crate) order: Box<dyn ScoreFn>,
 
     /// The maximum storage that the files can consume
     pub(crate) data_limit: DataLimit,
 
     /// A predicate which matches files which should be kept if possible
-    pub(crate) priority: FilePredicate,
+    pub(crate) priority: Box<dyn KeepFn>,
+
+    /// A predicate which matches the files which may be deleted or retained.
+    /// Files which do not match are left untouched.
+    pub(crate) scope: FilePredicate,
+
+    /// Paths of files which must never be deleted
+    pub(crate) pinned: HashSet<PathBuf>,
+
+    /// Patterns matching paths of files which must never be deleted
+    pub(crate) pinned_patterns: Vec<PathPattern>,
+
+    /// The clock giving the time against which file ages are measured
+    pub(crate) clock: Box<dyn Clock>,
+
+    /// Whether the newest file in each chat folder must never be deleted
+    pub(crate) keep_latest_per_chat: bool,
 }
 
 impl Default for FileQuery {
     fn default() -> FileQuery {
-        FileQuery { order: FileScore::Newer, data_limit: DataLimit::Infinite, priority: FilePredicate::none() }
+        FileQuery {
+            order: Box::new(FileScore::Newer),
+            data_limit: DataLimit::Infinite,
+            priority: Box::new(FilePredicate::none()),
+            scope: FilePredicate::all(),
+            pinned: HashSet::new(),
+            pinned_patterns: Vec::new(),
+            clock: Box::new(SystemClock),
+            keep_latest_per_chat: false,
+        }
     }
 }
 
 impl FileQuery {
     /// Sets the scoring function used to order files
-    pub fn set_order(&mut self, order: FileScore) { self.order = order; }
+    pub fn set_order(&mut self, order: FileScore) { self.order = Box::new(order); }
+
+    /// Sets a custom scoring function used to order files, for policies which
+    /// cannot be expressed by `FileScore`
+    pub fn set_score_fn(&mut self, score: Box<dyn ScoreFn>) { self.order = score; }
 
     /// Sets the maximum storage used by the returned files
     pub fn set_limit(&mut self, data_limit: DataLimit) { self.data_limit = data_limit; }
 
     /// Sets a predicate for high-priority files
-    pub fn set_priority(&mut self, predicate: FilePredicate) { self.priority = predicate; }
+    pub fn set_priority(&mut self, predicate: FilePredicate) { self.priority = Box::new(predicate); }
+
+    /// Sets a custom predicate for high-priority files, for policies which
+    /// cannot be expressed by `FilePredicate`
+    pub fn set_keep_fn(&mut self, keep: Box<dyn KeepFn>) { self.priority = keep; }
+
+    /// Restricts the files which may be deleted or retained to those matching
+    /// `predicate`
+    pub fn set_scope(&mut self, predicate: FilePredicate) { self.scope = predicate; }
+
+    /// Adds paths (relative to the index root) of files which must never be
+    /// deleted. Pinned files still count towards the data limit, so pinning
+    /// may cause other files to be deleted instead.
+    pub fn pin<I: IntoIterator<Item = impl AsRef<Path>>>(&mut self, paths: I) {
+        self.pinned.extend(paths.into_iter().map(|p| p.as_ref().to_path_buf()));
+    }
+
+    /// Pins all files whose path (relative to the index root) matches the
+    /// supplied regular expression
+    pub fn pin_pattern(&mut self, pattern: Regex) { self.pinned_patterns.push(PathPattern::Regex(pattern)); }
+
+    /// Pins all files whose path (relative to the index root) matches the
+    /// supplied glob e.g. `Media/WhatsApp Documents/*.pdf`
+    pub fn pin_glob(&mut self, glob: &str) -> Result<(), Error> {
+        let pattern = glob::Pattern::new(glob).map_err(|e| Error::InvalidGlob(glob.to_string(), e))?;
+        self.pinned_patterns.push(PathPattern::Glob(pattern));
+        Ok(())
+    }
+
+    /// Sets the clock giving the time against which file ages are measured.
+    /// The time is read once each time the query is evaluated. By default,
+    /// this is the system time.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) { self.clock = clock; }
+
+    /// Ensures the newest file in each chat folder is never deleted, so that
+    /// no chat loses all of its media. WhatsApp does not record which chat a
+    /// file belongs to in its paths, so each folder directly containing media
+    /// is treated as a chat, e.g. `Media/WhatsApp Images/Sent` or a per-chat
+    /// folder created by the user. Like pinned files, these files still count
+    /// towards the data limit.
+    pub fn set_keep_latest_per_chat(&mut self, enabled: bool) { self.keep_latest_per_chat = enabled; }
+
+    /// Is the file at `path` pinned
+    pub(crate) fn is_pinned(&self, path: &Path) -> bool {
+        self.pinned.contains(path) || self.pinned_patterns.iter().any(|p| p.matches(path))
+    }
+}
+
+/// A pattern matched against relative paths
+#[derive(Debug)]
+pub(crate) enum PathPattern {
+    Regex(Regex),
+    Glob(glob::Pattern),
+}
+
+impl PathPattern {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            PathPattern::Regex(regex) => regex.is_match(&path.to_string_lossy()),
+            PathPattern::Glob(glob) => glob.matches_path(path),
+        }
+    }
+}
+
+/// A function used to rank files. Files with lower scores are removed first.
+pub trait ScoreFn: Debug {
+    /// Scores a file. `path` is the path of the file relative to the index
+    /// root.
+    fn score(&self, path: &Path, info: &FileInfo) -> f64;
+
+    /// Scores a file as if the current time were `now`. Functions which
+    /// depend on the age of a file should override this so that they use the
+    /// clock of the query. By default, this ignores `now` and calls `score`.
+    fn score_at(&self, path: &Path, info: &FileInfo, _now: DateTime<Utc>) -> f64 { self.score(path, info) }
 }
 
 /// Ranking function for files
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug)]
 pub enum FileScore {
     /// Score is negatively proportional to file size
     Smaller,
@@ -43,20 +150,99 @@ pub enum FileScore {
 
     /// Score decreases proportionally with size and exponentially with age
     SmallerNewer,
+
+    /// As for `SmallerNewer`, but with the score of each file divided by the
+    /// weight of its media category, so that media in categories with a
+    /// higher weight is kept in preference to media of the same size and age
+    /// in other categories. Categories absent from `category_weights` have a
+    /// weight of 1. A category weight of zero or less causes files in that
+    /// category to be trimmed first. `default_category_weights` favours
+    /// photos and voice notes over stickers and GIFs.
+    SmallerNewerWeighted {
+        /// The relative importance of each media category
+        category_weights: BTreeMap<MediaCategory, f64>,
+    },
+
+    /// Score is negatively proportional to the time since the file was last
+    /// accessed, so the least recently accessed files are trimmed first.
+    ///
+    /// Access times are only meaningful if the filesystem records them. Where
+    /// it does not (e.g. `noatime` mounts), the modification time is used
+    /// instead. Reading a file (including when archiving it) may itself
+    /// update its access time. In sync mode files are ranked by their
+    /// archived copies, whose access times reflect when they were archived or
+    /// read from the archive rather than when they were viewed.
+    RecentlyAccessed,
+
+    /// Score combines size, age and media category according to the supplied
+    /// weights. The score of a file of `size` bytes, `age` days old (by
+    /// estimated creation date) in category `c` is
+    ///
+    /// `ln(category_weights[c]) - size_weight * ln(1 + size) - age_weight *
+    /// ln(1 + age)`
+    ///
+    /// i.e. the logarithm of `category_weights[c] / ((1 + size)^size_weight *
+    /// (1 + age)^age_weight)`. Categories absent from `category_weights` have
+    /// a weight of 1, so a category weight of 2 makes files in that category
+    /// twice as important as otherwise identical files in other categories.
+    /// A category weight of zero or less causes files in that category to be
+    /// trimmed first.
+    Weighted {
+        /// How strongly larger files are penalized
+        size_weight: f64,
+
+        /// How strongly older files are penalized
+        age_weight: f64,
+
+        /// The relative importance of each media category
+        category_weights: BTreeMap<MediaCategory, f64>,
+    },
 }
 
 impl FileScore {
-    /// Evaluates the score for a file (smaller is more important)
-    pub fn evaluate(&self, info: &FileInfo) -> f64 {
+    /// Category weights which value photos and voice notes twice as highly
+    /// as other media, and stickers and animated GIFs half as highly
+    pub fn default_category_weights() -> BTreeMap<MediaCategory, f64> {
+        BTreeMap::from([
+            (MediaCategory::Images, 2.0),
+            (MediaCategory::VoiceNotes, 2.0),
+            (MediaCategory::Stickers, 0.5),
+            (MediaCategory::AnimatedGifs, 0.5),
+        ])
+    }
+
+    /// Evaluates the score for a file (smaller is more important). `path` is
+    /// the path of the file relative to the WhatsApp folder.
+    pub fn evaluate(&self, path: &Path, info: &FileInfo) -> f64 { self.evaluate_at(path, info, Utc::now()) }
+
+    /// Evaluates the score for a file as if the current time were `now`
+    pub fn evaluate_at(&self, path: &Path, info: &FileInfo, now: DateTime<Utc>) -> f64 {
         #[allow(clippy::cast_precision_loss)]
-        match *self {
+        match self {
             FileScore::Smaller => -(info.get_size() as f64),
             FileScore::Newer => -(info.estimate_creation_date().and_utc().timestamp_millis() as f64),
             FileScore::SmallerNewer => {
-                let now = Utc::now().naive_utc();
-                let offset = now.signed_duration_since(info.estimate_creation_date());
+                let offset = info.estimate_age_at(now);
                 Self::evaluate_smaller_newer(info.get_size(), offset.num_milliseconds() as f64)
             }
+            FileScore::SmallerNewerWeighted { category_weights } => {
+                let category_weight = category_weights.get(&MediaCategory::from_path(path)).copied().unwrap_or(1.0);
+                if category_weight <= 0.0 {
+                    return f64::NEG_INFINITY;
+                }
+                let offset = info.estimate_age_at(now);
+                Self::evaluate_smaller_newer(info.get_size(), offset.num_milliseconds() as f64) / category_weight
+            }
+            FileScore::RecentlyAccessed => {
+                let last_used = info.estimate_last_used_time();
+                last_used.unix_seconds() as f64 * 1000.0 + f64::from(last_used.nanoseconds()) / 1_000_000.0
+            }
+            FileScore::Weighted { size_weight, age_weight, category_weights } => {
+                let age_days = info.estimate_age_at(now).num_seconds().max(0) as f64 / (60.0 * 60.0 * 24.0);
+                let category_weight =
+                    category_weights.get(&MediaCategory::from_path(path)).copied().unwrap_or(1.0).max(0.0);
+                category_weight.ln() - size_weight * (info.get_size() as f64).ln_1p() - age_weight * age_days.ln_1p()
+            }
         }
     }
 
@@ -68,6 +254,12 @@ impl FileScore {
     }
 }
 
+impl ScoreFn for FileScore {
+    fn score(&self, path: &Path, info: &FileInfo) -> f64 { self.evaluate(path, info) }
+
+    fn score_at(&self, path: &Path, info: &FileInfo, now: DateTime<Utc>) -> f64 { self.evaluate_at(path, info, now) }
+}
+
 /// A limit for the amout of data consumed
 #[derive(Clone, Copy, Debug)]
 pub enum DataLimit {
@@ -90,6 +282,43 @@ impl DataLimit {
             DataLimit::Bytes(count) => DataLimit::Bytes(f(count)),
         }
     }
+
+    /// Reduces the limit by `bytes` to leave headroom, without going below
+    /// zero. An infinite limit is unaffected.
+    #[must_use]
+    pub fn reserve(self, bytes: u64) -> DataLimit { self.map(|count| count.saturating_sub(bytes)) }
+
+    /// The limit on the size of a folder currently using `used` bytes, on a
+    /// filesystem with `available` bytes free, such that at least `min_free`
+    /// bytes are free once it is met. This assumes deleting a file frees
+    /// exactly its size.
+    pub fn for_free_space(used: u64, available: u64, min_free: u64) -> DataLimit {
+        DataLimit::Bytes(used.saturating_add(available).saturating_sub(min_free))
+    }
+
+    /// The stricter of this limit and `other`
+    #[must_use]
+    pub fn min(self, other: DataLimit) -> DataLimit {
+        match (self, other) {
+            (DataLimit::Infinite, limit) | (limit, DataLimit::Infinite) => limit,
+            (DataLimit::Bytes(a), DataLimit::Bytes(b)) => DataLimit::Bytes(a.min(b)),
+        }
+    }
+}
+
+/// A predicate matching files which should be kept if possible. Matching
+/// files are always ranked above those which do not match, regardless of
+/// score.
+pub trait KeepFn: Debug {
+    /// Returns `true` if the file should be kept. `path` is the path of the
+    /// file relative to the index root.
+    fn keep(&self, path: &Path, info: &FileInfo) -> bool;
+
+    /// Returns `true` if the file should be kept, as if the current time were
+    /// `now`. Predicates which depend on the age of a file should override
+    /// this so that they use the clock of the query. By default, this ignores
+    /// `now` and calls `keep`.
+    fn keep_at(&self, path: &Path, info: &FileInfo, _now: DateTime<Utc>) -> bool { self.keep(path, info) }
 }
 
 /// A predicate for files
@@ -100,6 +329,13 @@ pub enum FilePredicate {
 
     /// Only files younger or equal to the specified duration
     AgeLessThan(chrono::Duration),
+
+    /// Only files older than the specified duration
+    AgeGreaterThan(chrono::Duration),
+
+    /// Only files with an estimated creation date between the two dates
+    /// (inclusive)
+    CreatedBetween(NaiveDate, NaiveDate),
 }
 
 impl FilePredicate {
@@ -109,15 +345,156 @@ impl FilePredicate {
     /// Returns `false` for any file
     pub fn none() -> FilePredicate { FilePredicate::Constant(false) }
 
+    /// Matches files younger or equal to a duration such as `7d` or `2w`, in
+    /// the format accepted by `humantime`
+    pub fn age_less_than_str(duration: &str) -> Result<FilePredicate, Error> {
+        Ok(FilePredicate::AgeLessThan(Self::parse_duration(duration)?))
+    }
+
+    /// Matches files older than a duration such as `7d` or `2w`, in the
+    /// format accepted by `humantime`
+    pub fn age_greater_than_str(duration: &str) -> Result<FilePredicate, Error> {
+        Ok(FilePredicate::AgeGreaterThan(Self::parse_duration(duration)?))
+    }
+
+    fn parse_duration(duration: &str) -> Result<chrono::Duration, Error> {
+        let invalid = |reason: String| Error::DurationParse(duration.to_string(), reason);
+        let parsed = humantime::parse_duration(duration).map_err(|e| invalid(e.to_string()))?;
+        chrono::Duration::from_std(parsed).map_err(|_| invalid("duration is too large".to_string()))
+    }
+
     /// Does the predicate match the file
-    pub fn matches(&self, file_info: &FileInfo) -> bool {
+    pub fn matches(&self, file_info: &FileInfo) -> bool { self.matches_at(file_info, Utc::now()) }
+
+    /// Does the predicate match the file, as if the current time were `now`
+    pub fn matches_at(&self, file_info: &FileInfo, now: DateTime<Utc>) -> bool {
         match self {
             FilePredicate::Constant(b) => *b,
-            FilePredicate::AgeLessThan(max) => {
-                let now = Utc::now().naive_utc();
-                let age = now.signed_duration_since(file_info.estimate_creation_date());
-                age <= *max
+            FilePredicate::AgeLessThan(max) => file_info.estimate_age_at(now) <= *max,
+            FilePredicate::AgeGreaterThan(min) => file_info.estimate_age_at(now) > *min,
+            FilePredicate::CreatedBetween(since, until) => {
+                let date = file_info.estimate_creation_date().date();
+                *since <= date && date <= *until
             }
         }
     }
 }
+
+impl KeepFn for FilePredicate {
+    fn keep(&self, _path: &Path, info: &FileInfo) -> bool { self.matches(info) }
+
+    fn keep_at(&self, _path: &Path, info: &FileInfo, now: DateTime<Utc>) -> bool { self.matches_at(info, now) }
+}
+
+#[cfg(test)]
+mod tests {
+    use filetime::FileTime;
+
+    use super::*;
+    use crate::test_util::{write_file, TempDir, TEST_MTIME};
+    use crate::FixedClock;
+
+    #[test]
+    fn least_recently_accessed_scores_lowest() {
+        let temp = TempDir::new();
+        let viewed = write_file(temp.path(), "IMG-20240101-WA0001.jpg", b"viewed", TEST_MTIME);
+        let unviewed = write_file(temp.path(), "IMG-20240101-WA0002.jpg", b"unviewed", TEST_MTIME);
+        let mtime = FileTime::from_unix_time(TEST_MTIME, 0);
+        filetime::set_file_times(&viewed, FileTime::from_unix_time(TEST_MTIME + 3600, 0), mtime).unwrap();
+        filetime::set_file_times(&unviewed, FileTime::from_unix_time(TEST_MTIME + 60, 0), mtime).unwrap();
+
+        let score = |path: &Path| FileScore::RecentlyAccessed.evaluate(path, &FileInfo::new(path).unwrap());
+        assert!(score(&unviewed) < score(&viewed));
+    }
+
+    #[test]
+    fn access_time_older_than_modification_time_is_ignored() {
+        let temp = TempDir::new();
+        let path = write_file(temp.path(), "IMG-20240101-WA0001.jpg", b"contents", TEST_MTIME);
+        filetime::set_file_times(&path, FileTime::zero(), FileTime::from_unix_time(TEST_MTIME, 0)).unwrap();
+        let info = FileInfo::new(&path).unwrap();
+        #[allow(clippy::cast_precision_loss)]
+        let expected = (TEST_MTIME * 1000) as f64;
+        assert!((FileScore::RecentlyAccessed.evaluate(&path, &info) - expected).abs() < 1.0);
+    }
+
+    #[test]
+    fn files_matching_pinned_patterns_are_pinned() {
+        let mut query = FileQuery::default();
+        query.pin_pattern(Regex::new(r"/VID-2023\d{4}-").unwrap());
+        query.pin_glob("Media/WhatsApp Documents/*.pdf").unwrap();
+        assert!(query.is_pinned(Path::new("Media/WhatsApp Video/VID-20230704-WA0001.mp4")));
+        assert!(!query.is_pinned(Path::new("Media/WhatsApp Video/VID-20240704-WA0001.mp4")));
+        assert!(query.is_pinned(Path::new("Media/WhatsApp Documents/report.pdf")));
+        assert!(!query.is_pinned(Path::new("Media/WhatsApp Documents/report.docx")));
+        assert!(!query.is_pinned(Path::new("Media/WhatsApp Images/report.pdf")));
+        assert!(matches!(query.pin_glob("Media/[*.pdf"), Err(Error::InvalidGlob(..))));
+    }
+
+    fn bytes(limit: DataLimit) -> Option<u64> {
+        match limit {
+            DataLimit::Infinite => None,
+            DataLimit::Bytes(count) => Some(count),
+        }
+    }
+
+    #[test]
+    fn reserve_reduces_limit_without_going_below_zero() {
+        assert_eq!(bytes(DataLimit::from_bytes(1000).reserve(300)), Some(700));
+        assert_eq!(bytes(DataLimit::from_bytes(200).reserve(300)), Some(0));
+        assert_eq!(bytes(DataLimit::Infinite.reserve(300)), None);
+        // The reserve applies before the stricter of the two limits is taken
+        let free_space = DataLimit::for_free_space(800, 100, 200);
+        assert_eq!(bytes(DataLimit::from_bytes(1000).reserve(300).min(free_space)), Some(700));
+        assert_eq!(bytes(DataLimit::from_bytes(1000).reserve(500).min(free_space)), Some(500));
+        assert_eq!(bytes(DataLimit::Infinite.reserve(500).min(free_space)), Some(700));
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        let age_less_than = |duration: &str| match FilePredicate::age_less_than_str(duration).unwrap() {
+            FilePredicate::AgeLessThan(age) => age,
+            other => panic!("Unexpected predicate {:?}", other),
+        };
+        assert_eq!(age_less_than("7d"), chrono::Duration::days(7));
+        assert_eq!(age_less_than("2w"), chrono::Duration::weeks(2));
+        assert_eq!(age_less_than("1day 12h"), chrono::Duration::hours(36));
+        assert_eq!(age_less_than("90min"), chrono::Duration::minutes(90));
+        assert!(matches!(
+            FilePredicate::age_greater_than_str("1y"),
+            Ok(FilePredicate::AgeGreaterThan(age)) if age == chrono::Duration::seconds(31_557_600)
+        ));
+        for invalid in ["", "7", "seven days", "-1d"] {
+            assert!(
+                matches!(FilePredicate::age_less_than_str(invalid), Err(Error::DurationParse(ref d, _)) if d == invalid)
+            );
+        }
+    }
+
+    #[test]
+    fn scores_and_ages_use_the_fixed_clock() {
+        let created = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let info = FileInfo::from_parts(100, FileTime::zero(), created);
+        let path = Path::new("Media/WhatsApp Images/IMG-20240101-WA0001.jpg");
+        // One half-life after creation, the score has doubled in magnitude
+        let half_life = chrono::Duration::minutes(43_830);
+        let clock = FixedClock(created.and_utc() + half_life);
+        assert!((FileScore::SmallerNewer.score_at(path, &info, clock.now()) + 200.0).abs() < 1e-9);
+        let clock = FixedClock(created.and_utc() + half_life * 2);
+        assert!((FileScore::SmallerNewer.score_at(path, &info, clock.now()) + 400.0).abs() < 1e-9);
+
+        let recent = FilePredicate::AgeLessThan(chrono::Duration::days(30));
+        assert!(recent.keep_at(path, &info, FixedClock(created.and_utc() + chrono::Duration::days(30)).now()));
+        assert!(!recent.keep_at(path, &info, FixedClock(created.and_utc() + chrono::Duration::days(31)).now()));
+    }
+    #[test]
+    fn free_space_limit_leaves_minimum_free() {
+        // 100 used and 50 free, so 150 in total of which 30 must stay free
+        assert_eq!(bytes(DataLimit::for_free_space(100, 50, 30)), Some(120));
+        // Already enough free space still gives a limit above current usage
+        assert_eq!(bytes(DataLimit::for_free_space(100, 500, 30)), Some(570));
+        // More free space wanted than the folder and free space together
+        assert_eq!(bytes(DataLimit::for_free_space(100, 50, 1000)), Some(0));
+        assert_eq!(bytes(DataLimit::for_free_space(u64::MAX, u64::MAX, 1)), Some(u64::MAX - 1));
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::copy::{copy_with, CopyObserver, DEFAULT_COPY_CHUNK_SIZE};
+use crate::Error;
+
+/// A SHA-256 digest of a file's contents
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    /// Hashes everything readable from `reader`
+    pub fn from_reader<R: Read>(mut reader: R) -> std::io::Result<ContentHash> {
+        let mut hasher = ContentHasher::default();
+        copy_with(&mut reader, &mut io::sink(), DEFAULT_COPY_CHUNK_SIZE, &mut [&mut hasher])?;
+        Ok(hasher.finish())
+    }
+
+    /// Parses a hash from 64 hexadecimal digits, as produced by `Display`
+    pub fn from_hex(hex: &str) -> Option<ContentHash> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        for (idx, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok()?;
+        }
+        Some(ContentHash(bytes))
+    }
+
+    /// Hashes the contents of the file at `path`
+    pub fn from_file(path: &Path) -> Result<ContentHash, Error> {
+        let file = File::open(path).map_err(|e| (e, path))?;
+        let hash = Self::from_reader(BufReader::new(file)).map_err(|e| (e, path))?;
+        Ok(hash)
+    }
+}
+
+/// Computes a `ContentHash` of data as it is copied
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ContentHasher(Sha256);
+
+impl ContentHasher {
+    /// The hash of all data copied so far
+    pub fn finish(self) -> ContentHash { ContentHash(self.0.finalize().into()) }
+}
+
+impl CopyObserver for ContentHasher {
+    fn chunk_copied(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.0.update(chunk);
+        Ok(())
+    }
+}
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
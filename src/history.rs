@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Name of the file in the root of an archive recording the history of runs
+pub(crate) const HISTORY_NAME: &str = ".waa-history.jsonl";
+
+/// A summary of one run against an archive. The history is stored as one
+/// JSON record per line, appended to after each run.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HistoryRecord {
+    /// When the run completed, in seconds since the Unix epoch
+    pub timestamp: i64,
+
+    /// The size of the archive in bytes after the run
+    pub archive_bytes: u64,
+
+    /// The number of bytes copied into the archive
+    pub bytes_added: u64,
+
+    /// The number of bytes removed from the archive, e.g. by
+    /// `--mirror-delete` or `--archive-max-age`
+    pub bytes_pruned: u64,
+
+    /// The number of files deleted from the WhatsApp folder and the archive
+    pub files_deleted: usize,
+}
+
+impl HistoryRecord {
+    /// Appends the record to the history file at `path`, creating it if
+    /// necessary
+    pub(crate) fn append(&self, path: &Path) -> Result<(), Error> {
+        let mut line = serde_json::to_string(self).expect("Unable to serialize history record");
+        line.push('\n');
+        File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|e| (e, path))?;
+        Ok(())
+    }
+
+    /// Loads every record from the history file at `path`, oldest first
+    pub(crate) fn load_all(path: &Path) -> Result<Vec<HistoryRecord>, Error> {
+        let file = File::open(path).map_err(|e| (e, path))?;
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| (e, path))?;
+            if line.is_empty() {
+                continue;
+            }
+            records.push(serde_json::from_str(&line).map_err(|e| (io::Error::from(e), path))?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn records_are_appended_one_per_line() {
+        let temp = TempDir::new();
+        let path = temp.path().join(HISTORY_NAME);
+        let first =
+            HistoryRecord { timestamp: 1, archive_bytes: 100, bytes_added: 100, bytes_pruned: 0, files_deleted: 0 };
+        let second =
+            HistoryRecord { timestamp: 2, archive_bytes: 150, bytes_added: 70, bytes_pruned: 20, files_deleted: 3 };
+        first.append(&path).unwrap();
+        second.append(&path).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(contents.ends_with('\n'));
+        for line in lines {
+            let fields = serde_json::from_str::<serde_json::Value>(line).unwrap();
+            let keys: Vec<&str> = fields.as_object().unwrap().keys().map(String::as_str).collect();
+            assert_eq!(keys.len(), 5, "{}", line);
+        }
+        assert_eq!(HistoryRecord::load_all(&path).unwrap(), vec![first, second]);
+    }
+}
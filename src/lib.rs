@@ -1,3 +1,11 @@
+//! Archives the media, message databases and backups in a WhatsApp folder,
+//! and trims or restores media to keep the WhatsApp folder under a size limit.
+//!
+//! Runs are idempotent: if neither folder changes between two runs with the
+//! same options, the second copies, deletes and restores nothing. New steps
+//! in the pipeline must preserve this, e.g. by not copying files which a later
+//! step would remove.
+
 #![warn(clippy::pedantic)]
 #![allow(
     clippy::missing_errors_doc,
@@ -7,12 +15,61 @@
     clippy::must_use_candidate
 )]
 
+mod backup;
+mod cancel;
+mod category;
+mod clock;
+mod codec;
+mod copy;
+mod crypto;
+mod database;
+mod diff;
 mod error;
+mod export;
 mod file_index;
 mod file_info;
+mod filename;
 mod filter;
+mod hash;
+mod history;
+mod listing;
+mod manifest;
+mod pipeline;
+mod plan;
+mod progress;
+mod resume;
+mod skipped;
+mod stats;
+mod tag;
+#[cfg(test)]
+mod test_util;
+mod timezone;
+mod trimmed;
 
+pub use backup::{BackupKind, BackupRetention};
+pub use cancel::CancelToken;
+pub use category::MediaCategory;
+pub use clock::{Clock, FixedClock, SystemClock};
+pub use codec::Encoding;
+pub use copy::DEFAULT_COPY_CHUNK_SIZE;
+pub use crypto::EncryptionKey;
+pub use database::{DbPattern, DEFAULT_DB_DATE_FORMAT};
+pub use diff::{Change, Diff};
 pub use error::Error;
-pub use file_index::{ActionType, FileIndex, IndexType};
-pub use file_info::FileInfo;
-pub use filter::{DataLimit, FilePredicate, FileQuery, FileScore};
+pub use export::ExportLayout;
+pub use file_index::{ActionType, FileIndex, IndexOptions, IndexType};
+pub use file_info::{FileInfo, DEFAULT_ASSUMED_TIME};
+pub use filename::{extension, last_extension, split_extension};
+pub use filter::{DataLimit, FilePredicate, FileQuery, FileScore, KeepFn, ScoreFn};
+pub use hash::ContentHash;
+pub use history::HistoryRecord;
+pub use manifest::Manifest;
+pub use pipeline::{
+    run_backup, BackupError, BackupMode, BackupOptions, BackupReport, BackupStage, DEFAULT_DB_QUIET_PERIOD,
+};
+pub use plan::{MirrorPlan, MirrorReport};
+pub use progress::{NoProgress, Progress};
+pub use resume::ResumePhase;
+pub use skipped::{SkipReason, SkippedEntry};
+pub use stats::{FileStats, IndexStats};
+pub use timezone::Timezone;
@@ -0,0 +1,62 @@
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// The metadata of every file in an index, from which the index can be
+/// rebuilt without the files being present, e.g. to plan trimming on another
+/// machine. Paths which are not valid UTF-8 are recorded using their lossy
+/// conversion.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Listing {
+    /// Whether the listed index is an archive rather than a WhatsApp folder
+    pub archive: bool,
+
+    /// The listed files, in path order
+    pub files: Vec<ListedFile>,
+}
+
+/// The metadata of a single file in a `Listing`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ListedFile {
+    /// Path relative to the index root
+    pub path: String,
+
+    /// Size in bytes
+    pub size: u64,
+
+    /// Number of bytes occupied on disk
+    pub stored_size: u64,
+
+    /// Modification time in seconds since the Unix epoch
+    pub modified: i64,
+
+    /// Sub-second part of the modification time in nanoseconds
+    pub modified_nanos: u32,
+
+    /// Whether the file is stored compressed
+    #[serde(default)]
+    pub compressed: bool,
+
+    /// Whether the file is stored encrypted
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl Listing {
+    /// Loads the listing from the file at `path`
+    pub fn load(path: &Path) -> Result<Listing, Error> {
+        let contents = std::fs::read(path).map_err(|e| (e, path))?;
+        let listing = serde_json::from_slice(&contents).map_err(|e| (io::Error::from(e), path))?;
+        Ok(listing)
+    }
+
+    /// Saves the listing to the file at `path`
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let contents = serde_json::to_vec_pretty(self).expect("Unable to serialize listing");
+        std::fs::write(path, contents).map_err(|e| (e, path))?;
+        Ok(())
+    }
+}
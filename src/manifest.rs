@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+use rand::Rng;
+
+use crate::file_index::TEMP_SUFFIX;
+use crate::{ContentHash, Error};
+
+/// Name of the file in the root of an archive holding its manifest
+pub(crate) const MANIFEST_NAME: &str = ".waa-manifest";
+
+/// Prefix of the comment lines recording the size and modification time of a
+/// file when it was hashed
+const STAMP_PREFIX: &str = "# ";
+
+/// The content hashes of every file in an index.
+///
+/// The manifest is stored in the same format as `sha256sum` output, sorted by
+/// path, so it can also be checked with `sha256sum -c` against an
+/// uncompressed, unencrypted archive. Paths which are not valid UTF-8 are
+/// recorded using their lossy conversion.
+///
+/// The size and modification time of each file when it was hashed may also
+/// be recorded, as a comment line before its hash which `sha256sum` ignores.
+/// This allows files which have not changed to be skipped when the manifest
+/// is next updated.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Manifest {
+    entries: BTreeMap<String, ContentHash>,
+    stamps: BTreeMap<String, (u64, FileTime)>,
+}
+
+impl Manifest {
+    /// Records the hash of the file at `path`, relative to the index root
+    pub fn insert(&mut self, path: &Path, hash: ContentHash) {
+        let key = Self::key(path);
+        self.stamps.remove(&key);
+        self.entries.insert(key, hash);
+    }
+
+    /// Records the hash of the file at `path` along with its size and
+    /// modification time when it was hashed
+    pub(crate) fn insert_stamped(&mut self, path: &Path, hash: ContentHash, size: u64, modified: FileTime) {
+        let key = Self::key(path);
+        self.stamps.insert(key.clone(), (size, modified));
+        self.entries.insert(key, hash);
+    }
+
+    /// Gets the recorded hash of the file at `path` if its size and
+    /// modification time are unchanged since it was hashed
+    pub(crate) fn get_unchanged(&self, path: &Path, size: u64, modified: FileTime) -> Option<&ContentHash> {
+        let key = Self::key(path);
+        (self.stamps.get(&key) == Some(&(size, modified))).then(|| self.entries.get(&key)).flatten()
+    }
+
+    /// Gets the recorded hash of the file at `path`, relative to the index
+    /// root
+    pub fn get(&self, path: &Path) -> Option<&ContentHash> { self.entries.get(&Self::key(path)) }
+
+    /// Iterates over paths and hashes, in path order
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &ContentHash)> {
+        self.entries.iter().map(|(path, hash)| (Path::new(path), hash))
+    }
+
+    /// The number of files in the manifest
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    /// Returns `true` if the manifest has no files
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    fn key(path: &Path) -> String { path.to_string_lossy().into_owned() }
+
+    /// Writes the manifest to `writer`
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (path, hash) in &self.entries {
+            if let Some((size, modified)) = self.stamps.get(path) {
+                let (seconds, nanos) = (modified.unix_seconds(), modified.nanoseconds());
+                writeln!(writer, "{}{} {}.{:09}  {}", STAMP_PREFIX, size, seconds, nanos, path)?;
+            }
+            writeln!(writer, "{}  {}", hash, path)?;
+        }
+        writer.flush()
+    }
+
+    /// Reads a manifest previously written by `write`
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid manifest line {}", idx + 1));
+            if let Some(stamp) = line.strip_prefix(STAMP_PREFIX) {
+                let (stamp, path) = stamp.split_once("  ").ok_or_else(invalid)?;
+                let (size, modified) = Self::parse_stamp(stamp).ok_or_else(invalid)?;
+                manifest.stamps.insert(path.to_string(), (size, modified));
+                continue;
+            }
+            let (hash, path) = line.split_once("  ").ok_or_else(invalid)?;
+            let hash = ContentHash::from_hex(hash).ok_or_else(invalid)?;
+            manifest.entries.insert(path.to_string(), hash);
+        }
+        Ok(manifest)
+    }
+
+    /// Parses the size and modification time recorded in a comment line
+    fn parse_stamp(stamp: &str) -> Option<(u64, FileTime)> {
+        let (size, modified) = stamp.split_once(' ')?;
+        let (seconds, nanos) = modified.split_once('.')?;
+        Some((size.parse().ok()?, FileTime::from_unix_time(seconds.parse().ok()?, nanos.parse().ok()?)))
+    }
+
+    /// Loads the manifest from the file at `path`
+    pub fn load(path: &Path) -> Result<Manifest, Error> {
+        let file = File::open(path).map_err(|e| (e, path))?;
+        let manifest = Self::read(BufReader::new(file)).map_err(|e| (e, path))?;
+        Ok(manifest)
+    }
+
+    /// Saves the manifest to the file at `path`. The manifest is written to a
+    /// temporary file first so an existing manifest is never left partially
+    /// written.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let random: u32 = rand::thread_rng().gen();
+        let mut temp_name = path.file_name().map(ToOwned::to_owned).unwrap_or_default();
+        temp_name.push(format!(".{:x}{}", random, TEMP_SUFFIX));
+        let temp_path: PathBuf = path.with_file_name(temp_name);
+        let result = File::create(&temp_path)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                self.write(&mut writer)?;
+                writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()
+            })
+            .map_err(|e| Error::from((e, &temp_path)))
+            .and_then(|()| std::fs::rename(&temp_path, path).map_err(|e| Error::Mv(e, temp_path.clone(), path.into())));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
@@ -0,0 +1,1114 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{DataLimit, Error, FileIndex, FileQuery, IndexStats, MediaCategory, MirrorReport};
+
+/// How long a database must be unmodified before it is assumed that WhatsApp
+/// has finished writing it
+pub const DEFAULT_DB_QUIET_PERIOD: Duration = Duration::from_secs(5);
+
+/// Which steps of the pipeline are performed by `run_backup`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BackupMode {
+    /// Copy new and changed files from the WhatsApp folder to the archive
+    #[default]
+    Backup,
+
+    /// As for `Backup`, but only message databases are copied
+    DbOnly,
+
+    /// Back up, then delete archived media from the WhatsApp folder to bring
+    /// it under the size limit of the query
+    Trim,
+
+    /// As for `Trim`, but media chosen by the query which is missing from the
+    /// WhatsApp folder is also restored from the archive
+    Sync,
+}
+
+/// Options controlling the pipeline run by `run_backup`
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct BackupOptions {
+    /// Which steps are performed
+    pub mode: BackupMode,
+
+    /// Chooses which media is kept in the WhatsApp folder in trim and sync
+    /// modes. Its limit is reduced to account for non-media files.
+    pub query: FileQuery,
+
+    /// In trim and sync modes, also reduce the limit so that at least this
+    /// many bytes are free on the WhatsApp folder's filesystem afterwards
+    pub min_free_after: Option<u64>,
+
+    /// Check each file has not changed since it was indexed immediately
+    /// before deleting it from the WhatsApp folder, keeping it if it has
+    pub safe_trim: bool,
+
+    /// Delete files from the WhatsApp folder using this many threads, unless
+    /// `safe_trim` is set
+    pub delete_jobs: Option<usize>,
+
+    /// Instead of trimming according to the query, delete exactly these
+    /// paths in trim mode or restore exactly these paths in sync mode
+    pub paths: Option<Vec<PathBuf>>,
+
+    /// Only consider media dated on or after this date for copying. A date of
+    /// `None` means the date of the newest media already in the archive.
+    pub incremental_since: Option<Option<NaiveDate>>,
+
+    /// Archive media in the `.Statuses` folder, which is otherwise skipped
+    pub include_statuses: bool,
+
+    /// Do not archive empty media files
+    pub skip_empty: bool,
+
+    /// Copy files even if the archive appears to lack the space for them
+    pub force: bool,
+
+    /// How long a database must be unmodified before it is considered
+    /// complete
+    pub db_quiet_period: Duration,
+
+    /// Wait up to this long for recently modified databases to become
+    /// complete before copying, rather than only warning about them
+    pub wait_for_quiescent: Option<Duration>,
+
+    /// Files modified within this long of now are not copied, leaving them
+    /// for a later run in case they are still being written
+    pub skip_newer_than: Option<Duration>,
+
+    /// Media older than this is neither archived nor kept in the archive.
+    /// Ages are measured against the clock of `query`.
+    pub archive_max_age: Option<chrono::Duration>,
+
+    /// Remove files from the archive which are no longer in the WhatsApp
+    /// folder, other than those recorded as trimmed. Only meaningful in
+    /// backup mode.
+    pub mirror_delete: bool,
+
+    /// Remove folders from the archive which no longer hold any files
+    pub compact: bool,
+
+    /// The number of dates of message database backups kept in the archive
+    pub num_kept_dbs: usize,
+
+    /// Remove old message databases from the archive
+    pub db_cleanup: bool,
+
+    /// Remove old versions of the files in the `Backups` folder from the
+    /// archive
+    pub backup_cleanup: bool,
+
+    /// List the old databases and backups to be removed under their own
+    /// headings before removing them
+    pub list_cleanup: bool,
+
+    /// Write the archive's manifest using this many threads
+    pub manifest_jobs: Option<usize>,
+
+    /// In sync mode, only restore media younger than this, measured against
+    /// the clock of `query`
+    pub restore_newer_than: Option<chrono::Duration>,
+
+    /// In sync mode, only restore media within these folders, if any are
+    /// given
+    pub restore_only: Vec<PathBuf>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> BackupOptions {
+        BackupOptions {
+            mode: BackupMode::default(),
+            query: FileQuery::default(),
+            min_free_after: None,
+            safe_trim: false,
+            delete_jobs: None,
+            paths: None,
+            incremental_since: None,
+            include_statuses: false,
+            skip_empty: false,
+            force: false,
+            db_quiet_period: DEFAULT_DB_QUIET_PERIOD,
+            wait_for_quiescent: None,
+            skip_newer_than: None,
+            archive_max_age: None,
+            mirror_delete: false,
+            compact: false,
+            num_kept_dbs: 10,
+            db_cleanup: true,
+            backup_cleanup: true,
+            list_cleanup: false,
+            manifest_jobs: None,
+            restore_newer_than: None,
+            restore_only: Vec::new(),
+        }
+    }
+}
+
+/// What was done by `run_backup`
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct BackupReport {
+    /// The size of the archive in bytes before anything was copied
+    pub archive_bytes_before: Option<u64>,
+
+    /// The size of the archive in bytes after it was tidied
+    pub archive_bytes_after: Option<u64>,
+
+    /// The number of files copied to the archive
+    pub files_copied: usize,
+
+    /// The number of bytes copied to the archive
+    pub bytes_copied: u64,
+
+    /// The number of files removed from the archive by `mirror_delete` or
+    /// `archive_max_age`
+    pub files_removed_from_archive: usize,
+
+    /// The number of bytes removed from the archive by `mirror_delete` or
+    /// `archive_max_age`
+    pub bytes_removed_from_archive: u64,
+
+    /// The number of files deleted from the WhatsApp folder
+    pub files_trimmed: usize,
+
+    /// The number of files restored to the WhatsApp folder
+    pub files_restored: usize,
+
+    /// The number of bytes restored to the WhatsApp folder
+    pub bytes_restored: u64,
+
+    /// The run was stopped early because the cancellation token of one of
+    /// the indices was cancelled
+    #[serde(skip)]
+    pub cancelled: bool,
+}
+
+/// The step of `run_backup` which failed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackupStage {
+    /// Copying files to the archive
+    Mirror,
+
+    /// Removing old databases, backups and unwanted media from the archive
+    Tidy,
+
+    /// Writing the archive's manifest
+    Manifest,
+
+    /// Deleting files from the WhatsApp folder
+    Trim,
+
+    /// Restoring files to the WhatsApp folder
+    Restore,
+}
+
+impl fmt::Display for BackupStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            BackupStage::Mirror => "Unable to mirror files to archive",
+            BackupStage::Tidy => "Unable to clean unnecessary files from archive folder",
+            BackupStage::Manifest => "Unable to process manifest",
+            BackupStage::Trim => "Unable to trim files from WhatsApp folder",
+            BackupStage::Restore => "Unable to restore files to WhatsApp folder",
+        };
+        f.write_str(description)
+    }
+}
+
+/// A failure of `run_backup`, along with what was done before it
+#[derive(Debug, Error)]
+#[error("{stage}: {source}")]
+pub struct BackupError {
+    /// The step which failed
+    pub stage: BackupStage,
+
+    /// The cause of the failure
+    pub source: Error,
+
+    /// What was done before the failure
+    pub report: Box<BackupReport>,
+}
+
+impl BackupError {
+    /// The process exit code for this error, as classified by
+    /// `Error::exit_code`
+    pub fn exit_code(&self) -> i32 { self.source.exit_code() }
+}
+
+/// Tags an error with the step of the pipeline which failed
+fn during(stage: BackupStage) -> impl FnOnce(Error) -> (BackupStage, Error) { move |e| (stage, e) }
+
+/// Runs the standard pipeline: copies new and changed files from the WhatsApp
+/// folder to the archive, removes old databases, backups and unwanted media
+/// from the archive, then in trim and sync modes deletes media from the
+/// WhatsApp folder and, in sync mode, restores media to it.
+///
+/// Both indices should already be configured, e.g. with hashing, progress
+/// observers or a cancellation token. Nothing is printed: messages describing
+/// each step are passed to the archive's observer instead. Nothing is done if
+/// the WhatsApp folder and archive overlap. If the token is cancelled, the run
+/// stops at the next file and the report is returned with `cancelled` set.
+///
+/// Running again with the same options and unchanged folders does nothing.
+pub fn run_backup(
+    wa_index: &mut FileIndex, archive_index: &mut FileIndex, mut options: BackupOptions,
+) -> Result<BackupReport, BackupError> {
+    let mut report = BackupReport::default();
+    if let Err(source) = FileIndex::check_not_overlapping(wa_index.path(), archive_index.path()) {
+        return Err(BackupError { stage: BackupStage::Mirror, source, report: Box::new(report) });
+    }
+    match run_steps(wa_index, archive_index, &mut options, &mut report) {
+        Ok(()) => Ok(report),
+        Err((stage, source)) => Err(BackupError { stage, source, report: Box::new(report) }),
+    }
+}
+
+/// Runs each step of `run_backup` in turn, recording what was done in
+/// `report`
+fn run_steps(
+    wa_index: &mut FileIndex, archive_index: &mut FileIndex, options: &mut BackupOptions, report: &mut BackupReport,
+) -> Result<(), (BackupStage, Error)> {
+    let archive_size = archive_index.size_bytes();
+    report.archive_bytes_before = Some(archive_size);
+    archive_index.message(&format!("Archive size is currently {}", bytefmt::format(archive_size)));
+
+    mirror_to_archive(options, wa_index, archive_index, report).map_err(during(BackupStage::Mirror))?;
+    if report.cancelled {
+        return Ok(());
+    }
+    clean_old_dbs_and_backups(options, archive_index).map_err(during(BackupStage::Tidy))?;
+    remove_unwanted_from_archive(options, wa_index, archive_index, report).map_err(during(BackupStage::Tidy))?;
+    if let Some(jobs) = options.manifest_jobs {
+        let manifest = archive_index.write_manifest(jobs).map_err(during(BackupStage::Manifest))?;
+        archive_index.message(&format!("Recorded {} files in manifest", manifest.len()));
+    }
+
+    let archive_size = archive_index.size_bytes();
+    let archive_stored_size = archive_index.stored_size_bytes();
+    report.archive_bytes_after = Some(archive_size);
+    if archive_stored_size == archive_size {
+        archive_index.message(&format!("Archive size is now {}", bytefmt::format(archive_size)));
+    } else {
+        archive_index.message(&format!(
+            "Archive size is now {} ({} on disk)",
+            bytefmt::format(archive_size),
+            bytefmt::format(archive_stored_size)
+        ));
+    }
+
+    if matches!(options.mode, BackupMode::Trim | BackupMode::Sync) {
+        if wa_index.is_cancelled() {
+            report.cancelled = true;
+            return Ok(());
+        }
+        trim_and_restore(options, wa_index, archive_index, report)?;
+    }
+    Ok(())
+}
+
+/// Copies new and changed files from the WhatsApp folder to the archive
+fn mirror_to_archive(
+    options: &BackupOptions, wa_index: &mut FileIndex, archive_index: &mut FileIndex, report: &mut BackupReport,
+) -> Result<(), Error> {
+    let busy = match options.wait_for_quiescent {
+        Some(max_wait) => wa_index.wait_for_quiescent_databases(options.db_quiet_period, max_wait),
+        None => wa_index.busy_databases(options.db_quiet_period),
+    }?;
+    for path in busy {
+        archive_index.message(&format!(
+            "Warning: {} was modified in the last few seconds and may be incomplete",
+            path.display()
+        ));
+    }
+    let wa_index = &*wa_index;
+    let candidates = match options.incremental_since {
+        _ if options.mode == BackupMode::DbOnly => wa_index.database_paths(),
+        None => wa_index.get_all_paths(),
+        Some(since) => {
+            let since = since.or_else(|| archive_index.latest_named_date()).unwrap_or(NaiveDate::MIN);
+            archive_index.message(&format!("Only considering media dated {} or later", since));
+            wa_index.new_files_since(since)
+        }
+    };
+    let empty: HashSet<PathBuf> = wa_index.empty_files().into_iter().collect();
+    if !empty.is_empty() {
+        let action = if options.skip_empty { "skipping" } else { "archiving" };
+        archive_index.message(&format!("Found {} empty media files in WhatsApp folder, {} them", empty.len(), action));
+    }
+    let too_new: HashSet<PathBuf> = options
+        .skip_newer_than
+        .map(|window| wa_index.modified_within(window))
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    if !too_new.is_empty() {
+        archive_index
+            .message(&format!("Deferring {} recently modified files in WhatsApp folder to a later run", too_new.len()));
+    }
+    // Media past the maximum archive age would only be pruned again
+    let too_old: HashSet<PathBuf> = options
+        .archive_max_age
+        .map(|age| wa_index.media_older_than(age, options.query.clock.now()))
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    // Databases and backups which cleanup would remove straight away are
+    // never copied, otherwise every run would copy and remove them again
+    let superseded: HashSet<PathBuf> = archive_index
+        .superseded_by_cleanup(wa_index, options.db_cleanup.then_some(options.num_kept_dbs), options.backup_cleanup)
+        .into_iter()
+        .collect();
+    if !superseded.is_empty() {
+        archive_index
+            .message(&format!("Not copying {} old databases and backups which cleanup would remove", superseded.len()));
+    }
+    // Statuses are ephemeral, so are only archived if requested
+    let to_mirror = candidates
+        .into_iter()
+        .filter(|path| options.include_statuses || MediaCategory::from_path(path) != MediaCategory::Status)
+        .filter(|path| !options.skip_empty || !empty.contains(path))
+        .filter(|path| !too_old.contains(path))
+        .filter(|path| !too_new.contains(path))
+        .filter(|path| !superseded.contains(path));
+    let plan = archive_index.plan_mirror(wa_index, to_mirror)?;
+    if !options.force {
+        archive_index.check_space_for(&plan)?;
+    }
+    let mirror_report = archive_index.execute_plan(wa_index, &plan)?;
+    archive_index.message(&format!("Transferred {} to archive", bytefmt::format(mirror_report.bytes_transferred)));
+    if mirror_report.folders_created > 0 {
+        archive_index.message(&format!("Created {} new folders in archive", mirror_report.folders_created));
+    }
+    if mirror_report.files_renamed > 0 {
+        archive_index.message(&format!("Copied {} renamed files within archive", mirror_report.files_renamed));
+    }
+    if mirror_report.files_too_large > 0 {
+        archive_index
+            .message(&format!("Skipped {} files larger than the maximum file size", mirror_report.files_too_large));
+    }
+    if let Some(date) = archive_index.newest_media_date() {
+        archive_index.message(&format!("Most recent archived media: {}", date));
+    }
+    report.files_copied = mirror_report.files_copied();
+    report.bytes_copied = mirror_report.bytes_transferred;
+    if mirror_report.cancelled {
+        report_interrupted(archive_index, "copying", "to archive", &mirror_report);
+        report.cancelled = true;
+    }
+    Ok(())
+}
+
+/// Summarises what was done by a mirror stopped early by cancellation
+fn report_interrupted(archive_index: &FileIndex, action: &str, destination: &str, report: &MirrorReport) {
+    archive_index.message(&format!(
+        "Interrupted after {} {} files ({}) {}",
+        action,
+        report.files_copied(),
+        bytefmt::format(report.bytes_transferred),
+        destination
+    ));
+}
+
+/// Removes old message databases and old versions of the files in the
+/// Backups folder from the archive, unless disabled
+fn clean_old_dbs_and_backups(options: &BackupOptions, archive_index: &mut FileIndex) -> Result<(), Error> {
+    if options.list_cleanup {
+        if options.backup_cleanup {
+            list_planned_removals(
+                archive_index,
+                "Old backups to remove from archive",
+                &archive_index.plan_backup_cleanup(),
+            );
+        }
+        if options.db_cleanup {
+            let planned = archive_index.plan_db_cleanup(options.num_kept_dbs);
+            list_planned_removals(archive_index, "Old message databases to remove from archive", &planned);
+        }
+    }
+    if options.backup_cleanup {
+        archive_index.clean_old_backups()?;
+    } else {
+        archive_index.message("Keeping all versions of backups in archive");
+    }
+    if options.db_cleanup {
+        archive_index.clean_old_dbs(options.num_kept_dbs)?;
+    } else {
+        archive_index.message("Keeping all message databases in archive");
+    }
+    Ok(())
+}
+
+/// Lists the paths of files about to be removed under a heading
+fn list_planned_removals(archive_index: &FileIndex, heading: &str, paths: &[PathBuf]) {
+    archive_index.message(&format!("{} ({}):", heading, paths.len()));
+    for path in paths {
+        archive_index.message(&format!("  {}", path.display()));
+    }
+}
+
+/// Removes media from the archive which is no longer in the WhatsApp folder
+/// if `mirror_delete` is set, or is older than `archive_max_age`, then
+/// removes empty folders if `compact` is set
+fn remove_unwanted_from_archive(
+    options: &BackupOptions, wa_index: &FileIndex, archive_index: &mut FileIndex, report: &mut BackupReport,
+) -> Result<(), Error> {
+    let size_before_removal = archive_index.size_bytes();
+    if options.mirror_delete {
+        let orphans = archive_index.remove_orphans(wa_index)?;
+        archive_index
+            .message(&format!("Removed {} files from archive which are no longer in WhatsApp folder", orphans.len()));
+        report.files_removed_from_archive = orphans.len();
+    }
+    if let Some(max_age) = options.archive_max_age.filter(|_| options.mode != BackupMode::DbOnly) {
+        let pruned = archive_index.prune_media_older_than(max_age, options.query.clock.now())?;
+        archive_index
+            .message(&format!("Pruned {} media files from archive older than the maximum archive age", pruned.len()));
+        report.files_removed_from_archive += pruned.len();
+    }
+    report.bytes_removed_from_archive = size_before_removal.saturating_sub(archive_index.size_bytes());
+    if options.compact {
+        let removed = archive_index.remove_empty_dirs()?;
+        archive_index.message(&format!("Removed {} empty folders from archive", removed.len()));
+    }
+    Ok(())
+}
+
+/// Trims media from the WhatsApp folder and, in sync mode, restores media from
+/// the archive
+fn trim_and_restore(
+    options: &mut BackupOptions, wa_index: &mut FileIndex, archive_index: &mut FileIndex, report: &mut BackupReport,
+) -> Result<(), (BackupStage, Error)> {
+    if let Some(listed) = &options.paths {
+        return apply_path_list(options, listed, wa_index, archive_index, report);
+    }
+    archive_index.message("\nTrimming files from WhatsApp folder...");
+    let wa_folder_size = wa_index.size_bytes();
+    archive_index.message(&format!("WhatsApp folder size is currently {}", bytefmt::format(wa_folder_size)));
+
+    let limit = options.query.data_limit;
+    let limit = match (options.min_free_after, wa_index.available_space()) {
+        (None, _) => limit,
+        (Some(min_free), Some(available)) => limit.min(DataLimit::for_free_space(wa_folder_size, available, min_free)),
+        (Some(_), None) => {
+            archive_index
+                .message("Unable to determine free space for WhatsApp folder, ignoring the minimum free space");
+            limit
+        }
+    };
+    // Reduce limit to account for non-media files in WhatsApp folder
+    let non_media_bytes = wa_index.non_media_size_bytes();
+    let limit = limit.map(|bytes| bytes.saturating_sub(non_media_bytes));
+    // When syncing, candidates are chosen from the archive, so media which
+    // has not been archived, such as statuses, must also be accounted for.
+    // Otherwise sync restores more than a trim to the same limit would keep.
+    let limit = if options.mode == BackupMode::Sync {
+        let unarchived = archive_index.filter_missing(&wa_index.get_all_paths());
+        let unarchived_bytes = wa_index.stats_for(&unarchived).media.bytes;
+        limit.map(|bytes| bytes.saturating_sub(unarchived_bytes))
+    } else {
+        limit
+    };
+    options.query.set_limit(limit);
+    let options = &*options;
+
+    let deletion_source = if options.mode == BackupMode::Sync { &*archive_index } else { &*wa_index };
+    let (delete_candidates, retain_candidates) = deletion_source.get_delete_retain_candidates(&options.query);
+    // Only files which have been archived are deleted, which excludes e.g.
+    // statuses if they were not archived. A file which changed since it was
+    // archived, e.g. one deferred as recently modified, is kept until its
+    // archived copy is up to date.
+    let archived = archive_index.filter_existing(&wa_index.filter_existing(&delete_candidates));
+    let delete_candidates = wa_index.filter_matching(archive_index, &archived);
+    if delete_candidates.len() < archived.len() {
+        let count = archived.len() - delete_candidates.len();
+        archive_index
+            .message(&format!("Keeping {} files in WhatsApp folder whose archived copies are out of date", count));
+    }
+    summarize_categories(archive_index, "Deleting", &wa_index.stats_for(&delete_candidates));
+    summarize_categories(archive_index, "Keeping", &deletion_source.stats_for(&retain_candidates));
+    delete_from_whatsapp(options, wa_index, archive_index, &delete_candidates, report)
+        .map_err(during(BackupStage::Trim))?;
+
+    if options.mode == BackupMode::Sync {
+        let restore_candidates = select_restore_candidates(options, wa_index, archive_index, &retain_candidates);
+        restore_to_whatsapp(wa_index, archive_index, &restore_candidates, report)
+            .map_err(during(BackupStage::Restore))?;
+    }
+    Ok(())
+}
+
+/// Describes the number and size of the media files in each category, if any
+fn summarize_categories(archive_index: &FileIndex, heading: &str, stats: &IndexStats) {
+    if stats.categories.is_empty() {
+        return;
+    }
+    archive_index.message(&format!("{:<16} {:>8} {:>10}", heading, "Files", "Size"));
+    for (category, category_stats) in &stats.categories {
+        archive_index.message(&format!(
+            "  {:<14} {:>8} {:>10}",
+            category.to_string(),
+            category_stats.count,
+            bytefmt::format(category_stats.bytes)
+        ));
+    }
+}
+
+/// Chooses which of the retained files missing from the WhatsApp folder are
+/// restored, according to `restore_newer_than` and `restore_only`
+fn select_restore_candidates(
+    options: &BackupOptions, wa_index: &FileIndex, archive_index: &FileIndex, retain_candidates: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut restore_candidates = wa_index.filter_missing(retain_candidates);
+    let mut notes = Vec::new();
+    if let Some(max_age) = options.restore_newer_than {
+        // Retained media this old stays in the archive only
+        let too_old: HashSet<_> =
+            archive_index.media_older_than(max_age, options.query.clock.now()).into_iter().collect();
+        let count = restore_candidates.len();
+        restore_candidates.retain(|path| !too_old.contains(path));
+        notes.push((count - restore_candidates.len(), "older than the restore age limit"));
+    }
+    if !options.restore_only.is_empty() {
+        let count = restore_candidates.len();
+        restore_candidates = FileIndex::filter_by_prefix(&restore_candidates, &options.restore_only);
+        notes.push((count - restore_candidates.len(), "outside the folders to restore"));
+    }
+    archive_index.message(&format!("\nRestoring {} files to WhatsApp folder...", restore_candidates.len()));
+    for (count, reason) in notes.into_iter().filter(|(count, _)| *count > 0) {
+        archive_index.message(&format!("Not restoring {} files {}", count, reason));
+    }
+    restore_candidates
+}
+
+/// Deletes archived files from the WhatsApp folder, keeping any which changed
+/// after indexing if `safe_trim` is set, or in parallel if `delete_jobs` is
+/// set. The files are recorded as trimmed in the archive first, so that
+/// `--mirror-delete` never removes their only remaining copy.
+fn delete_from_whatsapp(
+    options: &BackupOptions, wa_index: &mut FileIndex, archive_index: &FileIndex, delete_candidates: &[PathBuf],
+    report: &mut BackupReport,
+) -> Result<(), Error> {
+    archive_index.message(&format!("Deleting {} files from WhatsApp folder...", delete_candidates.len()));
+    archive_index.update_trimmed(delete_candidates, &[])?;
+    let changed = if options.safe_trim {
+        wa_index.remove_unchanged_files(delete_candidates)?
+    } else {
+        match options.delete_jobs {
+            Some(jobs) => wa_index.remove_files_parallel(delete_candidates, jobs)?,
+            None => wa_index.remove_files(delete_candidates)?,
+        }
+        Vec::new()
+    };
+    if !changed.is_empty() {
+        archive_index
+            .message(&format!("Kept {} files which changed after the WhatsApp folder was indexed", changed.len()));
+    }
+    report.files_trimmed = delete_candidates.len() - changed.len();
+    if !delete_candidates.is_empty() {
+        let wa_folder_size = wa_index.size_bytes();
+        archive_index.message(&format!("WhatsApp folder size is now {}", bytefmt::format(wa_folder_size)));
+    }
+    Ok(())
+}
+
+/// Copies files missing from the WhatsApp folder back from the archive. Any
+/// `.nomedia` markers created in the WhatsApp folder are archived too, so the
+/// next run has nothing new to copy.
+fn restore_to_whatsapp(
+    wa_index: &mut FileIndex, archive_index: &mut FileIndex, restore_candidates: &[PathBuf], report: &mut BackupReport,
+) -> Result<(), Error> {
+    let restore_report = wa_index.mirror_specified(archive_index, restore_candidates)?;
+    archive_index.update_trimmed(&[], &wa_index.filter_existing(restore_candidates))?;
+    report.files_restored = restore_report.files_copied();
+    report.bytes_restored = restore_report.bytes_transferred;
+    if restore_report.cancelled {
+        report_interrupted(archive_index, "restoring", "to WhatsApp folder", &restore_report);
+        report.cancelled = true;
+        return Ok(());
+    }
+    let markers = wa_index.restore_nomedia_markers(restore_candidates)?;
+    archive_index.mirror_specified(wa_index, wa_index.filter_existing(&markers))?;
+
+    if !restore_candidates.is_empty() {
+        archive_index
+            .message(&format!("Transferred {} to WhatsApp folder", bytefmt::format(restore_report.bytes_transferred)));
+        let wa_folder_size = wa_index.size_bytes();
+        archive_index.message(&format!("WhatsApp folder size is now {}", bytefmt::format(wa_folder_size)));
+    }
+    Ok(())
+}
+
+/// Deletes (in trim mode) or restores (in sync mode) exactly the listed
+/// files, reporting any which cannot be acted on
+fn apply_path_list(
+    options: &BackupOptions, listed: &[PathBuf], wa_index: &mut FileIndex, archive_index: &mut FileIndex,
+    report: &mut BackupReport,
+) -> Result<(), (BackupStage, Error)> {
+    let report_unknown = |candidates: &[PathBuf], known: &[PathBuf], reason: &str| {
+        let known: HashSet<_> = known.iter().collect();
+        for path in candidates.iter().filter(|path| !known.contains(path)) {
+            archive_index.message(&format!("Ignoring listed path {}: {}", path.display(), reason));
+        }
+    };
+    if options.mode == BackupMode::Trim {
+        archive_index.message(&format!("\nDeleting {} listed files from WhatsApp folder...", listed.len()));
+        let present = wa_index.filter_existing(listed);
+        report_unknown(listed, &present, "not in the WhatsApp folder");
+        // As when trimming, only files which have been archived are deleted
+        let archived = archive_index.filter_existing(&present);
+        report_unknown(&present, &archived, "not in the archive");
+        let current = wa_index.filter_matching(archive_index, &archived);
+        report_unknown(&archived, &current, "the archived copy is out of date");
+        delete_from_whatsapp(options, wa_index, archive_index, &current, report).map_err(during(BackupStage::Trim))
+    } else {
+        archive_index.message(&format!("\nRestoring {} listed files to WhatsApp folder...", listed.len()));
+        let archived = archive_index.filter_existing(listed);
+        report_unknown(listed, &archived, "not in the archive");
+        let missing = wa_index.filter_missing(&archived);
+        report_unknown(&archived, &missing, "already in the WhatsApp folder");
+        restore_to_whatsapp(wa_index, archive_index, &missing, report).map_err(during(BackupStage::Restore))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::test_util::{indices, whatsapp_folder, write_file, TempDir, TEST_MTIME};
+    use crate::{FileScore, FixedClock, Progress};
+
+    const IMAGE: &str = "Media/WhatsApp Images/IMG-20240101-WA0001.jpg";
+    const RECENT: &str = "Media/WhatsApp Images/IMG-20240101-WA0002.jpg";
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn now() -> i64 {
+        let elapsed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        i64::try_from(elapsed.as_secs()).unwrap()
+    }
+
+    /// Options deferring files modified within the last ten minutes
+    fn deferring_options(mode: BackupMode) -> BackupOptions {
+        let mut options = BackupOptions { mode, skip_newer_than: Some(Duration::from_mins(10)), ..Default::default() };
+        options.query.set_limit(DataLimit::from_bytes(0));
+        options
+    }
+
+    #[test]
+    fn recently_modified_file_is_deferred() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, RECENT, b"downloading", now());
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, deferring_options(BackupMode::Backup)).unwrap();
+
+        assert_eq!(report.files_copied, 2);
+        let archive = archive_index.path();
+        assert!(archive.join("Databases/msgstore.db.crypt14").exists());
+        assert!(archive.join(IMAGE).exists());
+        assert!(!archive.join(RECENT).exists());
+    }
+
+    #[test]
+    fn archived_file_modified_within_window_is_not_trimmed() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, RECENT, b"old contents", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, BackupOptions::default()).unwrap();
+
+        write_file(&wa, RECENT, b"new contents", now());
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, deferring_options(BackupMode::Trim)).unwrap();
+
+        assert_eq!(report.files_trimmed, 1);
+        assert!(!wa.join(IMAGE).exists());
+        assert_eq!(std::fs::read(wa.join(RECENT)).unwrap(), b"new contents");
+        assert_eq!(std::fs::read(archive_index.path().join(RECENT)).unwrap(), b"old contents");
+        assert!(wa_index.get_all_paths().contains(&Path::new(RECENT).to_path_buf()));
+    }
+
+    #[test]
+    fn mirror_delete_keeps_trimmed_media() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"trimmed", TEST_MTIME);
+        write_file(&wa, RECENT, b"deleted on phone", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, BackupOptions::default()).unwrap();
+
+        let listed = |mode| BackupOptions { mode, paths: Some(vec![PathBuf::from(IMAGE)]), ..Default::default() };
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, listed(BackupMode::Trim)).unwrap();
+        std::fs::remove_file(wa.join(RECENT)).unwrap();
+
+        let mirror = || BackupOptions { mirror_delete: true, ..Default::default() };
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, mirror()).unwrap();
+        assert_eq!(report.files_removed_from_archive, 1);
+        assert!(archive_index.path().join(IMAGE).exists());
+        assert!(!archive_index.path().join(RECENT).exists());
+
+        // Once restored, the file is no longer protected if deleted again
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, listed(BackupMode::Sync)).unwrap();
+        assert!(archive_index.read_trimmed().unwrap().is_empty());
+        std::fs::remove_file(wa.join(IMAGE)).unwrap();
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, mirror()).unwrap();
+        assert!(!archive_index.path().join(IMAGE).exists());
+    }
+
+    /// Returns the path, size and modification time of every file under
+    /// `root`, sorted by path
+    fn snapshot(root: &Path) -> Vec<(PathBuf, u64, i64)> {
+        let mut files = Vec::new();
+        let mut folders = vec![root.to_path_buf()];
+        while let Some(folder) = folders.pop() {
+            for entry in std::fs::read_dir(folder).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = path.metadata().unwrap();
+                if metadata.is_dir() {
+                    folders.push(path);
+                } else {
+                    let mtime = filetime::FileTime::from_last_modification_time(&metadata).unix_seconds();
+                    files.push((path.strip_prefix(root).unwrap().to_path_buf(), metadata.len(), mtime));
+                }
+            }
+        }
+        files.sort();
+        files
+    }
+
+    /// Creates a WhatsApp folder holding twelve dated database backups and
+    /// twelve photos of increasing size
+    fn populated_whatsapp_folder(root: &Path) -> PathBuf {
+        let wa = whatsapp_folder(root);
+        for day in 1..=12_u8 {
+            let mtime = TEST_MTIME + i64::from(day) * DAY;
+            write_file(&wa, &format!("Databases/msgstore-2024-01-{:02}.1.db.crypt14", day), b"database", mtime);
+            let image = format!("Media/WhatsApp Images/IMG-202401{:02}-WA0001.jpg", day);
+            write_file(&wa, &image, &vec![0; 100 * usize::from(day)], mtime);
+        }
+        wa
+    }
+
+    #[test]
+    fn repeated_runs_change_nothing() {
+        for mode in [BackupMode::DbOnly, BackupMode::Backup, BackupMode::Trim, BackupMode::Sync] {
+            let temp = TempDir::new();
+            populated_whatsapp_folder(temp.path());
+            let options = || {
+                let mut options = BackupOptions {
+                    mode,
+                    num_kept_dbs: 3,
+                    mirror_delete: mode == BackupMode::Backup,
+                    ..Default::default()
+                };
+                options.query.set_limit(DataLimit::from_bytes(2000));
+                options
+            };
+            let (mut wa_index, mut archive_index) = indices(temp.path());
+            run_backup(&mut wa_index, &mut archive_index, options()).unwrap();
+            let wa_before = snapshot(&temp.path().join("WhatsApp"));
+            let archive_before = snapshot(&temp.path().join("Archive"));
+
+            let (mut wa_index, mut archive_index) = indices(temp.path());
+            let report = run_backup(&mut wa_index, &mut archive_index, options()).unwrap();
+            assert_eq!(report.files_copied, 0, "{:?}", mode);
+            assert_eq!(report.files_removed_from_archive, 0, "{:?}", mode);
+            assert_eq!(report.files_trimmed, 0, "{:?}", mode);
+            assert_eq!(report.files_restored, 0, "{:?}", mode);
+            assert_eq!(snapshot(&temp.path().join("WhatsApp")), wa_before, "{:?}", mode);
+            assert_eq!(snapshot(&temp.path().join("Archive")), archive_before, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn databases_superseded_by_cleanup_are_not_copied() {
+        let dated = |day: u32| PathBuf::from(format!("Databases/msgstore-2024-01-{:02}.1.db.crypt14", day));
+        for db_cleanup in [true, false] {
+            let temp = TempDir::new();
+            populated_whatsapp_folder(temp.path());
+            let (mut wa_index, mut archive_index) = indices(temp.path());
+            let options = BackupOptions { mode: BackupMode::DbOnly, num_kept_dbs: 3, db_cleanup, ..Default::default() };
+            let report = run_backup(&mut wa_index, &mut archive_index, options).unwrap();
+
+            // Only the current database and the three newest dated backups
+            // are copied when cleanup would remove the rest
+            let copied = if db_cleanup { 4 } else { 13 };
+            assert_eq!(report.files_copied, copied);
+            assert_eq!(report.files_removed_from_archive, 0);
+            for day in 1..=12 {
+                assert_eq!(archive_index.path().join(dated(day)).exists(), !db_cleanup || day > 9, "{}", day);
+            }
+        }
+    }
+
+    /// Records the messages passed to it
+    #[derive(Debug)]
+    struct Messages(Rc<RefCell<Vec<String>>>);
+
+    impl Progress for Messages {
+        fn message(&mut self, message: &str) { self.0.borrow_mut().push(message.to_string()); }
+    }
+
+    #[test]
+    fn steps_are_described_to_the_archive_observer() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        archive_index.set_progress(Box::new(Messages(messages.clone())));
+        let mut options = BackupOptions { mode: BackupMode::Trim, ..Default::default() };
+        options.query.set_limit(DataLimit::from_bytes(0));
+        run_backup(&mut wa_index, &mut archive_index, options).unwrap();
+
+        let messages = messages.borrow();
+        assert!(messages.iter().any(|m| m.starts_with("Transferred ")), "{:?}", messages);
+        assert!(messages.iter().any(|m| m == "Deleting 1 files from WhatsApp folder..."), "{:?}", messages);
+    }
+
+    #[test]
+    fn archive_max_age_uses_query_clock() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        let at = |year| {
+            let mut options =
+                BackupOptions { archive_max_age: Some(chrono::Duration::days(365)), ..Default::default() };
+            let now = NaiveDate::from_ymd_opt(year, 6, 1).unwrap().and_hms_opt(0, 0, 0).unwrap().and_utc();
+            options.query.set_clock(Box::new(FixedClock(now)));
+            options
+        };
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, at(2024)).unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert!(archive_index.path().join(IMAGE).exists());
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, at(2026)).unwrap();
+        assert_eq!(report.files_copied, 0);
+        assert_eq!(report.files_removed_from_archive, 1);
+        assert!(!archive_index.path().join(IMAGE).exists());
+        assert!(wa.join(IMAGE).exists());
+    }
+
+    #[test]
+    fn nomedia_markers_survive_trim_and_are_restored_by_sync() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let sent = "Media/WhatsApp Images/Sent/IMG-20240101-WA0003.jpg";
+        let marker = "Media/WhatsApp Images/Sent/.nomedia";
+        write_file(&wa, sent, b"sent image", TEST_MTIME);
+        write_file(&wa, marker, b"", TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, BackupOptions::default()).unwrap();
+        assert!(archive_index.path().join(marker).exists());
+
+        let mut trim = BackupOptions { mode: BackupMode::Trim, ..Default::default() };
+        trim.query.set_limit(DataLimit::from_bytes(0));
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        run_backup(&mut wa_index, &mut archive_index, trim).unwrap();
+        assert!(!wa.join(sent).exists());
+        assert!(wa.join(marker).exists());
+
+        std::fs::remove_file(wa.join(marker)).unwrap();
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let sync = BackupOptions { mode: BackupMode::Sync, ..Default::default() };
+        run_backup(&mut wa_index, &mut archive_index, sync).unwrap();
+        assert!(wa.join(sent).exists());
+        assert!(wa.join(marker).exists());
+    }
+
+    #[test]
+    fn statuses_are_only_archived_if_requested() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let status = "Media/.Statuses/IMG-20240101-WA0001.jpg";
+        write_file(&wa, IMAGE, b"image contents", TEST_MTIME);
+        write_file(&wa, status, b"status", TEST_MTIME);
+        assert_eq!(MediaCategory::from_path(Path::new(status)), MediaCategory::Status);
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let report = run_backup(&mut wa_index, &mut archive_index, BackupOptions::default()).unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert!(archive_index.path().join(IMAGE).exists());
+        assert!(!archive_index.path().join(status).exists());
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let options = BackupOptions { include_statuses: true, ..Default::default() };
+        let report = run_backup(&mut wa_index, &mut archive_index, options).unwrap();
+        assert_eq!(report.files_copied, 1);
+        assert!(archive_index.path().join(status).exists());
+    }
+
+    #[test]
+    fn empty_media_is_reported_and_optionally_skipped() {
+        for skip_empty in [false, true] {
+            let temp = TempDir::new();
+            let wa = whatsapp_folder(temp.path());
+            let marker = "Media/WhatsApp Images/Sent/.nomedia";
+            write_file(&wa, IMAGE, b"", TEST_MTIME);
+            write_file(&wa, marker, b"", TEST_MTIME);
+            let (mut wa_index, mut archive_index) = indices(temp.path());
+            assert_eq!(wa_index.empty_files(), vec![PathBuf::from(IMAGE)]);
+            let messages = Rc::new(RefCell::new(Vec::new()));
+            archive_index.set_progress(Box::new(Messages(messages.clone())));
+            run_backup(&mut wa_index, &mut archive_index, BackupOptions { skip_empty, ..Default::default() }).unwrap();
+
+            let action = if skip_empty { "skipping" } else { "archiving" };
+            let expected = format!("Found 1 empty media files in WhatsApp folder, {} them", action);
+            assert!(messages.borrow().contains(&expected));
+            assert_eq!(archive_index.path().join(IMAGE).exists(), !skip_empty);
+            assert!(archive_index.path().join(marker).exists());
+        }
+    }
+
+    #[test]
+    fn restored_hidden_media_folders_get_nomedia_markers() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let sent = "Media/WhatsApp Images/Sent/IMG-20240101-WA0003.jpg";
+        let private = "Media/WhatsApp Video/Private/VID-20240101-WA0001.mp4";
+        for name in [IMAGE, sent, private] {
+            write_file(&wa, name, b"contents", TEST_MTIME);
+        }
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let mut trim = BackupOptions { mode: BackupMode::Trim, ..Default::default() };
+        trim.query.set_limit(DataLimit::from_bytes(0));
+        run_backup(&mut wa_index, &mut archive_index, trim).unwrap();
+        assert!(!wa.join(sent).exists());
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let sync = BackupOptions { mode: BackupMode::Sync, ..Default::default() };
+        run_backup(&mut wa_index, &mut archive_index, sync).unwrap();
+        for marker in ["Media/WhatsApp Images/Sent/.nomedia", "Media/WhatsApp Video/Private/.nomedia"] {
+            assert!(wa.join(marker).exists());
+            assert!(archive_index.path().join(marker).exists());
+        }
+        assert!(wa.join(IMAGE).exists());
+        assert!(!wa.join("Media/WhatsApp Images/.nomedia").exists());
+    }
+
+    #[test]
+    fn candidates_are_summarized_by_category() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        write_file(&wa, IMAGE, &[0; 100], TEST_MTIME);
+        write_file(&wa, RECENT, &[0; 100], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Video/VID-20240101-WA0001.mp4", &[0; 300], TEST_MTIME);
+        write_file(&wa, "Media/WhatsApp Voice Notes/202401/PTT-20240101-WA0001.opus", &[0; 50], TEST_MTIME);
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        archive_index.set_progress(Box::new(Messages(messages.clone())));
+        let mut options = BackupOptions { mode: BackupMode::Trim, ..Default::default() };
+        options.query.set_order(FileScore::Smaller);
+        options.query.set_limit(DataLimit::from_bytes(158));
+        run_backup(&mut wa_index, &mut archive_index, options).unwrap();
+
+        // The limit includes the 8 byte database, so the video and one image
+        // are deleted to fit
+        let line = |category: MediaCategory, count: usize, bytes: u64| {
+            format!("  {:<14} {:>8} {:>10}", category.to_string(), count, bytefmt::format(bytes))
+        };
+        let messages = messages.borrow();
+        let deleting = messages.iter().position(|m| m.starts_with("Deleting")).unwrap();
+        let keeping = messages.iter().position(|m| m.starts_with("Keeping")).unwrap();
+        assert_eq!(
+            messages[deleting + 1..keeping],
+            [line(MediaCategory::Images, 1, 100), line(MediaCategory::Video, 1, 300)]
+        );
+        assert_eq!(
+            messages[keeping + 1..keeping + 3],
+            [line(MediaCategory::Images, 1, 100), line(MediaCategory::VoiceNotes, 1, 50)]
+        );
+    }
+
+    /// Backs up the files in the WhatsApp folder then trims them all from it
+    fn trim_everything(root: &Path) {
+        let (mut wa_index, mut archive_index) = indices(root);
+        let mut trim = BackupOptions { mode: BackupMode::Trim, ..Default::default() };
+        trim.query.set_limit(DataLimit::from_bytes(0));
+        run_backup(&mut wa_index, &mut archive_index, trim).unwrap();
+    }
+
+    #[test]
+    fn old_retained_media_is_not_restored() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let recent = "Media/WhatsApp Images/IMG-20240225-WA0001.jpg";
+        write_file(&wa, IMAGE, b"old image", TEST_MTIME);
+        write_file(&wa, recent, b"recent image", TEST_MTIME);
+        trim_everything(temp.path());
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        archive_index.set_progress(Box::new(Messages(messages.clone())));
+        let mut sync = BackupOptions {
+            mode: BackupMode::Sync,
+            restore_newer_than: Some(chrono::Duration::days(30)),
+            ..Default::default()
+        };
+        sync.query.set_clock(Box::new(FixedClock("2024-03-01T00:00:00Z".parse().unwrap())));
+        let report = run_backup(&mut wa_index, &mut archive_index, sync).unwrap();
+
+        assert_eq!(report.files_restored, 1);
+        assert!(wa.join(recent).exists());
+        assert!(!wa.join(IMAGE).exists());
+        assert!(archive_index.path().join(IMAGE).exists());
+        assert!(messages.borrow().contains(&"Not restoring 1 files older than the restore age limit".to_string()));
+    }
+
+    #[test]
+    fn only_files_under_given_prefixes_are_restored() {
+        let temp = TempDir::new();
+        let wa = whatsapp_folder(temp.path());
+        let family = "Media/WhatsApp Images/Family/IMG-20240101-WA0003.jpg";
+        write_file(&wa, IMAGE, b"image", TEST_MTIME);
+        write_file(&wa, family, b"family image", TEST_MTIME);
+        trim_everything(temp.path());
+
+        let (mut wa_index, mut archive_index) = indices(temp.path());
+        let restore_only = vec![PathBuf::from("Media/WhatsApp Images/Family")];
+        let sync = BackupOptions { mode: BackupMode::Sync, restore_only, ..Default::default() };
+        let report = run_backup(&mut wa_index, &mut archive_index, sync).unwrap();
+        assert_eq!(report.files_restored, 1);
+        assert!(wa.join(family).exists());
+        assert!(!wa.join(IMAGE).exists());
+    }
+    #[test]
+    fn cleanup_of_databases_and_backups_can_be_disabled() {
+        let backups = ["Backups/stickers.db.crypt1", "Backups/stickers.db.crypt14"];
+        for (db_cleanup, backup_cleanup) in [(true, true), (false, true), (true, false), (false, false)] {
+            let temp = TempDir::new();
+            let wa = populated_whatsapp_folder(temp.path());
+            write_file(&wa, backups[0], b"backup", TEST_MTIME);
+            write_file(&wa, backups[1], b"backup", TEST_MTIME + DAY);
+            let (mut wa_index, mut archive_index) = indices(temp.path());
+            let options = BackupOptions { num_kept_dbs: 2, db_cleanup, backup_cleanup, ..Default::default() };
+            run_backup(&mut wa_index, &mut archive_index, options).unwrap();
+
+            let archive = temp.path().join("Archive");
+            let dbs = archive.join("Databases").read_dir().unwrap().count();
+            // The current database and either all twelve dated ones or the
+            // two kept
+            assert_eq!(dbs, if db_cleanup { 3 } else { 13 }, "{} {}", db_cleanup, backup_cleanup);
+            assert_eq!(archive.join(backups[0]).exists(), !backup_cleanup, "{} {}", db_cleanup, backup_cleanup);
+            assert!(archive.join(backups[1]).exists());
+        }
+    }
+}
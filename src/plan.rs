@@ -0,0 +1,117 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::FileInfo;
+
+/// The changes required to mirror files from one index into another. The
+/// files in each part of the plan are in path order, so the same files are
+/// always copied in the same order.
+#[derive(Clone, Debug, Default)]
+pub struct MirrorPlan {
+    /// Files not present in the destination
+    pub(crate) missing: Vec<(PathBuf, FileInfo)>,
+
+    /// Files present in the destination whose contents need to be replaced
+    pub(crate) changed: Vec<(PathBuf, FileInfo)>,
+
+    /// Files present in the destination with identical contents, but whose
+    /// metadata needs updating
+    pub(crate) metadata_only: Vec<(PathBuf, FileInfo)>,
+
+    /// Files which would otherwise be copied but exceed the maximum file size
+    pub(crate) too_large: Vec<(PathBuf, FileInfo)>,
+
+    /// Files missing from the destination whose contents match media there
+    /// which is no longer in the source, so they will be copied from that
+    /// file rather than from the source. Each entry is the path of the
+    /// existing file, followed by the new path and its metadata in the
+    /// source.
+    pub(crate) renamed: Vec<(PathBuf, PathBuf, FileInfo)>,
+
+    /// Files present in the destination whose contents were verified or
+    /// previously confirmed to match the source
+    pub(crate) verified: Vec<PathBuf>,
+
+    /// Folders which do not yet exist in the destination and will be created
+    /// to hold missing files
+    pub(crate) new_folders: BTreeSet<PathBuf>,
+}
+
+impl MirrorPlan {
+    /// Files which will be copied because they are missing from the
+    /// destination, along with their metadata in the source
+    pub fn missing(&self) -> impl Iterator<Item = (&Path, &FileInfo)> { Self::iter(&self.missing) }
+
+    /// Files which will be copied because they differ from the destination,
+    /// along with their metadata in the source
+    pub fn changed(&self) -> impl Iterator<Item = (&Path, &FileInfo)> { Self::iter(&self.changed) }
+
+    /// Files which will only have their metadata updated
+    pub fn metadata_only(&self) -> impl Iterator<Item = (&Path, &FileInfo)> { Self::iter(&self.metadata_only) }
+
+    /// Files which will not be copied because they exceed the maximum file
+    /// size, along with their metadata in the source
+    pub fn too_large(&self) -> impl Iterator<Item = (&Path, &FileInfo)> { Self::iter(&self.too_large) }
+
+    /// Files which will be copied within the destination because they were
+    /// renamed in the source, as the existing path, the new path and the
+    /// metadata in the source
+    pub fn renamed(&self) -> impl Iterator<Item = (&Path, &Path, &FileInfo)> {
+        self.renamed.iter().map(|(from, to, info)| (from.as_path(), to.as_path(), info))
+    }
+
+    /// Folders, relative to the destination root, which will be created to
+    /// hold missing or renamed files, in path order. Parents are listed before
+    /// their children.
+    pub fn new_folders(&self) -> impl Iterator<Item = &Path> { self.new_folders.iter().map(PathBuf::as_path) }
+
+    /// The number of bytes which will be copied
+    pub fn bytes(&self) -> u64 { self.missing().chain(self.changed()).map(|(_, info)| info.get_size()).sum() }
+
+    /// The number of files which will be copied
+    pub fn file_count(&self) -> usize { self.missing.len() + self.changed.len() }
+
+    /// Returns `true` if the plan makes no changes
+    pub fn is_empty(&self) -> bool {
+        self.file_count() == 0 && self.metadata_only.is_empty() && self.renamed.is_empty()
+    }
+
+    fn iter(entries: &[(PathBuf, FileInfo)]) -> impl Iterator<Item = (&Path, &FileInfo)> {
+        entries.iter().map(|(path, info)| (path.as_path(), info))
+    }
+}
+
+/// What a mirror operation did
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MirrorReport {
+    /// The number of files copied because they were missing
+    pub files_added: usize,
+
+    /// The number of files copied because their contents had changed
+    pub files_updated: usize,
+
+    /// The number of files which only had their metadata updated
+    pub metadata_updated: usize,
+
+    /// The total size of all files copied in bytes
+    pub bytes_transferred: u64,
+
+    /// The number of files not copied because they exceeded the maximum file
+    /// size
+    pub files_too_large: usize,
+
+    /// The number of folders created to hold missing files
+    pub folders_created: usize,
+
+    /// The number of files copied within the destination rather than from the
+    /// source because they were renamed in the source
+    pub files_renamed: usize,
+
+    /// Whether the operation was cancelled before every file was processed
+    pub cancelled: bool,
+}
+
+impl MirrorReport {
+    /// The number of files copied
+    pub fn files_copied(&self) -> usize { self.files_added + self.files_updated }
+}
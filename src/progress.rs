@@ -0,0 +1,45 @@
+use std::fmt::Debug;
+use std::path::Path;
+
+/// Receives notifications about the progress of file copies, about files
+/// deleted or not copied, and messages describing each step taken. The
+/// library prints nothing itself, so these messages are the only record of
+/// what was done beyond the reports returned.
+///
+/// All methods have empty default implementations so implementors only need
+/// to handle the events they are interested in.
+pub trait Progress: Debug {
+    /// Called before a mirror operation copies any files with the total number
+    /// of bytes and files that are expected to be copied
+    fn mirror_started(&mut self, _total_bytes: u64, _total_files: usize) {}
+
+    /// Called when a file copy begins
+    fn file_started(&mut self, _path: &Path, _size: u64) {}
+
+    /// Called each time a chunk of the current file has been copied
+    fn bytes_copied(&mut self, _count: u64) {}
+
+    /// Called when a file copy completes
+    fn file_finished(&mut self, _path: &Path) {}
+
+    /// Called when a mirror operation stops, whether it completed, was
+    /// cancelled or failed. A file started but not finished was not copied.
+    fn mirror_finished(&mut self) {}
+
+    /// Called when a file is deleted, or would be in dry-run mode
+    fn file_deleted(&mut self, _path: &Path, _size: u64) {}
+
+    /// Called when a file is not copied because it exceeds the maximum file
+    /// size
+    fn file_too_large(&mut self, _path: &Path, _size: u64) {}
+
+    /// Called with a line of text describing a step taken, e.g. how many
+    /// files were copied
+    fn message(&mut self, _message: &str) {}
+}
+
+/// A `Progress` implementation which ignores all events
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+/// Name of the file in the root of an archive recording the progress of an
+/// interrupted mirror operation
+pub(crate) const RESUME_NAME: &str = ".waa-resume";
+
+/// Prefix of the lines in a resume marker which record a completed phase
+const PHASE_PREFIX: &str = "# phase: ";
+
+/// The stages of a mirror operation recorded in a resume marker once complete
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ResumePhase {
+    /// The contents of files already in the archive were verified
+    Verified,
+
+    /// The metadata of unchanged files was updated
+    MetadataUpdated,
+}
+
+impl ResumePhase {
+    fn name(self) -> &'static str {
+        match self {
+            ResumePhase::Verified => "verified",
+            ResumePhase::MetadataUpdated => "metadata-updated",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<ResumePhase> {
+        [ResumePhase::Verified, ResumePhase::MetadataUpdated].into_iter().find(|phase| phase.name() == name)
+    }
+}
+
+impl fmt::Display for ResumePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.name()) }
+}
+
+/// The state left behind by a mirror operation which did not complete.
+///
+/// Each line of the marker is either a phase record or the path of a file,
+/// relative to the archive root, whose archived copy is known to match the
+/// source. Paths which are not valid UTF-8 are recorded using their lossy
+/// conversion and so never match, meaning those files are always verified
+/// again.
+#[derive(Clone, Debug)]
+pub(crate) struct ResumeMarker {
+    phase: ResumePhase,
+    confirmed: HashSet<PathBuf>,
+}
+
+impl ResumeMarker {
+    /// Loads the marker at `path`. Returns `None` if there is no marker or it
+    /// was interrupted before recording its first phase.
+    pub fn load(path: &Path) -> Result<Option<ResumeMarker>, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err((e, path).into()),
+        };
+        let mut phase = None;
+        let mut confirmed = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| (e, path))?;
+            if let Some(name) = line.strip_prefix(PHASE_PREFIX) {
+                phase = ResumePhase::from_name(name).or(phase);
+            } else if !line.is_empty() {
+                confirmed.insert(PathBuf::from(line));
+            }
+        }
+        Ok(phase.map(|phase| ResumeMarker { phase, confirmed }))
+    }
+
+    /// The last phase the interrupted operation completed
+    pub fn phase(&self) -> ResumePhase { self.phase }
+
+    /// The number of files known to match the source
+    pub fn len(&self) -> usize { self.confirmed.len() }
+
+    /// Returns `true` if the archived copy of `path` is known to match the
+    /// source
+    pub fn contains(&self, path: &Path) -> bool { self.confirmed.contains(path) }
+}
+
+/// Records progress to a resume marker while a mirror operation runs. Each
+/// record is flushed immediately so the marker is up to date whenever the
+/// operation is interrupted.
+#[derive(Debug)]
+pub(crate) struct ResumeWriter {
+    file: File,
+    path: PathBuf,
+}
+
+impl ResumeWriter {
+    /// Replaces any marker at `path` with one recording `phase` and the
+    /// supplied confirmed files
+    pub fn create<'a, I: IntoIterator<Item = &'a Path>>(
+        path: &Path, phase: ResumePhase, confirmed: I,
+    ) -> Result<ResumeWriter, Error> {
+        let file = File::create(path).map_err(|e| (e, path))?;
+        let mut writer = ResumeWriter { file, path: path.to_owned() };
+        let mut contents = format!("{}{}\n", PHASE_PREFIX, phase);
+        for rel_path in confirmed {
+            contents.push_str(&rel_path.to_string_lossy());
+            contents.push('\n');
+        }
+        writer.append(&contents)?;
+        Ok(writer)
+    }
+
+    /// Records that `phase` has completed
+    pub fn phase(&mut self, phase: ResumePhase) -> Result<(), Error> {
+        self.append(&format!("{}{}\n", PHASE_PREFIX, phase))
+    }
+
+    /// Records that the archived copy of `path` matches the source
+    pub fn confirm(&mut self, path: &Path) -> Result<(), Error> {
+        self.append(&format!("{}\n", path.to_string_lossy()))
+    }
+
+    /// Removes the marker once the operation has completed
+    pub fn finish(self) -> Result<(), Error> {
+        drop(self.file);
+        std::fs::remove_file(&self.path).map_err(|e| (e, &self.path))?;
+        Ok(())
+    }
+
+    fn append(&mut self, contents: &str) -> Result<(), Error> {
+        self.file.write_all(contents.as_bytes()).and_then(|()| self.file.flush()).map_err(|e| (e, &self.path))?;
+        Ok(())
+    }
+}
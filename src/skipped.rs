@@ -0,0 +1,40 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why a directory entry was left out of an index
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// A symbolic link, which is only followed if requested
+    Symlink,
+
+    /// A symbolic link whose target does not exist
+    BrokenSymlink,
+
+    /// A symbolic link to a folder which had already been indexed
+    SymlinkLoop,
+
+    /// Neither a file nor a folder, e.g. a FIFO or device
+    Special,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            SkipReason::Symlink => "symbolic link",
+            SkipReason::BrokenSymlink => "broken symbolic link",
+            SkipReason::SymlinkLoop => "symbolic link to a folder already indexed",
+            SkipReason::Special => "not a file or folder",
+        };
+        f.write_str(description)
+    }
+}
+
+/// A directory entry which was left out of an index
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SkippedEntry {
+    /// The full path of the entry
+    pub path: PathBuf,
+
+    /// Why the entry was skipped
+    pub reason: SkipReason,
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDateTime;
+
+use crate::{FileInfo, MediaCategory};
+
+/// Aggregate statistics over a set of files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FileStats {
+    /// The number of files
+    pub count: usize,
+
+    /// The total size of the files in bytes
+    pub bytes: u64,
+
+    /// The earliest estimated creation date of any file
+    pub oldest: Option<NaiveDateTime>,
+
+    /// The latest estimated creation date of any file
+    pub newest: Option<NaiveDateTime>,
+}
+
+impl FileStats {
+    /// Includes a file in the statistics
+    pub fn add(&mut self, info: &FileInfo) {
+        let created = info.estimate_creation_date();
+        self.count += 1;
+        self.bytes += info.get_size();
+        self.oldest = Some(self.oldest.map_or(created, |oldest| oldest.min(created)));
+        self.newest = Some(self.newest.map_or(created, |newest| newest.max(created)));
+    }
+}
+
+/// A breakdown of where the space in an index is going
+#[derive(Clone, Debug, Default)]
+pub struct IndexStats {
+    /// Statistics over all media files
+    pub media: FileStats,
+
+    /// Statistics over all other files, e.g. databases and backups
+    pub non_media: FileStats,
+
+    /// Statistics over media files in each category. Categories without any
+    /// files are absent.
+    pub categories: BTreeMap<MediaCategory, FileStats>,
+}
+
+impl IndexStats {
+    /// The total size of all files in bytes
+    pub fn total_bytes(&self) -> u64 { self.media.bytes + self.non_media.bytes }
+
+    /// The total number of files
+    pub fn total_count(&self) -> usize { self.media.count + self.non_media.count }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// The version of the archive format written by this version of `waa`.
+/// Version 1 archives hold only files stored verbatim, while version 2
+/// archives may also hold files stored compressed or encrypted, which older
+/// versions of `waa` cannot read.
+pub(crate) const ARCHIVE_VERSION: u32 = 2;
+
+/// Contents of the tag file marking a folder as an archive. Archives created
+/// before the format was versioned have an empty tag file, which is treated as
+/// version 0.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub(crate) struct ArchiveTag {
+    pub version: u32,
+}
+
+impl ArchiveTag {
+    /// Reads the tag at `path`, failing if the archive is too new to be
+    /// understood
+    pub(crate) fn read(path: &Path) -> Result<ArchiveTag, Error> {
+        let contents = std::fs::read(path).map_err(|e| (e, path))?;
+        let tag = if contents.iter().all(u8::is_ascii_whitespace) {
+            ArchiveTag { version: 0 }
+        } else {
+            serde_json::from_slice(&contents).map_err(|e| (io::Error::from(e), path))?
+        };
+        if tag.version > ARCHIVE_VERSION {
+            return Err(Error::UnsupportedArchiveVersion(tag.version));
+        }
+        Ok(tag)
+    }
+
+    /// Writes a tag for the current format version to `path`
+    pub(crate) fn write_current(path: &Path) -> Result<(), Error> {
+        let contents =
+            serde_json::to_string(&ArchiveTag { version: ARCHIVE_VERSION }).expect("Unable to serialize tag");
+        std::fs::write(path, contents).map_err(|e| (e, path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn read_tag(contents: &str) -> Result<ArchiveTag, Error> {
+        let temp = TempDir::new();
+        let path = temp.path().join(".waa");
+        std::fs::write(&path, contents).unwrap();
+        ArchiveTag::read(&path)
+    }
+
+    #[test]
+    fn empty_tag_is_version_zero() {
+        assert_eq!(read_tag("").unwrap().version, 0);
+        assert_eq!(read_tag("\n").unwrap().version, 0);
+    }
+
+    #[test]
+    fn supported_versions_are_read() {
+        assert_eq!(read_tag(r#"{"version":1}"#).unwrap().version, 1);
+        assert_eq!(read_tag(r#"{"version":2}"#).unwrap().version, 2);
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let newer = ARCHIVE_VERSION + 1;
+        assert!(matches!(
+            read_tag(&format!(r#"{{"version":{}}}"#, newer)),
+            Err(Error::UnsupportedArchiveVersion(v)) if v == newer
+        ));
+    }
+
+    #[test]
+    fn current_version_is_written() {
+        let temp = TempDir::new();
+        let path = temp.path().join(".waa");
+        ArchiveTag::write_current(&path).unwrap();
+        assert_eq!(ArchiveTag::read(&path).unwrap().version, ARCHIVE_VERSION);
+    }
+}
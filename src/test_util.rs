@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use filetime::FileTime;
+
+use crate::{ActionType, FileIndex, IndexType};
+
+/// Modification time given to test files unless a test needs another,
+/// 2024-01-01 00:00:00 UTC
+pub(crate) const TEST_MTIME: i64 = 1_704_067_200;
+
+/// Makes `chrono::Local` use the rules for Sydney, where daylight saving time
+/// starts at 02:00 on the first Sunday of October and ends at 03:00 on the
+/// first Sunday of April. Every test which depends on the local timezone sets
+/// the same value, so tests running concurrently agree.
+pub(crate) fn use_sydney_time() { std::env::set_var("TZ", "AEST-10AEDT,M10.1.0,M4.1.0/3"); }
+
+/// Runs `f` with `dir` as the current directory, restoring the previous one
+/// afterwards. The current directory is shared by every test, so only one
+/// test at a time may change it.
+pub(crate) fn in_current_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+    static CURRENT_DIR: Mutex<()> = Mutex::new(());
+    let _guard = CURRENT_DIR.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let previous = std::env::current_dir().expect("Unable to get current directory");
+    std::env::set_current_dir(dir).expect("Unable to change current directory");
+    let result = f();
+    std::env::set_current_dir(previous).expect("Unable to restore current directory");
+    result
+}
+
+/// A uniquely named folder in the system temporary folder which is removed
+/// along with its contents when dropped
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("waa-test-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).expect("Unable to create temporary folder");
+        TempDir { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.path); }
+}
+
+/// Writes `contents` to `relative_path` within `root`, creating any missing
+/// folders, and sets its modification time to `mtime` seconds since the epoch
+pub(crate) fn write_file(root: &Path, relative_path: &str, contents: &[u8], mtime: i64) -> PathBuf {
+    let path = root.join(relative_path);
+    std::fs::create_dir_all(path.parent().expect("Test file has no parent")).expect("Unable to create folder");
+    std::fs::write(&path, contents).expect("Unable to write test file");
+    filetime::set_file_mtime(&path, FileTime::from_unix_time(mtime, 0)).expect("Unable to set modification time");
+    path
+}
+
+/// Creates a minimal WhatsApp folder named `WhatsApp` within `root`, holding
+/// only a message database, and returns its path
+pub(crate) fn whatsapp_folder(root: &Path) -> PathBuf {
+    let path = root.join("WhatsApp");
+    write_file(&path, "Databases/msgstore.db.crypt14", b"database", TEST_MTIME);
+    path
+}
+
+/// Indexes the WhatsApp folder created by `whatsapp_folder` and the archive
+/// named `Archive` within `root`, creating the archive if necessary
+pub(crate) fn indices(root: &Path) -> (FileIndex, FileIndex) {
+    let wa_index = FileIndex::new(IndexType::Original, root.join("WhatsApp"), ActionType::Real)
+        .expect("Unable to index WhatsApp folder");
+    let archive_index =
+        FileIndex::new(IndexType::Archive, root.join("Archive"), ActionType::Real).expect("Unable to index archive");
+    (wa_index, archive_index)
+}
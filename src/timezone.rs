@@ -0,0 +1,104 @@
+use chrono::{DateTime, FixedOffset, Local, LocalResult, NaiveDateTime, Offset, TimeDelta, TimeZone, Utc};
+
+/// The timezone in which dates are interpreted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Timezone {
+    /// A fixed offset from UTC
+    Fixed(FixedOffset),
+
+    /// The timezone of this machine. The offset is looked up for each time
+    /// converted, so times either side of a daylight saving change are each
+    /// converted using the offset in effect at the time.
+    Local,
+}
+
+impl Timezone {
+    /// The offset from UTC in effect at `time`
+    pub fn offset_at(self, time: DateTime<Utc>) -> FixedOffset {
+        match self {
+            Timezone::Fixed(offset) => offset,
+            Timezone::Local => time.with_timezone(&Local).offset().fix(),
+        }
+    }
+
+    /// Converts `time` to the local time in this timezone
+    pub fn local_time(self, time: DateTime<Utc>) -> NaiveDateTime {
+        time.with_timezone(&self.offset_at(time)).naive_local()
+    }
+
+    /// Converts the local time `local` in this timezone to UTC. A local time
+    /// repeated when daylight saving time ends is taken to be the earlier
+    /// one, and one skipped when it starts is converted using the offset in
+    /// effect before the change.
+    pub fn to_utc(self, local: NaiveDateTime) -> DateTime<Utc> {
+        match self {
+            Timezone::Fixed(offset) => (local - offset).and_utc(),
+            Timezone::Local => match Local.from_local_datetime(&local) {
+                LocalResult::Single(time) => time.to_utc(),
+                // The order of the candidates is unspecified
+                LocalResult::Ambiguous(a, b) => a.to_utc().min(b.to_utc()),
+                LocalResult::None => self.to_utc(local - TimeDelta::hours(1)) + TimeDelta::hours(1),
+            },
+        }
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Timezone { Timezone::Fixed(Utc.fix()) }
+}
+
+impl From<FixedOffset> for Timezone {
+    fn from(offset: FixedOffset) -> Timezone { Timezone::Fixed(offset) }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::test_util::use_sydney_time;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn naive(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap().and_hms_opt(h, min, 0).unwrap()
+    }
+
+    #[test]
+    fn fixed_offset_ignores_daylight_saving() {
+        let timezone = Timezone::from(FixedOffset::east_opt(10 * 60 * 60).unwrap());
+        assert_eq!(timezone.local_time(utc(2024, 1, 1, 0, 0)), naive(2024, 1, 1, 10, 0));
+        assert_eq!(timezone.local_time(utc(2024, 7, 1, 0, 0)), naive(2024, 7, 1, 10, 0));
+        assert_eq!(Timezone::default().local_time(utc(2024, 7, 1, 0, 0)), naive(2024, 7, 1, 0, 0));
+    }
+
+    #[test]
+    fn local_times_are_converted_back_to_utc() {
+        use_sydney_time();
+        let fixed = Timezone::from(FixedOffset::east_opt(-5 * 60 * 60).unwrap());
+        assert_eq!(fixed.to_utc(naive(2024, 1, 1, 19, 0)), utc(2024, 1, 2, 0, 0));
+        assert_eq!(Timezone::Local.to_utc(naive(2024, 1, 1, 11, 0)), utc(2024, 1, 1, 0, 0));
+        assert_eq!(Timezone::Local.to_utc(naive(2024, 7, 1, 10, 0)), utc(2024, 7, 1, 0, 0));
+        // 02:30 is skipped when daylight saving time starts and repeated when
+        // it ends
+        assert_eq!(Timezone::Local.to_utc(naive(2024, 10, 6, 2, 30)), utc(2024, 10, 5, 16, 30));
+        assert_eq!(Timezone::Local.to_utc(naive(2024, 4, 7, 2, 30)), utc(2024, 4, 6, 15, 30));
+    }
+
+    #[test]
+    fn local_offset_changes_at_daylight_saving_boundaries() {
+        use_sydney_time();
+        // Daylight saving starts at 02:00 AEST on 6 October 2024, i.e. 16:00
+        // UTC the previous day
+        assert_eq!(Timezone::Local.local_time(utc(2024, 10, 5, 15, 59)), naive(2024, 10, 6, 1, 59));
+        assert_eq!(Timezone::Local.local_time(utc(2024, 10, 5, 16, 0)), naive(2024, 10, 6, 3, 0));
+        // Daylight saving ends at 03:00 AEDT on 7 April 2024, i.e. 16:00 UTC
+        // the previous day, so the hour from 02:00 is repeated
+        assert_eq!(Timezone::Local.local_time(utc(2024, 4, 6, 15, 59)), naive(2024, 4, 7, 2, 59));
+        assert_eq!(Timezone::Local.local_time(utc(2024, 4, 6, 16, 0)), naive(2024, 4, 7, 2, 0));
+        assert_eq!(Timezone::Local.offset_at(utc(2024, 1, 1, 0, 0)), FixedOffset::east_opt(11 * 60 * 60).unwrap());
+        assert_eq!(Timezone::Local.offset_at(utc(2024, 7, 1, 0, 0)), FixedOffset::east_opt(10 * 60 * 60).unwrap());
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+/// Name of the file in the root of an archive recording which archived files
+/// were trimmed from the WhatsApp folder
+pub(crate) const TRIMMED_NAME: &str = ".waa-trimmed";
+
+/// Loads the paths, relative to the archive root, recorded in the trimmed
+/// record at `path`. A missing record is empty.
+pub(crate) fn load(path: &Path) -> Result<BTreeSet<PathBuf>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err((e, path).into()),
+    };
+    let mut trimmed = BTreeSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| (e, path))?;
+        if !line.is_empty() {
+            trimmed.insert(PathBuf::from(line));
+        }
+    }
+    Ok(trimmed)
+}
+
+/// Replaces the trimmed record at `path` with one listing `trimmed`, or
+/// removes it if `trimmed` is empty. Paths which are not valid UTF-8 are
+/// recorded using their lossy conversion and so are never protected.
+pub(crate) fn save(path: &Path, trimmed: &BTreeSet<PathBuf>) -> Result<(), Error> {
+    if trimmed.is_empty() {
+        return match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err((e, path).into()),
+            _ => Ok(()),
+        };
+    }
+    let mut contents = String::new();
+    for rel_path in trimmed {
+        contents.push_str(&rel_path.to_string_lossy());
+        contents.push('\n');
+    }
+    std::fs::write(path, contents).map_err(|e| (e, path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let temp = TempDir::new();
+        let path = temp.path().join(TRIMMED_NAME);
+        assert!(load(&path).unwrap().is_empty());
+        let trimmed: BTreeSet<_> = ["Media/a.jpg", "Media/b c.jpg"].into_iter().map(PathBuf::from).collect();
+        save(&path, &trimmed).unwrap();
+        assert_eq!(load(&path).unwrap(), trimmed);
+        save(&path, &BTreeSet::new()).unwrap();
+        assert!(!path.exists());
+    }
+}